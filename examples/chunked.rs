@@ -1,11 +1,11 @@
 use futures::prelude::*;
 use futures::stream::Stream;
-use tar_async::decode::{flat, full, raw};
+use tar_async::decode::full;
 use tokio_codec::{BytesCodec, FramedRead};
-use tokio_fs::{stderr, stdin, stdout};
+use tokio_fs::stdin;
 use tokio_threadpool::Builder;
 
-pub fn main() -> Result<(), Box<std::error::Error>> {
+pub fn main() -> Result<(), Box<dyn std::error::Error>> {
     let pool = Builder::new().pool_size(1).build();
 
     pool.spawn(futures::lazy(|| {
@@ -24,13 +24,13 @@ pub fn main() -> Result<(), Box<std::error::Error>> {
             .for_each(|item| {
                 if item.header().path().unwrap().starts_with("test/bar") {
                     eprintln!("chunked item={:?}", item.header());
-                    futures::future::Either::A(
-                        item.for_each(|chunk| Ok(println!("chunk={:?}", chunk))),
-                    )
+                    futures::future::Either::A(item.for_each(|chunk| {
+                        println!("chunk={:?}", chunk);
+                        Ok(())
+                    }))
                 } else {
-                    futures::future::Either::B(
-                        Ok(eprintln!("item={:?}", item.header())).into_future(),
-                    )
+                    eprintln!("item={:?}", item.header());
+                    futures::future::Either::B(Ok(()).into_future())
                 }
             })
             .map_err(|e| eprintln!("ERROR: {}", e))
@@ -18,8 +18,7 @@ fn main() -> Result<(), io::Error> {
     let stdout = io::stdout();
 
     for chunk_size in opt.pattern.into_iter().cycle() {
-        let mut buf = Vec::new();
-        buf.resize(chunk_size, 0);
+        let mut buf = vec![0; chunk_size];
 
         let len = stdin.lock().read(buf.as_mut())?;
         if len == 0 {
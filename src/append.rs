@@ -0,0 +1,330 @@
+//! Appending to an archive in place, like `tar -r`.
+//!
+//! [`find_end`] walks the headers of an uncompressed archive, seeking over
+//! the bodies, up to the end-of-archive marker. Checksums are verified, so
+//! that garbage is not taken for a header, and PAX `size` records honoured.
+//! [`append`] then writes new entries over the marker and ends them with a
+//! fresh one, so that only the new data is written.
+
+use crate::decode::flat::TarItem;
+use crate::decode::{raw, Error};
+use crate::encode;
+use bytes::Bytes;
+use futures::{prelude::*, try_ready};
+use std::fmt::Debug;
+use std::io::{self, Seek, SeekFrom};
+use tokio_io::{AsyncRead, AsyncWrite};
+
+const BLOCK_SIZE: u64 = 512;
+
+/// Seeking for non-blocking I/O objects, which `tokio-io` does not define.
+pub trait AsyncSeek {
+    /// Moves to `pos`, returning the new position from the start.
+    fn poll_seek(&mut self, pos: SeekFrom) -> Poll<u64, io::Error>;
+}
+
+impl AsyncSeek for tokio_fs::File {
+    fn poll_seek(&mut self, pos: SeekFrom) -> Poll<u64, io::Error> {
+        tokio_fs::File::poll_seek(self, pos)
+    }
+}
+
+/// Archive kept in memory.
+impl<T: AsRef<[u8]>> AsyncSeek for io::Cursor<T> {
+    fn poll_seek(&mut self, pos: SeekFrom) -> Poll<u64, io::Error> {
+        Ok(Async::Ready(self.seek(pos)?))
+    }
+}
+
+/// Checks a header block against its checksum: the sum of all bytes, with
+/// the checksum field counted as spaces. Sums of signed bytes, as written
+/// by some old implementations, are accepted as well.
+fn checksum_ok(header: &tar::Header) -> bool {
+    let expected = match header.cksum() {
+        Ok(cksum) => i64::from(cksum),
+        Err(_) => return false,
+    };
+    let bytes = header.as_bytes();
+    let field = 148..156;
+    let (mut unsigned, mut signed) = (0i64, 0i64);
+    for (i, b) in bytes.iter().enumerate() {
+        let b = if field.contains(&i) { b' ' } else { *b };
+        unsigned += i64::from(b);
+        signed += i64::from(b as i8);
+    }
+    expected == unsigned || expected == signed
+}
+
+/// Longest PAX header body read while looking for a `size` record.
+const MAX_PAX_BODY: u64 = 1 << 20;
+
+struct FindEnd<T> {
+    target: Option<T>,
+    /// Offset of the header block being read.
+    position: u64,
+    block: [u8; BLOCK_SIZE as usize],
+    filled: usize,
+    seeked: bool,
+    /// Body of a PAX header, read in full to learn the size of the next
+    /// entry.
+    pax: Option<Vec<u8>>,
+    sizes: raw::SizeTracker,
+}
+
+impl<T: AsyncRead + AsyncSeek> FindEnd<T> {
+    /// Reads the body of the PAX header at `position`, which directly follows
+    /// the header block.
+    fn poll_pax(&mut self) -> Poll<(), io::Error> {
+        let target = self.target.as_mut().expect("polled after completion");
+        let pax = self.pax.as_mut().unwrap();
+        while self.filled < pax.len() {
+            let n = try_ready!(target.poll_read(&mut pax[self.filled..]));
+            if n == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            self.filled += n;
+        }
+        self.sizes.chunk(pax);
+        Ok(Async::Ready(()))
+    }
+
+    /// Moves past the entry of the header in `block`.
+    fn skip(&mut self) -> io::Result<()> {
+        let mut header = tar::Header::new_old();
+        header.as_mut_bytes().copy_from_slice(&self.block);
+        if !checksum_ok(&header) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "tar header checksum mismatch",
+            ));
+        }
+        let size = self.sizes.header(&header)?;
+        if header.entry_type().is_pax_local_extensions() {
+            if size > MAX_PAX_BODY {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "pax header too large",
+                ));
+            }
+            self.pax = Some(vec![0; size as usize]);
+            self.filled = 0;
+        }
+        self.position += BLOCK_SIZE + ((size + BLOCK_SIZE - 1) & !(BLOCK_SIZE - 1));
+        Ok(())
+    }
+}
+
+impl<T: AsyncRead + AsyncSeek> Future for FindEnd<T> {
+    type Item = (T, u64);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Result<Async<(T, u64)>, io::Error> {
+        loop {
+            if self.pax.is_some() {
+                try_ready!(self.poll_pax());
+                self.pax = None;
+            }
+            let target = self.target.as_mut().expect("polled after completion");
+            if !self.seeked {
+                try_ready!(target.poll_seek(SeekFrom::Start(self.position)));
+                self.seeked = true;
+                self.filled = 0;
+            }
+            let n = try_ready!(target.poll_read(&mut self.block[self.filled..]));
+            if n == 0 {
+                if self.filled > 0 {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                // No marker at all; appending right after the last entry is
+                // what GNU tar does as well.
+                break;
+            }
+            self.filled += n;
+            if self.filled < self.block.len() {
+                continue;
+            }
+            if self.block.iter().all(|b| *b == 0) {
+                break;
+            }
+            self.skip()?;
+            self.seeked = false;
+        }
+        Ok(Async::Ready((self.target.take().unwrap(), self.position)))
+    }
+}
+
+/// Finds the offset of the end-of-archive marker of the tar in `target`,
+/// reading only the headers.
+///
+/// An archive that ends without a marker ends at the last entry.
+pub fn find_end<T>(target: T) -> impl Future<Item = (T, u64), Error = io::Error>
+where
+    T: AsyncRead + AsyncSeek,
+{
+    FindEnd {
+        target: Some(target),
+        position: 0,
+        block: [0; BLOCK_SIZE as usize],
+        filled: 0,
+        seeked: false,
+        pax: None,
+        sizes: raw::SizeTracker::default(),
+    }
+}
+
+struct WriteAt<T, B> {
+    target: Option<T>,
+    position: Option<u64>,
+    body: B,
+    chunk: Option<Bytes>,
+    flushing: bool,
+}
+
+impl<E, T, B> Future for WriteAt<T, B>
+where
+    E: Debug + Send + Sync + 'static,
+    T: AsyncWrite + AsyncSeek,
+    B: Stream<Item = Bytes, Error = Error<E>>,
+{
+    type Item = T;
+    type Error = Error<E>;
+
+    fn poll(&mut self) -> Result<Async<T>, Error<E>> {
+        loop {
+            let target = self.target.as_mut().expect("polled after completion");
+            if let Some(position) = self.position {
+                try_ready!(target
+                    .poll_seek(SeekFrom::Start(position))
+                    .map_err(Error::IoError));
+                self.position = None;
+            }
+            if self.flushing {
+                try_ready!(target.poll_flush().map_err(Error::IoError));
+                return Ok(Async::Ready(self.target.take().unwrap()));
+            }
+            match self.chunk.take() {
+                Some(mut chunk) => {
+                    let n = match target.poll_write(&chunk).map_err(Error::IoError)? {
+                        Async::Ready(n) => n,
+                        Async::NotReady => {
+                            self.chunk = Some(chunk);
+                            return Ok(Async::NotReady);
+                        }
+                    };
+                    if n == 0 {
+                        return Err(Error::IoError(io::ErrorKind::WriteZero.into()));
+                    }
+                    if n < chunk.len() {
+                        self.chunk = Some(chunk.split_off(n));
+                    }
+                }
+                None => match try_ready!(self.body.poll()) {
+                    Some(chunk) => self.chunk = Some(chunk),
+                    None => self.flushing = true,
+                },
+            }
+        }
+    }
+}
+
+/// Appends `items` to the uncompressed tar in `target`, writing over its
+/// end-of-archive marker, and resolves to `target` once everything is
+/// written and flushed.
+///
+/// Nothing before the marker is rewritten. The new marker may be followed
+/// by zeros left over from the old one; readers stop at the first.
+pub fn append<E, T, S>(target: T, items: S) -> impl Future<Item = T, Error = Error<E>>
+where
+    E: Debug + Send + Sync + 'static,
+    T: AsyncRead + AsyncWrite + AsyncSeek,
+    S: Stream<Item = TarItem, Error = Error<E>>,
+{
+    let body = encode::flat::encode_tar(items);
+    find_end(target)
+        .map_err(Error::IoError)
+        .and_then(move |(target, end)| WriteAt {
+            target: Some(target),
+            position: Some(end),
+            body,
+            chunk: None,
+            flushing: false,
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::decode::flat::TarEntry;
+    use futures::stream;
+
+    #[test]
+    fn test_append() {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(600);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, "a", &[1u8; 600][..])
+            .unwrap();
+        let tar = builder.into_inner().unwrap();
+        let end = 3 * BLOCK_SIZE;
+        let (_, found) = find_end(io::Cursor::new(tar.clone())).wait().unwrap();
+        assert_eq!(found, end);
+        // Without a marker, the archive ends after the last entry.
+        let (_, found) = find_end(io::Cursor::new(tar[..end as usize].to_vec()))
+            .wait()
+            .unwrap();
+        assert_eq!(found, end);
+
+        let mut entry = TarEntry::new(tar::EntryType::Regular, "b");
+        entry.set_size(3);
+        let items = stream::iter_ok::<_, Error<()>>(vec![
+            TarItem::Entry(entry),
+            TarItem::Chunk(Bytes::from_static(b"log")),
+        ]);
+        let target = append(io::Cursor::new(tar), items).wait().unwrap();
+
+        let appended = target.into_inner();
+        assert_eq!(appended.len() as u64, end + 4 * BLOCK_SIZE);
+        let mut archive = tar::Archive::new(appended.as_slice());
+        let entries: Vec<_> = archive
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let mut entry = entry.unwrap();
+                let mut body = Vec::new();
+                io::Read::read_to_end(&mut entry, &mut body).unwrap();
+                (entry.path_bytes().into_owned(), body.len())
+            })
+            .collect();
+        assert_eq!(entries, vec![(b"a".to_vec(), 600), (b"b".to_vec(), 3)]);
+    }
+
+    #[test]
+    fn test_find_end_pax_size() {
+        // A PAX size record overrides the size field of the next header.
+        let mut tar = Vec::new();
+        let record = b"13 size=1000\n";
+        let mut pax = tar::Header::new_ustar();
+        pax.set_entry_type(tar::EntryType::XHeader);
+        pax.set_size(record.len() as u64);
+        pax.set_cksum();
+        tar.extend_from_slice(pax.as_bytes());
+        tar.extend_from_slice(record);
+        tar.resize(2 * BLOCK_SIZE as usize, 0);
+        let mut header = tar::Header::new_ustar();
+        header.set_path("big").unwrap();
+        header.set_size(0);
+        header.set_cksum();
+        tar.extend_from_slice(header.as_bytes());
+        tar.resize((5 * BLOCK_SIZE) as usize, 1);
+        let end = tar.len() as u64;
+        tar.resize(tar.len() + 2 * BLOCK_SIZE as usize, 0);
+        let (_, found) = find_end(io::Cursor::new(tar.clone())).wait().unwrap();
+        assert_eq!(found, end);
+
+        // A damaged header is not written over.
+        tar[2 * BLOCK_SIZE as usize] ^= 1;
+        assert!(find_end(io::Cursor::new(tar)).wait().is_err());
+    }
+}
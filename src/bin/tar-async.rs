@@ -0,0 +1,481 @@
+//! Command-line front end to the library, for trying archives against the
+//! same decoding, extraction and creation code that services use.
+
+use bytes::{Bytes, BytesMut};
+use futures::{future, prelude::*, try_ready};
+use glob::Pattern;
+use std::io::{self, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use structopt::StructOpt;
+use tar_async::compare::{self, CompareOptions, Mismatch};
+use tar_async::compression::{self, Compression};
+use tar_async::create::{self, CreateOptions};
+use tar_async::decode::{flat, full};
+use tar_async::digest::Sha256;
+use tar_async::manifest::{self, Record};
+use tar_async::{unpack, Config};
+use tokio_codec::{BytesCodec, FramedRead};
+use tokio_io::AsyncWrite;
+
+type Error = tar_async::Error<io::Error>;
+type Input = Box<dyn Stream<Item = Bytes, Error = io::Error> + Send>;
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "tar-async")]
+enum Command {
+    /// Lists the entries of an archive.
+    #[structopt(name = "list")]
+    List {
+        #[structopt(flatten)]
+        archive: Archive,
+        /// Prints one JSON object per entry.
+        #[structopt(long = "json")]
+        json: bool,
+        /// Prints an mtree specification.
+        #[structopt(long = "mtree", conflicts_with = "json")]
+        mtree: bool,
+        /// Adds the SHA-256 digest of every body to JSON or mtree output.
+        #[structopt(long = "digests")]
+        digests: bool,
+    },
+    /// Extracts an archive into a directory.
+    #[structopt(name = "extract")]
+    Extract {
+        #[structopt(flatten)]
+        archive: Archive,
+        /// Directory to extract to.
+        #[structopt(
+            short = "C",
+            long = "directory",
+            default_value = ".",
+            parse(from_os_str)
+        )]
+        directory: PathBuf,
+        /// Leaves permissions to the umask.
+        #[structopt(long = "no-same-permissions")]
+        no_same_permissions: bool,
+        /// Leaves modification times at the time of extraction.
+        #[structopt(short = "m", long = "touch")]
+        touch: bool,
+    },
+    /// Archives the tree below a directory.
+    #[structopt(name = "create")]
+    Create {
+        /// Archive to write; standard output if absent or `-`. Compression is
+        /// chosen from the extension unless given.
+        #[structopt(short = "f", long = "file", parse(from_os_str))]
+        file: Option<PathBuf>,
+        /// Compresses with gzip.
+        #[structopt(short = "z", long = "gzip")]
+        gzip: bool,
+        /// Compresses with zstd.
+        #[structopt(long = "zstd")]
+        zstd: bool,
+        /// Compresses with xz.
+        #[structopt(short = "J", long = "xz")]
+        xz: bool,
+        /// Skips paths matching this glob pattern.
+        #[structopt(long = "exclude")]
+        exclude: Vec<String>,
+        /// Archives what symlinks point to.
+        #[structopt(short = "L", long = "dereference")]
+        dereference: bool,
+        /// Stays on the filesystem of the root directory.
+        #[structopt(long = "one-file-system")]
+        one_file_system: bool,
+        /// Archives the entries of this mtree specification instead, with
+        /// file bodies read from below the directory.
+        #[structopt(long = "mtree", parse(from_os_str))]
+        mtree: Option<PathBuf>,
+        /// Directory whose contents to archive.
+        #[structopt(parse(from_os_str))]
+        root: PathBuf,
+    },
+    /// Reads an archive to the end, and compares it with a directory if one
+    /// is given. Exits with status 1 on differences.
+    #[structopt(name = "verify")]
+    Verify {
+        #[structopt(flatten)]
+        archive: Archive,
+        /// Directory to compare with.
+        #[structopt(short = "C", long = "directory", parse(from_os_str))]
+        directory: Option<PathBuf>,
+        /// Ignores permission differences.
+        #[structopt(long = "no-mode")]
+        no_mode: bool,
+        /// Ignores modification time differences.
+        #[structopt(long = "no-mtime")]
+        no_mtime: bool,
+        /// Ignores owner differences.
+        #[structopt(long = "no-owner")]
+        no_owner: bool,
+    },
+    /// Writes the body of one entry to standard output.
+    #[structopt(name = "cat")]
+    Cat {
+        #[structopt(flatten)]
+        archive: Archive,
+        /// Path of the entry in the archive.
+        path: String,
+    },
+}
+
+#[derive(StructOpt, Debug)]
+struct Archive {
+    /// Archive to read, compressed or not; standard input if absent or `-`.
+    #[structopt(short = "f", long = "file", parse(from_os_str))]
+    file: Option<PathBuf>,
+}
+
+impl Archive {
+    fn open(&self) -> Input {
+        match self.file {
+            Some(ref path) if path != Path::new("-") => Box::new(
+                tokio_fs::File::open(path.clone())
+                    .map(|file| FramedRead::new(file, BytesCodec::new()))
+                    .flatten_stream()
+                    .map(BytesMut::freeze),
+            ),
+            _ => Box::new(
+                FramedRead::new(tokio_fs::stdin(), BytesCodec::new()).map(BytesMut::freeze),
+            ),
+        }
+    }
+
+    fn items(&self) -> impl Stream<Item = flat::TarItem, Error = Error> + Send {
+        flat::decode_nested(compression::decompress(self.open(), None))
+    }
+
+    fn entries(
+        &self,
+    ) -> impl Stream<
+        Item = full::Entry<impl Stream<Item = flat::TarItem, Error = Error> + Send>,
+        Error = Error,
+    > {
+        full::decode_items(self.items())
+    }
+}
+
+/// Strips what makes the same path look different in two archives.
+fn trim_path(path: &[u8]) -> &[u8] {
+    let path = path.strip_prefix(b"./").unwrap_or(path);
+    path.strip_suffix(b"/").unwrap_or(path)
+}
+
+fn describe(mismatch: &Mismatch) -> String {
+    match mismatch {
+        Mismatch::Missing => "missing".to_string(),
+        Mismatch::EntryType(archive, disk) => {
+            format!("type differs: {:?} in archive, {:?} on disk", archive, disk)
+        }
+        Mismatch::Size(archive, disk) => format!("size differs: {} != {}", archive, disk),
+        Mismatch::Mode(archive, disk) => format!("mode differs: {:o} != {:o}", archive, disk),
+        Mismatch::Mtime(archive, disk) => format!("mtime differs: {:?} != {:?}", archive, disk),
+        Mismatch::Owner(archive, disk) => format!(
+            "owner differs: {}:{} != {}:{}",
+            archive.0, archive.1, disk.0, disk.1
+        ),
+        Mismatch::LinkTarget(archive, disk) => format!(
+            "link target differs: {} != {}",
+            String::from_utf8_lossy(archive),
+            String::from_utf8_lossy(disk)
+        ),
+        Mismatch::Device(archive, disk) => {
+            format!("device differs: {:?} != {:?}", archive, disk)
+        }
+        Mismatch::Content => "contents differ".to_string(),
+        Mismatch::NotLinked => "not linked to its target".to_string(),
+    }
+}
+
+/// Compression of a new archive, from the flags or the file name.
+fn output_compression(file: Option<&Path>, gzip: bool, zstd: bool, xz: bool) -> Compression {
+    if gzip {
+        return Compression::Gzip;
+    }
+    if zstd {
+        return Compression::Zstd;
+    }
+    if xz {
+        return Compression::Xz;
+    }
+    let name = file
+        .and_then(Path::file_name)
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    if name.ends_with(".gz") || name.ends_with(".tgz") {
+        Compression::Gzip
+    } else if name.ends_with(".zst") || name.ends_with(".tzst") {
+        Compression::Zstd
+    } else if name.ends_with(".xz") || name.ends_with(".txz") {
+        Compression::Xz
+    } else {
+        Compression::None
+    }
+}
+
+enum Encoder {
+    None,
+    Gzip(flate2::write::GzEncoder<Vec<u8>>),
+    Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>),
+    Xz(xz2::write::XzEncoder<Vec<u8>>),
+}
+
+impl Encoder {
+    fn new(compression: Compression) -> io::Result<Encoder> {
+        Ok(match compression {
+            Compression::None => Encoder::None,
+            Compression::Gzip => Encoder::Gzip(flate2::write::GzEncoder::new(
+                Vec::new(),
+                flate2::Compression::default(),
+            )),
+            Compression::Zstd => Encoder::Zstd(zstd::stream::write::Encoder::new(Vec::new(), 0)?),
+            Compression::Xz => Encoder::Xz(xz2::write::XzEncoder::new(Vec::new(), 6)),
+        })
+    }
+
+    fn encode(&mut self, chunk: Bytes) -> io::Result<Bytes> {
+        let output = match self {
+            Encoder::None => return Ok(chunk),
+            Encoder::Gzip(encoder) => {
+                encoder.write_all(&chunk)?;
+                encoder.get_mut()
+            }
+            Encoder::Zstd(encoder) => {
+                encoder.write_all(&chunk)?;
+                encoder.get_mut()
+            }
+            Encoder::Xz(encoder) => {
+                encoder.write_all(&chunk)?;
+                encoder.get_mut()
+            }
+        };
+        Ok(Bytes::from(mem::take(output)))
+    }
+
+    fn finish(self) -> io::Result<Bytes> {
+        Ok(Bytes::from(match self {
+            Encoder::None => Vec::new(),
+            Encoder::Gzip(encoder) => encoder.finish()?,
+            Encoder::Zstd(encoder) => encoder.finish()?,
+            Encoder::Xz(encoder) => encoder.finish()?,
+        }))
+    }
+}
+
+struct Compress<S> {
+    upstream: S,
+    encoder: Option<Encoder>,
+}
+
+impl<S: Stream<Item = Bytes, Error = Error>> Stream for Compress<S> {
+    type Item = Bytes;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Bytes>, Error> {
+        loop {
+            let encoder = match self.encoder {
+                Some(ref mut encoder) => encoder,
+                None => return Ok(Async::Ready(None)),
+            };
+            let output = match try_ready!(self.upstream.poll()) {
+                Some(chunk) => encoder.encode(chunk),
+                None => self.encoder.take().unwrap().finish(),
+            }
+            .map_err(Error::IoError)?;
+            if !output.is_empty() {
+                return Ok(Async::Ready(Some(output)));
+            }
+        }
+    }
+}
+
+/// Writes all of `body` to `output` and flushes it.
+fn write_out<S, W>(body: S, output: W) -> impl Future<Item = (), Error = Error>
+where
+    S: Stream<Item = Bytes, Error = Error>,
+    W: AsyncWrite,
+{
+    body.fold(output, |output, chunk| {
+        tokio_io::io::write_all(output, chunk)
+            .map(|(output, _)| output)
+            .map_err(Error::IoError)
+    })
+    .and_then(|output| tokio_io::io::flush(output).map_err(Error::IoError))
+    .map(|_| ())
+}
+
+/// Runs the command; resolves to `false` if it found differences.
+fn run(command: Command) -> Box<dyn Future<Item = bool, Error = Error> + Send> {
+    match command {
+        Command::List {
+            archive,
+            json,
+            mtree,
+            digests,
+        } => {
+            if !json && !mtree {
+                let names = archive.entries().map(|entry| {
+                    let mut line = entry.header().path_bytes().to_vec();
+                    line.push(b'\n');
+                    Bytes::from(line)
+                });
+                return Box::new(write_out(names, tokio_fs::stdout()).map(|_| true));
+            }
+            let records: Box<dyn Stream<Item = Record, Error = Error> + Send> = if digests {
+                Box::new(manifest::records_with_digests(
+                    archive.items(),
+                    Sha256::new(),
+                ))
+            } else {
+                Box::new(manifest::records(archive.items()))
+            };
+            let output: Box<dyn Stream<Item = Bytes, Error = Error> + Send> = if mtree {
+                Box::new(manifest::mtree::encode(records))
+            } else {
+                Box::new(manifest::json_lines(records))
+            };
+            Box::new(write_out(output, tokio_fs::stdout()).map(|_| true))
+        }
+        Command::Extract {
+            archive,
+            directory,
+            no_same_permissions,
+            touch,
+        } => {
+            let mut config = Config::default();
+            config
+                .set_preserve_permissions(!no_same_permissions)
+                .set_preserve_mtime(!touch);
+            Box::new(unpack::unpack(archive.entries(), directory, config).map(|_| true))
+        }
+        Command::Create {
+            file,
+            gzip,
+            zstd,
+            xz,
+            exclude,
+            dereference,
+            one_file_system,
+            mtree,
+            root,
+        } => {
+            let mut options = CreateOptions::new();
+            options
+                .set_follow_symlinks(dereference)
+                .set_one_file_system(one_file_system);
+            for pattern in exclude {
+                match Pattern::new(&pattern) {
+                    Ok(pattern) => options.add_exclude(pattern),
+                    Err(e) => {
+                        let e = io::Error::new(io::ErrorKind::InvalidInput, e);
+                        return Box::new(future::err(Error::IoError(e)));
+                    }
+                };
+            }
+            let file = file.filter(|path| path != Path::new("-"));
+            let compression = output_compression(file.as_deref(), gzip, zstd, xz);
+            let encoder = match Encoder::new(compression) {
+                Ok(encoder) => encoder,
+                Err(e) => return Box::new(future::err(Error::IoError(e))),
+            };
+            let tar: Box<dyn Stream<Item = Bytes, Error = Error> + Send> = match mtree {
+                Some(spec) => Box::new(
+                    tokio_fs::read(spec)
+                        .and_then(|spec| manifest::mtree::parse(&spec))
+                        .map_err(Error::IoError)
+                        .map(|entries| create::mtree::create_from_mtree(entries, root))
+                        .flatten_stream(),
+                ),
+                None => Box::new(create::create_from_dir(root, options)),
+            };
+            let body = Compress {
+                upstream: tar,
+                encoder: Some(encoder),
+            };
+            match file {
+                Some(path) => Box::new(
+                    tokio_fs::File::create(path)
+                        .map_err(Error::IoError)
+                        .and_then(|output| write_out(body, output))
+                        .map(|_| true),
+                ),
+                None => Box::new(write_out(body, tokio_fs::stdout()).map(|_| true)),
+            }
+        }
+        Command::Verify {
+            archive,
+            directory: None,
+            ..
+        } => Box::new(
+            archive
+                .entries()
+                .for_each(|entry| entry.for_each(|_| Ok(())))
+                .map(|_| true),
+        ),
+        Command::Verify {
+            archive,
+            directory: Some(directory),
+            no_mode,
+            no_mtime,
+            no_owner,
+        } => {
+            let mut options = CompareOptions::new();
+            options
+                .set_check_mode(!no_mode)
+                .set_check_mtime(!no_mtime)
+                .set_check_owner(!no_owner);
+            let differs = Arc::new(AtomicBool::new(false));
+            let found = differs.clone();
+            let lines =
+                compare::compare(archive.entries(), directory, options).map(move |discrepancy| {
+                    found.store(true, Ordering::Relaxed);
+                    Bytes::from(format!(
+                        "{}: {}\n",
+                        discrepancy.path().display(),
+                        describe(discrepancy.mismatch())
+                    ))
+                });
+            Box::new(
+                write_out(lines, tokio_fs::stdout()).map(move |_| !differs.load(Ordering::Relaxed)),
+            )
+        }
+        Command::Cat { archive, path } => {
+            let wanted = trim_path(path.as_bytes()).to_vec();
+            Box::new(
+                archive
+                    .entries()
+                    .filter(move |entry| trim_path(entry.header().path_bytes()) == &wanted[..])
+                    .into_future()
+                    .map_err(|(e, _)| e)
+                    .and_then(move |(entry, _)| match entry {
+                        Some(entry) => future::Either::A(write_out(entry, tokio_fs::stdout())),
+                        None => future::Either::B(future::err(Error::IoError(io::Error::new(
+                            io::ErrorKind::NotFound,
+                            format!("{}: not found in archive", path),
+                        )))),
+                    })
+                    .map(|_| true),
+            )
+        }
+    }
+}
+
+fn main() {
+    let command = Command::from_args();
+    let pool = tokio_threadpool::Builder::new().build();
+    let result = pool.spawn_handle(future::lazy(move || run(command))).wait();
+    pool.shutdown_on_idle().wait().ok();
+    match result {
+        Ok(true) => (),
+        Ok(false) => process::exit(1),
+        Err(e) => {
+            eprintln!("tar-async: {}", e);
+            process::exit(2);
+        }
+    }
+}
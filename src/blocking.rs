@@ -0,0 +1,22 @@
+//! Filesystem calls that `tokio-fs` does not wrap.
+//!
+//! Same approach as `tokio-fs` itself: the call runs on the current thread
+//! pool worker after announcing it with `tokio_threadpool::blocking`.
+
+use futures::{future, prelude::*};
+use std::io;
+
+pub(crate) fn run<F, T>(f: F) -> impl Future<Item = T, Error = io::Error>
+where
+    F: FnOnce() -> io::Result<T>,
+{
+    let mut f = Some(f);
+    future::poll_fn(move || {
+        match tokio_threadpool::blocking(|| (f.take().expect("polled after completion"))()) {
+            Ok(Async::Ready(Ok(v))) => Ok(Async::Ready(v)),
+            Ok(Async::Ready(Err(e))) => Err(e),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(e) => Err(io::Error::other(e)),
+        }
+    })
+}
@@ -0,0 +1,524 @@
+//! Checking entries against a directory on disk, like `tar --diff`.
+//!
+//! Every entry is looked up under the directory the archive was, or is
+//! assumed to have been, extracted to. Metadata is read with the same
+//! blocking mechanism as extraction and file contents through `tokio-fs`, so
+//! the returned stream must run on a tokio thread pool.
+
+use crate::blocking;
+use crate::create;
+use crate::decode::{flat, full, Error};
+use crate::incremental;
+use crate::unpack::{entry_path, safe_join};
+use bytes::Bytes;
+use futures::{future, prelude::*, stream};
+use std::fmt::Debug;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Clone, Copy, Debug)]
+pub struct CompareOptions {
+    check_mode: bool,
+    check_mtime: bool,
+    check_owner: bool,
+}
+
+impl Default for CompareOptions {
+    fn default() -> Self {
+        CompareOptions {
+            check_mode: true,
+            check_mtime: true,
+            check_owner: true,
+        }
+    }
+}
+
+impl CompareOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[inline]
+    pub fn check_mode(&self) -> bool {
+        self.check_mode
+    }
+
+    #[inline]
+    pub fn set_check_mode(&mut self, check_mode: bool) -> &mut Self {
+        self.check_mode = check_mode;
+        self
+    }
+
+    #[inline]
+    pub fn check_mtime(&self) -> bool {
+        self.check_mtime
+    }
+
+    /// Compares modification times, to the second.
+    #[inline]
+    pub fn set_check_mtime(&mut self, check_mtime: bool) -> &mut Self {
+        self.check_mtime = check_mtime;
+        self
+    }
+
+    #[inline]
+    pub fn check_owner(&self) -> bool {
+        self.check_owner
+    }
+
+    /// Compares numeric uid and gid. Extraction does not change owners, so
+    /// this only makes sense for trees restored as root or by other tools.
+    #[inline]
+    pub fn set_check_owner(&mut self, check_owner: bool) -> &mut Self {
+        self.check_owner = check_owner;
+        self
+    }
+}
+
+/// How a path on disk differs from its entry, archive value first.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Mismatch {
+    /// Nothing at the path.
+    Missing,
+    EntryType(tar::EntryType, tar::EntryType),
+    Size(u64, u64),
+    Mode(u32, u32),
+    Mtime(SystemTime, SystemTime),
+    /// Uid and gid.
+    Owner((u64, u64), (u64, u64)),
+    LinkTarget(Vec<u8>, Vec<u8>),
+    Device(Option<(u32, u32)>, Option<(u32, u32)>),
+    /// A file of the same size with other bytes.
+    Content,
+    /// A hardlink whose path is not the same file as its target.
+    NotLinked,
+}
+
+/// One difference found for an entry.
+#[derive(Clone, Debug)]
+pub struct Discrepancy {
+    path: PathBuf,
+    mismatch: Mismatch,
+}
+
+impl Discrepancy {
+    /// Path on disk.
+    #[inline]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    #[inline]
+    pub fn mismatch(&self) -> &Mismatch {
+        &self.mismatch
+    }
+}
+
+/// Kinds of entries as far as comparing goes; the rest are skipped.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    File,
+    Directory,
+    Symlink,
+    Fifo,
+    Device,
+}
+
+fn kind(entry: &flat::TarEntry) -> Option<Kind> {
+    let entry_type = entry.entry_type();
+    if entry_type.is_file() {
+        Some(Kind::File)
+    } else if entry_type.is_dir() || incremental::is_dumpdir(entry) {
+        Some(Kind::Directory)
+    } else if entry_type.is_symlink() {
+        Some(Kind::Symlink)
+    } else if entry_type.is_fifo() {
+        Some(Kind::Fifo)
+    } else if entry_type.is_character_special() || entry_type.is_block_special() {
+        Some(Kind::Device)
+    } else {
+        None
+    }
+}
+
+fn seconds(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(after) => after.as_secs() as i64,
+        Err(before) => -(before.duration().as_secs() as i64),
+    }
+}
+
+/// Differences between an entry and what is on disk, described as an
+/// entry by [`create::describe`], except for file contents.
+fn compare_metadata(
+    archive: &flat::TarEntry,
+    disk: &flat::TarEntry,
+    options: CompareOptions,
+) -> Vec<Mismatch> {
+    let (archive_kind, disk_kind) = (kind(archive), kind(disk));
+    if archive_kind != disk_kind
+        || (archive_kind == Some(Kind::Device) && archive.entry_type() != disk.entry_type())
+    {
+        return vec![Mismatch::EntryType(archive.entry_type(), disk.entry_type())];
+    }
+    let mut mismatches = Vec::new();
+    match archive_kind {
+        Some(Kind::File) if archive.size() != disk.size() => {
+            mismatches.push(Mismatch::Size(archive.size(), disk.size()));
+        }
+        Some(Kind::Symlink) if archive.link_bytes() != disk.link_bytes() => {
+            mismatches.push(Mismatch::LinkTarget(
+                archive.link_bytes().unwrap_or_default().to_vec(),
+                disk.link_bytes().unwrap_or_default().to_vec(),
+            ));
+        }
+        Some(Kind::Device) if archive.device() != disk.device() => {
+            mismatches.push(Mismatch::Device(archive.device(), disk.device()));
+        }
+        _ => (),
+    }
+    // Symlink permissions are not meaningful on most systems.
+    if options.check_mode
+        && archive_kind != Some(Kind::Symlink)
+        && archive.mode() & 0o7777 != disk.mode() & 0o7777
+    {
+        mismatches.push(Mismatch::Mode(
+            archive.mode() & 0o7777,
+            disk.mode() & 0o7777,
+        ));
+    }
+    if options.check_mtime && seconds(archive.mtime()) != seconds(disk.mtime()) {
+        mismatches.push(Mismatch::Mtime(archive.mtime(), disk.mtime()));
+    }
+    if options.check_owner && (archive.uid(), archive.gid()) != (disk.uid(), disk.gid()) {
+        mismatches.push(Mismatch::Owner(
+            (archive.uid(), archive.gid()),
+            (disk.uid(), disk.gid()),
+        ));
+    }
+    mismatches
+}
+
+/// What a blocking look at the path of an entry found.
+struct Found {
+    mismatches: Vec<Mismatch>,
+    /// A file of the same size, whose contents are still to be compared.
+    same_size: bool,
+}
+
+impl Found {
+    fn mismatches(mismatches: Vec<Mismatch>) -> Self {
+        Found {
+            mismatches,
+            same_size: false,
+        }
+    }
+}
+
+fn inspect(
+    dst: &Path,
+    path: &Path,
+    header: &flat::TarEntry,
+    options: CompareOptions,
+) -> io::Result<Found> {
+    let meta = match fs::symlink_metadata(path) {
+        Ok(meta) => meta,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            return Ok(Found::mismatches(vec![Mismatch::Missing]));
+        }
+        Err(e) => return Err(e),
+    };
+    let visit = create::describe(path, &meta, Vec::new())?;
+
+    if header.entry_type().is_hard_link() {
+        let target = header
+            .link()?
+            .and_then(|link| safe_join(dst, link))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "bad hardlink target"))?;
+        let linked = match (visit, fs::symlink_metadata(&target)) {
+            (Some(visit), Ok(target_meta)) => create::describe(&target, &target_meta, Vec::new())?
+                .is_some_and(|target| target.id == visit.id),
+            _ => false,
+        };
+        return Ok(Found::mismatches(if linked {
+            Vec::new()
+        } else {
+            vec![Mismatch::NotLinked]
+        }));
+    }
+
+    let disk = match visit {
+        Some(visit) => visit.entry,
+        // A socket; report it with a type that matches nothing.
+        None => flat::TarEntry::new(tar::EntryType::new(b's'), Vec::new()),
+    };
+    let mismatches = compare_metadata(header, &disk, options);
+    let same_size = header.entry_type().is_file()
+        && header.size() > 0
+        && !matches!(
+            mismatches.first(),
+            Some(Mismatch::EntryType(..)) | Some(Mismatch::Size(..))
+        );
+    Ok(Found {
+        mismatches,
+        same_size,
+    })
+}
+
+/// Reads the file at `path` alongside the body of `entry`; `true` if they
+/// are the same bytes.
+fn same_contents<E, S>(
+    entry: full::Entry<S>,
+    path: PathBuf,
+) -> impl Future<Item = bool, Error = Error<E>>
+where
+    E: Debug + Send + Sync + 'static,
+    S: Stream<Item = flat::TarItem, Error = Error<E>>,
+{
+    tokio_fs::File::open(path)
+        .map_err(Error::IoError)
+        .and_then(|file| {
+            // The file is dropped at the first difference and the rest of the
+            // body only drained.
+            entry.fold(Some(file), |file, chunk: Bytes| {
+                let file = match file {
+                    Some(file) => file,
+                    None => return future::Either::A(future::ok(None)),
+                };
+                future::Either::B(tokio_io::io::read_exact(file, vec![0; chunk.len()]).then(
+                    move |read| {
+                        match read {
+                            Ok((file, buf)) => Ok(if buf[..] == chunk[..] {
+                                Some(file)
+                            } else {
+                                None
+                            }),
+                            // Shrunk since it was looked at.
+                            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+                            Err(e) => Err(Error::IoError(e)),
+                        }
+                    },
+                ))
+            })
+        })
+        .map(|file| file.is_some())
+}
+
+/// Compares a single entry with what is at its path under `dst`, consuming
+/// its body.
+pub fn compare_entry<E, S>(
+    entry: full::Entry<S>,
+    dst: &Path,
+    options: CompareOptions,
+) -> Box<dyn Future<Item = Vec<Discrepancy>, Error = Error<E>> + Send>
+where
+    E: Debug + Send + Sync + 'static,
+    S: Stream<Item = flat::TarItem, Error = Error<E>> + Send + 'static,
+{
+    let header = entry.header().clone();
+    if kind(&header).is_none() && !header.entry_type().is_hard_link() {
+        return Box::new(future::ok(Vec::new()));
+    }
+    let path = match entry_path(dst, &header) {
+        Ok(path) => path,
+        Err(e) => return Box::new(future::err(Error::IoError(e))),
+    };
+    let discrepancies = {
+        let path = path.clone();
+        move |mismatches: Vec<Mismatch>| {
+            mismatches
+                .into_iter()
+                .map(|mismatch| Discrepancy {
+                    path: path.clone(),
+                    mismatch,
+                })
+                .collect::<Vec<_>>()
+        }
+    };
+    let dst = dst.to_path_buf();
+    let inspected = {
+        let path = path.clone();
+        blocking::run(move || inspect(&dst, &path, &header, options)).map_err(Error::IoError)
+    };
+    Box::new(inspected.and_then(move |found| {
+        let mut mismatches = found.mismatches;
+        if !found.same_size {
+            return future::Either::A(future::ok(discrepancies(mismatches)));
+        }
+        future::Either::B(same_contents(entry, path).map(move |same| {
+            if !same {
+                mismatches.push(Mismatch::Content);
+            }
+            discrepancies(mismatches)
+        }))
+    }))
+}
+
+/// Compares every entry of `entries` with the tree under `dst`, reporting
+/// the differences as they are found.
+///
+/// Only paths in the archive are looked at; files on disk that are not in
+/// it are not reported. Entry types that extraction skips are skipped here
+/// as well.
+pub fn compare<E, S, T>(
+    entries: T,
+    dst: PathBuf,
+    options: CompareOptions,
+) -> impl Stream<Item = Discrepancy, Error = Error<E>>
+where
+    E: Debug + Send + Sync + 'static,
+    S: Stream<Item = flat::TarItem, Error = Error<E>> + Send + 'static,
+    T: Stream<Item = full::Entry<S>, Error = Error<E>>,
+{
+    entries
+        .and_then(move |entry| compare_entry(entry, &dst, options))
+        .map(stream::iter_ok)
+        .flatten()
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+    use crate::unpack::unpack;
+    use crate::Config;
+    use std::os::unix::fs::PermissionsExt;
+    use std::time::Duration;
+
+    #[test]
+    fn test_compare_metadata() {
+        let mut archive = flat::TarEntry::new(tar::EntryType::Regular, "a");
+        archive
+            .set_size(3)
+            .set_mtime(UNIX_EPOCH + Duration::from_secs(1000));
+        let mut disk = archive.clone();
+        disk.set_mtime(UNIX_EPOCH + Duration::new(1000, 500_000_000));
+        let options = CompareOptions::new();
+        assert_eq!(compare_metadata(&archive, &disk, options), vec![]);
+
+        disk.set_size(4).set_mode(0o600).set_uid(1000);
+        assert_eq!(
+            compare_metadata(&archive, &disk, options),
+            vec![
+                Mismatch::Size(3, 4),
+                Mismatch::Mode(0o644, 0o600),
+                Mismatch::Owner((0, 0), (1000, 0)),
+            ]
+        );
+        let mut lenient = CompareOptions::new();
+        lenient.set_check_mode(false).set_check_owner(false);
+        assert_eq!(
+            compare_metadata(&archive, &disk, lenient),
+            vec![Mismatch::Size(3, 4)]
+        );
+
+        let mut link = flat::TarEntry::new(tar::EntryType::Symlink, "a");
+        link.set_link_bytes(Some(b"b".to_vec()));
+        assert_eq!(
+            compare_metadata(&archive, &link, options),
+            vec![Mismatch::EntryType(
+                tar::EntryType::Regular,
+                tar::EntryType::Symlink
+            )]
+        );
+        let mut other = link.clone();
+        other.set_link_bytes(Some(b"c".to_vec())).set_mode(0o777);
+        assert_eq!(
+            compare_metadata(&link, &other, options),
+            vec![Mismatch::LinkTarget(b"b".to_vec(), b"c".to_vec())]
+        );
+    }
+
+    #[test]
+    fn test_compare() {
+        let dst = std::env::temp_dir().join(format!("tar-async-compare-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dst);
+        fs::create_dir_all(&dst).unwrap();
+
+        let header = |entry_type, size| {
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(entry_type);
+            header.set_size(size);
+            header.set_mode(if entry_type.is_dir() { 0o755 } else { 0o644 });
+            header.set_uid(0);
+            header.set_gid(0);
+            header.set_mtime(1000);
+            header
+        };
+        let mut builder = tar::Builder::new(Vec::new());
+        builder
+            .append_data(&mut header(tar::EntryType::Directory, 0), "d", io::empty())
+            .unwrap();
+        for (path, body) in &[
+            ("d/a", &b"hello"[..]),
+            ("d/b", b"world"),
+            ("d/c", b"12345678"),
+            ("d/gone", b""),
+        ] {
+            let mut file = header(tar::EntryType::Regular, body.len() as u64);
+            builder.append_data(&mut file, path, *body).unwrap();
+        }
+        for (path, target) in &[("d/link", "d/a"), ("d/link2", "d/b")] {
+            let mut link = header(tar::EntryType::Link, 0);
+            builder.append_link(&mut link, path, target).unwrap();
+        }
+        let tar = Bytes::from(builder.into_inner().unwrap());
+        let entries = || full::decode_tar(stream::once::<_, ()>(Ok(tar.clone())));
+
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime
+            .block_on(unpack(entries(), dst.clone(), Config::default()))
+            .unwrap();
+        let mut options = CompareOptions::new();
+        options.set_check_owner(false);
+        let compared = |runtime: &mut tokio::runtime::Runtime| {
+            let mut found: Vec<_> = runtime
+                .block_on(compare(entries(), dst.clone(), options).collect())
+                .unwrap()
+                .into_iter()
+                .map(|d| (d.path().strip_prefix(&dst).unwrap().to_owned(), d.mismatch))
+                .collect();
+            found.sort_by(|a, b| a.0.cmp(&b.0));
+            found
+        };
+        assert_eq!(compared(&mut runtime), vec![]);
+
+        // One byte, in place, so that the hardlink to it stays one.
+        fs::write(dst.join("d/a"), b"hellO").unwrap();
+        fs::set_permissions(dst.join("d/b"), fs::Permissions::from_mode(0o600)).unwrap();
+        fs::write(dst.join("d/c"), b"1234").unwrap();
+        fs::remove_file(dst.join("d/gone")).unwrap();
+        fs::remove_file(dst.join("d/link2")).unwrap();
+        fs::copy(dst.join("d/b"), dst.join("d/link2")).unwrap();
+        let mtime = filetime::FileTime::from_unix_time(1000, 0);
+        for path in &["d", "d/a", "d/c"] {
+            filetime::set_file_mtime(dst.join(path), mtime).unwrap();
+        }
+        let path = PathBuf::from;
+        assert_eq!(
+            compared(&mut runtime),
+            vec![
+                (path("d/a"), Mismatch::Content),
+                (path("d/b"), Mismatch::Mode(0o644, 0o600)),
+                (path("d/c"), Mismatch::Size(8, 4)),
+                (path("d/gone"), Mismatch::Missing),
+                (path("d/link2"), Mismatch::NotLinked),
+            ]
+        );
+
+        // A file that shrank after its size was checked.
+        let shrunk = dst.join("d/c");
+        let same = runtime
+            .block_on(
+                entries()
+                    .filter(|entry| entry.header().path_bytes() == b"d/c")
+                    .into_future()
+                    .map_err(|(e, _)| e)
+                    .and_then(move |(entry, _)| same_contents(entry.unwrap(), shrunk)),
+            )
+            .unwrap();
+        assert!(!same);
+        fs::remove_dir_all(&dst).unwrap();
+    }
+}
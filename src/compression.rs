@@ -0,0 +1,348 @@
+//! Streaming decompression in front of the decoders.
+
+#[cfg(any(feature = "gzip", feature = "zstd"))]
+pub mod parallel;
+
+use crate::error::Error;
+use bytes::{Bytes, BytesMut};
+use futures::{prelude::*, stream, try_ready};
+use std::fmt::Debug;
+use std::io;
+#[cfg(any(feature = "gzip", feature = "xz"))]
+use std::io::Write;
+use std::mem;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+/// Longest magic number that [`Compression::detect`] looks at.
+pub const MAGIC_LEN: usize = 6;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+    Xz,
+}
+
+impl Compression {
+    /// Guesses the compression from the first bytes of a stream.
+    ///
+    /// Returns `None` when `head` is too short to tell; anything of at least
+    /// [`MAGIC_LEN`] bytes without a known magic number is uncompressed.
+    pub fn detect(head: &[u8]) -> Option<Compression> {
+        let known = [
+            (GZIP_MAGIC, Compression::Gzip),
+            (ZSTD_MAGIC, Compression::Zstd),
+            (XZ_MAGIC, Compression::Xz),
+        ];
+        for (magic, compression) in known.iter() {
+            let len = std::cmp::min(magic.len(), head.len());
+            if head[..len] == magic[..len] {
+                if len == magic.len() {
+                    return Some(*compression);
+                }
+                return None;
+            }
+        }
+        Some(Compression::None)
+    }
+}
+
+/// Push-style decompressor: input goes in, whatever is ready comes out.
+trait Decoder: Send {
+    fn decode(&mut self, input: &[u8]) -> io::Result<Bytes>;
+    fn finish(&mut self) -> io::Result<Bytes>;
+}
+
+#[cfg(any(feature = "gzip", feature = "xz"))]
+fn take_output(buf: &mut Vec<u8>) -> Bytes {
+    Bytes::from(mem::take(buf))
+}
+
+#[cfg(feature = "gzip")]
+impl Decoder for flate2::write::MultiGzDecoder<Vec<u8>> {
+    fn decode(&mut self, input: &[u8]) -> io::Result<Bytes> {
+        self.write_all(input)?;
+        Ok(take_output(self.get_mut()))
+    }
+
+    fn finish(&mut self) -> io::Result<Bytes> {
+        self.try_finish()?;
+        Ok(take_output(self.get_mut()))
+    }
+}
+
+/// Zstandard through the raw API, which tells a finished frame from a
+/// truncated one.
+#[cfg(feature = "zstd")]
+struct ZstdDecoder {
+    raw: zstd::stream::raw::Decoder<'static>,
+    /// Whether the last frame is still incomplete.
+    in_frame: bool,
+}
+
+#[cfg(feature = "zstd")]
+impl Decoder for ZstdDecoder {
+    fn decode(&mut self, mut input: &[u8]) -> io::Result<Bytes> {
+        use zstd::stream::raw::Operation;
+
+        let mut output = Vec::new();
+        let mut buf = [0; 1 << 14];
+        loop {
+            let status = self.raw.run_on_buffers(input, &mut buf)?;
+            input = &input[status.bytes_read..];
+            output.extend_from_slice(&buf[..status.bytes_written]);
+            if status.bytes_read > 0 || status.bytes_written > 0 {
+                // `decompress_stream` returns 0 once a frame is complete
+                // and flushed.
+                self.in_frame = status.remaining != 0;
+            }
+            if input.is_empty() && status.bytes_written < buf.len() {
+                return Ok(Bytes::from(output));
+            }
+        }
+    }
+
+    fn finish(&mut self) -> io::Result<Bytes> {
+        let tail = self.decode(&[])?;
+        if self.in_frame {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "truncated zstd frame",
+            ));
+        }
+        Ok(tail)
+    }
+}
+
+#[cfg(feature = "xz")]
+impl Decoder for xz2::write::XzDecoder<Vec<u8>> {
+    fn decode(&mut self, input: &[u8]) -> io::Result<Bytes> {
+        self.write_all(input)?;
+        self.flush()?;
+        Ok(take_output(self.get_mut()))
+    }
+
+    fn finish(&mut self) -> io::Result<Bytes> {
+        Ok(Bytes::from(xz2::write::XzDecoder::finish(self)?))
+    }
+}
+
+/// Error for a compression whose feature is not enabled.
+pub(crate) fn unsupported(compression: Compression) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("{:?} decompression is not enabled", compression),
+    )
+}
+
+fn decoder(compression: Compression) -> io::Result<Option<Box<dyn Decoder>>> {
+    Ok(match compression {
+        Compression::None => None,
+        #[cfg(feature = "gzip")]
+        Compression::Gzip => Some(Box::new(flate2::write::MultiGzDecoder::new(Vec::new()))),
+        #[cfg(feature = "zstd")]
+        Compression::Zstd => Some(Box::new(ZstdDecoder {
+            raw: zstd::stream::raw::Decoder::new()?,
+            in_frame: false,
+        })),
+        #[cfg(feature = "xz")]
+        Compression::Xz => Some(Box::new(xz2::write::XzDecoder::new_multi_decoder(
+            Vec::new(),
+        ))),
+        #[allow(unreachable_patterns)]
+        other => return Err(unsupported(other)),
+    })
+}
+
+enum State {
+    /// Compression given by the caller, decoder not created yet.
+    Known(Compression),
+    /// Collecting the first bytes to recognize the format.
+    Sniffing(BytesMut),
+    Decoding(Option<Box<dyn Decoder>>),
+    Done,
+}
+
+pub struct Decompress<S> {
+    upstream: S,
+    state: State,
+}
+
+impl<E, S> Decompress<S>
+where
+    E: Debug + Send + Sync + 'static,
+    S: Stream<Item = Bytes, Error = Error<E>>,
+{
+    fn start(&mut self, compression: Compression) -> Result<(), Error<E>> {
+        self.state = State::Decoding(decoder(compression).map_err(Error::IoError)?);
+        Ok(())
+    }
+
+    fn decode(&mut self, input: Bytes) -> Result<Option<Bytes>, Error<E>> {
+        let output = match self.state {
+            State::Decoding(Some(ref mut decoder)) => {
+                decoder.decode(input.as_ref()).map_err(Error::IoError)?
+            }
+            _ => input,
+        };
+        Ok(Some(output).filter(|b| !b.is_empty()))
+    }
+}
+
+impl<E, S> Stream for Decompress<S>
+where
+    E: Debug + Send + Sync + 'static,
+    S: Stream<Item = Bytes, Error = Error<E>>,
+{
+    type Item = Bytes;
+    type Error = Error<E>;
+
+    fn poll(&mut self) -> Result<Async<Option<<Self as Stream>::Item>>, <Self as Stream>::Error> {
+        if let State::Known(compression) = self.state {
+            self.start(compression)?;
+        }
+        loop {
+            let chunk = match self.state {
+                State::Done => return Ok(Async::Ready(None)),
+                _ => try_ready!(self.upstream.poll()),
+            };
+
+            if let State::Sniffing(ref mut head) = self.state {
+                if let Some(ref chunk) = chunk {
+                    head.extend_from_slice(chunk);
+                }
+                match Compression::detect(head) {
+                    Some(compression) => {
+                        let head = mem::take(head).freeze();
+                        self.start(compression)?;
+                        if let Some(output) = self.decode(head)? {
+                            return Ok(Async::Ready(Some(output)));
+                        }
+                    }
+                    None if chunk.is_none() => {
+                        let head = mem::take(head).freeze();
+                        self.state = State::Done;
+                        return Ok(Async::Ready(Some(head).filter(|b| !b.is_empty())));
+                    }
+                    None => (),
+                }
+                if chunk.is_some() {
+                    continue;
+                }
+            }
+
+            match chunk {
+                Some(chunk) => {
+                    if let Some(output) = self.decode(chunk)? {
+                        return Ok(Async::Ready(Some(output)));
+                    }
+                }
+                None => {
+                    let tail = match mem::replace(&mut self.state, State::Done) {
+                        State::Decoding(Some(mut decoder)) => {
+                            decoder.finish().map_err(|e| match e.kind() {
+                                io::ErrorKind::UnexpectedEof => Error::UnexpectedEof,
+                                _ => Error::IoError(e),
+                            })?
+                        }
+                        _ => Bytes::new(),
+                    };
+                    return Ok(Async::Ready(Some(tail).filter(|b| !b.is_empty())));
+                }
+            }
+        }
+    }
+}
+
+/// Like [`decompress`], for streams that already fail with this crate's
+/// [`Error`]; errors are passed through instead of being wrapped again.
+pub fn decompress_items<E, S>(upstream: S, compression: Option<Compression>) -> Decompress<S>
+where
+    E: Debug + Send + Sync + 'static,
+    S: Stream<Item = Bytes, Error = Error<E>>,
+{
+    let state = match compression {
+        Some(compression) => State::Known(compression),
+        None => State::Sniffing(BytesMut::with_capacity(MAGIC_LEN)),
+    };
+    Decompress { upstream, state }
+}
+
+/// Byte stream whose errors were wrapped into this crate's [`Error`].
+pub type Wrapped<S> = stream::MapErr<S, fn(<S as Stream>::Error) -> Error<<S as Stream>::Error>>;
+
+/// Decompresses `upstream`; with `compression` set to `None` the format is
+/// detected from the magic number.
+pub fn decompress<S>(upstream: S, compression: Option<Compression>) -> Decompress<Wrapped<S>>
+where
+    S: Stream<Item = Bytes>,
+    S::Error: Debug + Send + Sync + 'static,
+{
+    decompress_items(
+        upstream.map_err(Error::UpstreamError as fn(S::Error) -> Error<S::Error>),
+        compression,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[cfg(feature = "zstd")]
+    fn chunks(data: &[u8]) -> impl Stream<Item = Bytes, Error = ()> {
+        let chunks: Vec<Bytes> = data.chunks(3).map(Bytes::from).collect();
+        stream::iter_ok(chunks)
+    }
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(Compression::detect(&[0x1f]), None);
+        assert_eq!(Compression::detect(&[0x1f, 0x8b]), Some(Compression::Gzip));
+        assert_eq!(Compression::detect(&[0x28, 0xb5, 0x2f]), None);
+        assert_eq!(Compression::detect(b"ustar"), Some(Compression::None));
+    }
+
+    #[test]
+    #[cfg(all(feature = "gzip", feature = "zstd", feature = "xz"))]
+    fn test_decompress() {
+        use std::io::Write;
+
+        let data = b"hello world, hello world, hello world".to_vec();
+
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gz.write_all(&data).unwrap();
+        let gz = gz.finish().unwrap();
+        let zst = zstd::stream::encode_all(data.as_slice(), 3).unwrap();
+        let mut xz = xz2::write::XzEncoder::new(Vec::new(), 6);
+        xz.write_all(&data).unwrap();
+        let xz = xz.finish().unwrap();
+
+        for input in &[gz, zst, xz, data.clone()] {
+            let output = decompress(chunks(input), None).concat2().wait().unwrap();
+            assert_eq!(output.as_ref(), data.as_slice());
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "zstd")]
+    fn test_truncated() {
+        let data = vec![7; 1000];
+        let zst = zstd::stream::encode_all(data.as_slice(), 3).unwrap();
+        for len in &[zst.len() / 2, zst.len() - 1] {
+            match decompress(chunks(&zst[..*len]), None).concat2().wait() {
+                Err(Error::UnexpectedEof) => (),
+                other => panic!("unexpected result: {:?}", other),
+            }
+        }
+
+        // Two whole frames are fine.
+        let output = decompress(chunks(&[zst.clone(), zst].concat()), None)
+            .concat2()
+            .wait()
+            .unwrap();
+        assert_eq!(output.len(), 2000);
+    }
+}
@@ -0,0 +1,519 @@
+//! Decompression spread over a thread pool.
+//!
+//! Some formats can be split into independently compressed units without
+//! decompressing anything: BGZF (gzip members whose extra field gives their
+//! length) and zstd (frames made of length-prefixed blocks). Each unit is
+//! decompressed as a job on an executor, and the results are handed on in
+//! input order. Only a bounded number of units is in flight at a time, and
+//! each is decompressed into memory only up to a limit; a unit that holds
+//! more is streamed through the sequential decoder in its turn.
+//!
+//! Input that cannot be split this way, such as an ordinary `.tar.gz` or
+//! `.tar.xz`, or a zstd frame larger than the split limit, falls back to
+//! [`Decompress`](super::Decompress) from that point on.
+
+use super::{decompress_items, unsupported, Compression, Decompress, Wrapped};
+use crate::error::Error;
+use bytes::{Bytes, BytesMut};
+use futures::future::Executor;
+use futures::sync::oneshot::{self, Execute, SpawnHandle};
+use futures::{prelude::*, stream, try_ready};
+use std::collections::VecDeque;
+use std::fmt::Debug;
+use std::io::{self, Read};
+
+const ZSTD_FRAME_MAGIC: u32 = 0xFD2F_B528;
+const SKIPPABLE_MASK: u32 = 0xFFFF_FFF0;
+const SKIPPABLE_MAGIC: u32 = 0x184D_2A50;
+
+#[derive(Clone, Copy, Debug)]
+pub struct ParallelOptions {
+    max_in_flight: usize,
+    max_unit_size: usize,
+    max_unit_output: usize,
+}
+
+impl Default for ParallelOptions {
+    fn default() -> Self {
+        ParallelOptions {
+            max_in_flight: 16,
+            max_unit_size: 8 << 20,
+            max_unit_output: 16 << 20,
+        }
+    }
+}
+
+impl ParallelOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[inline]
+    pub fn max_in_flight(&self) -> usize {
+        self.max_in_flight
+    }
+
+    /// Number of units being decompressed or waiting to be handed on.
+    #[inline]
+    pub fn set_max_in_flight(&mut self, max_in_flight: usize) -> &mut Self {
+        self.max_in_flight = max_in_flight.max(1);
+        self
+    }
+
+    #[inline]
+    pub fn max_unit_size(&self) -> usize {
+        self.max_unit_size
+    }
+
+    /// Largest compressed unit to buffer; a larger zstd frame switches to
+    /// sequential decompression.
+    #[inline]
+    pub fn set_max_unit_size(&mut self, max_unit_size: usize) -> &mut Self {
+        self.max_unit_size = max_unit_size;
+        self
+    }
+
+    #[inline]
+    pub fn max_unit_output(&self) -> usize {
+        self.max_unit_output
+    }
+
+    /// Largest decompressed unit to hold in memory; a zstd frame declaring
+    /// a larger content size switches to sequential decompression, and any
+    /// other unit found to be larger is decompressed sequentially on its own.
+    #[inline]
+    pub fn set_max_unit_output(&mut self, max_unit_output: usize) -> &mut Self {
+        self.max_unit_output = max_unit_output;
+        self
+    }
+}
+
+#[inline]
+fn le32(bytes: &[u8]) -> u32 {
+    u32::from(bytes[0])
+        | u32::from(bytes[1]) << 8
+        | u32::from(bytes[2]) << 16
+        | u32::from(bytes[3]) << 24
+}
+
+/// Length of the BGZF block at the start of `buf`; `Ok(None)` if more bytes
+/// are needed, `Err` if this is not a BGZF block.
+fn bgzf_block_len(buf: &[u8]) -> Result<Option<usize>, ()> {
+    if buf.len() < 12 {
+        return Ok(None);
+    }
+    if buf[..3] != [0x1f, 0x8b, 0x08] || buf[3] & 0x04 == 0 {
+        return Err(());
+    }
+    let xlen = usize::from(buf[10]) | usize::from(buf[11]) << 8;
+    if buf.len() < 12 + xlen {
+        return Ok(None);
+    }
+    let mut extra = &buf[12..12 + xlen];
+    while extra.len() >= 4 {
+        let len = usize::from(extra[2]) | usize::from(extra[3]) << 8;
+        if extra[..2] == *b"BC" && len == 2 && extra.len() >= 6 {
+            let bsize = usize::from(extra[4]) | usize::from(extra[5]) << 8;
+            let total = bsize + 1;
+            return Ok(Some(total).filter(|total| buf.len() >= *total));
+        }
+        extra = &extra[(4 + len).min(extra.len())..];
+    }
+    Err(())
+}
+
+/// Length of the zstd or skippable frame at the start of `buf`, found by
+/// walking the block headers; `Ok(None)` if more bytes are needed.
+fn zstd_frame_len(buf: &[u8]) -> Result<Option<usize>, &'static str> {
+    if buf.len() < 8 {
+        return Ok(None);
+    }
+    let magic = le32(buf);
+    if magic & SKIPPABLE_MASK == SKIPPABLE_MAGIC {
+        let total = 8 + le32(&buf[4..]) as usize;
+        return Ok(Some(total).filter(|total| buf.len() >= *total));
+    }
+    if magic != ZSTD_FRAME_MAGIC {
+        return Err("invalid zstd frame");
+    }
+    let checksum = buf[4] & 0x04 != 0;
+    let mut pos = match zstd_frame_header(buf) {
+        Some((len, _)) => len,
+        None => return Ok(None),
+    };
+    loop {
+        if buf.len() < pos + 3 {
+            return Ok(None);
+        }
+        let header =
+            u32::from(buf[pos]) | u32::from(buf[pos + 1]) << 8 | u32::from(buf[pos + 2]) << 16;
+        let size = match (header >> 1) & 0x03 {
+            // RLE: a single byte repeated.
+            1 => 1,
+            3 => return Err("invalid zstd block"),
+            _ => (header >> 3) as usize,
+        };
+        pos += 3 + size;
+        if header & 0x01 != 0 {
+            break;
+        }
+    }
+    if checksum {
+        pos += 4;
+    }
+    Ok(Some(pos).filter(|pos| buf.len() >= *pos))
+}
+
+/// Length of the header of the zstd frame at the start of `buf`, and the
+/// content size it declares; `None` if more bytes are needed.
+fn zstd_frame_header(buf: &[u8]) -> Option<(usize, Option<u64>)> {
+    let descriptor = *buf.get(4)?;
+    let single_segment = descriptor & 0x20 != 0;
+    let dictionary = [0, 1, 2, 4][usize::from(descriptor & 0x03)];
+    let content_size = match descriptor >> 6 {
+        0 if single_segment => 1,
+        0 => 0,
+        1 => 2,
+        2 => 4,
+        _ => 8,
+    };
+    let start = 5 + usize::from(!single_segment) + dictionary;
+    let field = buf.get(start..start + content_size)?;
+    let declared = field
+        .iter()
+        .rev()
+        .fold(0, |size, byte| size << 8 | u64::from(*byte));
+    let declared = match content_size {
+        0 => None,
+        // The two byte field is offset by 256.
+        2 => Some(declared + 256),
+        _ => Some(declared),
+    };
+    Some((start + content_size, declared))
+}
+
+/// Decompression of a single unit, run on the executor. Resolves to `None`
+/// if the unit holds more than the limit it was given.
+pub struct Job {
+    input: Bytes,
+    compression: Compression,
+    max_output: usize,
+}
+
+impl Future for Job {
+    type Item = Option<Bytes>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Result<Async<Option<Bytes>>, io::Error> {
+        let reader: Box<dyn Read> = match self.compression {
+            #[cfg(feature = "gzip")]
+            Compression::Gzip => Box::new(flate2::read::GzDecoder::new(self.input.as_ref())),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => Box::new(zstd::stream::read::Decoder::with_buffer(
+                self.input.as_ref(),
+            )?),
+            other => return Err(unsupported(other)),
+        };
+        let mut output = Vec::new();
+        reader
+            .take(self.max_output as u64 + 1)
+            .read_to_end(&mut output)?;
+        if output.len() > self.max_output {
+            return Ok(Async::Ready(None));
+        }
+        Ok(Async::Ready(Some(Bytes::from(output))))
+    }
+}
+
+type Sequential<E, S> = Decompress<stream::Chain<stream::Once<Bytes, Error<E>>, stream::Fuse<S>>>;
+
+enum Mode<E, S>
+where
+    E: Debug + Send + Sync + 'static,
+{
+    /// Collecting the first bytes to recognize the format.
+    Sniffing,
+    Splitting(Compression),
+    Sequential(Sequential<E, S>),
+}
+
+pub struct ParallelDecompress<E, S, X>
+where
+    E: Debug + Send + Sync + 'static,
+{
+    upstream: Option<stream::Fuse<S>>,
+    executor: X,
+    options: ParallelOptions,
+    buffer: BytesMut,
+    eof: bool,
+    /// Units in flight, with their input and format in case they turn out
+    /// too large.
+    jobs: VecDeque<(SpawnHandle<Option<Bytes>, io::Error>, Bytes, Compression)>,
+    /// A unit too large for a job, decompressed in its turn.
+    oversized: Option<Decompress<stream::Once<Bytes, Error<E>>>>,
+    mode: Mode<E, S>,
+}
+
+impl<E, S, X> ParallelDecompress<E, S, X>
+where
+    E: Debug + Send + Sync + 'static,
+    S: Stream<Item = Bytes, Error = Error<E>>,
+    X: Executor<Execute<Job>>,
+{
+    fn go_sequential(&mut self) {
+        let buffered = self.buffer.take().freeze();
+        let rest = stream::once(Ok(buffered)).chain(self.upstream.take().unwrap());
+        self.mode = Mode::Sequential(decompress_items(rest, None));
+    }
+
+    /// Cuts the next unit off the buffer, if it is complete. Returns whether
+    /// anything changed.
+    fn split(&mut self) -> Result<bool, Error<E>> {
+        let compression = match self.mode {
+            Mode::Sniffing => match Compression::detect(&self.buffer) {
+                Some(compression @ Compression::Gzip) | Some(compression @ Compression::Zstd) => {
+                    self.mode = Mode::Splitting(compression);
+                    return Ok(true);
+                }
+                Some(_) => {
+                    self.go_sequential();
+                    return Ok(true);
+                }
+                None => return Ok(false),
+            },
+            Mode::Splitting(compression) => compression,
+            Mode::Sequential(_) => return Ok(false),
+        };
+        let len = match compression {
+            Compression::Gzip => match bgzf_block_len(&self.buffer) {
+                Ok(len) => len,
+                Err(()) => {
+                    self.go_sequential();
+                    return Ok(true);
+                }
+            },
+            _ => zstd_frame_len(&self.buffer).map_err(Error::Format)?,
+        };
+        let len = match len {
+            Some(len) => len,
+            None if self.buffer.len() > self.options.max_unit_size => {
+                self.go_sequential();
+                return Ok(true);
+            }
+            None => return Ok(false),
+        };
+
+        if compression == Compression::Zstd {
+            if le32(&self.buffer) & SKIPPABLE_MASK == SKIPPABLE_MAGIC {
+                self.buffer.split_to(len);
+                return Ok(true);
+            }
+            let declared = zstd_frame_header(&self.buffer).and_then(|(_, declared)| declared);
+            if declared.is_some_and(|declared| declared > self.options.max_unit_output as u64) {
+                self.go_sequential();
+                return Ok(true);
+            }
+        }
+
+        let unit = self.buffer.split_to(len).freeze();
+        let job = Job {
+            input: unit.clone(),
+            compression,
+            max_output: self.options.max_unit_output,
+        };
+        let handle = oneshot::spawn(job, &self.executor);
+        self.jobs.push_back((handle, unit, compression));
+        Ok(true)
+    }
+
+    /// Reads and splits input until enough jobs are in flight or the
+    /// upstream has nothing more right now.
+    fn fill(&mut self) -> Result<(), Error<E>> {
+        while !self.eof && self.jobs.len() < self.options.max_in_flight {
+            if let Mode::Sequential(_) = self.mode {
+                return Ok(());
+            }
+            if self.split()? {
+                continue;
+            }
+            match self.upstream.as_mut().unwrap().poll()? {
+                Async::Ready(Some(bytes)) => self.buffer.extend_from_slice(&bytes),
+                Async::Ready(None) => {
+                    self.eof = true;
+                    if !self.buffer.is_empty() {
+                        // Let the sequential decoder deal with the rest,
+                        // including reporting it as truncated.
+                        self.go_sequential();
+                    }
+                }
+                Async::NotReady => return Ok(()),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<E, S, X> Stream for ParallelDecompress<E, S, X>
+where
+    E: Debug + Send + Sync + 'static,
+    S: Stream<Item = Bytes, Error = Error<E>>,
+    X: Executor<Execute<Job>>,
+{
+    type Item = Bytes;
+    type Error = Error<E>;
+
+    fn poll(&mut self) -> Result<Async<Option<<Self as Stream>::Item>>, <Self as Stream>::Error> {
+        loop {
+            if let Some(ref mut oversized) = self.oversized {
+                match try_ready!(oversized.poll()) {
+                    Some(output) => return Ok(Async::Ready(Some(output))),
+                    None => self.oversized = None,
+                }
+            }
+            self.fill()?;
+            if let Some((job, _, _)) = self.jobs.front_mut() {
+                let output = match job.poll().map_err(Error::IoError)? {
+                    Async::Ready(output) => output,
+                    Async::NotReady => return Ok(Async::NotReady),
+                };
+                let (_, input, compression) = self.jobs.pop_front().unwrap();
+                match output {
+                    Some(output) if !output.is_empty() => return Ok(Async::Ready(Some(output))),
+                    Some(_) => (),
+                    None => {
+                        let input = stream::once(Ok(input));
+                        self.oversized = Some(decompress_items(input, Some(compression)));
+                    }
+                }
+                continue;
+            }
+            return match self.mode {
+                Mode::Sequential(ref mut sequential) => sequential.poll(),
+                _ if self.eof => Ok(Async::Ready(None)),
+                _ => Ok(Async::NotReady),
+            };
+        }
+    }
+}
+
+/// Like [`decompress_parallel`], for streams that already fail with this
+/// crate's [`Error`].
+pub fn decompress_parallel_items<E, S, X>(
+    upstream: S,
+    executor: X,
+    options: ParallelOptions,
+) -> ParallelDecompress<E, S, X>
+where
+    E: Debug + Send + Sync + 'static,
+    S: Stream<Item = Bytes, Error = Error<E>>,
+    X: Executor<Execute<Job>>,
+{
+    ParallelDecompress {
+        upstream: Some(upstream.fuse()),
+        executor,
+        options,
+        buffer: BytesMut::new(),
+        eof: false,
+        jobs: VecDeque::new(),
+        oversized: None,
+        mode: Mode::Sniffing,
+    }
+}
+
+/// Decompresses a byte stream of autodetected format, spreading the work
+/// over `executor` where the format allows it, e.g. a
+/// `tokio_threadpool::Sender`. The output is in order and can go straight to
+/// [`flat::decode_nested`](crate::decode::flat::decode_nested).
+pub fn decompress_parallel<S, X>(
+    upstream: S,
+    executor: X,
+    options: ParallelOptions,
+) -> ParallelDecompress<S::Error, Wrapped<S>, X>
+where
+    S: Stream<Item = Bytes>,
+    S::Error: Debug + Send + Sync + 'static,
+    X: Executor<Execute<Job>>,
+{
+    decompress_parallel_items(
+        upstream.map_err(Error::UpstreamError as fn(S::Error) -> Error<S::Error>),
+        executor,
+        options,
+    )
+}
+
+#[cfg(all(test, feature = "gzip", feature = "zstd"))]
+mod test {
+    use super::*;
+    use std::io::Write;
+
+    fn bgzf_block(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::GzBuilder::new()
+            .extra(vec![b'B', b'C', 2, 0, 0, 0])
+            .write(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        let mut block = encoder.finish().unwrap();
+        let bsize = block.len() - 1;
+        block[16] = bsize as u8;
+        block[17] = (bsize >> 8) as u8;
+        block
+    }
+
+    fn run(input: Vec<u8>) -> Vec<u8> {
+        run_with(input, ParallelOptions::new())
+    }
+
+    fn run_with(input: Vec<u8>, mut options: ParallelOptions) -> Vec<u8> {
+        let pool = tokio_threadpool::ThreadPool::new();
+        options.set_max_in_flight(2);
+        // Small chunks, so that units arrive in pieces.
+        let chunks: Vec<Bytes> = input.chunks(7).map(Bytes::from).collect();
+        let output = decompress_parallel(
+            stream::iter_ok::<_, ()>(chunks),
+            pool.sender().clone(),
+            options,
+        )
+        .concat2()
+        .wait()
+        .unwrap();
+        output.to_vec()
+    }
+
+    #[test]
+    fn test_decompress_parallel() {
+        let parts: Vec<Vec<u8>> = (0..5u8).map(|i| vec![b'a' + i; 1000]).collect();
+        let expected = parts.concat();
+
+        let bgzf: Vec<u8> = parts.iter().flat_map(|part| bgzf_block(part)).collect();
+        assert_eq!(run(bgzf), expected);
+
+        let mut zstd = Vec::new();
+        for part in &parts {
+            zstd.extend(zstd::stream::encode_all(part.as_slice(), 3).unwrap());
+            // Skippable frames are dropped.
+            zstd.extend_from_slice(&[0x50, 0x2a, 0x4d, 0x18, 2, 0, 0, 0, 1, 2]);
+        }
+        assert_eq!(run(zstd.clone()), expected);
+
+        // Units larger than the output limit are decompressed sequentially:
+        // one at a time as they turn out too large, or all from the first
+        // that declares too much.
+        let mut options = ParallelOptions::new();
+        options.set_max_unit_output(999);
+        assert_eq!(run_with(zstd, options), expected);
+        let bgzf: Vec<u8> = parts.iter().flat_map(|part| bgzf_block(part)).collect();
+        assert_eq!(run_with(bgzf, options), expected);
+        let declared: Vec<u8> = parts
+            .iter()
+            .flat_map(|part| zstd::bulk::compress(part, 3).unwrap())
+            .collect();
+        assert_eq!(zstd_frame_header(&declared), Some((7, Some(1000))));
+        assert_eq!(run_with(declared, options), expected);
+
+        // Plain gzip, after a BGZF block, goes sequential.
+        let mut mixed = bgzf_block(&parts[0]);
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gz.write_all(&parts[1..].concat()).unwrap();
+        mixed.extend(gz.finish().unwrap());
+        assert_eq!(run(mixed), expected);
+    }
+}
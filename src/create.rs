@@ -0,0 +1,562 @@
+//! Archives built from a directory tree.
+//!
+//! [`walk_dir`] produces flat items for everything below a root directory,
+//! in sorted depth-first order; [`create_from_dir`] encodes them. Metadata and
+//! directory listings are read with the same blocking mechanism as
+//! extraction, file bodies through `tokio-fs`, so the returned streams must
+//! run on a tokio thread pool. [`mtree`] builds archives from a
+//! specification instead.
+
+#[cfg(feature = "json")]
+pub mod mtree;
+
+use crate::blocking;
+use crate::decode::flat::{TarEntry, TarItem};
+use crate::decode::Error;
+use crate::encode;
+use crate::source::{FileRange, FileSource, RangeSource};
+use bytes::Bytes;
+use futures::{prelude::*, try_ready};
+#[cfg(feature = "glob")]
+use glob::Pattern;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::fmt::Debug;
+use std::fs;
+use std::io;
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+#[cfg(unix)]
+use std::time::{Duration, UNIX_EPOCH};
+
+#[derive(Clone, Debug, Default)]
+pub struct CreateOptions {
+    #[cfg(feature = "glob")]
+    excludes: Vec<Pattern>,
+    follow_symlinks: bool,
+    one_file_system: bool,
+}
+
+impl CreateOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Skips files, and whole directories, whose name or path relative to
+    /// the root matches `pattern`.
+    #[cfg(feature = "glob")]
+    #[inline]
+    pub fn add_exclude(&mut self, pattern: Pattern) -> &mut Self {
+        self.excludes.push(pattern);
+        self
+    }
+
+    #[inline]
+    pub fn follow_symlinks(&self) -> bool {
+        self.follow_symlinks
+    }
+
+    /// Archives what symlinks point to instead of the links themselves.
+    #[inline]
+    pub fn set_follow_symlinks(&mut self, follow_symlinks: bool) -> &mut Self {
+        self.follow_symlinks = follow_symlinks;
+        self
+    }
+
+    #[inline]
+    pub fn one_file_system(&self) -> bool {
+        self.one_file_system
+    }
+
+    /// Stays on the filesystem of the root: mount points are archived as
+    /// empty directories.
+    #[inline]
+    pub fn set_one_file_system(&mut self, one_file_system: bool) -> &mut Self {
+        self.one_file_system = one_file_system;
+        self
+    }
+
+    /// Patterns are text, so names that are not UTF-8 are matched lossily.
+    #[cfg(feature = "glob")]
+    pub(crate) fn excluded(&self, name: &[u8], path: &[u8]) -> bool {
+        let (name, path) = (String::from_utf8_lossy(name), String::from_utf8_lossy(path));
+        self.excludes
+            .iter()
+            .any(|pattern| pattern.matches(&name) || pattern.matches(&path))
+    }
+
+    #[cfg(not(feature = "glob"))]
+    pub(crate) fn excluded(&self, _name: &[u8], _path: &[u8]) -> bool {
+        false
+    }
+}
+
+/// Raw bytes of a file name, as they go into an archive.
+#[cfg(unix)]
+pub(crate) fn os_bytes(name: &OsStr) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+
+    name.as_bytes().to_vec()
+}
+
+#[cfg(not(unix))]
+pub(crate) fn os_bytes(name: &OsStr) -> Vec<u8> {
+    name.to_string_lossy().into_owned().into_bytes()
+}
+
+/// `archive_path/name`, or just `name` below the root.
+pub(crate) fn child_path(archive_path: &[u8], name: &[u8]) -> Vec<u8> {
+    let mut path = archive_path.to_vec();
+    if !path.is_empty() {
+        path.push(b'/');
+    }
+    path.extend_from_slice(name);
+    path
+}
+
+/// What a single blocking call found out about a path.
+pub(crate) struct Visit {
+    pub(crate) entry: TarEntry,
+    /// Device and inode.
+    pub(crate) id: (u64, u64),
+    pub(crate) nlink: u64,
+    /// Later of the modification and status change times.
+    pub(crate) changed: SystemTime,
+    /// Sorted names of directory members.
+    pub(crate) children: Vec<PathBuf>,
+}
+
+#[cfg(unix)]
+fn unix_metadata(
+    meta: &fs::Metadata,
+    entry: &mut TarEntry,
+) -> io::Result<((u64, u64), u64, SystemTime)> {
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+    let file_type = meta.file_type();
+    if file_type.is_fifo() {
+        entry.set_entry_type(tar::EntryType::Fifo);
+    } else if file_type.is_char_device() || file_type.is_block_device() {
+        entry
+            .set_entry_type(if file_type.is_char_device() {
+                tar::EntryType::Char
+            } else {
+                tar::EntryType::Block
+            })
+            .set_device(Some((
+                libc::major(meta.rdev() as libc::dev_t) as u32,
+                libc::minor(meta.rdev() as libc::dev_t) as u32,
+            )));
+    }
+    entry
+        .set_mode(meta.mode() & 0o7777)
+        .set_uid(u64::from(meta.uid()))
+        .set_gid(u64::from(meta.gid()));
+    let ctime = if meta.ctime() >= 0 {
+        UNIX_EPOCH + Duration::new(meta.ctime() as u64, meta.ctime_nsec() as u32)
+    } else {
+        UNIX_EPOCH
+    };
+    Ok(((meta.dev(), meta.ino()), meta.nlink(), ctime))
+}
+
+#[cfg(not(unix))]
+fn unix_metadata(
+    meta: &fs::Metadata,
+    entry: &mut TarEntry,
+) -> io::Result<((u64, u64), u64, SystemTime)> {
+    if meta.permissions().readonly() {
+        entry.set_mode(entry.mode() & !0o222);
+    }
+    Ok(((0, 0), 1, meta.modified()?))
+}
+
+/// Describes `path` and lists it if it is a directory, unless it is on
+/// another device than `device`. `None` if it vanished or cannot be
+/// archived; a directory that cannot be read is listed as empty, as `tar`
+/// does after its warning.
+pub(crate) fn visit(
+    path: &Path,
+    archive_path: Vec<u8>,
+    follow_symlinks: bool,
+    device: Option<u64>,
+) -> io::Result<Option<Visit>> {
+    let meta = if follow_symlinks {
+        fs::metadata(path).or_else(|_| fs::symlink_metadata(path))
+    } else {
+        fs::symlink_metadata(path)
+    };
+    let meta = match meta {
+        Ok(meta) => meta,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let mut visit = match describe(path, &meta, archive_path)? {
+        Some(visit) => visit,
+        None => return Ok(None),
+    };
+    if meta.is_dir() && device.is_none_or(|device| device == visit.id.0) {
+        match fs::read_dir(path) {
+            Ok(children) => {
+                for child in children {
+                    visit.children.push(PathBuf::from(child?.file_name()));
+                }
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::PermissionDenied => (),
+            Err(e) => return Err(e),
+        }
+        visit.children.sort();
+    }
+    Ok(Some(visit))
+}
+
+/// The entry `path` would be archived as, given its metadata; `None` for
+/// sockets and other things tar cannot hold. Directory members are left out.
+pub(crate) fn describe(
+    path: &Path,
+    meta: &fs::Metadata,
+    archive_path: Vec<u8>,
+) -> io::Result<Option<Visit>> {
+    let file_type = meta.file_type();
+    let mut entry = if file_type.is_dir() {
+        TarEntry::new(tar::EntryType::Directory, archive_path)
+    } else if file_type.is_symlink() {
+        let mut entry = TarEntry::new(tar::EntryType::Symlink, archive_path);
+        entry.set_link_bytes(Some(os_bytes(fs::read_link(path)?.as_os_str())));
+        entry
+    } else {
+        TarEntry::new(tar::EntryType::Regular, archive_path)
+    };
+    let mtime = meta.modified()?;
+    entry.set_mtime(mtime);
+    let (id, nlink, ctime) = unix_metadata(meta, &mut entry)?;
+
+    if entry.entry_type() == tar::EntryType::Regular {
+        if !file_type.is_file() {
+            // Sockets and other things tar cannot hold.
+            return Ok(None);
+        }
+        entry.set_size(meta.len());
+    }
+    Ok(Some(Visit {
+        entry,
+        id,
+        nlink,
+        changed: mtime.max(ctime),
+        children: Vec::new(),
+    }))
+}
+
+/// The body of a file as described: a file that shrank, vanished or cannot
+/// be read any more is padded with zeros to its size, with a warning, as
+/// `tar` does, rather than failing the whole archive.
+pub(crate) struct FileBody {
+    path: PathBuf,
+    range: Option<FileRange>,
+    remaining: u64,
+}
+
+impl FileBody {
+    pub(crate) fn new(path: PathBuf, size: u64) -> Self {
+        FileBody {
+            range: Some(FileSource::new(&path).read_range(0, size)),
+            path,
+            remaining: size,
+        }
+    }
+}
+
+impl Stream for FileBody {
+    type Item = Bytes;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Result<Async<Option<Bytes>>, io::Error> {
+        if let Some(ref mut range) = self.range {
+            match range.poll() {
+                Ok(Async::Ready(Some(bytes))) => {
+                    self.remaining -= bytes.len() as u64;
+                    return Ok(Async::Ready(Some(bytes)));
+                }
+                Ok(Async::Ready(None)) => (),
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(e) => log::warn!(
+                    "{}: {}; padding {} bytes with zeros",
+                    self.path.display(),
+                    e,
+                    self.remaining
+                ),
+            }
+            self.range = None;
+        }
+        if self.remaining == 0 {
+            return Ok(Async::Ready(None));
+        }
+        let len = std::cmp::min(self.remaining, ZERO_CHUNK);
+        self.remaining -= len;
+        Ok(Async::Ready(Some(Bytes::from(vec![0; len as usize]))))
+    }
+}
+
+const ZERO_CHUNK: u64 = 64 * 1024;
+
+enum State {
+    Idle,
+    Visiting(Box<dyn Future<Item = Option<Visit>, Error = io::Error> + Send>),
+    Body(FileBody),
+}
+
+struct Walk<E> {
+    options: CreateOptions,
+    /// Paths still to visit, with their archive paths; the next one is last.
+    stack: Vec<(PathBuf, Vec<u8>)>,
+    state: State,
+    root_dev: Option<u64>,
+    links: HashMap<(u64, u64), Vec<u8>>,
+    dirs: HashSet<(u64, u64)>,
+    _error: PhantomData<E>,
+}
+
+impl<E> Walk<E> {
+    fn push_children(&mut self, path: &Path, archive_path: &[u8], children: Vec<PathBuf>) {
+        for name in children.into_iter().rev() {
+            let name_bytes = os_bytes(name.as_os_str());
+            let child_archive = child_path(archive_path, &name_bytes);
+            if !self.options.excluded(&name_bytes, &child_archive) {
+                self.stack.push((path.join(name), child_archive));
+            }
+        }
+    }
+
+    /// Turns a visit into the entry to emit, if any.
+    fn accept(&mut self, path: PathBuf, archive_path: Vec<u8>, visit: Visit) -> Option<TarEntry> {
+        let Visit {
+            mut entry,
+            id,
+            nlink,
+            children,
+            ..
+        } = visit;
+        let root = archive_path.is_empty();
+
+        if entry.entry_type().is_dir() {
+            let root_dev = *self.root_dev.get_or_insert(id.0);
+            let descend = !(self.options.one_file_system && id.0 != root_dev)
+                // Followed symlinks may lead back up the tree.
+                && (!self.options.follow_symlinks || self.dirs.insert(id));
+            if descend {
+                self.push_children(&path, &archive_path, children);
+            }
+            if root {
+                return None;
+            }
+            entry.set_path_bytes(child_path(&archive_path, b""));
+            return Some(entry);
+        }
+        if root {
+            return None;
+        }
+
+        if nlink > 1 {
+            if let Some(first) = self.links.get(&id) {
+                entry
+                    .set_entry_type(tar::EntryType::Link)
+                    .set_link_bytes(Some(first.clone()))
+                    .set_size(0);
+                return Some(entry);
+            }
+            self.links.insert(id, archive_path);
+        }
+        if entry.size() > 0 {
+            self.state = State::Body(FileBody::new(path, entry.size()));
+        }
+        Some(entry)
+    }
+}
+
+impl<E> Stream for Walk<E>
+where
+    E: Debug + Send + Sync + 'static,
+{
+    type Item = TarItem;
+    type Error = Error<E>;
+
+    fn poll(&mut self) -> Result<Async<Option<<Self as Stream>::Item>>, <Self as Stream>::Error> {
+        loop {
+            match self.state {
+                State::Idle => {
+                    let (path, archive_path) = match self.stack.last() {
+                        Some(next) => next.clone(),
+                        None => return Ok(Async::Ready(None)),
+                    };
+                    // The root is followed even if it is a symlink.
+                    let follow = self.options.follow_symlinks || archive_path.is_empty();
+                    let device = self.root_dev.filter(|_| self.options.one_file_system);
+                    self.state = State::Visiting(Box::new(blocking::run(move || {
+                        visit(&path, archive_path, follow, device)
+                    })));
+                }
+                State::Visiting(ref mut f) => {
+                    let visit = try_ready!(f.poll().map_err(Error::IoError));
+                    let (path, archive_path) = self.stack.pop().unwrap();
+                    self.state = State::Idle;
+                    let is_dir = |visit: &Visit| visit.entry.entry_type().is_dir();
+                    if archive_path.is_empty() && !visit.as_ref().is_some_and(is_dir) {
+                        return Err(Error::IoError(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!("{} is not a directory", path.display()),
+                        )));
+                    }
+                    if let Some(entry) = visit.and_then(|v| self.accept(path, archive_path, v)) {
+                        return Ok(Async::Ready(Some(TarItem::Entry(entry))));
+                    }
+                }
+                State::Body(ref mut body) => {
+                    match try_ready!(body.poll().map_err(Error::IoError)) {
+                        Some(bytes) => return Ok(Async::Ready(Some(TarItem::Chunk(bytes)))),
+                        None => self.state = State::Idle,
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Walks the tree below `root` and yields an entry, followed by its body,
+/// for every file, directory, symlink, hardlink, fifo and device in it.
+///
+/// The root itself is not included and paths are relative to it, with a
+/// trailing `/` on directories. Files linked more than once are stored once
+/// and then as hardlinks; sockets, and files that vanish during the walk,
+/// are skipped. Files that shrink or vanish once described are padded with
+/// zeros to their described size. Directories that cannot be read are stored empty. A root
+/// that is not a directory fails the stream.
+pub fn walk_dir<E>(
+    root: PathBuf,
+    options: CreateOptions,
+) -> impl Stream<Item = TarItem, Error = Error<E>>
+where
+    E: Debug + Send + Sync + 'static,
+{
+    Walk {
+        options,
+        stack: vec![(root, Vec::new())],
+        state: State::Idle,
+        root_dev: None,
+        links: HashMap::new(),
+        dirs: HashSet::new(),
+        _error: PhantomData,
+    }
+}
+
+/// Tar byte stream of the tree below `root`; see [`walk_dir`].
+pub fn create_from_dir<E>(
+    root: PathBuf,
+    options: CreateOptions,
+) -> impl Stream<Item = Bytes, Error = Error<E>>
+where
+    E: Debug + Send + Sync + 'static,
+{
+    encode::flat::encode_tar(walk_dir(root, options))
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+
+    #[test]
+    #[cfg(feature = "glob")]
+    fn test_create_from_dir() {
+        use std::os::unix::ffi::OsStrExt;
+        use std::os::unix::fs::PermissionsExt;
+
+        let src = std::env::temp_dir().join(format!("tar-async-create-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&src);
+        fs::create_dir_all(src.join("dir/skip")).unwrap();
+        fs::write(src.join("dir/file"), b"data").unwrap();
+        fs::write(src.join("dir/file.tmp"), b"temp").unwrap();
+        fs::hard_link(src.join("dir/file"), src.join("link")).unwrap();
+        std::os::unix::fs::symlink("dir/file", src.join("sym")).unwrap();
+        crate::unpack::make_fifo(&src.join("fifo"), 0o644).unwrap();
+        // Names and link targets are bytes, not necessarily UTF-8.
+        let odd = std::ffi::OsStr::from_bytes(b"odd\xff");
+        std::os::unix::fs::symlink(odd, src.join(odd)).unwrap();
+        // Unless running as root, this one cannot be listed.
+        fs::create_dir_all(src.join("locked/hidden")).unwrap();
+        fs::set_permissions(src.join("locked"), fs::Permissions::from_mode(0o300)).unwrap();
+
+        let mut options = CreateOptions::new();
+        options
+            .add_exclude(Pattern::new("*.tmp").unwrap())
+            .add_exclude(Pattern::new("dir/skip").unwrap());
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let tar = runtime
+            .block_on(create_from_dir::<()>(src.clone(), options.clone()).concat2())
+            .unwrap();
+        // The root must be a directory.
+        assert!(runtime
+            .block_on(create_from_dir::<()>(src.join("dir/file"), options).concat2())
+            .is_err());
+        fs::set_permissions(src.join("locked"), fs::Permissions::from_mode(0o755)).unwrap();
+        fs::remove_dir_all(&src).unwrap();
+
+        let mut archive = tar::Archive::new(tar.as_ref());
+        let entries: Vec<_> = archive
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let mut entry = entry.unwrap();
+                let mut body = String::new();
+                io::Read::read_to_string(&mut entry, &mut body).unwrap();
+                (
+                    entry.path_bytes().into_owned(),
+                    entry.header().entry_type(),
+                    entry.link_name_bytes().map(|link| link.into_owned()),
+                    body,
+                )
+            })
+            .filter(|(path, ..)| !path.starts_with(b"locked/hidden"))
+            .collect();
+        let entry = |path: &str, entry_type, link: Option<&str>, body: &str| {
+            (
+                path.as_bytes().to_vec(),
+                entry_type,
+                link.map(|link| link.as_bytes().to_vec()),
+                body.to_string(),
+            )
+        };
+        assert_eq!(
+            entries,
+            vec![
+                entry("dir/", tar::EntryType::Directory, None, ""),
+                entry("dir/file", tar::EntryType::Regular, None, "data"),
+                entry("fifo", tar::EntryType::Fifo, None, ""),
+                entry("link", tar::EntryType::Link, Some("dir/file"), ""),
+                entry("locked/", tar::EntryType::Directory, None, ""),
+                (
+                    b"odd\xff".to_vec(),
+                    tar::EntryType::Symlink,
+                    Some(b"odd\xff".to_vec()),
+                    String::new()
+                ),
+                entry("sym", tar::EntryType::Symlink, Some("dir/file"), ""),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_file_body() {
+        let src = std::env::temp_dir().join(format!("tar-async-body-{}", std::process::id()));
+        fs::write(&src, b"data").unwrap();
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        // Shrunk since it was described.
+        let body = runtime
+            .block_on(FileBody::new(src.clone(), 6).concat2())
+            .unwrap();
+        assert_eq!(&body[..], b"data\0\0");
+        fs::remove_file(&src).unwrap();
+        // Vanished since it was described.
+        let body = runtime.block_on(FileBody::new(src, 3).concat2()).unwrap();
+        assert_eq!(&body[..], b"\0\0\0");
+    }
+}
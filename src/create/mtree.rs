@@ -0,0 +1,174 @@
+//! Archives built from an `mtree` specification.
+//!
+//! Metadata comes from the specification alone, so root-owned files and
+//! device nodes can be archived without privileges or anything on disk;
+//! only file bodies are read, from their `contents` path or else from the
+//! entry path, relative to a base directory.
+
+use crate::blocking;
+use crate::decode::flat::TarItem;
+use crate::decode::Error;
+use crate::encode;
+use crate::manifest::mtree::SpecEntry;
+use crate::source::{FileSource, RangeSource};
+use bytes::Bytes;
+use futures::{future, prelude::*, stream};
+use std::fmt::Debug;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+type Items<E> = Box<dyn Stream<Item = TarItem, Error = Error<E>> + Send>;
+
+/// Path of the body of a file entry.
+fn body_path(base: &Path, spec: &SpecEntry) -> io::Result<PathBuf> {
+    match spec.contents {
+        Some(ref contents) => Ok(base.join(contents)),
+        None => {
+            let path = spec.entry.path()?;
+            Ok(base.join(path.strip_prefix("./").unwrap_or(path)))
+        }
+    }
+}
+
+/// The entry and body of one specification entry.
+fn items<E>(
+    spec: SpecEntry,
+    base: &Path,
+) -> Box<dyn Future<Item = Items<E>, Error = Error<E>> + Send>
+where
+    E: Debug + Send + Sync + 'static,
+{
+    let mut entry = spec.entry.clone();
+    // Empty files given as such need nothing on disk.
+    if !entry.entry_type().is_file() || (spec.size == Some(0) && spec.contents.is_none()) {
+        return Box::new(future::ok(
+            Box::new(stream::once(Ok(TarItem::Entry(entry)))) as Items<E>,
+        ));
+    }
+    let path = match body_path(base, &spec) {
+        Ok(path) => path,
+        Err(e) => return Box::new(future::err(Error::IoError(e))),
+    };
+    let stat_path = path.clone();
+    Box::new(
+        blocking::run(move || fs::metadata(&stat_path))
+            .map_err(Error::IoError)
+            .and_then(move |meta| {
+                if spec.size.is_some_and(|size| size != meta.len()) {
+                    return Err(Error::IoError(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "{} is {} bytes, the specification says {}",
+                            path.display(),
+                            meta.len(),
+                            spec.size.unwrap()
+                        ),
+                    )));
+                }
+                entry.set_size(meta.len());
+                let body = FileSource::new(path)
+                    .read_range(0, meta.len())
+                    .map(TarItem::Chunk)
+                    .map_err(Error::IoError);
+                Ok(Box::new(stream::once(Ok(TarItem::Entry(entry))).chain(body)) as Items<E>)
+            }),
+    )
+}
+
+/// Flat items for the entries of a specification, in its order, with
+/// file bodies read from below `base`.
+///
+/// A file whose size on disk differs from its `size` keyword fails the
+/// stream. Files with `size=0` and no `contents` need not exist.
+pub fn walk_mtree<E>(
+    entries: Vec<SpecEntry>,
+    base: PathBuf,
+) -> impl Stream<Item = TarItem, Error = Error<E>>
+where
+    E: Debug + Send + Sync + 'static,
+{
+    stream::iter_ok(entries)
+        .and_then(move |spec| items(spec, &base))
+        .flatten()
+}
+
+/// Tar byte stream of the entries of a specification; see [`walk_mtree`].
+pub fn create_from_mtree<E>(
+    entries: Vec<SpecEntry>,
+    base: PathBuf,
+) -> impl Stream<Item = Bytes, Error = Error<E>>
+where
+    E: Debug + Send + Sync + 'static,
+{
+    encode::flat::encode_tar(walk_mtree(entries, base))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::manifest::mtree::parse;
+
+    #[test]
+    fn test_create_from_mtree() {
+        let base = std::env::temp_dir().join(format!("tar-async-mtree-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&base);
+        fs::create_dir_all(base.join("build")).unwrap();
+        fs::create_dir_all(base.join("etc")).unwrap();
+        fs::write(base.join("build/sh"), b"#!/").unwrap();
+        fs::write(base.join("etc/motd"), b"hello").unwrap();
+
+        let spec = parse(
+            b"/set uid=0 gid=0 uname=root gname=root\n\
+              ./bin type=dir mode=0755\n\
+              ./bin/sh mode=04755 size=3 contents=build/sh\n\
+              ./dev/console type=char mode=0600 device=native,5,1\n\
+              ./etc/motd\n\
+              ./etc/empty size=0\n",
+        )
+        .unwrap();
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let tar = runtime
+            .block_on(create_from_mtree::<()>(spec.clone(), base.clone()).concat2())
+            .unwrap();
+
+        let mut archive = tar::Archive::new(tar.as_ref());
+        let entries: Vec<_> = archive
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let mut entry = entry.unwrap();
+                let mut body = String::new();
+                io::Read::read_to_string(&mut entry, &mut body).unwrap();
+                let header = entry.header();
+                (
+                    String::from_utf8_lossy(&entry.path_bytes()).into_owned(),
+                    header.mode().unwrap(),
+                    header.uid().unwrap(),
+                    header.device_major().ok().flatten(),
+                    body,
+                )
+            })
+            .collect();
+        let entry = |path: &str, mode, uid, major, body: &str| {
+            (path.to_string(), mode, uid, major, body.to_string())
+        };
+        assert_eq!(
+            entries,
+            vec![
+                entry("bin/", 0o755, 0, None, ""),
+                entry("bin/sh", 0o4755, 0, None, "#!/"),
+                entry("dev/console", 0o600, 0, Some(5), ""),
+                entry("etc/motd", 0o644, 0, None, "hello"),
+                entry("etc/empty", 0o644, 0, None, ""),
+            ]
+        );
+
+        // A body that does not match its declared size is refused.
+        let spec = parse(b"./etc/motd size=4\n").unwrap();
+        assert!(runtime
+            .block_on(create_from_mtree::<()>(spec, base.clone()).concat2())
+            .is_err());
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
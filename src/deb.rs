@@ -0,0 +1,346 @@
+//! Debian binary packages.
+//!
+//! A `.deb` is an `ar` archive holding `debian-binary` (the format version),
+//! `control.tar[.gz|.xz|.zst]` with the package metadata and maintainer
+//! scripts, and `data.tar[.gz|.xz|.zst]` with the files to install. The
+//! control tarball is small and read into memory; the data tarball is
+//! decoded as it streams in.
+
+use crate::compression;
+use crate::decode::ar::{self, ArItem};
+use crate::decode::hardlink::normalize_path;
+use crate::decode::{flat, full, Error};
+use bytes::{Bytes, BytesMut};
+use futures::{prelude::*, stream, try_ready};
+use std::fmt::Debug;
+
+const VERSION_MEMBER: &[u8] = b"debian-binary";
+const CONTROL_MEMBER: &[u8] = b"control.tar";
+const DATA_MEMBER: &[u8] = b"data.tar";
+const CONTROL_FILE: &str = "control";
+
+/// Contents of the control tarball.
+#[derive(Clone, Debug, Default)]
+pub struct Control {
+    fields: Vec<(String, String)>,
+    files: Vec<(String, Bytes)>,
+}
+
+impl Control {
+    fn new(files: Vec<(String, Bytes)>) -> Option<Control> {
+        let control = files.iter().find(|(name, _)| name == CONTROL_FILE)?;
+        let fields = parse_fields(&String::from_utf8_lossy(&control.1));
+        Some(Control { fields, files })
+    }
+
+    /// Value of a field of the `control` file; names are case-insensitive.
+    /// Continuation lines are joined with `\n`.
+    pub fn field(&self, name: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// All fields of the `control` file, in order.
+    pub fn fields(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.fields
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    #[inline]
+    pub fn package(&self) -> Option<&str> {
+        self.field("Package")
+    }
+
+    #[inline]
+    pub fn version(&self) -> Option<&str> {
+        self.field("Version")
+    }
+
+    #[inline]
+    pub fn architecture(&self) -> Option<&str> {
+        self.field("Architecture")
+    }
+
+    /// Raw contents of a control tarball member, e.g. `md5sums` or `postinst`.
+    pub fn file(&self, name: &str) -> Option<&Bytes> {
+        self.files
+            .iter()
+            .find(|(file, _)| file == name)
+            .map(|(_, body)| body)
+    }
+}
+
+fn parse_fields(text: &str) -> Vec<(String, String)> {
+    let mut fields: Vec<(String, String)> = Vec::new();
+    for line in text.lines() {
+        if line.starts_with(' ') || line.starts_with('\t') {
+            if let Some((_, value)) = fields.last_mut() {
+                value.push('\n');
+                value.push_str(&line[1..]);
+            }
+        } else if line.trim().is_empty() {
+            // Binary packages have a single paragraph.
+            break;
+        } else if let Some(pos) = line.find(':') {
+            let (key, value) = line.split_at(pos);
+            fields.push((key.trim().to_string(), value[1..].trim().to_string()));
+        }
+    }
+    fields
+}
+
+pub struct Deb<S> {
+    control: Control,
+    data: S,
+}
+
+impl<S> Deb<S> {
+    #[inline]
+    pub fn control(&self) -> &Control {
+        &self.control
+    }
+
+    /// Entries of the data tarball.
+    #[inline]
+    pub fn data(self) -> S {
+        self.data
+    }
+
+    #[inline]
+    pub fn into_parts(self) -> (Control, S) {
+        (self.control, self.data)
+    }
+}
+
+enum Member {
+    Version(BytesMut),
+    Control(BytesMut),
+    Other,
+}
+
+/// Reads the `ar` members up to the data tarball.
+struct ReadHead<S> {
+    items: Option<S>,
+    member: Member,
+    version: bool,
+    control: Option<Bytes>,
+}
+
+impl<E, S> ReadHead<S>
+where
+    E: Debug + Send + Sync + 'static,
+    S: Stream<Item = ArItem, Error = Error<E>>,
+{
+    fn finish_member(&mut self) -> Result<(), Error<E>> {
+        match std::mem::replace(&mut self.member, Member::Other) {
+            Member::Version(version) => {
+                if !version.starts_with(b"2.") {
+                    return Err(Error::Format("unsupported deb format version"));
+                }
+                self.version = true;
+            }
+            Member::Control(control) => self.control = Some(control.freeze()),
+            Member::Other => (),
+        }
+        Ok(())
+    }
+}
+
+impl<E, S> Future for ReadHead<S>
+where
+    E: Debug + Send + Sync + 'static,
+    S: Stream<Item = ArItem, Error = Error<E>>,
+{
+    type Item = (Bytes, S);
+    type Error = Error<E>;
+
+    fn poll(&mut self) -> Result<Async<Self::Item>, Self::Error> {
+        loop {
+            let item = try_ready!(self.items.as_mut().unwrap().poll());
+            match item {
+                Some(ArItem::Chunk(bytes)) => match self.member {
+                    Member::Version(ref mut buf) | Member::Control(ref mut buf) => {
+                        buf.extend_from_slice(&bytes)
+                    }
+                    Member::Other => (),
+                },
+                Some(ArItem::Header(header)) => {
+                    self.finish_member()?;
+                    let name = header.name();
+                    if name == VERSION_MEMBER {
+                        self.member = Member::Version(BytesMut::new());
+                    } else if name.starts_with(CONTROL_MEMBER) {
+                        self.member = Member::Control(BytesMut::new());
+                    } else if name.starts_with(DATA_MEMBER) {
+                        if !self.version {
+                            return Err(Error::Format("not a debian package"));
+                        }
+                        let control = self
+                            .control
+                            .take()
+                            .ok_or(Error::Format("control.tar missing from package"))?;
+                        return Ok(Async::Ready((control, self.items.take().unwrap())));
+                    }
+                }
+                None => return Err(Error::Format("data.tar missing from package")),
+            }
+        }
+    }
+}
+
+/// Body of the current `ar` member.
+struct MemberBody<S> {
+    items: S,
+    done: bool,
+}
+
+impl<E, S> Stream for MemberBody<S>
+where
+    E: Debug + Send + Sync + 'static,
+    S: Stream<Item = ArItem, Error = Error<E>>,
+{
+    type Item = Bytes;
+    type Error = Error<E>;
+
+    fn poll(&mut self) -> Result<Async<Option<<Self as Stream>::Item>>, <Self as Stream>::Error> {
+        if self.done {
+            return Ok(Async::Ready(None));
+        }
+        match try_ready!(self.items.poll()) {
+            Some(ArItem::Chunk(bytes)) => Ok(Async::Ready(Some(bytes))),
+            // Signatures may follow the data tarball.
+            Some(ArItem::Header(_)) | None => {
+                self.done = true;
+                Ok(Async::Ready(None))
+            }
+        }
+    }
+}
+
+fn read_control<E>(tar: Bytes) -> impl Future<Item = Control, Error = Error<E>>
+where
+    E: Debug + Send + Sync + 'static,
+{
+    let tar = compression::decompress_items(stream::once(Ok(tar)), None);
+    full::decode_items(flat::decode_nested(tar))
+        .filter(|entry| entry.header().entry_type().is_file())
+        .and_then(|entry| {
+            let name =
+                String::from_utf8_lossy(normalize_path(entry.header().path_bytes())).into_owned();
+            entry.concat2().map(move |body| (name, body))
+        })
+        .collect()
+        .and_then(|files| {
+            Control::new(files).ok_or(Error::Format("control file missing from package"))
+        })
+}
+
+/// Reads the control data of a package given its `ar` items; the data
+/// tarball is left in the stream for the returned [`Deb`].
+pub fn deb_items<E, S>(
+    items: S,
+) -> impl Future<Item = Deb<impl Stream<Item = flat::TarItem, Error = Error<E>>>, Error = Error<E>>
+where
+    E: Debug + Send + Sync + 'static,
+    S: Stream<Item = ArItem, Error = Error<E>>,
+{
+    ReadHead {
+        items: Some(items),
+        member: Member::Other,
+        version: false,
+        control: None,
+    }
+    .and_then(|(control, items)| {
+        read_control(control).map(move |control| {
+            let body = MemberBody { items, done: false };
+            Deb {
+                control,
+                data: flat::decode_nested(compression::decompress_items(body, None)),
+            }
+        })
+    })
+}
+
+/// Reads a `.deb` package from a byte stream.
+pub fn decode_deb<DebStream: Stream<Item = Bytes>>(
+    upstream: DebStream,
+) -> impl Future<
+    Item = Deb<impl Stream<Item = flat::TarItem, Error = Error<DebStream::Error>>>,
+    Error = Error<DebStream::Error>,
+>
+where
+    DebStream::Error: Debug + Sync + Send + 'static,
+{
+    deb_items(ar::decode_ar(upstream))
+}
+
+#[cfg(all(test, feature = "gzip", feature = "xz"))]
+mod test {
+    use super::*;
+    use std::io::Write;
+
+    fn tar(path: &str, data: &[u8]) -> Vec<u8> {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mtime(0);
+        header.set_cksum();
+        let mut builder = tar::Builder::new(Vec::new());
+        builder.append_data(&mut header, path, data).unwrap();
+        builder.into_inner().unwrap()
+    }
+
+    fn ar_member(out: &mut Vec<u8>, name: &str, data: &[u8]) {
+        let header = format!(
+            "{:<16}{:<12}{:<6}{:<6}{:<8}{:<10}`\n",
+            name,
+            0,
+            0,
+            0,
+            100_644,
+            data.len()
+        );
+        out.extend_from_slice(header.as_bytes());
+        out.extend_from_slice(data);
+        if data.len() % 2 == 1 {
+            out.push(b'\n');
+        }
+    }
+
+    #[test]
+    fn test_decode_deb() {
+        let control = b"Package: hello\nVersion: 1.0-1\nDescription: greeter\n more text\n";
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gz.write_all(&tar("./control", control)).unwrap();
+        let mut xz = xz2::write::XzEncoder::new(Vec::new(), 6);
+        xz.write_all(&tar("./usr/bin/hello", b"#!/bin/sh\n"))
+            .unwrap();
+
+        let mut deb = b"!<arch>\n".to_vec();
+        ar_member(&mut deb, "debian-binary", b"2.0\n");
+        ar_member(&mut deb, "control.tar.gz", &gz.finish().unwrap());
+        ar_member(&mut deb, "data.tar.xz", &xz.finish().unwrap());
+
+        let (control, data) = decode_deb(stream::once::<_, ()>(Ok(Bytes::from(deb))))
+            .wait()
+            .unwrap()
+            .into_parts();
+        assert_eq!(control.package(), Some("hello"));
+        assert_eq!(control.version(), Some("1.0-1"));
+        assert_eq!(control.field("description"), Some("greeter\nmore text"));
+
+        let items = data.collect().wait().unwrap();
+        match (&items[0], &items[1]) {
+            (flat::TarItem::Entry(entry), flat::TarItem::Chunk(body)) => {
+                assert_eq!(entry.path_bytes(), b"usr/bin/hello");
+                assert_eq!(body.as_ref(), b"#!/bin/sh\n");
+            }
+            other => panic!("unexpected items: {:?}", other),
+        }
+    }
+}
@@ -1,8 +1,12 @@
 mod pax;
 
+pub mod ar;
+pub mod cpio;
 pub mod flat;
 pub mod full;
+pub mod hardlink;
 pub mod raw;
+mod reader;
 mod time;
 
 pub use super::error::Error;
@@ -0,0 +1,274 @@
+//! Streaming `ar` archive decoder.
+//!
+//! Understands the common variant plus GNU (`//` long name table, `name/`)
+//! and BSD (`#1/<len>` names stored in front of the data) extensions.
+
+use super::reader::ByteReader;
+use super::Error;
+use bytes::Bytes;
+use futures::{prelude::*, try_ready};
+
+const MAGIC: &[u8] = b"!<arch>\n";
+const HEADER_SIZE: usize = 60;
+const BSD_NAME_PREFIX: &[u8] = b"#1/";
+const GNU_NAME_TABLE: &[u8] = b"//";
+
+#[derive(Clone, Debug)]
+pub struct ArHeader {
+    name: Vec<u8>,
+    mtime: u64,
+    uid: u32,
+    gid: u32,
+    mode: u32,
+    size: u64,
+}
+
+impl ArHeader {
+    #[inline]
+    pub fn name(&self) -> &[u8] {
+        &self.name
+    }
+
+    #[inline]
+    pub fn mtime(&self) -> u64 {
+        self.mtime
+    }
+
+    #[inline]
+    pub fn uid(&self) -> u32 {
+        self.uid
+    }
+
+    #[inline]
+    pub fn gid(&self) -> u32 {
+        self.gid
+    }
+
+    #[inline]
+    pub fn mode(&self) -> u32 {
+        self.mode
+    }
+
+    /// Size of the member data, excluding a BSD name stored in front of it.
+    #[inline]
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+#[derive(Debug)]
+pub enum ArItem {
+    Header(ArHeader),
+    Chunk(Bytes),
+}
+
+fn field(bytes: &[u8]) -> &[u8] {
+    let end = bytes
+        .iter()
+        .rposition(|b| *b != b' ' && *b != 0)
+        .map_or(0, |pos| pos + 1);
+    &bytes[..end]
+}
+
+fn number(bytes: &[u8], radix: u32) -> Option<u64> {
+    let field = field(bytes);
+    if field.is_empty() {
+        return Some(0);
+    }
+    u64::from_str_radix(std::str::from_utf8(field).ok()?, radix).ok()
+}
+
+struct ArStream<Upstream> {
+    reader: ByteReader<Upstream>,
+    started: bool,
+    in_entry: u64,
+    padding: bool,
+    long_names: Option<Bytes>,
+    /// Header whose name is still being read.
+    pending: Option<ArHeader>,
+}
+
+impl<Upstream: Stream<Item = Bytes>> ArStream<Upstream>
+where
+    Upstream::Error: std::fmt::Debug + Sync + Send + 'static,
+{
+    fn new(upstream: Upstream) -> Self {
+        ArStream {
+            reader: ByteReader::new(upstream),
+            started: false,
+            in_entry: 0,
+            padding: false,
+            long_names: None,
+            pending: None,
+        }
+    }
+
+    fn long_name(&self, offset: &[u8]) -> Result<Vec<u8>, Error<Upstream::Error>> {
+        let table = self
+            .long_names
+            .as_ref()
+            .ok_or(Error::Format("ar long name without a name table"))?;
+        let offset = number(offset, 10)
+            .filter(|offset| *offset < table.len() as u64)
+            .ok_or(Error::Format("invalid ar long name offset"))? as usize;
+        let name = &table[offset..];
+        let end = name
+            .windows(2)
+            .position(|w| w == b"/\n")
+            .or_else(|| name.iter().position(|b| *b == b'\n'))
+            .unwrap_or(name.len());
+        Ok(name[..end].to_vec())
+    }
+
+    fn parse_header(&self, block: &[u8]) -> Result<ArHeader, Error<Upstream::Error>> {
+        if &block[58..60] != b"`\n" {
+            return Err(Error::Format("invalid ar member header"));
+        }
+        let invalid = || Error::Format("invalid number in ar member header");
+        let mut name = field(&block[..16]).to_vec();
+        if name.len() > 1 && name[0] == b'/' && name[1..].iter().all(u8::is_ascii_digit) {
+            name = self.long_name(&name[1..])?;
+        } else if name.len() > 1 && name.ends_with(b"/") && name != GNU_NAME_TABLE {
+            name.pop();
+        }
+        Ok(ArHeader {
+            name,
+            mtime: number(&block[16..28], 10).ok_or_else(invalid)?,
+            uid: number(&block[28..34], 10).ok_or_else(invalid)? as u32,
+            gid: number(&block[34..40], 10).ok_or_else(invalid)? as u32,
+            mode: number(&block[40..48], 8).ok_or_else(invalid)? as u32,
+            size: number(&block[48..58], 10).ok_or_else(invalid)?,
+        })
+    }
+}
+
+impl<Upstream: Stream<Item = Bytes>> Stream for ArStream<Upstream>
+where
+    Upstream::Error: std::fmt::Debug + Sync + Send + 'static,
+{
+    type Item = ArItem;
+    type Error = Error<Upstream::Error>;
+
+    fn poll(&mut self) -> Result<Async<Option<<Self as Stream>::Item>>, <Self as Stream>::Error> {
+        if !self.started {
+            match try_ready!(self.reader.fetch_exact(MAGIC.len())) {
+                Some(ref magic) if magic.as_ref() == MAGIC => self.started = true,
+                Some(_) => return Err(Error::Format("not an ar archive")),
+                None => return Err(Error::UnexpectedEof),
+            }
+        }
+        loop {
+            if self.in_entry > 0 {
+                let bytes = try_ready!(self.reader.fetch_upto(self.in_entry));
+                self.in_entry -= bytes.len() as u64;
+                return Ok(Async::Ready(Some(ArItem::Chunk(bytes))));
+            }
+            if self.pending.is_none() {
+                if self.padding {
+                    // Some writers leave out the padding of the last member.
+                    try_ready!(self.reader.fetch_exact(1));
+                    self.padding = false;
+                }
+                let block = match try_ready!(self.reader.fetch_exact(HEADER_SIZE)) {
+                    Some(block) => block,
+                    None => return Ok(Async::Ready(None)),
+                };
+                let header = self.parse_header(&block)?;
+                self.padding = header.size % 2 == 1;
+                self.pending = Some(header);
+            }
+            let mut header = self.pending.take().unwrap();
+
+            if header.name == GNU_NAME_TABLE {
+                match self.reader.fetch_exact(header.size as usize)? {
+                    Async::Ready(Some(table)) => self.long_names = Some(table),
+                    Async::Ready(None) => return Err(Error::UnexpectedEof),
+                    Async::NotReady => {
+                        self.pending = Some(header);
+                        return Ok(Async::NotReady);
+                    }
+                }
+                continue;
+            }
+            if header.name.starts_with(BSD_NAME_PREFIX) {
+                let len = number(&header.name[BSD_NAME_PREFIX.len()..], 10)
+                    .filter(|len| *len <= header.size)
+                    .ok_or(Error::Format("invalid ar BSD name length"))?;
+                let name = match self.reader.fetch_exact(len as usize)? {
+                    Async::Ready(name) => name.ok_or(Error::UnexpectedEof)?,
+                    Async::NotReady => {
+                        self.pending = Some(header);
+                        return Ok(Async::NotReady);
+                    }
+                };
+                header.name = field(&name).to_vec();
+                header.size -= len;
+            }
+            self.in_entry = header.size;
+            return Ok(Async::Ready(Some(ArItem::Header(header))));
+        }
+    }
+}
+
+pub fn decode_ar<ArStreamT: Stream<Item = Bytes>>(
+    upstream: ArStreamT,
+) -> impl Stream<Item = ArItem, Error = Error<ArStreamT::Error>>
+where
+    ArStreamT::Error: std::fmt::Debug + Sync + Send + 'static,
+{
+    ArStream::new(upstream)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::stream;
+
+    fn member(name: &str, data: &[u8]) -> Vec<u8> {
+        let mut out = format!(
+            "{:<16}{:<12}{:<6}{:<6}{:<8}{:<10}`\n",
+            name,
+            0,
+            0,
+            0,
+            644,
+            data.len()
+        )
+        .into_bytes();
+        out.extend_from_slice(data);
+        if data.len() % 2 == 1 {
+            out.push(b'\n');
+        }
+        out
+    }
+
+    #[test]
+    fn test_decode() {
+        let mut ar = MAGIC.to_vec();
+        ar.extend(member("//", b"a_rather_long_member_name/\n"));
+        ar.extend(member("short/", b"abc"));
+        ar.extend(member("/0", b"long"));
+        ar.extend(member("#1/4", b"bsd!body"));
+        let chunks: Vec<Bytes> = ar.chunks(7).map(Bytes::from).collect();
+
+        let items = decode_ar(stream::iter_ok::<_, ()>(chunks))
+            .collect()
+            .wait()
+            .unwrap();
+        let mut members = Vec::new();
+        for item in items {
+            match item {
+                ArItem::Header(header) => members.push((header.name().to_vec(), Vec::new())),
+                ArItem::Chunk(bytes) => members.last_mut().unwrap().1.extend_from_slice(&bytes),
+            }
+        }
+        assert_eq!(
+            members,
+            vec![
+                (b"short".to_vec(), b"abc".to_vec()),
+                (b"a_rather_long_member_name".to_vec(), b"long".to_vec()),
+                (b"bsd!".to_vec(), b"body".to_vec()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,546 @@
+//! Streaming cpio decoder producing the same items as [`flat`](super::flat).
+//!
+//! Supports the portable ASCII formats: `newc` (`070701`, also used with
+//! checksums as `070702`) as found in initramfs images and RPM payloads, and
+//! `odc` (`070707`). Symlink targets, stored as the body in cpio, become the
+//! entry's link. In `newc` the data of a hard-linked file is stored with its
+//! last link only; the earlier links are held back and emitted as
+//! [`tar::EntryType::Link`] entries right after it, so the output follows
+//! tar's convention of data first. Sockets have no tar counterpart and are
+//! skipped. Archives concatenated after a trailer, as in initramfs images,
+//! are read on, with any zero padding between them.
+
+use super::flat::{TarEntry, TarItem};
+use super::reader::ByteReader;
+use super::Error;
+use bytes::Bytes;
+use futures::{prelude::*, try_ready};
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, UNIX_EPOCH};
+
+const MAGIC_LEN: usize = 6;
+const NEWC_MAGIC: &[u8] = b"070701";
+const NEWC_CRC_MAGIC: &[u8] = b"070702";
+const ODC_MAGIC: &[u8] = b"070707";
+const NEWC_HEADER_SIZE: usize = 110;
+const ODC_HEADER_SIZE: usize = 76;
+const TRAILER: &[u8] = b"TRAILER!!!";
+/// Longest entry name read into memory, `PATH_MAX` on Linux.
+const MAX_NAME: usize = 4096;
+/// Longest symlink target read into memory.
+const MAX_LINK_TARGET: u64 = 1 << 16;
+
+const S_IFMT: u32 = 0o170_000;
+const S_IFSOCK: u32 = 0o140_000;
+const S_IFLNK: u32 = 0o120_000;
+const S_IFREG: u32 = 0o100_000;
+const S_IFBLK: u32 = 0o060_000;
+const S_IFDIR: u32 = 0o040_000;
+const S_IFCHR: u32 = 0o020_000;
+const S_IFIFO: u32 = 0o010_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Format {
+    Newc,
+    Odc,
+}
+
+impl Format {
+    fn header_size(self) -> usize {
+        match self {
+            Format::Newc => NEWC_HEADER_SIZE,
+            Format::Odc => ODC_HEADER_SIZE,
+        }
+    }
+
+    /// Padding after a header plus name, or after a body.
+    fn padding(self, len: u64) -> usize {
+        match self {
+            Format::Newc => ((4 - len % 4) % 4) as usize,
+            Format::Odc => 0,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Fields {
+    dev: (u32, u32),
+    ino: u64,
+    mode: u32,
+    uid: u64,
+    gid: u64,
+    nlink: u64,
+    mtime: u64,
+    size: u64,
+    rdev: (u32, u32),
+    name_size: usize,
+}
+
+struct Cursor<'a>(&'a [u8]);
+
+impl<'a> Cursor<'a> {
+    fn next(&mut self, len: usize, radix: u32) -> Option<u64> {
+        let (field, rest) = self.0.split_at(len);
+        self.0 = rest;
+        u64::from_str_radix(std::str::from_utf8(field).ok()?, radix).ok()
+    }
+}
+
+fn parse_fields(format: Format, header: &[u8]) -> Option<Fields> {
+    let mut c = Cursor(header);
+    Some(match format {
+        Format::Newc => {
+            let ino = c.next(8, 16)?;
+            let mode = c.next(8, 16)? as u32;
+            let uid = c.next(8, 16)?;
+            let gid = c.next(8, 16)?;
+            let nlink = c.next(8, 16)?;
+            let mtime = c.next(8, 16)?;
+            let size = c.next(8, 16)?;
+            let dev = (c.next(8, 16)? as u32, c.next(8, 16)? as u32);
+            let rdev = (c.next(8, 16)? as u32, c.next(8, 16)? as u32);
+            let name_size = c.next(8, 16)? as usize;
+            Fields {
+                dev,
+                ino,
+                mode,
+                uid,
+                gid,
+                nlink,
+                mtime,
+                size,
+                rdev,
+                name_size,
+            }
+        }
+        Format::Odc => {
+            let dev = c.next(6, 8)?;
+            let ino = c.next(6, 8)?;
+            let mode = c.next(6, 8)? as u32;
+            let uid = c.next(6, 8)?;
+            let gid = c.next(6, 8)?;
+            let nlink = c.next(6, 8)?;
+            let rdev = c.next(6, 8)?;
+            let mtime = c.next(11, 8)?;
+            let name_size = c.next(6, 8)? as usize;
+            let size = c.next(11, 8)?;
+            let split = |dev: u64| ((dev >> 8) as u32, (dev & 0xff) as u32);
+            Fields {
+                dev: split(dev),
+                ino,
+                mode,
+                uid,
+                gid,
+                nlink,
+                mtime,
+                size,
+                rdev: split(rdev),
+                name_size,
+            }
+        }
+    })
+}
+
+fn entry_type(mode: u32) -> Option<tar::EntryType> {
+    Some(match mode & S_IFMT {
+        S_IFREG => tar::EntryType::Regular,
+        S_IFDIR => tar::EntryType::Directory,
+        S_IFLNK => tar::EntryType::Symlink,
+        S_IFCHR => tar::EntryType::Char,
+        S_IFBLK => tar::EntryType::Block,
+        S_IFIFO => tar::EntryType::Fifo,
+        _ => return None,
+    })
+}
+
+enum State {
+    Magic,
+    Header(Format),
+    Name(Format, Fields),
+    LinkTarget(Format, TarEntry, u64),
+    /// Bytes of the body left, and padding after it.
+    Data(u64, usize),
+    /// Like `Data`, for a body that is dropped.
+    Skip(u64, usize),
+    Padding(usize),
+    /// After a trailer: zero padding, then another archive or the end.
+    Trailer,
+    Done,
+}
+
+struct CpioStream<Upstream> {
+    reader: ByteReader<Upstream>,
+    state: State,
+    pending: VecDeque<TarItem>,
+    /// Earlier links of `newc` hard-linked files, by device and inode.
+    links: HashMap<((u32, u32), u64), Vec<TarEntry>>,
+    /// Links to emit once the current body is done, with their target.
+    after_body: Option<(Vec<u8>, Vec<TarEntry>)>,
+}
+
+impl<Upstream: Stream<Item = Bytes>> CpioStream<Upstream>
+where
+    Upstream::Error: std::fmt::Debug + Sync + Send + 'static,
+{
+    fn new(upstream: Upstream) -> Self {
+        CpioStream {
+            reader: ByteReader::new(upstream),
+            state: State::Magic,
+            pending: VecDeque::new(),
+            links: HashMap::new(),
+            after_body: None,
+        }
+    }
+
+    fn link_to(target: &[u8], mut link: TarEntry) -> TarItem {
+        link.set_entry_type(tar::EntryType::Link)
+            .set_link_bytes(Some(target.to_vec()))
+            .set_size(0);
+        TarItem::Entry(link)
+    }
+
+    /// Handles a complete header; returns the entry to emit, if any.
+    fn on_entry(
+        &mut self,
+        format: Format,
+        fields: Fields,
+        name: &[u8],
+    ) -> Result<Option<TarEntry>, Error<Upstream::Error>> {
+        if fields.mode & S_IFMT == S_IFSOCK {
+            self.state = State::Skip(fields.size, format.padding(fields.size));
+            return Ok(None);
+        }
+        let entry_type =
+            entry_type(fields.mode).ok_or(Error::Format("unsupported cpio file type"))?;
+        let mut entry = TarEntry::new(entry_type, name);
+        entry
+            .set_mode(fields.mode & 0o7777)
+            .set_uid(fields.uid)
+            .set_gid(fields.gid)
+            .set_mtime(UNIX_EPOCH + Duration::from_secs(fields.mtime));
+        if entry_type.is_character_special() || entry_type.is_block_special() {
+            entry.set_device(Some(fields.rdev));
+        }
+
+        if entry_type.is_symlink() {
+            if fields.size > MAX_LINK_TARGET {
+                return Err(Error::Format("cpio symlink target too long"));
+            }
+            self.state = State::LinkTarget(format, entry, fields.size);
+            return Ok(None);
+        }
+        entry.set_size(fields.size);
+        if entry_type.is_file() && format == Format::Newc && fields.nlink > 1 {
+            let key = (fields.dev, fields.ino);
+            if fields.size == 0 {
+                self.links.entry(key).or_default().push(entry);
+                self.state = State::Magic;
+                return Ok(None);
+            }
+            if let Some(links) = self.links.remove(&key) {
+                self.after_body = Some((entry.path_bytes().to_vec(), links));
+            }
+        }
+        self.state = State::Data(entry.size(), format.padding(entry.size()));
+        Ok(Some(entry))
+    }
+
+    /// Queues links whose data never showed up: the first becomes an empty
+    /// file, the rest link to it.
+    fn flush_links(&mut self) {
+        let mut groups: Vec<_> = self.links.drain().map(|(_, links)| links).collect();
+        groups.sort_by(|a, b| a[0].path_bytes().cmp(b[0].path_bytes()));
+        for mut links in groups {
+            let first = links.remove(0);
+            let target = first.path_bytes().to_vec();
+            self.pending.push_back(TarItem::Entry(first));
+            for link in links {
+                self.pending.push_back(Self::link_to(&target, link));
+            }
+        }
+    }
+}
+
+impl<Upstream: Stream<Item = Bytes>> Stream for CpioStream<Upstream>
+where
+    Upstream::Error: std::fmt::Debug + Sync + Send + 'static,
+{
+    type Item = TarItem;
+    type Error = Error<Upstream::Error>;
+
+    fn poll(&mut self) -> Result<Async<Option<<Self as Stream>::Item>>, <Self as Stream>::Error> {
+        loop {
+            if let Some(item) = self.pending.pop_front() {
+                return Ok(Async::Ready(Some(item)));
+            }
+            match self.state {
+                State::Done => return Ok(Async::Ready(None)),
+                State::Trailer => {
+                    self.state = match try_ready!(self.reader.skip_zeros()) {
+                        true => State::Magic,
+                        false => State::Done,
+                    };
+                }
+                State::Magic => {
+                    let magic = try_ready!(self.reader.fetch_exact(MAGIC_LEN))
+                        .ok_or(Error::UnexpectedEof)?;
+                    self.state = match magic.as_ref() {
+                        NEWC_MAGIC | NEWC_CRC_MAGIC => State::Header(Format::Newc),
+                        ODC_MAGIC => State::Header(Format::Odc),
+                        _ => return Err(Error::Format("invalid cpio header magic")),
+                    };
+                }
+                State::Header(format) => {
+                    let header =
+                        try_ready!(self.reader.fetch_exact(format.header_size() - MAGIC_LEN))
+                            .ok_or(Error::UnexpectedEof)?;
+                    let fields = parse_fields(format, &header)
+                        .filter(|fields| fields.name_size > 0)
+                        .ok_or(Error::Format("invalid cpio header"))?;
+                    if fields.name_size > MAX_NAME {
+                        return Err(Error::Format("cpio entry name too long"));
+                    }
+                    self.state = State::Name(format, fields);
+                }
+                State::Name(format, ref fields) => {
+                    let len = fields.name_size
+                        + format.padding((format.header_size() + fields.name_size) as u64);
+                    let name =
+                        try_ready!(self.reader.fetch_exact(len)).ok_or(Error::UnexpectedEof)?;
+                    let name = &name[..fields.name_size];
+                    let name = name.split(|b| *b == 0).next().unwrap_or(name);
+                    let fields = match std::mem::replace(&mut self.state, State::Magic) {
+                        State::Name(_, fields) => fields,
+                        _ => unreachable!(),
+                    };
+                    if name == TRAILER {
+                        self.state = State::Trailer;
+                        self.flush_links();
+                        continue;
+                    }
+                    if let Some(entry) = self.on_entry(format, fields, name)? {
+                        return Ok(Async::Ready(Some(TarItem::Entry(entry))));
+                    }
+                }
+                State::LinkTarget(format, _, size) => {
+                    let len = size as usize + format.padding(size);
+                    let target =
+                        try_ready!(self.reader.fetch_exact(len)).ok_or(Error::UnexpectedEof)?;
+                    let target = &target[..size as usize];
+                    if let State::LinkTarget(_, mut entry, _) =
+                        std::mem::replace(&mut self.state, State::Magic)
+                    {
+                        entry.set_link_bytes(Some(target.to_vec()));
+                        return Ok(Async::Ready(Some(TarItem::Entry(entry))));
+                    }
+                }
+                State::Data(ref mut remaining, padding) => {
+                    if *remaining > 0 {
+                        let bytes = try_ready!(self.reader.fetch_upto(*remaining));
+                        *remaining -= bytes.len() as u64;
+                        return Ok(Async::Ready(Some(TarItem::Chunk(bytes))));
+                    }
+                    self.state = State::Padding(padding);
+                }
+                State::Skip(ref mut remaining, padding) => {
+                    if *remaining > 0 {
+                        let bytes = try_ready!(self.reader.fetch_upto(*remaining));
+                        *remaining -= bytes.len() as u64;
+                        continue;
+                    }
+                    self.state = State::Padding(padding);
+                }
+                State::Padding(len) => {
+                    try_ready!(self.reader.fetch_exact(len)).ok_or(Error::UnexpectedEof)?;
+                    self.state = State::Magic;
+                    if let Some((target, links)) = self.after_body.take() {
+                        for link in links {
+                            self.pending.push_back(Self::link_to(&target, link));
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub fn decode_cpio<CpioStreamT: Stream<Item = Bytes>>(
+    upstream: CpioStreamT,
+) -> impl Stream<Item = TarItem, Error = Error<CpioStreamT::Error>>
+where
+    CpioStreamT::Error: std::fmt::Debug + Sync + Send + 'static,
+{
+    CpioStream::new(upstream)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::stream;
+
+    fn newc(out: &mut Vec<u8>, name: &str, mode: u32, ino: u64, nlink: u64, data: &[u8]) {
+        let header = format!(
+            "070701{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}",
+            ino,
+            mode,
+            0,
+            0,
+            nlink,
+            1_546_272_612,
+            data.len(),
+            0,
+            0,
+            0,
+            0,
+            name.len() + 1,
+            0
+        );
+        out.extend_from_slice(header.as_bytes());
+        out.extend_from_slice(name.as_bytes());
+        out.push(0);
+        out.resize(
+            out.len() + Format::Newc.padding((header.len() + name.len() + 1) as u64),
+            0,
+        );
+        out.extend_from_slice(data);
+        out.resize(out.len() + Format::Newc.padding(data.len() as u64), 0);
+    }
+
+    fn decode(archive: Vec<u8>) -> Vec<TarItem> {
+        let chunks: Vec<Bytes> = archive.chunks(5).map(Bytes::from).collect();
+        decode_cpio(stream::iter_ok::<_, ()>(chunks))
+            .collect()
+            .wait()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_newc() {
+        let mut archive = Vec::new();
+        newc(&mut archive, "bin", S_IFDIR | 0o755, 1, 2, b"");
+        newc(&mut archive, "bin/sh", S_IFLNK | 0o777, 2, 1, b"busybox");
+        newc(&mut archive, "bin/ls", S_IFREG | 0o755, 3, 2, b"");
+        newc(&mut archive, "bin/busybox", S_IFREG | 0o755, 3, 2, b"elf");
+        newc(&mut archive, "run/sock", S_IFSOCK | 0o755, 4, 1, b"junk");
+        newc(&mut archive, "TRAILER!!!", 0, 0, 1, b"");
+
+        let items = decode(archive);
+        let mut summary: Vec<String> = Vec::new();
+        for item in &items {
+            match item {
+                TarItem::Entry(entry) => summary.push(format!(
+                    "{:?} {} {:?} {:o}",
+                    entry.entry_type(),
+                    String::from_utf8_lossy(entry.path_bytes()),
+                    entry.link_bytes().map(String::from_utf8_lossy),
+                    entry.mode()
+                )),
+                // Chunks of one body are joined into a single line.
+                TarItem::Chunk(bytes) => match summary.last_mut() {
+                    Some(body) if !body.contains(' ') => {
+                        body.push_str(&String::from_utf8_lossy(bytes))
+                    }
+                    _ => summary.push(String::from_utf8_lossy(bytes).into_owned()),
+                },
+            }
+        }
+        assert_eq!(
+            summary,
+            vec![
+                "Directory bin None 755",
+                "Symlink bin/sh Some(\"busybox\") 777",
+                "Regular bin/busybox None 755",
+                "elf",
+                "Link bin/ls Some(\"bin/busybox\") 755",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_odc() {
+        let odc = |name: &str, mode: u32, data: &[u8]| {
+            let mut out = format!(
+                "070707{:06o}{:06o}{:06o}{:06o}{:06o}{:06o}{:06o}{:011o}{:06o}{:011o}",
+                0,
+                1,
+                mode,
+                1000,
+                1000,
+                1,
+                0,
+                0,
+                name.len() + 1,
+                data.len()
+            )
+            .into_bytes();
+            out.extend_from_slice(name.as_bytes());
+            out.push(0);
+            out.extend_from_slice(data);
+            out
+        };
+        let mut archive = odc("file", S_IFREG | 0o644, b"hi");
+        archive.extend(odc("TRAILER!!!", 0, b""));
+
+        let items = decode(archive);
+        match (&items[0], &items[1]) {
+            (TarItem::Entry(entry), TarItem::Chunk(body)) => {
+                assert_eq!(entry.path_bytes(), b"file");
+                assert_eq!(entry.uid(), 1000);
+                assert_eq!(body.as_ref(), b"hi");
+            }
+            other => panic!("unexpected items: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_long_link() {
+        let mut archive = Vec::new();
+        let target = vec![b'a'; MAX_LINK_TARGET as usize + 1];
+        newc(&mut archive, "link", S_IFLNK | 0o777, 1, 1, &target);
+        newc(&mut archive, "TRAILER!!!", 0, 0, 1, b"");
+        let chunks = vec![Bytes::from(archive)];
+        match decode_cpio(stream::iter_ok::<_, ()>(chunks))
+            .collect()
+            .wait()
+        {
+            Err(Error::Format(_)) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_long_name() {
+        let mut archive = Vec::new();
+        let name = "a".repeat(MAX_NAME);
+        newc(&mut archive, &name, S_IFREG | 0o644, 1, 1, b"");
+        newc(&mut archive, "TRAILER!!!", 0, 0, 1, b"");
+        let chunks = vec![Bytes::from(archive)];
+        match decode_cpio(stream::iter_ok::<_, ()>(chunks))
+            .collect()
+            .wait()
+        {
+            Err(Error::Format(_)) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_concatenated() {
+        let mut archive = Vec::new();
+        newc(&mut archive, "early", S_IFREG | 0o644, 1, 1, b"one");
+        newc(&mut archive, "TRAILER!!!", 0, 0, 1, b"");
+        archive.resize(512, 0);
+        newc(&mut archive, "late", S_IFREG | 0o644, 1, 1, b"two");
+        newc(&mut archive, "TRAILER!!!", 0, 0, 1, b"");
+        archive.resize(1024, 0);
+
+        let paths: Vec<Vec<u8>> = decode(archive)
+            .into_iter()
+            .filter_map(|item| match item {
+                TarItem::Entry(entry) => Some(entry.path_bytes().to_vec()),
+                TarItem::Chunk(_) => None,
+            })
+            .collect();
+        assert_eq!(paths, vec![b"early".to_vec(), b"late".to_vec()]);
+    }
+}
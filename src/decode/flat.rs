@@ -7,10 +7,10 @@ use futures::{prelude::*, try_ready};
 use std::fmt::{self, Debug, Formatter};
 use std::mem;
 use std::path::Path;
-use std::{convert, io, str, time};
+use std::{io, str, time};
 
-fn bytes2path(bytes: &[u8]) -> io::Result<&Path> {
-    let s = str::from_utf8(bytes).map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+pub(crate) fn bytes2path(bytes: &[u8]) -> io::Result<&Path> {
+    let s = str::from_utf8(bytes).map_err(io::Error::other)?;
     Ok(Path::new(s))
 }
 
@@ -22,6 +22,7 @@ fn gnu_str_buffer2vec(buf: Bytes) -> Vec<u8> {
     .into()
 }
 
+#[derive(Clone)]
 pub struct TarEntry {
     entry_type: tar::EntryType,
     path_bytes: Vec<u8>,
@@ -29,24 +30,161 @@ pub struct TarEntry {
     atime: Option<FileTime>,
     ctime: Option<FileTime>,
     mtime: FileTime,
+    mode: u32,
     uid: u64,
     uname: Option<Vec<u8>>,
     gid: u64,
     gname: Option<Vec<u8>>,
+    device: Option<(u32, u32)>,
+    xattrs: Vec<(Vec<u8>, Vec<u8>)>,
     size: u64,
+    header_offset: u64,
+    link_target: Option<Box<TarEntry>>,
 }
 
 impl TarEntry {
+    /// Creates an entry with the given type and path, owned by root, mode
+    /// 0644 (0755 for directories), mtime at the epoch and no body.
+    pub fn new<P: Into<Vec<u8>>>(entry_type: tar::EntryType, path: P) -> Self {
+        TarEntry {
+            entry_type,
+            path_bytes: path.into(),
+            link_bytes: None,
+            atime: None,
+            ctime: None,
+            mtime: FileTime::from_secs(0),
+            mode: if entry_type.is_dir() { 0o755 } else { 0o644 },
+            uid: 0,
+            uname: None,
+            gid: 0,
+            gname: None,
+            device: None,
+            xattrs: Vec::new(),
+            size: 0,
+            header_offset: 0,
+            link_target: None,
+        }
+    }
+
     #[inline]
     pub fn entry_type(&self) -> tar::EntryType {
         self.entry_type
     }
 
+    #[inline]
+    pub fn set_entry_type(&mut self, entry_type: tar::EntryType) -> &mut Self {
+        self.entry_type = entry_type;
+        self
+    }
+
+    #[inline]
+    pub fn set_path_bytes<P: Into<Vec<u8>>>(&mut self, path: P) -> &mut Self {
+        self.path_bytes = path.into();
+        self
+    }
+
+    #[inline]
+    pub fn set_link_bytes(&mut self, link: Option<Vec<u8>>) -> &mut Self {
+        self.link_bytes = link;
+        self
+    }
+
+    /// Sets the body length; exactly this many bytes of chunks must follow
+    /// the entry when it is encoded.
+    #[inline]
+    pub fn set_size(&mut self, size: u64) -> &mut Self {
+        self.size = size;
+        self
+    }
+
+    #[inline]
+    pub fn set_mode(&mut self, mode: u32) -> &mut Self {
+        self.mode = mode;
+        self
+    }
+
+    #[inline]
+    pub fn set_uid(&mut self, uid: u64) -> &mut Self {
+        self.uid = uid;
+        self
+    }
+
+    #[inline]
+    pub fn set_gid(&mut self, gid: u64) -> &mut Self {
+        self.gid = gid;
+        self
+    }
+
+    #[inline]
+    pub fn set_uname(&mut self, uname: Option<Vec<u8>>) -> &mut Self {
+        self.uname = uname;
+        self
+    }
+
+    #[inline]
+    pub fn set_gname(&mut self, gname: Option<Vec<u8>>) -> &mut Self {
+        self.gname = gname;
+        self
+    }
+
+    #[inline]
+    pub fn set_mtime(&mut self, mtime: time::SystemTime) -> &mut Self {
+        self.mtime = mtime.into();
+        self
+    }
+
+    #[inline]
+    pub fn set_atime(&mut self, atime: Option<time::SystemTime>) -> &mut Self {
+        self.atime = atime.map(Into::into);
+        self
+    }
+
+    #[inline]
+    pub fn set_ctime(&mut self, ctime: Option<time::SystemTime>) -> &mut Self {
+        self.ctime = ctime.map(Into::into);
+        self
+    }
+
+    /// Major and minor numbers of character and block devices.
+    #[inline]
+    pub fn device(&self) -> Option<(u32, u32)> {
+        self.device
+    }
+
+    #[inline]
+    pub fn set_device(&mut self, device: Option<(u32, u32)>) -> &mut Self {
+        self.device = device;
+        self
+    }
+
+    /// Extended attributes as name and value pairs, from `SCHILY.xattr.*`
+    /// PAX records.
+    #[inline]
+    pub fn xattrs(&self) -> &[(Vec<u8>, Vec<u8>)] {
+        &self.xattrs
+    }
+
+    #[inline]
+    pub fn set_xattrs(&mut self, xattrs: Vec<(Vec<u8>, Vec<u8>)>) -> &mut Self {
+        self.xattrs = xattrs;
+        self
+    }
+
     #[inline]
     pub fn path(&self) -> io::Result<&Path> {
         bytes2path(self.path_bytes.as_slice())
     }
 
+    #[inline]
+    pub fn path_bytes(&self) -> &[u8] {
+        self.path_bytes.as_slice()
+    }
+
+    #[inline]
+    pub fn link_bytes(&self) -> Option<&[u8]> {
+        self.link_bytes.as_deref()
+    }
+
     #[inline]
     pub fn link(&self) -> io::Result<Option<&Path>> {
         Ok(match self.link_bytes.as_ref() {
@@ -60,6 +198,11 @@ impl TarEntry {
         self.size
     }
 
+    #[inline]
+    pub fn mode(&self) -> u32 {
+        self.mode
+    }
+
     #[inline]
     pub fn uid(&self) -> u64 {
         self.uid
@@ -70,6 +213,46 @@ impl TarEntry {
         self.gid
     }
 
+    #[inline]
+    pub fn uname(&self) -> Option<&[u8]> {
+        self.uname.as_deref()
+    }
+
+    #[inline]
+    pub fn gname(&self) -> Option<&[u8]> {
+        self.gname.as_deref()
+    }
+
+    /// Offset of the entry's own header block in the uncompressed archive.
+    ///
+    /// PAX and GNU extension headers that precede the entry are not counted.
+    #[inline]
+    pub fn header_offset(&self) -> u64 {
+        self.header_offset
+    }
+
+    /// Offset of the first byte of the entry's body in the uncompressed archive.
+    #[inline]
+    pub fn data_offset(&self) -> u64 {
+        self.header_offset + HEADER_SIZE
+    }
+
+    /// Metadata of the entry a hardlink points to.
+    ///
+    /// Only set when the stream was passed through [`hardlink::resolve`](super::hardlink::resolve)
+    /// or one of its variants, and the target was seen earlier in the archive.
+    #[inline]
+    pub fn link_target(&self) -> Option<&TarEntry> {
+        self.link_target.as_deref()
+    }
+
+    /// Attaches a resolved hardlink target; `size` is the length of the body
+    /// that will follow the entry in the stream.
+    pub(super) fn set_link_target(&mut self, target: TarEntry, size: u64) {
+        self.link_target = Some(Box::new(target));
+        self.size = size;
+    }
+
     pub fn mtime(&self) -> time::SystemTime {
         self.mtime.into()
     }
@@ -84,6 +267,7 @@ impl TarEntry {
 }
 
 #[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
 pub enum TarItem {
     Entry(TarEntry),
     Chunk(Bytes),
@@ -93,28 +277,29 @@ impl Debug for TarEntry {
     fn fmt(&self, f: &mut Formatter) -> Result<(), fmt::Error> {
         write!(
             f,
-            "Entry {} entry_type={:?} path={:?}, link={:?}, size={:?}, uid={}, gid={}, mtime={:?} ctime={:?} atime={:?} {}",
-            '{',
+            "Entry {{ entry_type={:?} path={:?}, link={:?}, size={:?}, mode={:o}, uid={}, gid={}, mtime={:?} ctime={:?} atime={:?} }}",
             self.entry_type(),
             self.path(),
             self.link(),
             self.size(),
+            self.mode(),
             self.uid(),
             self.gid(),
             self.mtime,
             self.ctime,
             self.atime,
-            '}'
         )
     }
 }
 
+const HEADER_SIZE: u64 = 512;
+
 struct EntryStream<U> {
     upstream: U,
     buffer: Option<BytesMut>,
     attributes: PaxAttributes,
     state: State,
-    processed: u32,
+    position: u64,
 }
 
 #[derive(Debug)]
@@ -141,7 +326,7 @@ impl<E: Debug + Send + Sync + 'static, U: Stream<Item = RawTarItem, Error = Erro
     fn poll(&mut self) -> Result<Async<Option<<Self as Stream>::Item>>, <Self as Stream>::Error> {
         loop {
             match self.state {
-                State::Clean => match try_ready!(self.upstream.poll()) {
+                State::Clean => match try_ready!(self.poll_raw()) {
                     Some(RawTarItem::Header(header)) => return self.poll_next_header(header),
                     None => return Ok(Async::Ready(None)),
                     Some(RawTarItem::Chunk(bytes)) => {
@@ -164,10 +349,33 @@ impl<E: Debug + Send + Sync + 'static, U: Stream<Item = RawTarItem, Error = Erro
             buffer: None,
             attributes: PaxAttributes::default(),
             state: State::Clean,
-            processed: 0,
+            position: 0,
         }
     }
 
+    /// Tracks the position in the archive past the given raw item.
+    ///
+    /// Raw chunks carry only the body bytes, so block padding is added back
+    /// when the next header arrives.
+    #[inline]
+    fn advance(&mut self, item: &RawTarItem) {
+        match item {
+            RawTarItem::Chunk(bytes) => self.position += bytes.len() as u64,
+            RawTarItem::Header(_) | RawTarItem::EmptyHeader => {
+                self.position =
+                    ((self.position + HEADER_SIZE - 1) & !(HEADER_SIZE - 1)) + HEADER_SIZE
+            }
+        }
+    }
+
+    fn poll_raw(&mut self) -> Result<Async<Option<RawTarItem>>, Error<E>> {
+        let item = try_ready!(self.upstream.poll());
+        if let Some(ref item) = item {
+            self.advance(item);
+        }
+        Ok(Async::Ready(item))
+    }
+
     fn poll_next_header(
         &mut self,
         entry: tar::Header,
@@ -189,14 +397,14 @@ impl<E: Debug + Send + Sync + 'static, U: Stream<Item = RawTarItem, Error = Erro
         if entry.as_gnu().is_some() && entry.entry_type().is_gnu_longname() {
             // TODO: Check max size
             self.buffer = Some(BytesMut::with_capacity(
-                entry.size().map_err(|e| Error::IoError(e))? as usize,
+                entry.size().map_err(Error::IoError)? as usize,
             ));
             self.state = State::InGnuLongName;
             return self.poll_data();
         }
         if entry.as_gnu().is_some() && entry.entry_type().is_gnu_longlink() {
             self.buffer = Some(BytesMut::with_capacity(
-                entry.size().map_err(|e| Error::IoError(e))? as usize,
+                entry.size().map_err(Error::IoError)? as usize,
             ));
             self.state = State::InGnuLongLink;
             return self.poll_data();
@@ -216,7 +424,7 @@ impl<E: Debug + Send + Sync + 'static, U: Stream<Item = RawTarItem, Error = Erro
             .attributes
             .path
             .take()
-            .unwrap_or(entry.path_bytes().into());
+            .unwrap_or_else(|| entry.path_bytes().into());
         let link_bytes = self
             .attributes
             .link_path
@@ -227,19 +435,19 @@ impl<E: Debug + Send + Sync + 'static, U: Stream<Item = RawTarItem, Error = Erro
             .attributes
             .size
             .take()
-            .unwrap_or(entry.size().map_err(|e| Error::IoError(e))?);
+            .unwrap_or(entry.size().map_err(Error::IoError)?);
 
         let uid = match self.attributes.uid.take() {
             Some(uid) => uid,
-            None => entry.uid().map_err(|e| Error::IoError(e))?,
+            None => entry.uid().map_err(Error::IoError)?,
         };
         let gid = match self.attributes.gid.take() {
             Some(gid) => gid,
-            None => entry.gid().map_err(|e| Error::IoError(e))?,
+            None => entry.gid().map_err(Error::IoError)?,
         };
         let mtime = match self.attributes.mtime.take() {
             Some(mtime) => mtime,
-            None => entry.mtime().map_err(|e| Error::IoError(e))?.into(),
+            None => entry.mtime().map_err(Error::IoError)?.into(),
         };
 
         let ctime = self.attributes.ctime.take();
@@ -255,6 +463,17 @@ impl<E: Debug + Send + Sync + 'static, U: Stream<Item = RawTarItem, Error = Erro
             .gname
             .take()
             .or_else(|| entry.groupname_bytes().map(|b| b.into()));
+        let mode = entry.mode().map_err(Error::IoError)?;
+        let xattrs = mem::take(&mut self.attributes.xattrs);
+        let device = match (entry.device_major(), entry.device_minor()) {
+            (Ok(Some(major)), Ok(Some(minor)))
+                if entry.entry_type().is_character_special()
+                    || entry.entry_type().is_block_special() =>
+            {
+                Some((major, minor))
+            }
+            _ => None,
+        };
 
         Ok(Async::Ready(Some(TarItem::Entry(TarEntry {
             entry_type: entry.entry_type(),
@@ -268,6 +487,11 @@ impl<E: Debug + Send + Sync + 'static, U: Stream<Item = RawTarItem, Error = Erro
             atime,
             uname,
             gname,
+            mode,
+            device,
+            xattrs,
+            header_offset: self.position - HEADER_SIZE,
+            link_target: None,
         }))))
     }
 
@@ -275,7 +499,7 @@ impl<E: Debug + Send + Sync + 'static, U: Stream<Item = RawTarItem, Error = Erro
         &mut self,
     ) -> Result<Async<Option<<Self as Stream>::Item>>, <Self as Stream>::Error> {
         loop {
-            match try_ready!(self.upstream.poll()) {
+            match try_ready!(self.poll_raw()) {
                 Some(RawTarItem::Chunk(bytes)) => match self.state {
                     State::InGnuLongLink | State::InGnuLongName => {
                         self.buffer.as_mut().unwrap().put(bytes)
@@ -301,3 +525,13 @@ where
 {
     EntryStream::new(raw::decode_tar(upstream))
 }
+
+/// Like [`decode_tar`], for a tar produced by another stream of this crate,
+/// e.g. a decompressed layer or package member. Errors are not nested.
+pub fn decode_nested<E, S>(upstream: S) -> impl Stream<Item = TarItem, Error = Error<E>>
+where
+    E: Debug + Send + Sync + 'static,
+    S: Stream<Item = Bytes, Error = Error<E>>,
+{
+    decode_tar(upstream).map_err(Error::flatten)
+}
@@ -1,8 +1,9 @@
 use super::flat;
 use super::Error;
+use crate::digest::{self, Digest, Hasher};
 use bytes::Bytes;
 use futures::prelude::*;
-use futures::{stream, try_ready};
+use futures::try_ready;
 use std::fmt::Debug;
 use std::sync::Arc;
 use std::sync::Mutex;
@@ -11,6 +12,9 @@ struct DeepTarStreamInner<S> {
     upstream: S,
     position: u64,
     bytes: u64,
+    hasher: Option<Box<dyn Hasher>>,
+    /// Digest of the body of the entry at `position`, once it has ended.
+    digest: Option<Digest>,
 }
 
 struct DeepTarStream<S> {
@@ -26,11 +30,28 @@ where
     inner: Arc<Mutex<DeepTarStreamInner<S>>>,
 }
 
-impl<E: Sync + Send + Debug + 'static, S: Stream<Item = flat::TarItem, Error = Error<E>>> Entry<S> {
+impl<S: Stream<Item = flat::TarItem>> Entry<S>
+where
+    S::Error: Sync + Send + Debug + 'static,
+{
     #[inline]
     pub fn header(&self) -> &flat::TarEntry {
         &self.header
     }
+
+    /// Digest of the body, available once it has been read to the end.
+    ///
+    /// Only entries of streams from [`decode_items_with_digests`] have one,
+    /// and only files or entries with a body. Read the body through
+    /// `by_ref()` to keep the entry around.
+    pub fn digest(&self) -> Option<Digest> {
+        let inner = self.inner.lock().unwrap();
+        if inner.position == self.position {
+            inner.digest.clone()
+        } else {
+            None
+        }
+    }
 }
 
 impl<E: Sync + Send + Debug + 'static, S: Stream<Item = flat::TarItem, Error = Error<E>>> Stream
@@ -62,6 +83,14 @@ where
             upstream,
             position: 0,
             bytes: 0,
+            hasher: None,
+            digest: None,
+        }
+    }
+
+    fn finish_digest(&mut self) {
+        if let Some(ref mut hasher) = self.hasher {
+            self.digest = Some(hasher.finish());
         }
     }
 
@@ -70,6 +99,12 @@ where
             match try_ready!(self.upstream.poll()) {
                 Some(flat::TarItem::Chunk(bytes)) => {
                     self.bytes -= bytes.len() as u64;
+                    if let Some(ref mut hasher) = self.hasher {
+                        hasher.update(&bytes);
+                        if self.bytes == 0 {
+                            self.finish_digest();
+                        }
+                    }
                     Ok(Async::Ready(Some(bytes)))
                 }
                 None => Err(Error::UnexpectedEof),
@@ -90,6 +125,15 @@ where
                 Some(flat::TarItem::Entry(entry)) => {
                     self.position += 1;
                     self.bytes = entry.size();
+                    if self.digest.take().is_none() {
+                        // The previous body may have been dropped half read.
+                        if let Some(ref mut hasher) = self.hasher {
+                            hasher.finish();
+                        }
+                    }
+                    if self.bytes == 0 && digest::has_digest(&entry) {
+                        self.finish_digest();
+                    }
                     return Ok(Async::Ready(Some((entry, self.position))));
                 }
                 None => return Ok(Async::Ready(None)),
@@ -130,10 +174,38 @@ pub fn decode_tar<TarStream: Stream<Item = Bytes>>(
 >
 where
     TarStream::Error: std::fmt::Debug + Sync + Send + 'static,
+{
+    decode_items(flat::decode_tar(upstream))
+}
+
+/// Groups an already decoded flat item stream into entries.
+///
+/// Useful when the flat stream went through an adapter first, e.g.
+/// [`hardlink::resolve`](super::hardlink::resolve).
+pub fn decode_items<E, S>(items: S) -> impl Stream<Item = Entry<S>, Error = Error<E>>
+where
+    E: Sync + Send + Debug + 'static,
+    S: Stream<Item = flat::TarItem, Error = Error<E>>,
 {
     DeepTarStream {
-        inner: Arc::new(Mutex::new(DeepTarStreamInner::new(flat::decode_tar(
-            upstream,
-        )))),
+        inner: Arc::new(Mutex::new(DeepTarStreamInner::new(items))),
+    }
+}
+
+/// Like [`decode_items`], and every entry computes the digest of its body as
+/// it is read; see [`Entry::digest`].
+pub fn decode_items_with_digests<E, S, H>(
+    items: S,
+    hasher: H,
+) -> impl Stream<Item = Entry<S>, Error = Error<E>>
+where
+    E: Sync + Send + Debug + 'static,
+    S: Stream<Item = flat::TarItem, Error = Error<E>>,
+    H: Hasher + 'static,
+{
+    let mut inner = DeepTarStreamInner::new(items);
+    inner.hasher = Some(Box::new(hasher));
+    DeepTarStream {
+        inner: Arc::new(Mutex::new(inner)),
     }
 }
@@ -0,0 +1,335 @@
+//! Hardlink resolution for flat item streams.
+//!
+//! A hardlink entry arrives with size 0 and only the path of an earlier entry.
+//! The adapters here remember regular files by path and attach the target's
+//! metadata to each hardlink (see [`TarEntry::link_target`]). Optionally the
+//! target body is replayed after the link entry, either from a bounded in-memory
+//! cache or from a [`RangeSource`] over the same archive. When a body is
+//! replayed the link entry's `size()` reports its length, so the stream keeps
+//! the usual flat contract and can be grouped with [`full::decode_items`].
+//!
+//! [`full::decode_items`]: super::full::decode_items
+
+use super::flat::{TarEntry, TarItem};
+use super::Error;
+use crate::source::RangeSource;
+use bytes::Bytes;
+use futures::{prelude::*, stream, try_ready};
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Debug;
+use std::io;
+
+/// Source type for resolvers that never read the archive a second time.
+#[derive(Debug)]
+pub enum NoSource {}
+
+impl RangeSource for NoSource {
+    type Body = stream::Empty<Bytes, io::Error>;
+
+    fn read_range(&mut self, _offset: u64, _len: u64) -> Self::Body {
+        match *self {}
+    }
+}
+
+/// Strips `./` prefixes and trailing slashes so that `./a/b` and `a/b` match.
+pub(crate) fn normalize_path(mut path: &[u8]) -> &[u8] {
+    while path.starts_with(b"./") {
+        path = &path[2..];
+        while path.first() == Some(&b'/') {
+            path = &path[1..];
+        }
+    }
+    while path.len() > 1 && path.last() == Some(&b'/') {
+        path = &path[..path.len() - 1];
+    }
+    path
+}
+
+struct BodyCache {
+    limit: u64,
+    used: u64,
+    bodies: HashMap<Vec<u8>, Vec<Bytes>>,
+    order: VecDeque<Vec<u8>>,
+}
+
+impl BodyCache {
+    fn new(limit: u64) -> Self {
+        BodyCache {
+            limit,
+            used: 0,
+            bodies: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        if let Some(chunks) = self.bodies.remove(key) {
+            self.used -= body_len(&chunks);
+            self.order.retain(|k| k.as_slice() != key);
+        }
+    }
+
+    /// Stores a body, evicting the oldest ones until it fits.
+    fn insert(&mut self, key: Vec<u8>, chunks: Vec<Bytes>) {
+        let len = body_len(&chunks);
+        if len > self.limit {
+            return;
+        }
+        self.remove(&key);
+        while self.used + len > self.limit {
+            match self.order.pop_front() {
+                Some(old) => {
+                    if let Some(chunks) = self.bodies.remove(&old) {
+                        self.used -= body_len(&chunks);
+                    }
+                }
+                None => break,
+            }
+        }
+        self.used += len;
+        self.order.push_back(key.clone());
+        self.bodies.insert(key, chunks);
+    }
+}
+
+fn body_len(chunks: &[Bytes]) -> u64 {
+    chunks.iter().map(|c| c.len() as u64).sum()
+}
+
+enum Replay<B> {
+    Idle,
+    Cached(VecDeque<Bytes>),
+    Source(B),
+}
+
+pub struct HardlinkResolver<S, R: RangeSource> {
+    upstream: S,
+    source: Option<R>,
+    seen: HashMap<Vec<u8>, TarEntry>,
+    cache: Option<BodyCache>,
+    recording: Option<(Vec<u8>, Vec<Bytes>)>,
+    replay: Replay<R::Body>,
+}
+
+impl<E, S, R> HardlinkResolver<S, R>
+where
+    E: Debug + Send + Sync + 'static,
+    S: Stream<Item = TarItem, Error = Error<E>>,
+    R: RangeSource,
+{
+    fn new(upstream: S, source: Option<R>, cache: Option<BodyCache>) -> Self {
+        HardlinkResolver {
+            upstream,
+            source,
+            seen: HashMap::new(),
+            cache,
+            recording: None,
+            replay: Replay::Idle,
+        }
+    }
+
+    fn finish_recording(&mut self) {
+        if let (Some((key, chunks)), Some(cache)) = (self.recording.take(), self.cache.as_mut()) {
+            cache.insert(key, chunks);
+        }
+    }
+
+    fn on_entry(&mut self, mut entry: TarEntry) -> TarEntry {
+        let key = normalize_path(entry.path_bytes()).to_vec();
+
+        if entry.entry_type().is_file() {
+            if let Some(cache) = self.cache.as_mut() {
+                cache.remove(&key);
+                if entry.size() <= cache.limit {
+                    self.recording = Some((key.clone(), Vec::new()));
+                }
+            }
+            self.seen.insert(key, entry.clone());
+        } else if entry.entry_type().is_hard_link() {
+            let target = entry
+                .link_bytes()
+                .and_then(|link| self.seen.get(normalize_path(link)))
+                .cloned();
+
+            if let Some(target) = target {
+                let size = if entry.size() > 0 || target.size() == 0 {
+                    entry.size()
+                } else {
+                    self.start_replay(&target)
+                };
+                self.seen.insert(key, target.clone());
+                entry.set_link_target(target, size);
+            } else {
+                self.seen.remove(&key);
+            }
+        } else {
+            self.seen.remove(&key);
+        }
+        entry
+    }
+
+    /// Prepares the target body for replay and returns its length, or 0 when
+    /// the body is not available.
+    fn start_replay(&mut self, target: &TarEntry) -> u64 {
+        let key = normalize_path(target.path_bytes());
+        if let Some(chunks) = self.cache.as_ref().and_then(|c| c.bodies.get(key)) {
+            self.replay = Replay::Cached(chunks.iter().cloned().collect());
+            return target.size();
+        }
+        if let Some(source) = self.source.as_mut() {
+            self.replay = Replay::Source(source.read_range(target.data_offset(), target.size()));
+            return target.size();
+        }
+        0
+    }
+}
+
+impl<E, S, R> Stream for HardlinkResolver<S, R>
+where
+    E: Debug + Send + Sync + 'static,
+    S: Stream<Item = TarItem, Error = Error<E>>,
+    R: RangeSource,
+{
+    type Item = TarItem;
+    type Error = Error<E>;
+
+    fn poll(&mut self) -> Result<Async<Option<<Self as Stream>::Item>>, <Self as Stream>::Error> {
+        match self.replay {
+            Replay::Idle => (),
+            Replay::Cached(ref mut chunks) => match chunks.pop_front() {
+                Some(chunk) => return Ok(Async::Ready(Some(TarItem::Chunk(chunk)))),
+                None => self.replay = Replay::Idle,
+            },
+            Replay::Source(ref mut body) => match try_ready!(body.poll().map_err(Error::IoError)) {
+                Some(chunk) => return Ok(Async::Ready(Some(TarItem::Chunk(chunk)))),
+                None => self.replay = Replay::Idle,
+            },
+        }
+
+        match try_ready!(self.upstream.poll()) {
+            Some(TarItem::Chunk(chunk)) => {
+                if let Some((_, ref mut chunks)) = self.recording {
+                    chunks.push(chunk.clone());
+                }
+                Ok(Async::Ready(Some(TarItem::Chunk(chunk))))
+            }
+            Some(TarItem::Entry(entry)) => {
+                self.finish_recording();
+                Ok(Async::Ready(Some(TarItem::Entry(self.on_entry(entry)))))
+            }
+            None => {
+                self.finish_recording();
+                Ok(Async::Ready(None))
+            }
+        }
+    }
+}
+
+/// Attaches target metadata to hardlinks without replaying bodies.
+pub fn resolve<E, S>(upstream: S) -> HardlinkResolver<S, NoSource>
+where
+    E: Debug + Send + Sync + 'static,
+    S: Stream<Item = TarItem, Error = Error<E>>,
+{
+    HardlinkResolver::new(upstream, None, None)
+}
+
+/// Like [`resolve`], and replays target bodies kept in a cache of at most
+/// `limit` bytes. Links whose target was evicted or larger than the cache are
+/// reported with metadata only.
+pub fn resolve_cached<E, S>(upstream: S, limit: u64) -> HardlinkResolver<S, NoSource>
+where
+    E: Debug + Send + Sync + 'static,
+    S: Stream<Item = TarItem, Error = Error<E>>,
+{
+    HardlinkResolver::new(upstream, None, Some(BodyCache::new(limit)))
+}
+
+/// Like [`resolve`], and replays target bodies by reading them again from
+/// `source`, which must hold the same uncompressed archive as the stream.
+pub fn resolve_with_source<E, S, R>(upstream: S, source: R) -> HardlinkResolver<S, R>
+where
+    E: Debug + Send + Sync + 'static,
+    S: Stream<Item = TarItem, Error = Error<E>>,
+    R: RangeSource,
+{
+    HardlinkResolver::new(upstream, Some(source), None)
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{flat, full};
+    use super::*;
+
+    fn new_header(entry_type: tar::EntryType, size: u64) -> tar::Header {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(entry_type);
+        header.set_size(size);
+        header.set_mode(0o644);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mtime(0);
+        header
+    }
+
+    fn archive() -> Bytes {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = new_header(tar::EntryType::Regular, 5);
+        builder
+            .append_data(&mut header, "dir/file", b"hello".as_ref())
+            .unwrap();
+
+        let mut header = new_header(tar::EntryType::Link, 0);
+        builder
+            .append_link(&mut header, "dir/link", "./dir/file")
+            .unwrap();
+        Bytes::from(builder.into_inner().unwrap())
+    }
+
+    fn bodies<S>(items: S) -> Vec<(String, Option<String>, Vec<u8>)>
+    where
+        S: Stream<Item = TarItem, Error = Error<()>>,
+    {
+        full::decode_items(items)
+            .and_then(|entry| {
+                let path = entry.header().path().unwrap().display().to_string();
+                let target = entry
+                    .header()
+                    .link_target()
+                    .map(|t| t.path().unwrap().display().to_string());
+                entry
+                    .concat2()
+                    .map(move |body| (path, target, body.to_vec()))
+            })
+            .collect()
+            .wait()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_resolve_metadata() {
+        let items = resolve(flat::decode_tar(stream::once::<_, ()>(Ok(archive()))));
+        let result = bodies(items);
+        assert_eq!(result[1].0, "dir/link");
+        assert_eq!(result[1].1.as_deref(), Some("dir/file"));
+        assert!(result[1].2.is_empty());
+    }
+
+    #[test]
+    fn test_resolve_cached() {
+        let items = resolve_cached(flat::decode_tar(stream::once::<_, ()>(Ok(archive()))), 1024);
+        let result = bodies(items);
+        assert_eq!(result[1].2, b"hello");
+
+        let items = resolve_cached(flat::decode_tar(stream::once::<_, ()>(Ok(archive()))), 4);
+        assert!(bodies(items)[1].2.is_empty());
+    }
+
+    #[test]
+    fn test_resolve_with_source() {
+        let tar = archive();
+        let chunks: Vec<Result<Bytes, ()>> = tar.chunks(100).map(|c| Ok(Bytes::from(c))).collect();
+        let items = resolve_with_source(flat::decode_tar(stream::iter_result(chunks)), tar);
+        assert_eq!(bodies(items)[1].2, b"hello");
+    }
+}
@@ -1,8 +1,8 @@
 use super::time;
 use bytes::{BufMut, Bytes, BytesMut};
 use failure::Fail;
+use std::mem;
 use std::str::FromStr;
-use std::{io, mem};
 
 #[derive(Debug, Fail)]
 pub enum ParseError {
@@ -26,6 +26,9 @@ impl From<time::ParseError> for ParseError {
     }
 }
 
+/// Extended attributes as written by star and GNU tar.
+const XATTR_PREFIX: &[u8] = b"SCHILY.xattr.";
+
 #[derive(Default, Debug)]
 pub struct PaxAttributes {
     pub path: Option<Vec<u8>>,
@@ -38,14 +41,14 @@ pub struct PaxAttributes {
     pub gid: Option<u64>,
     pub gname: Option<Vec<u8>>,
     pub size: Option<u64>,
+    pub xattrs: Vec<(Vec<u8>, Vec<u8>)>,
 }
 
 impl PaxAttributes {
     fn decode_record(&mut self, record: &[u8]) -> Result<(), ParseError> {
-        eprintln!("record='{}'", std::str::from_utf8(record).unwrap());
-        let (key, val) = cut_sep(record, b'=').ok_or_else(|| ParseError::ExpectedEq)?;
+        let (key, val) = cut_sep(record, b'=').ok_or(ParseError::ExpectedEq)?;
         let val = &val[1..];
-        Ok(match key {
+        match key {
             b"path" => self.path = Some(val.into()),
             b"linkpath" => self.link_path = Some(val.into()),
             b"mtime" => self.mtime = Some(parse_str(val)?),
@@ -56,8 +59,12 @@ impl PaxAttributes {
             b"gid" => self.gid = Some(parse_str(val)?),
             b"uname" => self.uname = Some(val.into()),
             b"gname" => self.gname = Some(val.into()),
-            _ => return Ok(()),
-        })
+            _ if key.starts_with(XATTR_PREFIX) => self
+                .xattrs
+                .push((key[XATTR_PREFIX.len()..].into(), val.into())),
+            _ => (),
+        }
+        Ok(())
     }
 }
 
@@ -80,14 +87,14 @@ fn parse_str<T: FromStr>(bytes: &[u8]) -> Result<T, ParseError> {
 
     let s = str::from_utf8(bytes).map_err(|_| ParseError::ExpectedUtf8)?;
 
-    Ok(s.parse().map_err(|_e| ParseError::ExpectedUtf8)?)
+    s.parse().map_err(|_e| ParseError::ExpectedUtf8)
 }
 
 fn parse_size(bytes: &[u8]) -> Result<u64, ParseError> {
     let mut val = 0u64;
     for b in bytes {
-        val = if *b >= b'0' && *b <= b'9' {
-            val.checked_mul(10).ok_or_else(|| ParseError::Overflow)? + (*b - b'0') as u64
+        val = if b.is_ascii_digit() {
+            val.checked_mul(10).ok_or(ParseError::Overflow)? + (*b - b'0') as u64
         } else {
             return Err(ParseError::InvalidSizeChar(*b as char));
         }
@@ -95,7 +102,10 @@ fn parse_size(bytes: &[u8]) -> Result<u64, ParseError> {
     Ok(val)
 }
 
-fn cut_record(bytes: &[u8]) -> Result<Option<(usize, &[u8], &[u8])>, ParseError> {
+/// Record length, record body and the remaining bytes.
+type Record<'a> = (usize, &'a [u8], &'a [u8]);
+
+fn cut_record(bytes: &[u8]) -> Result<Option<Record<'_>>, ParseError> {
     let (size_bytes, tail_bytes) = match cut_sep(bytes, b' ') {
         Some(v) => v,
         None => return Ok(None),
@@ -130,33 +140,16 @@ impl PaxDecoder {
     }
 
     pub fn decode(&mut self, bytes: Bytes) -> Result<(), ParseError> {
-        eprintln!("adv={}, len={}", self.adv, self.buffer.len());
         if self.adv > 0 {
             self.buffer.advance(mem::replace(&mut self.adv, 0));
-            eprintln!(
-                "E adv={}, len={}, buf='{}'",
-                self.adv,
-                self.buffer.len(),
-                std::str::from_utf8(self.buffer.as_ref()).unwrap()
-            );
         }
         self.buffer.reserve(bytes.len());
         self.buffer.put(bytes);
-        eprintln!(
-            "E2 adv={}, len={}, buf='{}'",
-            self.adv,
-            self.buffer.len(),
-            std::str::from_utf8(self.buffer.as_ref()).unwrap()
-        );
         let mut bb = self.buffer.as_ref();
-        loop {
-            if let Some((n, record, b)) = cut_record(bb)? {
-                self.attributes.decode_record(record)?;
-                self.adv += n;
-                bb = b;
-            } else {
-                break;
-            }
+        while let Some((n, record, b)) = cut_record(bb)? {
+            self.attributes.decode_record(record)?;
+            self.adv += n;
+            bb = b;
         }
         Ok(())
     }
@@ -192,5 +185,4 @@ mod test {
         decoder.decode(Bytes::from_static(rest_bytes)).unwrap();
         eprintln!("{:?}", decoder.into_attr())
     }
-
 }
@@ -4,6 +4,7 @@ use futures::{prelude::*, try_ready};
 use tar::Header;
 
 #[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
 pub enum RawTarItem {
     Header(Header),
     EmptyHeader,
@@ -12,12 +13,81 @@ pub enum RawTarItem {
 
 const HEADER_SIZE: usize = 512;
 
+/// Value of the last `key` record in the body of a PAX extended header.
+///
+/// Parsing stops at the first malformed record; the PAX decoder reports
+/// those.
+pub(crate) fn pax_value<'a>(mut body: &'a [u8], key: &[u8]) -> Option<&'a [u8]> {
+    let mut found = None;
+    while let Some(space) = body.iter().position(|b| *b == b' ') {
+        let len: usize = match std::str::from_utf8(&body[..space])
+            .ok()
+            .and_then(|l| l.parse().ok())
+        {
+            Some(len) if len > space && len <= body.len() => len,
+            _ => break,
+        };
+        let record = &body[space + 1..len];
+        let record = record.strip_suffix(b"\n").unwrap_or(record);
+        if let Some(value) = record
+            .strip_prefix(key)
+            .and_then(|rest| rest.strip_prefix(b"="))
+        {
+            found = Some(value);
+        }
+        body = &body[len..];
+    }
+    found
+}
+
+/// Whether a header only describes the next one, which carries the body.
+pub(crate) fn is_extension(header: &Header) -> bool {
+    matches!(header.entry_type().as_byte(), b'x' | b'g' | b'L' | b'K')
+}
+
+/// Follows PAX `size` records through a sequence of headers and bodies, so
+/// that the body length of every header is known.
+#[derive(Debug, Default)]
+pub(crate) struct SizeTracker {
+    /// Body of the PAX header being read.
+    pax: Option<BytesMut>,
+    /// Size record of the last PAX header, for the entry it describes.
+    size: Option<u64>,
+}
+
+impl SizeTracker {
+    /// Length of the body that follows `header`.
+    pub(crate) fn header(&mut self, header: &Header) -> std::io::Result<u64> {
+        if let Some(body) = self.pax.take() {
+            self.size = pax_value(&body, b"size")
+                .and_then(|size| std::str::from_utf8(size).ok())
+                .and_then(|size| size.parse().ok());
+        }
+        let size = header.entry_size()?;
+        if header.entry_type().is_pax_local_extensions() {
+            self.pax = Some(BytesMut::new());
+        }
+        if is_extension(header) {
+            return Ok(size);
+        }
+        Ok(self.size.take().unwrap_or(size))
+    }
+
+    /// Takes in a piece of the body of the last header.
+    pub(crate) fn chunk(&mut self, bytes: &[u8]) {
+        if let Some(ref mut pax) = self.pax {
+            pax.extend_from_slice(bytes);
+        }
+    }
+}
+
 struct RawTarStream<Upstream> {
     upstream: Upstream,
     buffer: BytesMut,
     tail: Option<Bytes>,
     in_entry_raw: u64,
     in_entry: u64,
+    sizes: SizeTracker,
 }
 
 impl<Upstream: Stream<Item = Bytes>> RawTarStream<Upstream>
@@ -31,6 +101,7 @@ where
             tail: None,
             in_entry_raw: 0,
             in_entry: 0,
+            sizes: SizeTracker::default(),
         }
     }
 
@@ -40,7 +111,7 @@ where
                 self.in_entry -= tail.len() as u64;
                 Ok(Async::Ready(Some(tail)))
             } else {
-                assert!(self.in_entry < std::usize::MAX as u64);
+                assert!(self.in_entry < usize::MAX as u64);
                 let chunk = tail.split_to(self.in_entry as usize);
                 self.in_entry = 0;
                 self.tail = Some(tail);
@@ -123,14 +194,15 @@ where
                 if let Some(mut bytes) = try_ready!(self.fetch_entry_bytes()) {
                     if self.in_entry_raw >= bytes.len() as u64 {
                         self.in_entry_raw -= bytes.len() as u64;
+                        self.sizes.chunk(&bytes);
                         return Ok(Async::Ready(Some(RawTarItem::Chunk(bytes))));
                     } else {
                         if self.in_entry_raw > 0 {
                             let chunk_size = self.in_entry_raw as usize;
                             self.in_entry_raw = 0;
-                            return Ok(Async::Ready(Some(RawTarItem::Chunk(
-                                bytes.split_to(chunk_size),
-                            ))));
+                            let chunk = bytes.split_to(chunk_size);
+                            self.sizes.chunk(&chunk);
+                            return Ok(Async::Ready(Some(RawTarItem::Chunk(chunk))));
                         }
                         // read more
                     }
@@ -145,10 +217,7 @@ where
             if header.as_bytes().iter().all(|i| *i == 0) {
                 Ok(Async::Ready(Some(RawTarItem::EmptyHeader)))
             } else {
-                let size = match header.entry_size() {
-                    Ok(size) => size,
-                    Err(e) => return Err(Error::IoError(e)),
-                };
+                let size = self.sizes.header(&header).map_err(Error::IoError)?;
 
                 self.in_entry = (size + 511) & !(512 - 1);
                 self.in_entry_raw = size;
@@ -0,0 +1,92 @@
+//! Byte-level reading shared by the non-tar decoders.
+
+use super::Error;
+use bytes::{Bytes, BytesMut};
+use futures::{prelude::*, try_ready};
+
+/// Splits an upstream byte stream into fixed size records and body chunks.
+pub(super) struct ByteReader<Upstream> {
+    upstream: Upstream,
+    buffer: BytesMut,
+    tail: Option<Bytes>,
+}
+
+impl<Upstream: Stream<Item = Bytes>> ByteReader<Upstream>
+where
+    Upstream::Error: std::fmt::Debug + Sync + Send + 'static,
+{
+    pub(super) fn new(upstream: Upstream) -> Self {
+        ByteReader {
+            upstream,
+            buffer: BytesMut::new(),
+            tail: None,
+        }
+    }
+
+    /// Collects exactly `n` bytes; `None` on a clean end of input.
+    ///
+    /// Bytes collected before `NotReady` are kept, so the next call must ask
+    /// for the same `n`.
+    pub(super) fn fetch_exact(
+        &mut self,
+        n: usize,
+    ) -> Result<Async<Option<Bytes>>, Error<Upstream::Error>> {
+        if n == 0 {
+            return Ok(Async::Ready(Some(Bytes::new())));
+        }
+        loop {
+            if let Some(mut tail) = self.tail.take() {
+                let missing = n - self.buffer.len();
+                if tail.len() > missing {
+                    self.tail = Some(tail.split_off(missing));
+                }
+                if self.buffer.is_empty() && tail.len() == n {
+                    return Ok(Async::Ready(Some(tail)));
+                }
+                self.buffer.extend_from_slice(&tail);
+                if self.buffer.len() == n {
+                    return Ok(Async::Ready(Some(self.buffer.take().freeze())));
+                }
+            }
+            match try_ready!(self.upstream.poll()) {
+                Some(bytes) => self.tail = Some(bytes),
+                None if self.buffer.is_empty() => return Ok(Async::Ready(None)),
+                None => return Err(Error::UnexpectedEof),
+            }
+        }
+    }
+
+    /// Skips zero bytes; ready with `false` on a clean end of input, and
+    /// with `true` once a non-zero byte comes next.
+    pub(super) fn skip_zeros(&mut self) -> Result<Async<bool>, Error<Upstream::Error>> {
+        debug_assert!(self.buffer.is_empty());
+        loop {
+            if let Some(mut tail) = self.tail.take() {
+                if let Some(at) = tail.iter().position(|b| *b != 0) {
+                    self.tail = Some(tail.split_off(at));
+                    return Ok(Async::Ready(true));
+                }
+            }
+            match try_ready!(self.upstream.poll()) {
+                Some(bytes) => self.tail = Some(bytes),
+                None => return Ok(Async::Ready(false)),
+            }
+        }
+    }
+
+    /// Next chunk of at most `max` bytes, without copying.
+    pub(super) fn fetch_upto(&mut self, max: u64) -> Result<Async<Bytes>, Error<Upstream::Error>> {
+        debug_assert!(self.buffer.is_empty());
+        let mut bytes = match self.tail.take() {
+            Some(tail) => tail,
+            None => match try_ready!(self.upstream.poll()) {
+                Some(bytes) => bytes,
+                None => return Err(Error::UnexpectedEof),
+            },
+        };
+        if bytes.len() as u64 > max {
+            self.tail = Some(bytes.split_off(max as usize));
+        }
+        Ok(Async::Ready(bytes))
+    }
+}
@@ -1,5 +1,5 @@
 use failure::Fail;
-use std::{convert, str, time};
+use std::{str, time};
 
 #[derive(Debug, Fail)]
 pub enum ParseError {
@@ -32,6 +32,20 @@ impl FileTime {
     pub fn into_system_time(self) -> time::SystemTime {
         time::UNIX_EPOCH + time::Duration::new(self.0, self.1)
     }
+
+    /// Times before the epoch are clamped to it.
+    #[inline]
+    pub fn from_system_time(t: time::SystemTime) -> Self {
+        let d = t.duration_since(time::UNIX_EPOCH).unwrap_or_default();
+        FileTime(d.as_secs(), d.subsec_nanos())
+    }
+}
+
+impl From<time::SystemTime> for FileTime {
+    #[inline]
+    fn from(t: time::SystemTime) -> Self {
+        Self::from_system_time(t)
+    }
 }
 
 impl From<u64> for FileTime {
@@ -41,9 +55,9 @@ impl From<u64> for FileTime {
     }
 }
 
-impl Into<time::SystemTime> for FileTime {
-    fn into(self) -> time::SystemTime {
-        self.into_system_time()
+impl From<FileTime> for time::SystemTime {
+    fn from(t: FileTime) -> Self {
+        t.into_system_time()
     }
 }
 
@@ -66,7 +80,7 @@ fn parse_from(value: &[u8]) -> Result<FileTime, ParseError> {
 
     while i < value.len() {
         let b = value[i];
-        if b >= b'0' && b <= b'9' {
+        if b.is_ascii_digit() {
             u = u
                 .checked_mul(10)
                 .ok_or(ParseError::Overflow)?
@@ -84,7 +98,7 @@ fn parse_from(value: &[u8]) -> Result<FileTime, ParseError> {
         i += 1;
         while i < value.len() {
             let b = value[i];
-            if b >= b'0' && b <= b'9' {
+            if b.is_ascii_digit() {
                 d = d
                     .checked_mul(10)
                     .ok_or(ParseError::Overflow)?
@@ -125,5 +139,4 @@ mod test {
         eprintln!("s={:?}", time::SystemTime::now());
         eprintln!("s={:?}", s);
     }
-
 }
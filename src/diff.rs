@@ -0,0 +1,517 @@
+//! Entry-level comparison of two archives.
+//!
+//! Both sides are reduced to [`Summary`]s, an entry and the digest of its
+//! body, as the bodies stream by. Entries are matched by path; a matched
+//! pair whose type, metadata or content differ is reported as changed.
+//!
+//! Archives whose entries are in path order, such as those of
+//! [`create_from_dir`](crate::create::create_from_dir) or
+//! [`normalize`](crate::transform::normalize::normalize), can be compared as
+//! a merge with [`DiffOptions::set_sorted`], holding one summary per side
+//! and reporting added and removed entries as the merge passes them.
+//! Otherwise unmatched summaries are kept by path until their counterpart
+//! turns up, and whatever is left at the end was added or removed. Entries
+//! that share a path are matched in the order they come.
+
+use crate::decode::flat::{TarEntry, TarItem};
+use crate::decode::hardlink::normalize_path;
+use crate::decode::Error;
+use crate::digest::{digest_items, Digest, Digested, Hasher};
+use crate::transform::normalize::path_order;
+use futures::{prelude::*, stream, try_ready};
+use std::cmp::Ordering;
+use std::collections::{btree_map, BTreeMap, VecDeque};
+use std::fmt::Debug;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DiffOptions {
+    sorted: bool,
+}
+
+impl DiffOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[inline]
+    pub fn sorted(&self) -> bool {
+        self.sorted
+    }
+
+    /// Both archives are in [`path_order`] and contain every path once, so
+    /// they can be merged in bounded memory. From the first entry out of
+    /// order on, the rest is compared as if this was not set; differences
+    /// already reported stand.
+    #[inline]
+    pub fn set_sorted(&mut self, sorted: bool) -> &mut Self {
+        self.sorted = sorted;
+        self
+    }
+}
+
+/// An entry and the digest of its body, if it has one.
+#[derive(Clone, Debug)]
+pub struct Summary {
+    entry: TarEntry,
+    digest: Option<Digest>,
+}
+
+impl Summary {
+    #[inline]
+    pub fn entry(&self) -> &TarEntry {
+        &self.entry
+    }
+
+    #[inline]
+    pub fn digest(&self) -> Option<&Digest> {
+        self.digest.as_ref()
+    }
+
+    fn key(&self) -> Vec<u8> {
+        normalize_path(self.entry.path_bytes()).to_vec()
+    }
+}
+
+/// What differs between two entries at the same path.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Changes {
+    pub entry_type: bool,
+    /// Mode, owner, mtime, device numbers or extended attributes.
+    pub metadata: bool,
+    /// Body, or link target.
+    pub content: bool,
+}
+
+impl Changes {
+    pub fn between(old: &Summary, new: &Summary) -> Changes {
+        let (a, b) = (&old.entry, &new.entry);
+        Changes {
+            entry_type: a.entry_type() != b.entry_type(),
+            metadata: a.mode() != b.mode()
+                || a.uid() != b.uid()
+                || a.gid() != b.gid()
+                || a.uname() != b.uname()
+                || a.gname() != b.gname()
+                || a.mtime() != b.mtime()
+                || a.device() != b.device()
+                || a.xattrs() != b.xattrs(),
+            content: old.digest != new.digest
+                || a.size() != b.size()
+                || a.link_bytes() != b.link_bytes(),
+        }
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        !(self.entry_type || self.metadata || self.content)
+    }
+}
+
+#[derive(Clone, Debug)]
+#[allow(clippy::large_enum_variant)]
+pub enum Difference {
+    /// Only in the second archive.
+    Added(Summary),
+    /// Only in the first archive.
+    Removed(Summary),
+    Changed {
+        old: Summary,
+        new: Summary,
+        changes: Changes,
+    },
+}
+
+impl Difference {
+    /// Path of the entry, as in the archive that has it, or the second one.
+    pub fn path(&self) -> &[u8] {
+        match self {
+            Difference::Added(summary) | Difference::Removed(summary) => summary.entry.path_bytes(),
+            Difference::Changed { new, .. } => new.entry.path_bytes(),
+        }
+    }
+}
+
+struct Summaries<S> {
+    upstream: S,
+    pending: Option<TarEntry>,
+}
+
+impl<E, S> Stream for Summaries<S>
+where
+    E: Debug + Send + Sync + 'static,
+    S: Stream<Item = Digested, Error = Error<E>>,
+{
+    type Item = Summary;
+    type Error = Error<E>;
+
+    fn poll(&mut self) -> Result<Async<Option<Summary>>, Error<E>> {
+        loop {
+            let (entry, digest) = match try_ready!(self.upstream.poll()) {
+                Some(Digested::Item(TarItem::Entry(entry))) => match self.pending.replace(entry) {
+                    Some(previous) => (previous, None),
+                    None => continue,
+                },
+                Some(Digested::Item(TarItem::Chunk(_))) => continue,
+                Some(Digested::Digest(digest)) => match self.pending.take() {
+                    Some(entry) => (entry, Some(digest)),
+                    None => continue,
+                },
+                None => match self.pending.take() {
+                    Some(entry) => (entry, None),
+                    None => return Ok(Async::Ready(None)),
+                },
+            };
+            return Ok(Async::Ready(Some(Summary { entry, digest })));
+        }
+    }
+}
+
+/// Reduces a flat item stream to one summary per entry, digesting bodies
+/// with `hasher`.
+pub fn summarize<E, S, H>(items: S, hasher: H) -> impl Stream<Item = Summary, Error = Error<E>>
+where
+    E: Debug + Send + Sync + 'static,
+    S: Stream<Item = TarItem, Error = Error<E>>,
+    H: Hasher,
+{
+    Summaries {
+        upstream: digest_items(items, hasher),
+        pending: None,
+    }
+}
+
+/// One side of the comparison.
+struct Side<S> {
+    summaries: stream::Fuse<S>,
+    head: Option<(Vec<u8>, Summary)>,
+    last: Option<Vec<u8>>,
+    /// Set once an entry came out of order.
+    unsorted: bool,
+    /// Summaries waiting for their counterpart, oldest first for each path.
+    unmatched: BTreeMap<Vec<u8>, VecDeque<Summary>>,
+}
+
+impl<E, S> Side<S>
+where
+    E: Debug + Send + Sync + 'static,
+    S: Stream<Item = Summary, Error = Error<E>>,
+{
+    fn new(summaries: S) -> Self {
+        Side {
+            summaries: summaries.fuse(),
+            head: None,
+            last: None,
+            unsorted: false,
+            unmatched: BTreeMap::new(),
+        }
+    }
+
+    /// Takes the oldest unmatched summary at `key`.
+    fn take_unmatched(&mut self, key: &[u8]) -> Option<Summary> {
+        match self.unmatched.entry(key.to_vec()) {
+            btree_map::Entry::Occupied(mut waiting) => {
+                let summary = waiting.get_mut().pop_front();
+                if waiting.get().is_empty() {
+                    waiting.remove();
+                }
+                summary
+            }
+            btree_map::Entry::Vacant(_) => None,
+        }
+    }
+
+    fn keep_unmatched(&mut self, key: Vec<u8>, summary: Summary) {
+        self.unmatched.entry(key).or_default().push_back(summary);
+    }
+
+    /// Fills `head`; ready with `false` once the side is exhausted.
+    fn poll_head(&mut self, sorted: bool) -> Poll<bool, Error<E>> {
+        if self.head.is_none() {
+            if let Some(summary) = try_ready!(self.summaries.poll()) {
+                let key = summary.key();
+                if sorted {
+                    if let Some(ref last) = self.last {
+                        if path_order(last, &key) != Ordering::Less {
+                            self.unsorted = true;
+                        }
+                    }
+                    self.last = Some(key.clone());
+                }
+                self.head = Some((key, summary));
+            }
+        }
+        Ok(Async::Ready(self.head.is_some()))
+    }
+}
+
+struct Diff<L, R> {
+    left: Side<L>,
+    right: Side<R>,
+    sorted: bool,
+    /// Added and removed entries, once both sides are exhausted.
+    rest: Option<VecDeque<Difference>>,
+}
+
+fn changed(old: Summary, new: Summary) -> Option<Difference> {
+    let changes = Changes::between(&old, &new);
+    if changes.is_empty() {
+        None
+    } else {
+        Some(Difference::Changed { old, new, changes })
+    }
+}
+
+impl<E, L, R> Diff<L, R>
+where
+    E: Debug + Send + Sync + 'static,
+    L: Stream<Item = Summary, Error = Error<E>>,
+    R: Stream<Item = Summary, Error = Error<E>>,
+{
+    fn merge_step(&mut self) -> Option<Difference> {
+        let order = match (&self.left.head, &self.right.head) {
+            (Some((left, _)), Some((right, _))) => path_order(left, right),
+            (Some(_), None) => Ordering::Less,
+            _ => Ordering::Greater,
+        };
+        match order {
+            Ordering::Less => Some(Difference::Removed(self.left.head.take().unwrap().1)),
+            Ordering::Greater => Some(Difference::Added(self.right.head.take().unwrap().1)),
+            Ordering::Equal => {
+                let (_, old) = self.left.head.take().unwrap();
+                let (_, new) = self.right.head.take().unwrap();
+                changed(old, new)
+            }
+        }
+    }
+
+    fn index_step(&mut self) -> Option<Difference> {
+        // Taking the smaller path first keeps the maps small for archives
+        // that are mostly in order.
+        let from_left = match (&self.left.head, &self.right.head) {
+            (Some((left, _)), Some((right, _))) => path_order(left, right) != Ordering::Greater,
+            (left, _) => left.is_some(),
+        };
+        if from_left {
+            let (key, old) = self.left.head.take().unwrap();
+            match self.right.take_unmatched(&key) {
+                Some(new) => changed(old, new),
+                None => {
+                    self.left.keep_unmatched(key, old);
+                    None
+                }
+            }
+        } else {
+            let (key, new) = self.right.head.take().unwrap();
+            match self.left.take_unmatched(&key) {
+                Some(old) => changed(old, new),
+                None => {
+                    self.right.keep_unmatched(key, new);
+                    None
+                }
+            }
+        }
+    }
+
+    fn unmatched(&mut self) -> VecDeque<Difference> {
+        // The map is in byte order; the result is in path order.
+        let flatten = |unmatched: BTreeMap<Vec<u8>, VecDeque<Summary>>| {
+            let mut flat: Vec<_> = unmatched
+                .into_iter()
+                .flat_map(|(key, summaries)| summaries.into_iter().map(move |s| (key.clone(), s)))
+                .collect();
+            flat.sort_by(|(a, _), (b, _)| path_order(a, b));
+            flat.into_iter()
+        };
+        let mut removed = flatten(std::mem::take(&mut self.left.unmatched)).peekable();
+        let mut added = flatten(std::mem::take(&mut self.right.unmatched)).peekable();
+        let mut rest = VecDeque::new();
+        loop {
+            let take_removed = match (removed.peek(), added.peek()) {
+                (Some((left, _)), Some((right, _))) => path_order(left, right) == Ordering::Less,
+                (left, right) => match (left, right) {
+                    (Some(_), None) => true,
+                    (None, Some(_)) => false,
+                    _ => break,
+                },
+            };
+            if take_removed {
+                rest.push_back(Difference::Removed(removed.next().unwrap().1));
+            } else {
+                rest.push_back(Difference::Added(added.next().unwrap().1));
+            }
+        }
+        rest
+    }
+}
+
+impl<E, L, R> Stream for Diff<L, R>
+where
+    E: Debug + Send + Sync + 'static,
+    L: Stream<Item = Summary, Error = Error<E>>,
+    R: Stream<Item = Summary, Error = Error<E>>,
+{
+    type Item = Difference;
+    type Error = Error<E>;
+
+    fn poll(&mut self) -> Result<Async<Option<Difference>>, Error<E>> {
+        loop {
+            if let Some(ref mut rest) = self.rest {
+                return Ok(Async::Ready(rest.pop_front()));
+            }
+            let left = self.left.poll_head(self.sorted)?;
+            let right = self.right.poll_head(self.sorted)?;
+            let (left, right) = match (left, right) {
+                (Async::Ready(left), Async::Ready(right)) => (left, right),
+                _ => return Ok(Async::NotReady),
+            };
+            if !left && !right {
+                self.rest = Some(self.unmatched());
+                continue;
+            }
+            if self.left.unsorted || self.right.unsorted {
+                // The heads are compared by index from here on.
+                self.sorted = false;
+            }
+            let difference = if self.sorted {
+                self.merge_step()
+            } else {
+                self.index_step()
+            };
+            if let Some(difference) = difference {
+                return Ok(Async::Ready(Some(difference)));
+            }
+        }
+    }
+}
+
+/// Compares two flat item streams entry by entry, digesting bodies with
+/// clones of `hasher`.
+///
+/// Changed entries are reported as they are matched. Added and removed ones
+/// are reported as the merge passes them for [sorted](DiffOptions::set_sorted)
+/// archives, and otherwise follow at the end, in path order.
+pub fn diff<E, L, R, H>(
+    left: L,
+    right: R,
+    hasher: H,
+    options: DiffOptions,
+) -> impl Stream<Item = Difference, Error = Error<E>>
+where
+    E: Debug + Send + Sync + 'static,
+    L: Stream<Item = TarItem, Error = Error<E>>,
+    R: Stream<Item = TarItem, Error = Error<E>>,
+    H: Hasher + Clone,
+{
+    Diff {
+        left: Side::new(summarize(left, hasher.clone())),
+        right: Side::new(summarize(right, hasher)),
+        sorted: options.sorted,
+        rest: None,
+    }
+}
+
+#[cfg(all(test, feature = "sha2"))]
+mod test {
+    use super::*;
+    use crate::digest::Sha256;
+    use bytes::Bytes;
+
+    fn items(entries: &[(&str, &str, u32)]) -> Vec<TarItem> {
+        let mut items = Vec::new();
+        for (path, body, mode) in entries {
+            let mut entry = TarEntry::new(tar::EntryType::Regular, *path);
+            entry.set_size(body.len() as u64).set_mode(*mode);
+            items.push(TarItem::Entry(entry));
+            items.push(TarItem::Chunk(Bytes::from(*body)));
+        }
+        items
+    }
+
+    fn run(left: Vec<TarItem>, right: Vec<TarItem>, sorted: bool) -> Vec<(String, String)> {
+        let mut options = DiffOptions::new();
+        options.set_sorted(sorted);
+        diff(
+            stream::iter_ok::<_, Error<()>>(left),
+            stream::iter_ok(right),
+            Sha256::new(),
+            options,
+        )
+        .collect()
+        .wait()
+        .unwrap()
+        .into_iter()
+        .map(|difference| {
+            let kind = match difference {
+                Difference::Added(_) => "added".to_string(),
+                Difference::Removed(_) => "removed".to_string(),
+                Difference::Changed { changes, .. } => format!(
+                    "changed{}{}",
+                    if changes.metadata { " metadata" } else { "" },
+                    if changes.content { " content" } else { "" },
+                ),
+            };
+            (
+                String::from_utf8_lossy(difference.path()).into_owned(),
+                kind,
+            )
+        })
+        .collect()
+    }
+
+    #[test]
+    fn test_diff() {
+        let old = [
+            ("a", "a", 0o644),
+            ("b", "b", 0o644),
+            ("c", "c", 0o644),
+            ("d/e", "e", 0o644),
+        ];
+        let new = [
+            ("a", "a", 0o644),
+            ("b", "B", 0o644),
+            ("c", "c", 0o755),
+            ("d/f", "f", 0o644),
+            ("d.txt", "", 0o644),
+        ];
+        let expected = vec![
+            ("b".to_string(), "changed content".to_string()),
+            ("c".to_string(), "changed metadata".to_string()),
+            ("d/e".to_string(), "removed".to_string()),
+            ("d/f".to_string(), "added".to_string()),
+            ("d.txt".to_string(), "added".to_string()),
+        ];
+        assert_eq!(run(items(&old), items(&new), true), expected);
+
+        let mut shuffled = new;
+        shuffled.reverse();
+        let unsorted = run(items(&old), items(&shuffled), false);
+        let mut sorted_unsorted = unsorted.clone();
+        sorted_unsorted.sort();
+        let mut sorted_expected = expected;
+        sorted_expected.sort();
+        assert_eq!(sorted_unsorted, sorted_expected);
+
+        // `b` came out of order: it was passed as removed before the
+        // fallback, and has no counterpart left after it.
+        let late = [("a", "a", 0o644), ("c", "c", 0o644), ("b", "b", 0o644)];
+        let expected = vec![
+            ("b".to_string(), "removed".to_string()),
+            ("b".to_string(), "added".to_string()),
+            ("d/e".to_string(), "removed".to_string()),
+        ];
+        assert_eq!(run(items(&old), items(&late), true), expected);
+    }
+
+    #[test]
+    fn test_diff_duplicates() {
+        let old = [("a", "1", 0o644), ("a", "2", 0o644), ("b", "b", 0o644)];
+        let new = [("b", "b", 0o644), ("a", "1", 0o644), ("a", "3", 0o644)];
+        let expected = vec![("a".to_string(), "changed content".to_string())];
+        assert_eq!(run(items(&old), items(&new), false), expected);
+        let in_order = [("a", "1", 0o644), ("a", "3", 0o644), ("b", "b", 0o644)];
+        assert_eq!(run(items(&old), items(&in_order), true), expected);
+
+        let expected = vec![("a".to_string(), "removed".to_string())];
+        assert_eq!(run(items(&old), items(&new[..2]), false), expected);
+    }
+}
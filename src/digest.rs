@@ -0,0 +1,426 @@
+//! Content digests computed as data streams by.
+//!
+//! A [`Hasher`] sees every body chunk exactly once, as the decoder hands it
+//! on, so checksumming costs no extra pass and no copies. [`digest_items`]
+//! does this for flat item streams;
+//! [`full::decode_items_with_digests`](crate::decode::full::decode_items_with_digests)
+//! for entries.
+//!
+//! Whole byte streams are digested with [`tee_digest`]; [`decode_digested`]
+//! stacks it below and above decompression to get the digest of a
+//! compressed layer and its OCI diff-id in a single pass.
+
+use crate::compression;
+use crate::decode::flat::{self, TarItem};
+use crate::decode::Error;
+use bytes::Bytes;
+use futures::{prelude::*, try_ready};
+use std::fmt::{self, Debug};
+use std::sync::{Arc, Mutex};
+
+/// Output of a [`Hasher`], e.g. `sha256:e3b0c442...`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Digest {
+    algorithm: &'static str,
+    bytes: Vec<u8>,
+}
+
+impl Digest {
+    pub fn new(algorithm: &'static str, bytes: Vec<u8>) -> Self {
+        Digest { algorithm, bytes }
+    }
+
+    #[inline]
+    pub fn algorithm(&self) -> &'static str {
+        self.algorithm
+    }
+
+    #[inline]
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn hex(&self) -> String {
+        self.bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.algorithm, self.hex())
+    }
+}
+
+/// Incremental hash function.
+pub trait Hasher: Send {
+    fn update(&mut self, data: &[u8]);
+
+    /// Returns the digest of everything seen so far and starts over.
+    fn finish(&mut self) -> Digest;
+}
+
+#[cfg(feature = "sha2")]
+#[derive(Clone, Default)]
+pub struct Sha256(sha2::Sha256);
+
+#[cfg(feature = "sha2")]
+impl Sha256 {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[cfg(feature = "sha2")]
+impl Hasher for Sha256 {
+    fn update(&mut self, data: &[u8]) {
+        sha2::Digest::update(&mut self.0, data);
+    }
+
+    fn finish(&mut self) -> Digest {
+        Digest::new("sha256", sha2::Digest::finalize_reset(&mut self.0).to_vec())
+    }
+}
+
+impl<H: Hasher + ?Sized> Hasher for Box<H> {
+    fn update(&mut self, data: &[u8]) {
+        (**self).update(data)
+    }
+
+    fn finish(&mut self) -> Digest {
+        (**self).finish()
+    }
+}
+
+/// Whether an entry gets a digest: files, even empty ones, and anything
+/// else that carries a body.
+pub(crate) fn has_digest(entry: &crate::decode::flat::TarEntry) -> bool {
+    entry.entry_type().is_file() || entry.size() > 0
+}
+
+/// Output of [`digest_items`].
+#[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
+pub enum Digested {
+    Item(TarItem),
+    /// Digest of the body that just ended.
+    Digest(Digest),
+}
+
+impl Digested {
+    /// Drops digests, for feeding the result to an encoder.
+    pub fn into_item(self) -> Option<TarItem> {
+        match self {
+            Digested::Item(item) => Some(item),
+            Digested::Digest(_) => None,
+        }
+    }
+}
+
+struct DigestItems<S, H> {
+    upstream: S,
+    hasher: H,
+    /// Body bytes left in the current entry; `None` outside of digested
+    /// entries.
+    remaining: Option<u64>,
+}
+
+impl<E, S, H> Stream for DigestItems<S, H>
+where
+    E: Debug + Send + Sync + 'static,
+    S: Stream<Item = TarItem, Error = Error<E>>,
+    H: Hasher,
+{
+    type Item = Digested;
+    type Error = Error<E>;
+
+    fn poll(&mut self) -> Result<Async<Option<<Self as Stream>::Item>>, <Self as Stream>::Error> {
+        if self.remaining == Some(0) {
+            self.remaining = None;
+            return Ok(Async::Ready(Some(Digested::Digest(self.hasher.finish()))));
+        }
+        let item = try_ready!(self.upstream.poll());
+        match item {
+            Some(TarItem::Entry(ref entry)) => {
+                if self.remaining.is_some() {
+                    return Err(Error::UnexpectedEof);
+                }
+                if has_digest(entry) {
+                    self.remaining = Some(entry.size());
+                }
+            }
+            Some(TarItem::Chunk(ref bytes)) => {
+                self.hasher.update(bytes);
+                if let Some(ref mut remaining) = self.remaining {
+                    *remaining = remaining.saturating_sub(bytes.len() as u64);
+                }
+            }
+            None if self.remaining.is_some() => return Err(Error::UnexpectedEof),
+            None => (),
+        }
+        Ok(Async::Ready(item.map(Digested::Item)))
+    }
+}
+
+/// Passes a flat item stream through and follows the body of every file
+/// entry with its digest; an empty file is followed by the digest of no
+/// data.
+pub fn digest_items<E, S, H>(items: S, hasher: H) -> impl Stream<Item = Digested, Error = Error<E>>
+where
+    E: Debug + Send + Sync + 'static,
+    S: Stream<Item = TarItem, Error = Error<E>>,
+    H: Hasher,
+{
+    DigestItems {
+        upstream: items,
+        hasher,
+        remaining: None,
+    }
+}
+
+/// Digest and length of a complete byte stream.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Summary {
+    digest: Digest,
+    size: u64,
+}
+
+impl Summary {
+    pub fn new(digest: Digest, size: u64) -> Self {
+        Summary { digest, size }
+    }
+
+    #[inline]
+    pub fn digest(&self) -> &Digest {
+        &self.digest
+    }
+
+    #[inline]
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+/// Where a [`TeeDigest`] leaves its [`Summary`].
+#[derive(Clone, Debug, Default)]
+pub struct DigestHandle(Arc<Mutex<Option<Summary>>>);
+
+impl DigestHandle {
+    /// The summary, once the stream has ended.
+    pub fn get(&self) -> Option<Summary> {
+        self.0.lock().unwrap().clone()
+    }
+
+    pub(crate) fn set(&self, summary: Summary) {
+        *self.0.lock().unwrap() = Some(summary);
+    }
+}
+
+pub struct TeeDigest<S, H> {
+    upstream: S,
+    hasher: H,
+    size: u64,
+    handle: DigestHandle,
+}
+
+impl<S, H> Stream for TeeDigest<S, H>
+where
+    S: Stream<Item = Bytes>,
+    H: Hasher,
+{
+    type Item = Bytes;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Result<Async<Option<Bytes>>, S::Error> {
+        match try_ready!(self.upstream.poll()) {
+            Some(bytes) => {
+                self.hasher.update(&bytes);
+                self.size += bytes.len() as u64;
+                Ok(Async::Ready(Some(bytes)))
+            }
+            None => {
+                if self.handle.get().is_none() {
+                    self.handle
+                        .set(Summary::new(self.hasher.finish(), self.size));
+                }
+                Ok(Async::Ready(None))
+            }
+        }
+    }
+}
+
+/// Passes a byte stream through unchanged, digesting it on the way.
+pub fn tee_digest<S, H>(upstream: S, hasher: H) -> (TeeDigest<S, H>, DigestHandle)
+where
+    S: Stream<Item = Bytes>,
+    H: Hasher,
+{
+    let handle = DigestHandle::default();
+    let tee = TeeDigest {
+        upstream,
+        hasher,
+        size: 0,
+        handle: handle.clone(),
+    };
+    (tee, handle)
+}
+
+/// Summaries of both levels of a possibly compressed archive.
+#[derive(Clone, Debug)]
+pub struct ArchiveDigests {
+    compressed: DigestHandle,
+    uncompressed: DigestHandle,
+}
+
+impl ArchiveDigests {
+    /// The bytes as they came in; for an OCI layer this is the digest in
+    /// the manifest.
+    pub fn compressed(&self) -> Option<Summary> {
+        self.compressed.get()
+    }
+
+    /// The decompressed tar; for an OCI layer this is the diff-id.
+    pub fn uncompressed(&self) -> Option<Summary> {
+        self.uncompressed.get()
+    }
+}
+
+/// Decompresses (autodetecting the format) and decodes an archive while
+/// digesting it before and after decompression.
+///
+/// Both summaries are available once the returned stream has ended.
+pub fn decode_digested<S, H>(
+    upstream: S,
+    hasher: H,
+) -> (
+    impl Stream<Item = TarItem, Error = Error<S::Error>>,
+    ArchiveDigests,
+)
+where
+    S: Stream<Item = Bytes>,
+    S::Error: Debug + Send + Sync + 'static,
+    H: Hasher + Clone,
+{
+    let (compressed, compressed_handle) = tee_digest(upstream, hasher.clone());
+    let (tar, tar_handle) = tee_digest(compression::decompress(compressed, None), hasher);
+    let digests = ArchiveDigests {
+        compressed: compressed_handle,
+        uncompressed: tar_handle,
+    };
+    (flat::decode_nested(tar), digests)
+}
+
+#[cfg(all(test, feature = "sha2"))]
+mod test {
+    use super::*;
+    use crate::decode::flat::TarEntry;
+    use bytes::Bytes;
+    use futures::stream;
+
+    const EMPTY: &str = "sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+    const HELLO: &str = "sha256:2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+    #[test]
+    fn test_digest_items() {
+        let mut file = TarEntry::new(tar::EntryType::Regular, "file");
+        file.set_size(5);
+        let items = vec![
+            TarItem::Entry(TarEntry::new(tar::EntryType::Directory, "dir/")),
+            TarItem::Entry(file),
+            TarItem::Chunk(Bytes::from_static(b"hel")),
+            TarItem::Chunk(Bytes::from_static(b"lo")),
+            TarItem::Entry(TarEntry::new(tar::EntryType::Regular, "empty")),
+        ];
+
+        let output: Vec<String> =
+            digest_items(stream::iter_ok::<_, Error<()>>(items), Sha256::new())
+                .map(|item| match item {
+                    Digested::Item(TarItem::Entry(entry)) => {
+                        String::from_utf8_lossy(entry.path_bytes()).into_owned()
+                    }
+                    Digested::Item(TarItem::Chunk(_)) => "chunk".to_string(),
+                    Digested::Digest(digest) => digest.to_string(),
+                })
+                .collect()
+                .wait()
+                .unwrap();
+        assert_eq!(
+            output,
+            vec!["dir/", "file", "chunk", "chunk", HELLO, "empty", EMPTY]
+        );
+    }
+
+    #[test]
+    fn test_entry_digest() {
+        let mut tar = tar::Builder::new(Vec::new());
+        for (path, data) in &[("skipped", &b"partial"[..]), ("hello", b"hello")] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_uid(0);
+            header.set_gid(0);
+            header.set_mtime(0);
+            tar.append_data(&mut header, path, *data).unwrap();
+        }
+        let tar = Bytes::from(tar.into_inner().unwrap());
+        // Small chunks, so that the first body is dropped half read.
+        let chunks: Vec<Bytes> = tar.chunks(3).map(Bytes::from).collect();
+
+        let items = crate::decode::flat::decode_tar(stream::iter_ok::<_, ()>(chunks));
+        let mut digests = Vec::new();
+        for entry in crate::decode::full::decode_items_with_digests(items, Sha256::new()).wait() {
+            let mut entry = entry.unwrap();
+            let limit = if entry.header().path_bytes() == b"skipped" {
+                1
+            } else {
+                u64::MAX
+            };
+            entry
+                .by_ref()
+                .take(limit)
+                .for_each(|_| Ok(()))
+                .wait()
+                .unwrap();
+            digests.push(entry.digest().map(|digest| digest.to_string()));
+        }
+        assert_eq!(digests, vec![None, Some(HELLO.to_string())]);
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_decode_digested() {
+        use std::io::Write;
+
+        let mut tar = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(5);
+        header.set_mode(0o644);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mtime(0);
+        tar.append_data(&mut header, "hello", &b"hello"[..])
+            .unwrap();
+        let tar = tar.into_inner().unwrap();
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gz.write_all(&tar).unwrap();
+        let gz = gz.finish().unwrap();
+
+        let (items, digests) = decode_digested(
+            stream::once::<_, ()>(Ok(Bytes::from(gz.clone()))),
+            Sha256::new(),
+        );
+        assert!(digests.uncompressed().is_none());
+        assert_eq!(items.collect().wait().unwrap().len(), 2);
+
+        let expect = |data: &[u8]| {
+            let mut hasher = Sha256::new();
+            hasher.update(data);
+            hasher.finish()
+        };
+        let compressed = digests.compressed().unwrap();
+        assert_eq!(compressed.size(), gz.len() as u64);
+        assert_eq!(compressed.digest(), &expect(&gz));
+        let diff_id = digests.uncompressed().unwrap();
+        assert_eq!(diff_id.size(), tar.len() as u64);
+        assert_eq!(diff_id.digest(), &expect(&tar));
+    }
+}
@@ -1 +1,4 @@
+pub mod flat;
 pub mod raw;
+
+pub use super::error::Error;
@@ -0,0 +1,312 @@
+use super::raw;
+use super::Error;
+use crate::decode::flat::{TarEntry, TarItem};
+use crate::decode::raw::RawTarItem;
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::{prelude::*, try_ready};
+use std::collections::VecDeque;
+use std::fmt::Debug;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const NAME_LEN: usize = 100;
+const PREFIX_LEN: usize = 155;
+const OWNER_NAME_LEN: usize = 32;
+const MAX_OCTAL_ID: u64 = 0o7_777_777;
+const MAX_OCTAL_SIZE: u64 = 0o77_777_777_777;
+const XATTR_PREFIX: &[u8] = b"SCHILY.xattr.";
+
+/// Accumulates PAX extended header records.
+#[derive(Default)]
+struct PaxRecords(BytesMut);
+
+impl PaxRecords {
+    fn add(&mut self, key: &[u8], value: &[u8]) {
+        // The length prefix counts itself, so it may need one more digit.
+        let rest = key.len() + value.len() + 3;
+        let mut len = rest + 1;
+        while len.to_string().len() + rest > len {
+            len += 1;
+        }
+        self.0.reserve(len);
+        self.0.put_slice(len.to_string().as_bytes());
+        self.0.put_u8(b' ');
+        self.0.put_slice(key);
+        self.0.put_u8(b'=');
+        self.0.put_slice(value);
+        self.0.put_u8(b'\n');
+    }
+
+    fn add_time(&mut self, key: &str, time: SystemTime) {
+        self.add(key.as_bytes(), format_time(time).as_bytes())
+    }
+}
+
+fn format_time(time: SystemTime) -> String {
+    let d = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    if d.subsec_nanos() == 0 {
+        d.as_secs().to_string()
+    } else {
+        let nanos = format!("{:09}", d.subsec_nanos());
+        format!("{}.{}", d.as_secs(), nanos.trim_end_matches('0'))
+    }
+}
+
+/// Splits a path into ustar `prefix` and `name` fields, if it fits.
+fn split_ustar_path(path: &[u8]) -> Option<(&[u8], &[u8])> {
+    if path.len() <= NAME_LEN {
+        return Some((&[], path));
+    }
+    let start = path.len().saturating_sub(NAME_LEN + 1);
+    path[start..]
+        .iter()
+        .position(|b| *b == b'/')
+        .map(|pos| start + pos)
+        .filter(|pos| *pos <= PREFIX_LEN && *pos > 0)
+        .map(|pos| (&path[..pos], &path[pos + 1..]))
+}
+
+fn copy_truncated(dst: &mut [u8], src: &[u8]) {
+    let len = std::cmp::min(dst.len(), src.len());
+    dst[..len].copy_from_slice(&src[..len]);
+}
+
+/// Builds the header blocks for an entry: an optional PAX extended header
+/// with its records, followed by the ustar header itself.
+///
+/// Values that do not fit the ustar fields (long paths and names, large ids
+/// and sizes, sub-second or extra timestamps, extended attributes) go to the
+/// PAX records.
+pub fn entry_headers(entry: &TarEntry) -> Vec<RawTarItem> {
+    let mut pax = PaxRecords::default();
+    let mut header = tar::Header::new_ustar();
+    header.set_entry_type(entry.entry_type());
+    header.set_mode(entry.mode());
+
+    {
+        let ustar = header.as_ustar_mut().unwrap();
+        let path = entry.path_bytes();
+        match split_ustar_path(path) {
+            Some((prefix, name)) => {
+                ustar.prefix[..prefix.len()].copy_from_slice(prefix);
+                ustar.name[..name.len()].copy_from_slice(name);
+            }
+            None => {
+                copy_truncated(&mut ustar.name, path);
+                pax.add(b"path", path);
+            }
+        }
+
+        if let Some(link) = entry.link_bytes() {
+            copy_truncated(&mut ustar.linkname, link);
+            if link.len() > NAME_LEN {
+                pax.add(b"linkpath", link);
+            }
+        }
+
+        if let Some(uname) = entry.uname() {
+            copy_truncated(&mut ustar.uname, uname);
+            if uname.len() > OWNER_NAME_LEN {
+                pax.add(b"uname", uname);
+            }
+        }
+        if let Some(gname) = entry.gname() {
+            copy_truncated(&mut ustar.gname, gname);
+            if gname.len() > OWNER_NAME_LEN {
+                pax.add(b"gname", gname);
+            }
+        }
+
+        if let Some((major, minor)) = entry.device() {
+            ustar.set_device_major(major);
+            ustar.set_device_minor(minor);
+        }
+    }
+
+    header.set_uid(entry.uid());
+    if entry.uid() > MAX_OCTAL_ID {
+        pax.add(b"uid", entry.uid().to_string().as_bytes());
+    }
+    header.set_gid(entry.gid());
+    if entry.gid() > MAX_OCTAL_ID {
+        pax.add(b"gid", entry.gid().to_string().as_bytes());
+    }
+    header.set_size(entry.size());
+    if entry.size() > MAX_OCTAL_SIZE {
+        pax.add(b"size", entry.size().to_string().as_bytes());
+    }
+
+    let mtime = entry.mtime();
+    let mtime_secs = mtime.duration_since(UNIX_EPOCH).unwrap_or_default();
+    header.set_mtime(mtime_secs.as_secs());
+    if mtime_secs.subsec_nanos() != 0 {
+        pax.add_time("mtime", mtime);
+    }
+    if let Some(atime) = entry.atime() {
+        pax.add_time("atime", atime);
+    }
+    if let Some(ctime) = entry.ctime() {
+        pax.add_time("ctime", ctime);
+    }
+    for (name, value) in entry.xattrs() {
+        pax.add(&[XATTR_PREFIX, name].concat(), value);
+    }
+    header.set_cksum();
+
+    let mut items = Vec::with_capacity(3);
+    if !pax.0.is_empty() {
+        let records = pax.0.freeze();
+        let mut pax_header = tar::Header::new_ustar();
+        pax_header.set_entry_type(tar::EntryType::XHeader);
+        pax_header.set_mode(0o644);
+        pax_header.set_uid(0);
+        pax_header.set_gid(0);
+        pax_header.set_mtime(mtime_secs.as_secs());
+        pax_header.set_size(records.len() as u64);
+        {
+            let ustar = pax_header.as_ustar_mut().unwrap();
+            let mut name = b"PaxHeaders/".to_vec();
+            let path = entry.path_bytes();
+            name.extend_from_slice(match path.iter().rposition(|b| *b == b'/') {
+                Some(pos) if pos + 1 < path.len() => &path[pos + 1..],
+                _ => path,
+            });
+            copy_truncated(&mut ustar.name, &name);
+        }
+        pax_header.set_cksum();
+        items.push(RawTarItem::Header(pax_header));
+        items.push(RawTarItem::Chunk(records));
+    }
+    items.push(RawTarItem::Header(header));
+    items
+}
+
+struct HeaderEncoder<Upstream> {
+    upstream: Upstream,
+    pending: VecDeque<RawTarItem>,
+}
+
+impl<E, Upstream> Stream for HeaderEncoder<Upstream>
+where
+    E: Debug + Send + Sync + 'static,
+    Upstream: Stream<Item = TarItem, Error = Error<E>>,
+{
+    type Item = RawTarItem;
+    type Error = Error<E>;
+
+    fn poll(&mut self) -> Result<Async<Option<<Self as Stream>::Item>>, <Self as Stream>::Error> {
+        if let Some(item) = self.pending.pop_front() {
+            return Ok(Async::Ready(Some(item)));
+        }
+        Ok(Async::Ready(match try_ready!(self.upstream.poll()) {
+            Some(TarItem::Entry(entry)) => {
+                self.pending.extend(entry_headers(&entry));
+                self.pending.pop_front()
+            }
+            Some(TarItem::Chunk(bytes)) => Some(RawTarItem::Chunk(bytes)),
+            None => None,
+        }))
+    }
+}
+
+/// Turns flat items into raw header and chunk items.
+pub fn encode_items<E, S>(items: S) -> impl Stream<Item = RawTarItem, Error = Error<E>>
+where
+    E: Debug + Send + Sync + 'static,
+    S: Stream<Item = TarItem, Error = Error<E>>,
+{
+    HeaderEncoder {
+        upstream: items,
+        pending: VecDeque::new(),
+    }
+}
+
+/// Serializes flat items into a tar byte stream.
+///
+/// Every entry must be followed by chunks totalling exactly its `size()`.
+/// Chunks are forwarded as they are, without copying.
+pub fn encode_tar<E, S>(items: S) -> impl Stream<Item = Bytes, Error = Error<E>>
+where
+    E: Debug + Send + Sync + 'static,
+    S: Stream<Item = TarItem, Error = Error<E>>,
+{
+    raw::encode_tar(encode_items(items))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::decode::flat;
+    use futures::stream;
+    use std::time::Duration;
+
+    #[test]
+    fn test_pax_record_length() {
+        let mut pax = PaxRecords::default();
+        pax.add(b"path", b"abcd");
+        assert_eq!(pax.0.as_ref(), b"13 path=abcd\n");
+
+        let mut pax = PaxRecords::default();
+        pax.add(b"path", &[b'a'; 93]);
+        assert_eq!(pax.0.len(), 103);
+        assert!(pax.0.starts_with(b"103 path="));
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let long_path = format!("{}/file", "d".repeat(200));
+        let mut file = TarEntry::new(tar::EntryType::Regular, long_path.as_bytes());
+        file.set_size(5)
+            .set_uid(1 << 30)
+            .set_uname(Some(b"user".to_vec()))
+            .set_xattrs(vec![(b"user.\xff".to_vec(), b"v".to_vec())])
+            .set_mtime(UNIX_EPOCH + Duration::new(1_546_272_612, 201_798_006));
+        let mut link = TarEntry::new(tar::EntryType::Symlink, "a/link");
+        link.set_link_bytes(Some(long_path.clone().into_bytes()));
+
+        let items = vec![
+            TarItem::Entry(file),
+            TarItem::Chunk(Bytes::from_static(b"he")),
+            TarItem::Chunk(Bytes::from_static(b"llo")),
+            TarItem::Entry(link),
+        ];
+        let tar = encode_tar(stream::iter_ok::<_, Error<()>>(items))
+            .concat2()
+            .wait()
+            .unwrap();
+        assert_eq!(tar.len() % 512, 0);
+
+        let decoded = flat::decode_tar(stream::once::<_, ()>(Ok(tar)))
+            .collect()
+            .wait()
+            .unwrap();
+        match (&decoded[0], &decoded[1], &decoded[2]) {
+            (TarItem::Entry(file), TarItem::Chunk(body), TarItem::Entry(link)) => {
+                assert_eq!(file.path_bytes(), long_path.as_bytes());
+                assert_eq!(file.uid(), 1 << 30);
+                assert_eq!(file.uname(), Some(b"user".as_ref()));
+                assert_eq!(file.xattrs(), &[(b"user.\xff".to_vec(), b"v".to_vec())][..]);
+                assert_eq!(
+                    file.mtime(),
+                    UNIX_EPOCH + Duration::new(1_546_272_612, 201_798_006)
+                );
+                assert_eq!(body.as_ref(), b"hello");
+                assert_eq!(link.link_bytes(), Some(long_path.as_bytes()));
+            }
+            other => panic!("unexpected items: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_size_mismatch() {
+        let mut file = TarEntry::new(tar::EntryType::Regular, "file");
+        file.set_size(5);
+        let items = vec![
+            TarItem::Entry(file),
+            TarItem::Chunk(Bytes::from_static(b"hi")),
+        ];
+        assert!(encode_tar(stream::iter_ok::<_, Error<()>>(items))
+            .concat2()
+            .wait()
+            .is_err());
+    }
+}
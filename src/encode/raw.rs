@@ -1,3 +1,131 @@
 //! raw tar encoder
 
-use tar;
+use super::Error;
+use crate::decode::raw::{RawTarItem, SizeTracker};
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::{prelude::*, try_ready};
+use std::fmt::Debug;
+
+pub(crate) const BLOCK_SIZE: u64 = 512;
+
+/// Enough zeros for any padding, or for the end-of-archive marker.
+pub(crate) static ZEROS: [u8; 1024] = [0; 1024];
+
+/// Zeros needed after a body of `size` bytes to fill its last block.
+#[inline]
+pub(crate) fn padding(size: u64) -> usize {
+    ((BLOCK_SIZE - size % BLOCK_SIZE) % BLOCK_SIZE) as usize
+}
+
+struct RawTarEncoder<Upstream> {
+    upstream: Upstream,
+    in_entry: u64,
+    padding: usize,
+    sizes: SizeTracker,
+    trailer: bool,
+    done: bool,
+}
+
+impl<E, Upstream> RawTarEncoder<Upstream>
+where
+    E: Debug + Send + Sync + 'static,
+    Upstream: Stream<Item = RawTarItem, Error = Error<E>>,
+{
+    fn new(upstream: Upstream, trailer: bool) -> Self {
+        RawTarEncoder {
+            upstream,
+            in_entry: 0,
+            padding: 0,
+            sizes: SizeTracker::default(),
+            trailer,
+            done: false,
+        }
+    }
+
+    /// Block padding still owed by the previous entry followed by `block`.
+    fn with_padding(&mut self, block: &[u8]) -> Bytes {
+        let mut out = BytesMut::with_capacity(self.padding + block.len());
+        out.put_slice(&ZEROS[..self.padding]);
+        out.put_slice(block);
+        self.padding = 0;
+        out.freeze()
+    }
+}
+
+impl<E, Upstream> Stream for RawTarEncoder<Upstream>
+where
+    E: Debug + Send + Sync + 'static,
+    Upstream: Stream<Item = RawTarItem, Error = Error<E>>,
+{
+    type Item = Bytes;
+    type Error = Error<E>;
+
+    fn poll(&mut self) -> Result<Async<Option<<Self as Stream>::Item>>, <Self as Stream>::Error> {
+        if self.done {
+            return Ok(Async::Ready(None));
+        }
+        match try_ready!(self.upstream.poll()) {
+            Some(RawTarItem::Chunk(bytes)) => {
+                if bytes.len() as u64 > self.in_entry {
+                    return Err(Error::Format("entry body longer than its header"));
+                }
+                self.in_entry -= bytes.len() as u64;
+                self.sizes.chunk(&bytes);
+                Ok(Async::Ready(Some(bytes)))
+            }
+            Some(RawTarItem::Header(header)) => {
+                if self.in_entry > 0 {
+                    return Err(Error::Format("entry body shorter than its header"));
+                }
+                let size = self.sizes.header(&header).map_err(Error::IoError)?;
+                let block = self.with_padding(header.as_bytes());
+                self.in_entry = size;
+                self.padding = padding(size);
+                Ok(Async::Ready(Some(block)))
+            }
+            Some(RawTarItem::EmptyHeader) => {
+                if self.in_entry > 0 {
+                    return Err(Error::Format("entry body shorter than its header"));
+                }
+                Ok(Async::Ready(Some(self.with_padding(&ZEROS[..512]))))
+            }
+            None => {
+                if self.in_entry > 0 {
+                    return Err(Error::Format("entry body shorter than its header"));
+                }
+                self.done = true;
+                let trailer = if self.trailer {
+                    &ZEROS[..]
+                } else {
+                    &ZEROS[..0]
+                };
+                let tail = self.with_padding(trailer);
+                Ok(Async::Ready(if tail.is_empty() {
+                    None
+                } else {
+                    Some(tail)
+                }))
+            }
+        }
+    }
+}
+
+/// Serializes raw items, adding block padding after bodies and the two zero
+/// blocks that end an archive.
+pub fn encode_tar<E, S>(items: S) -> impl Stream<Item = Bytes, Error = Error<E>>
+where
+    E: Debug + Send + Sync + 'static,
+    S: Stream<Item = RawTarItem, Error = Error<E>>,
+{
+    RawTarEncoder::new(items, true)
+}
+
+/// Like [`encode_tar`], but without the end-of-archive marker, for output
+/// that will be continued or concatenated.
+pub fn encode_entries<E, S>(items: S) -> impl Stream<Item = Bytes, Error = Error<E>>
+where
+    E: Debug + Send + Sync + 'static,
+    S: Stream<Item = RawTarItem, Error = Error<E>>,
+{
+    RawTarEncoder::new(items, false)
+}
@@ -0,0 +1,363 @@
+//! Metadata access shared by the entries of every decoder.
+//!
+//! Raw tar headers, flat and full entries, cpio entries (decoded into
+//! [`flat::TarEntry`]) and `ar` members all implement [`ArchiveEntry`], so
+//! listing, filtering or indexing code can be written once for all of them.
+
+use crate::decode::ar::ArHeader;
+use crate::decode::flat::{self, bytes2path, TarEntry};
+use crate::decode::full;
+use futures::Stream;
+use std::borrow::Cow;
+use std::fmt::Debug;
+use std::io;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub trait ArchiveEntry {
+    fn path_bytes(&self) -> Cow<'_, [u8]>;
+
+    fn path(&self) -> io::Result<PathBuf> {
+        bytes2path(&self.path_bytes()).map(|path| path.to_path_buf())
+    }
+
+    fn entry_type(&self) -> tar::EntryType;
+
+    /// Length of the entry's body.
+    fn size(&self) -> u64;
+
+    /// Permission bits, including setuid, setgid and sticky.
+    fn mode(&self) -> u32;
+
+    fn uid(&self) -> u64;
+
+    fn gid(&self) -> u64;
+
+    fn uname(&self) -> Option<&[u8]> {
+        None
+    }
+
+    fn gname(&self) -> Option<&[u8]> {
+        None
+    }
+
+    fn mtime(&self) -> SystemTime;
+
+    fn atime(&self) -> Option<SystemTime> {
+        None
+    }
+
+    fn ctime(&self) -> Option<SystemTime> {
+        None
+    }
+
+    /// Target of a symlink or hardlink.
+    fn link_bytes(&self) -> Option<Cow<'_, [u8]>> {
+        None
+    }
+
+    fn link(&self) -> io::Result<Option<PathBuf>> {
+        match self.link_bytes() {
+            Some(bytes) => bytes2path(&bytes).map(|path| Some(path.to_path_buf())),
+            None => Ok(None),
+        }
+    }
+
+    /// Extended attributes as name and value pairs.
+    fn xattrs(&self) -> &[(Vec<u8>, Vec<u8>)] {
+        &[]
+    }
+
+    /// Major and minor numbers of character and block devices.
+    fn device(&self) -> Option<(u32, u32)> {
+        None
+    }
+}
+
+impl ArchiveEntry for TarEntry {
+    fn path_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(TarEntry::path_bytes(self))
+    }
+
+    fn entry_type(&self) -> tar::EntryType {
+        TarEntry::entry_type(self)
+    }
+
+    fn size(&self) -> u64 {
+        TarEntry::size(self)
+    }
+
+    fn mode(&self) -> u32 {
+        TarEntry::mode(self)
+    }
+
+    fn uid(&self) -> u64 {
+        TarEntry::uid(self)
+    }
+
+    fn gid(&self) -> u64 {
+        TarEntry::gid(self)
+    }
+
+    fn uname(&self) -> Option<&[u8]> {
+        TarEntry::uname(self)
+    }
+
+    fn gname(&self) -> Option<&[u8]> {
+        TarEntry::gname(self)
+    }
+
+    fn mtime(&self) -> SystemTime {
+        TarEntry::mtime(self)
+    }
+
+    fn atime(&self) -> Option<SystemTime> {
+        TarEntry::atime(self)
+    }
+
+    fn ctime(&self) -> Option<SystemTime> {
+        TarEntry::ctime(self)
+    }
+
+    fn link_bytes(&self) -> Option<Cow<'_, [u8]>> {
+        TarEntry::link_bytes(self).map(Cow::Borrowed)
+    }
+
+    fn xattrs(&self) -> &[(Vec<u8>, Vec<u8>)] {
+        TarEntry::xattrs(self)
+    }
+
+    fn device(&self) -> Option<(u32, u32)> {
+        TarEntry::device(self)
+    }
+}
+
+impl<S: Stream<Item = flat::TarItem>> ArchiveEntry for full::Entry<S>
+where
+    S::Error: Sync + Send + Debug + 'static,
+{
+    fn path_bytes(&self) -> Cow<'_, [u8]> {
+        ArchiveEntry::path_bytes(self.header())
+    }
+
+    fn entry_type(&self) -> tar::EntryType {
+        self.header().entry_type()
+    }
+
+    fn size(&self) -> u64 {
+        self.header().size()
+    }
+
+    fn mode(&self) -> u32 {
+        self.header().mode()
+    }
+
+    fn uid(&self) -> u64 {
+        self.header().uid()
+    }
+
+    fn gid(&self) -> u64 {
+        self.header().gid()
+    }
+
+    fn uname(&self) -> Option<&[u8]> {
+        self.header().uname()
+    }
+
+    fn gname(&self) -> Option<&[u8]> {
+        self.header().gname()
+    }
+
+    fn mtime(&self) -> SystemTime {
+        self.header().mtime()
+    }
+
+    fn atime(&self) -> Option<SystemTime> {
+        self.header().atime()
+    }
+
+    fn ctime(&self) -> Option<SystemTime> {
+        self.header().ctime()
+    }
+
+    fn link_bytes(&self) -> Option<Cow<'_, [u8]>> {
+        ArchiveEntry::link_bytes(self.header())
+    }
+
+    fn xattrs(&self) -> &[(Vec<u8>, Vec<u8>)] {
+        self.header().xattrs()
+    }
+
+    fn device(&self) -> Option<(u32, u32)> {
+        self.header().device()
+    }
+}
+
+/// A single header block, as yielded by [`raw`](crate::decode::raw).
+///
+/// Extensions carried by earlier PAX or GNU headers are not applied at this
+/// level, and numeric fields that fail to parse read as zero.
+impl ArchiveEntry for tar::Header {
+    fn path_bytes(&self) -> Cow<'_, [u8]> {
+        tar::Header::path_bytes(self)
+    }
+
+    fn entry_type(&self) -> tar::EntryType {
+        tar::Header::entry_type(self)
+    }
+
+    fn size(&self) -> u64 {
+        tar::Header::size(self).unwrap_or(0)
+    }
+
+    fn mode(&self) -> u32 {
+        tar::Header::mode(self).unwrap_or(0)
+    }
+
+    fn uid(&self) -> u64 {
+        tar::Header::uid(self).unwrap_or(0)
+    }
+
+    fn gid(&self) -> u64 {
+        tar::Header::gid(self).unwrap_or(0)
+    }
+
+    fn uname(&self) -> Option<&[u8]> {
+        self.username_bytes()
+    }
+
+    fn gname(&self) -> Option<&[u8]> {
+        self.groupname_bytes()
+    }
+
+    fn mtime(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(tar::Header::mtime(self).unwrap_or(0))
+    }
+
+    fn atime(&self) -> Option<SystemTime> {
+        let atime = self.as_gnu()?.atime().ok()?;
+        Some(UNIX_EPOCH + Duration::from_secs(atime))
+    }
+
+    fn ctime(&self) -> Option<SystemTime> {
+        let ctime = self.as_gnu()?.ctime().ok()?;
+        Some(UNIX_EPOCH + Duration::from_secs(ctime))
+    }
+
+    fn link_bytes(&self) -> Option<Cow<'_, [u8]>> {
+        self.link_name_bytes()
+    }
+
+    fn device(&self) -> Option<(u32, u32)> {
+        let entry_type = tar::Header::entry_type(self);
+        if !entry_type.is_character_special() && !entry_type.is_block_special() {
+            return None;
+        }
+        match (self.device_major(), self.device_minor()) {
+            (Ok(Some(major)), Ok(Some(minor))) => Some((major, minor)),
+            _ => None,
+        }
+    }
+}
+
+impl ArchiveEntry for ArHeader {
+    fn path_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self.name())
+    }
+
+    fn entry_type(&self) -> tar::EntryType {
+        tar::EntryType::Regular
+    }
+
+    fn size(&self) -> u64 {
+        ArHeader::size(self)
+    }
+
+    fn mode(&self) -> u32 {
+        ArHeader::mode(self) & 0o7777
+    }
+
+    fn uid(&self) -> u64 {
+        ArHeader::uid(self).into()
+    }
+
+    fn gid(&self) -> u64 {
+        ArHeader::gid(self).into()
+    }
+
+    fn mtime(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(ArHeader::mtime(self))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::decode::raw::{self, RawTarItem};
+    use crate::encode;
+    use crate::Error;
+    use bytes::Bytes;
+    use futures::{stream, Future};
+
+    fn describe<E: ArchiveEntry>(entry: &E) -> String {
+        format!(
+            "{} {:?} {:o} {} {:?}",
+            String::from_utf8_lossy(&entry.path_bytes()),
+            entry.entry_type(),
+            entry.mode(),
+            entry.size(),
+            entry
+                .xattrs()
+                .iter()
+                .map(|(name, value)| format!(
+                    "{}={}",
+                    String::from_utf8_lossy(name),
+                    String::from_utf8_lossy(value)
+                ))
+                .collect::<Vec<_>>()
+        )
+    }
+
+    #[test]
+    fn test_levels_agree() {
+        let mut file = TarEntry::new(tar::EntryType::Regular, "etc/passwd");
+        file.set_size(2)
+            .set_xattrs(vec![(b"user.origin".to_vec(), b"test".to_vec())]);
+        let items = vec![
+            flat::TarItem::Entry(file),
+            flat::TarItem::Chunk(Bytes::from_static(b"ok")),
+        ];
+        let tar = encode::flat::encode_tar(stream::iter_ok::<_, Error<()>>(items))
+            .concat2()
+            .wait()
+            .unwrap();
+
+        let flat = flat::decode_tar(stream::once::<_, ()>(Ok(tar.clone())))
+            .collect()
+            .wait()
+            .unwrap();
+        let flat = match flat[0] {
+            flat::TarItem::Entry(ref entry) => describe(entry),
+            _ => panic!("expected an entry"),
+        };
+        assert_eq!(flat, "etc/passwd Regular 644 2 [\"user.origin=test\"]");
+
+        let full = full::decode_tar(stream::once::<_, ()>(Ok(tar.clone())))
+            .map(|entry| describe(&entry))
+            .collect()
+            .wait()
+            .unwrap();
+        assert_eq!(full, vec![flat]);
+
+        // The raw level sees the PAX header and the ustar header separately.
+        let raw = raw::decode_tar(stream::once::<_, ()>(Ok(tar)))
+            .filter_map(|item| match item {
+                RawTarItem::Header(header) => Some(header.entry_type()),
+                _ => None,
+            })
+            .collect()
+            .wait()
+            .unwrap();
+        assert_eq!(raw, vec![tar::EntryType::XHeader, tar::EntryType::Regular]);
+    }
+}
@@ -19,6 +19,19 @@ impl<E: std::fmt::Debug + Sync + Send + 'static> From<E> for Error<E> {
     }
 }
 
+impl<E: std::fmt::Debug + Sync + Send + 'static> Error<Error<E>> {
+    /// Collapses the error of a decoder stacked on top of another one, e.g. a
+    /// tar nested inside a tar.
+    pub fn flatten(self) -> Error<E> {
+        match self {
+            Error::UpstreamError(e) => e,
+            Error::IoError(e) => Error::IoError(e),
+            Error::UnexpectedEof => Error::UnexpectedEof,
+            Error::Format(msg) => Error::Format(msg),
+        }
+    }
+}
+
 /*
 impl<E: std::fmt::Debug + Sync + Send + 'static> From<io::Error> for Error<E> {
     fn from(e : io::Error) -> Self {
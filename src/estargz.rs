@@ -0,0 +1,572 @@
+//! eStargz: gzip tarballs that can be read one file at a time.
+//!
+//! The blob is a plain `.tar.gz` to any other reader. Every regular file body
+//! starts a new gzip member (its tar header ends the previous one), and a
+//! table of contents in a member of its own maps each path to the offset of
+//! its member. A fixed-size footer, itself an empty gzip member, points at
+//! the table of contents, so a reader with random access fetches the footer,
+//! the table of contents and then only the members of the files it wants.
+
+use crate::compression::{self, Compression};
+use crate::decode::flat::{self, TarEntry, TarItem};
+use crate::decode::hardlink::normalize_path;
+use crate::decode::raw::RawTarItem;
+use crate::decode::{full, Error};
+use crate::digest::{DigestHandle, Hasher, Sha256, Summary};
+use crate::encode::{self, raw::ZEROS};
+use crate::source::RangeSource;
+use bytes::Bytes;
+use flate2::write::GzEncoder;
+use futures::future::{self, Either};
+use futures::{prelude::*, stream, try_ready};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::io::{self, Write};
+use std::mem;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Name of the table of contents inside its own tar member.
+pub const TOC_NAME: &str = "stargz.index.json";
+pub const FOOTER_SIZE: u64 = 51;
+
+fn is_default<T: Default + PartialEq>(value: &T) -> bool {
+    *value == T::default()
+}
+
+/// Table of contents, as stored in the blob.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Toc {
+    pub version: u32,
+    pub entries: Vec<TocEntry>,
+}
+
+/// One entry of the table of contents. Field names follow the eStargz JSON.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TocEntry {
+    pub name: String,
+    /// `dir`, `reg`, `symlink`, `hardlink`, `char`, `block` or `fifo`.
+    #[serde(rename = "type")]
+    pub entry_type: String,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub size: u64,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub modtime: String,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub link_name: String,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub mode: u32,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub uid: u64,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub gid: u64,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub user_name: String,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub group_name: String,
+    /// Offset in the blob of the gzip member holding the body.
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub offset: u64,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub dev_major: u32,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub dev_minor: u32,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub digest: String,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub chunk_digest: String,
+}
+
+/// Formats a time as RFC 3339 in UTC, with second precision.
+fn rfc3339(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let (days, rem) = ((secs / 86_400) as i64, secs % 86_400);
+    // Days to civil date, after Howard Hinnant's algorithm.
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+fn toc_entry(entry: &TarEntry) -> TocEntry {
+    let entry_type = match entry.entry_type() {
+        tar::EntryType::Directory => "dir",
+        tar::EntryType::Symlink => "symlink",
+        tar::EntryType::Link => "hardlink",
+        tar::EntryType::Char => "char",
+        tar::EntryType::Block => "block",
+        tar::EntryType::Fifo => "fifo",
+        _ => "reg",
+    };
+    let string = |bytes: Option<&[u8]>| {
+        bytes
+            .map(|b| String::from_utf8_lossy(b).into_owned())
+            .unwrap_or_default()
+    };
+    let (dev_major, dev_minor) = entry.device().unwrap_or((0, 0));
+    TocEntry {
+        name: string(Some(normalize_path(entry.path_bytes()))),
+        entry_type: entry_type.to_string(),
+        size: if entry_type == "reg" { entry.size() } else { 0 },
+        modtime: rfc3339(entry.mtime()),
+        link_name: string(entry.link_bytes().map(normalize_path)),
+        mode: entry.mode(),
+        uid: entry.uid(),
+        gid: entry.gid(),
+        user_name: string(entry.uname()),
+        group_name: string(entry.gname()),
+        dev_major,
+        dev_minor,
+        ..TocEntry::default()
+    }
+}
+
+/// The footer: an empty gzip member whose extra field holds the offset of
+/// the table of contents.
+fn footer(toc_offset: u64) -> Vec<u8> {
+    let mut out = vec![
+        0x1f, 0x8b, 0x08, 0x04, 0, 0, 0, 0, 0, 0xff, // header with FEXTRA
+        26, 0, b'S', b'G', 22, 0, // extra field: one "SG" subfield
+    ];
+    out.extend_from_slice(format!("{:016x}STARGZ", toc_offset).as_bytes());
+    // An empty stored block, then CRC-32 and length of no data.
+    out.extend_from_slice(&[0x01, 0x00, 0x00, 0xff, 0xff, 0, 0, 0, 0, 0, 0, 0, 0]);
+    out
+}
+
+fn parse_footer(footer: &[u8]) -> Option<u64> {
+    if footer.len() as u64 != FOOTER_SIZE
+        || footer[..4] != [0x1f, 0x8b, 0x08, 0x04]
+        || &footer[12..14] != b"SG"
+        || &footer[32..38] != b"STARGZ"
+    {
+        return None;
+    }
+    let hex = std::str::from_utf8(&footer[16..32]).ok()?;
+    u64::from_str_radix(hex, 16).ok()
+}
+
+fn new_member() -> GzEncoder<Vec<u8>> {
+    GzEncoder::new(Vec::new(), flate2::Compression::default())
+}
+
+struct Encoder<S> {
+    upstream: S,
+    member: Option<GzEncoder<Vec<u8>>>,
+    /// Bytes handed downstream so far.
+    emitted: u64,
+    /// Block padding owed by the previous body.
+    padding: usize,
+    toc: Toc,
+    /// TOC index of the file whose body is being written, and whether its
+    /// member has been started yet.
+    body: Option<(usize, bool)>,
+    remaining: u64,
+    hasher: Sha256,
+    toc_digest: DigestHandle,
+    done: bool,
+}
+
+impl<S> Encoder<S> {
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.member.as_mut().unwrap().write_all(data)
+    }
+
+    fn write_raw(&mut self, item: &RawTarItem) -> io::Result<()> {
+        match item {
+            RawTarItem::Header(header) => {
+                let padding = mem::replace(
+                    &mut self.padding,
+                    encode::raw::padding(header.entry_size()?),
+                );
+                self.write(&ZEROS[..padding])?;
+                self.write(header.as_bytes())
+            }
+            RawTarItem::Chunk(bytes) => self.write(bytes),
+            RawTarItem::EmptyHeader => self.write(&ZEROS[..512]),
+        }
+    }
+
+    /// Ends the current member, appending its tail to `out`, and starts the
+    /// next one.
+    fn split(&mut self, out: &mut Vec<u8>) -> io::Result<()> {
+        out.extend(self.member.take().unwrap().finish()?);
+        self.member = Some(new_member());
+        Ok(())
+    }
+
+    /// Compressed output accumulated so far.
+    fn drain(&mut self, mut out: Vec<u8>) -> Option<Bytes> {
+        out.append(self.member.as_mut().unwrap().get_mut());
+        self.emitted += out.len() as u64;
+        if out.is_empty() {
+            None
+        } else {
+            Some(Bytes::from(out))
+        }
+    }
+
+    fn finish(&mut self) -> io::Result<Bytes> {
+        let mut out = Vec::new();
+        let padding = mem::replace(&mut self.padding, 0);
+        self.write(&ZEROS[..padding])?;
+        self.split(&mut out)?;
+        let toc_offset = self.emitted + out.len() as u64;
+
+        let json = serde_json::to_vec(&self.toc).map_err(io::Error::other)?;
+        let mut digest = Sha256::new();
+        digest.update(&json);
+        self.toc_digest
+            .set(Summary::new(digest.finish(), json.len() as u64));
+
+        let mut header = TarEntry::new(tar::EntryType::Regular, TOC_NAME);
+        header.set_size(json.len() as u64);
+        for item in encode::flat::entry_headers(&header) {
+            self.write_raw(&item)?;
+        }
+        self.write(&json)?;
+        let padding = encode::raw::padding(json.len() as u64);
+        self.write(&ZEROS[..padding])?;
+        self.write(&ZEROS)?;
+        out.extend(self.member.take().unwrap().finish()?);
+        out.extend(footer(toc_offset));
+        self.emitted += out.len() as u64;
+        Ok(Bytes::from(out))
+    }
+}
+
+impl<E, S> Stream for Encoder<S>
+where
+    E: Debug + Send + Sync + 'static,
+    S: Stream<Item = TarItem, Error = Error<E>>,
+{
+    type Item = Bytes;
+    type Error = Error<E>;
+
+    fn poll(&mut self) -> Result<Async<Option<<Self as Stream>::Item>>, <Self as Stream>::Error> {
+        loop {
+            if self.done {
+                return Ok(Async::Ready(None));
+            }
+            let mut out = Vec::new();
+            match try_ready!(self.upstream.poll()) {
+                Some(TarItem::Entry(entry)) => {
+                    if self.remaining > 0 {
+                        return Err(Error::Format("entry body shorter than its header"));
+                    }
+                    for item in encode::flat::entry_headers(&entry) {
+                        self.write_raw(&item).map_err(Error::IoError)?;
+                    }
+                    self.remaining = entry.size();
+                    let toc_entry = toc_entry(&entry);
+                    if toc_entry.name.is_empty() || toc_entry.name == "." {
+                        continue;
+                    }
+                    if toc_entry.entry_type == "reg" && entry.size() > 0 {
+                        self.body = Some((self.toc.entries.len(), false));
+                    }
+                    self.toc.entries.push(toc_entry);
+                }
+                Some(TarItem::Chunk(bytes)) => {
+                    if bytes.len() as u64 > self.remaining {
+                        return Err(Error::Format("entry body longer than its header"));
+                    }
+                    if let Some((index, false)) = self.body {
+                        self.split(&mut out).map_err(Error::IoError)?;
+                        self.toc.entries[index].offset = self.emitted + out.len() as u64;
+                        self.body = Some((index, true));
+                    }
+                    self.write(&bytes).map_err(Error::IoError)?;
+                    self.hasher.update(&bytes);
+                    self.remaining -= bytes.len() as u64;
+                    if self.remaining == 0 {
+                        if let Some((index, _)) = self.body.take() {
+                            let digest = self.hasher.finish().to_string();
+                            let entry = &mut self.toc.entries[index];
+                            entry.chunk_digest = digest.clone();
+                            entry.digest = digest;
+                        }
+                    }
+                }
+                None => {
+                    if self.remaining > 0 {
+                        return Err(Error::Format("entry body shorter than its header"));
+                    }
+                    self.done = true;
+                    return Ok(Async::Ready(Some(self.finish().map_err(Error::IoError)?)));
+                }
+            }
+            if let Some(bytes) = self.drain(out) {
+                return Ok(Async::Ready(Some(bytes)));
+            }
+        }
+    }
+}
+
+/// Encodes flat items as an eStargz blob.
+///
+/// The returned handle gets the digest and size of the table of contents
+/// JSON once the stream has ended; eStargz consumers expect the digest as
+/// the `containerd.io/snapshot/stargz/toc.digest` layer annotation.
+pub fn encode_estargz<E, S>(items: S) -> (impl Stream<Item = Bytes, Error = Error<E>>, DigestHandle)
+where
+    E: Debug + Send + Sync + 'static,
+    S: Stream<Item = TarItem, Error = Error<E>>,
+{
+    let toc_digest = DigestHandle::default();
+    let encoder = Encoder {
+        upstream: items,
+        member: Some(new_member()),
+        emitted: 0,
+        padding: 0,
+        toc: Toc {
+            version: 1,
+            entries: Vec::new(),
+        },
+        body: None,
+        remaining: 0,
+        hasher: Sha256::new(),
+        toc_digest: toc_digest.clone(),
+        done: false,
+    };
+    (encoder, toc_digest)
+}
+
+/// An eStargz blob opened for random access.
+pub struct Estargz<R> {
+    source: R,
+    toc: Toc,
+    toc_offset: u64,
+    /// Member offsets of all bodies, sorted.
+    offsets: Vec<u64>,
+    /// Position of the last entry at every path, which takes precedence as
+    /// it does on extraction. Chunks of a file share its name and are left
+    /// out.
+    by_name: HashMap<Vec<u8>, usize>,
+}
+
+impl<R: RangeSource> Estargz<R> {
+    #[inline]
+    pub fn toc(&self) -> &Toc {
+        &self.toc
+    }
+
+    /// Looks up an entry; `name` is an archive path such as `usr/bin/env`.
+    pub fn entry(&self, name: &str) -> Option<&TocEntry> {
+        let index = *self.by_name.get(normalize_path(name.as_bytes()))?;
+        self.toc.entries.get(index)
+    }
+
+    /// Streams the body of a regular file, following hardlinks; `None` if
+    /// there is no such file.
+    ///
+    /// Only the gzip member holding the body is fetched and decompressed.
+    pub fn open_file(
+        &mut self,
+        name: &str,
+    ) -> Option<impl Stream<Item = Bytes, Error = Error<io::Error>>> {
+        let mut entry = self.entry(name)?;
+        if entry.entry_type == "hardlink" {
+            entry = self.entry(&entry.link_name)?;
+        }
+        if entry.entry_type != "reg" {
+            return None;
+        }
+        if entry.size == 0 {
+            return Some(Either::A(stream::empty()));
+        }
+
+        let (offset, mut remaining) = (entry.offset, entry.size);
+        let end = match self.offsets.binary_search(&offset) {
+            Ok(i) => self.offsets.get(i + 1).cloned().unwrap_or(self.toc_offset),
+            Err(_) => return None,
+        };
+        let member = self.source.read_range(offset, end - offset);
+        Some(Either::B(
+            compression::decompress(member, Some(Compression::Gzip))
+                // The member goes on with the headers of the next entries.
+                .map(move |mut bytes| {
+                    bytes.truncate(remaining.min(bytes.len() as u64) as usize);
+                    remaining -= bytes.len() as u64;
+                    bytes
+                })
+                .filter(|bytes| !bytes.is_empty()),
+        ))
+    }
+
+    #[inline]
+    pub fn into_source(self) -> R {
+        self.source
+    }
+}
+
+fn read_toc(member: Bytes) -> impl Future<Item = Toc, Error = Error<io::Error>> {
+    let tar = compression::decompress_items(stream::once(Ok(member)), Some(Compression::Gzip));
+    full::decode_items(flat::decode_nested(tar))
+        .filter(|entry| entry.header().path_bytes() == TOC_NAME.as_bytes())
+        .and_then(|entry| entry.concat2())
+        .collect()
+        .and_then(|mut bodies| {
+            let json = bodies
+                .pop()
+                .ok_or(Error::Format("table of contents missing"))?;
+            serde_json::from_slice(&json).map_err(|_| Error::Format("invalid table of contents"))
+        })
+}
+
+/// Opens an eStargz blob of `blob_size` bytes, reading its footer and table
+/// of contents.
+pub fn open_estargz<R>(
+    mut source: R,
+    blob_size: u64,
+) -> impl Future<Item = Estargz<R>, Error = Error<io::Error>>
+where
+    R: RangeSource,
+{
+    if blob_size < FOOTER_SIZE {
+        return Either::A(future::err(Error::Format("blob too small for eStargz")));
+    }
+    let toc_end = blob_size - FOOTER_SIZE;
+    let footer = source.read_range(toc_end, FOOTER_SIZE).concat2();
+    Either::B(
+        footer
+            .map_err(Error::UpstreamError)
+            .and_then(move |footer| {
+                let toc_offset = match parse_footer(&footer) {
+                    Some(offset) if offset <= toc_end => offset,
+                    _ => return Either::A(future::err(Error::Format("invalid eStargz footer"))),
+                };
+                let member = source
+                    .read_range(toc_offset, toc_end - toc_offset)
+                    .concat2();
+                Either::B(
+                    member
+                        .map_err(Error::UpstreamError)
+                        .and_then(read_toc)
+                        .map(move |toc| {
+                            let mut offsets: Vec<u64> = toc
+                                .entries
+                                .iter()
+                                .filter(|entry| entry.offset > 0)
+                                .map(|entry| entry.offset)
+                                .collect();
+                            offsets.sort();
+                            offsets.dedup();
+                            let by_name = toc
+                                .entries
+                                .iter()
+                                .enumerate()
+                                .filter(|(_, entry)| entry.entry_type != "chunk")
+                                .map(|(i, entry)| {
+                                    (normalize_path(entry.name.as_bytes()).to_vec(), i)
+                                })
+                                .collect();
+                            Estargz {
+                                source,
+                                toc,
+                                toc_offset,
+                                offsets,
+                                by_name,
+                            }
+                        }),
+                )
+            }),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Read;
+
+    fn items() -> Vec<TarItem> {
+        let mut items = vec![TarItem::Entry(TarEntry::new(
+            tar::EntryType::Directory,
+            "bin/",
+        ))];
+        for (path, body) in &[
+            ("bin/a", &b"alpha"[..]),
+            ("bin/empty", b""),
+            ("bin/b", b"beta"),
+        ] {
+            let mut entry = TarEntry::new(tar::EntryType::Regular, *path);
+            entry.set_size(body.len() as u64);
+            items.push(TarItem::Entry(entry));
+            if !body.is_empty() {
+                items.push(TarItem::Chunk(Bytes::from(*body)));
+            }
+        }
+        let mut link = TarEntry::new(tar::EntryType::Link, "bin/c");
+        link.set_link_bytes(Some(b"bin/b".to_vec()));
+        items.push(TarItem::Entry(link));
+        items
+    }
+
+    #[test]
+    fn test_estargz() {
+        let (blob, toc_digest) = encode_estargz(stream::iter_ok::<_, Error<()>>(items()));
+        let blob = blob.concat2().wait().unwrap();
+        assert_eq!(&blob[..2], &[0x1f, 0x8b]);
+        assert!(toc_digest.get().is_some());
+
+        // Still an ordinary tar.gz.
+        let mut tar = Vec::new();
+        flate2::read::MultiGzDecoder::new(blob.as_ref())
+            .read_to_end(&mut tar)
+            .unwrap();
+        let mut archive = tar::Archive::new(tar.as_slice());
+        let paths: Vec<_> = archive
+            .entries()
+            .unwrap()
+            .map(|entry| String::from_utf8_lossy(&entry.unwrap().path_bytes()).into_owned())
+            .collect();
+        assert_eq!(
+            paths,
+            vec!["bin/", "bin/a", "bin/empty", "bin/b", "bin/c", TOC_NAME]
+        );
+
+        let size = blob.len() as u64;
+        let mut reader = open_estargz(blob, size).wait().unwrap();
+        assert_eq!(reader.toc().entries.len(), 5);
+        assert_eq!(reader.entry("bin/").unwrap().entry_type, "dir");
+        let mut read = |name| reader.open_file(name).unwrap().concat2().wait().unwrap();
+        assert_eq!(read("bin/a").as_ref(), b"alpha");
+        assert_eq!(read("bin/b").as_ref(), b"beta");
+        assert_eq!(read("./bin/c").as_ref(), b"beta");
+        assert_eq!(read("bin/empty").as_ref(), b"");
+    }
+
+    #[test]
+    fn test_estargz_last_entry() {
+        let mut items = items();
+        let mut entry = TarEntry::new(tar::EntryType::Regular, "bin/a");
+        entry.set_size(5);
+        items.push(TarItem::Entry(entry));
+        items.push(TarItem::Chunk(Bytes::from_static(b"omega")));
+        let (blob, _) = encode_estargz(stream::iter_ok::<_, Error<()>>(items));
+        let blob = blob.concat2().wait().unwrap();
+
+        let size = blob.len() as u64;
+        let mut reader = open_estargz(blob, size).wait().unwrap();
+        let body = reader.open_file("bin/a").unwrap().concat2().wait().unwrap();
+        assert_eq!(body.as_ref(), b"omega");
+    }
+}
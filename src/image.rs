@@ -0,0 +1,544 @@
+//! Reading layers out of image archives.
+//!
+//! Both `docker save` output (`manifest.json` listing layer paths) and OCI
+//! image layouts (`index.json` pointing at a manifest blob) are understood.
+//! Layers are yielded in manifest order, bottom first. When the manifest
+//! precedes the layers and they appear in order, each layer is streamed
+//! straight from the outer archive; anything seen before it is needed is kept
+//! in memory until its turn, up to [`ImageOptions::set_max_buffered`].
+//! `docker save` writes the manifest last, so its layers are all kept; a
+//! larger image fails with an error rather than filling memory, and is best
+//! read from a seekable copy instead.
+
+use crate::compression;
+use crate::decode::hardlink::normalize_path;
+use crate::decode::{flat, full, Error};
+use bytes::{Bytes, BytesMut};
+use futures::{prelude::*, try_ready};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt::Debug;
+
+const DOCKER_MANIFEST: &str = "manifest.json";
+const OCI_INDEX: &str = "index.json";
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct DockerImage {
+    layers: Vec<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Descriptor {
+    media_type: Option<String>,
+    digest: String,
+}
+
+/// Either an OCI image index or an image manifest.
+#[derive(Deserialize)]
+struct OciDocument {
+    manifests: Option<Vec<Descriptor>>,
+    layers: Option<Vec<Descriptor>>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ImageOptions {
+    max_buffered: u64,
+}
+
+impl Default for ImageOptions {
+    fn default() -> Self {
+        ImageOptions {
+            max_buffered: 256 << 20,
+        }
+    }
+}
+
+impl ImageOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[inline]
+    pub fn max_buffered(&self) -> u64 {
+        self.max_buffered
+    }
+
+    /// Most bytes of entries seen before they are needed to keep in memory;
+    /// the stream fails once they would exceed it.
+    #[inline]
+    pub fn set_max_buffered(&mut self, max_buffered: u64) -> &mut Self {
+        self.max_buffered = max_buffered;
+        self
+    }
+}
+
+fn blob_path(digest: &str) -> String {
+    format!("blobs/{}", digest.replacen(':', "/", 1))
+}
+
+#[derive(Clone, Debug)]
+struct LayerRef {
+    path: String,
+    media_type: Option<String>,
+    digest: Option<String>,
+}
+
+#[allow(clippy::large_enum_variant)]
+enum Body<S: Stream<Item = flat::TarItem>>
+where
+    S::Error: Sync + Send + Debug + 'static,
+{
+    Live(full::Entry<S>),
+    Buffered(Option<Bytes>),
+}
+
+/// One layer of an image, as stored in the archive (possibly compressed).
+///
+/// A live layer shares the outer decoder, so like [`full::Entry`] it has to
+/// be consumed or dropped before the next layer is polled.
+pub struct Layer<S: Stream<Item = flat::TarItem>>
+where
+    S::Error: Sync + Send + Debug + 'static,
+{
+    index: usize,
+    layer: LayerRef,
+    body: Body<S>,
+}
+
+impl<S: Stream<Item = flat::TarItem>> Layer<S>
+where
+    S::Error: Sync + Send + Debug + 'static,
+{
+    /// Position in the manifest, 0 being the bottom layer.
+    #[inline]
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Path of the layer inside the image archive.
+    #[inline]
+    pub fn path(&self) -> &str {
+        &self.layer.path
+    }
+
+    /// Media type from an OCI manifest; Docker manifests do not have one.
+    #[inline]
+    pub fn media_type(&self) -> Option<&str> {
+        self.layer.media_type.as_deref()
+    }
+
+    /// Digest from an OCI manifest, e.g. `sha256:...`.
+    #[inline]
+    pub fn digest(&self) -> Option<&str> {
+        self.layer.digest.as_deref()
+    }
+
+    /// Whether the layer is streamed from the outer archive rather than
+    /// replayed from memory.
+    #[inline]
+    pub fn is_live(&self) -> bool {
+        match self.body {
+            Body::Live(_) => true,
+            Body::Buffered(_) => false,
+        }
+    }
+}
+
+impl<E, S> Layer<S>
+where
+    E: Debug + Send + Sync + 'static,
+    S: Stream<Item = flat::TarItem, Error = Error<E>>,
+{
+    /// Decompresses the layer if needed and decodes its entries.
+    pub fn entries(
+        self,
+    ) -> impl Stream<
+        Item = full::Entry<impl Stream<Item = flat::TarItem, Error = Error<E>>>,
+        Error = Error<E>,
+    > {
+        let tar = compression::decompress_items(self, None);
+        full::decode_items(flat::decode_nested(tar))
+    }
+}
+
+impl<E, S> Stream for Layer<S>
+where
+    E: Debug + Send + Sync + 'static,
+    S: Stream<Item = flat::TarItem, Error = Error<E>>,
+{
+    type Item = Bytes;
+    type Error = Error<E>;
+
+    fn poll(&mut self) -> Result<Async<Option<<Self as Stream>::Item>>, <Self as Stream>::Error> {
+        match self.body {
+            Body::Live(ref mut entry) => entry.poll(),
+            Body::Buffered(ref mut bytes) => Ok(Async::Ready(bytes.take())),
+        }
+    }
+}
+
+/// What the reader is looking for.
+enum Wanted {
+    /// Nothing known yet.
+    Manifest,
+    /// An OCI index pointed at this blob.
+    Blob(String),
+    Layers(Vec<LayerRef>),
+}
+
+/// Why an entry body is being read into memory.
+enum Purpose {
+    DockerManifest,
+    OciDocument,
+    Keep(String),
+}
+
+struct ImageReader<T, S: Stream<Item = flat::TarItem>>
+where
+    S::Error: Sync + Send + Debug + 'static,
+{
+    entries: T,
+    options: ImageOptions,
+    reading: Option<(full::Entry<S>, BytesMut, Purpose)>,
+    wanted: Wanted,
+    next: usize,
+    kept: HashMap<String, Bytes>,
+    /// Bytes held in `kept`.
+    buffered: u64,
+}
+
+impl<E, S, T> ImageReader<T, S>
+where
+    E: Debug + Send + Sync + 'static,
+    S: Stream<Item = flat::TarItem, Error = Error<E>>,
+    T: Stream<Item = full::Entry<S>, Error = Error<E>>,
+{
+    fn set_layers(&mut self, layers: Vec<LayerRef>) {
+        self.kept
+            .retain(|path, _| layers.iter().any(|layer| layer.path == *path));
+        self.buffered = self.kept.values().map(|bytes| bytes.len() as u64).sum();
+        self.wanted = Wanted::Layers(layers);
+    }
+
+    fn on_docker_manifest(&mut self, json: &[u8]) -> Result<(), Error<E>> {
+        let images: Vec<DockerImage> =
+            serde_json::from_slice(json).map_err(|_| Error::Format("invalid image manifest"))?;
+        let image = images
+            .into_iter()
+            .next()
+            .ok_or(Error::Format("image manifest lists no images"))?;
+        let layers = image
+            .layers
+            .into_iter()
+            .map(|path| LayerRef {
+                path: String::from_utf8_lossy(normalize_path(path.as_bytes())).into_owned(),
+                media_type: None,
+                digest: None,
+            })
+            .collect();
+        self.set_layers(layers);
+        Ok(())
+    }
+
+    fn on_oci_document(&mut self, json: &[u8]) -> Result<(), Error<E>> {
+        let doc: OciDocument =
+            serde_json::from_slice(json).map_err(|_| Error::Format("invalid image manifest"))?;
+        if let Some(layers) = doc.layers {
+            let layers = layers
+                .into_iter()
+                .map(|layer| LayerRef {
+                    path: blob_path(&layer.digest),
+                    media_type: layer.media_type,
+                    digest: Some(layer.digest),
+                })
+                .collect();
+            self.set_layers(layers);
+            return Ok(());
+        }
+        let manifest = doc
+            .manifests
+            .and_then(|manifests| manifests.into_iter().next())
+            .ok_or(Error::Format("image index lists no manifests"))?;
+        let path = blob_path(&manifest.digest);
+        match self.kept.remove(&path) {
+            Some(json) => {
+                self.buffered -= json.len() as u64;
+                self.on_oci_document(&json)
+            }
+            None => {
+                self.wanted = Wanted::Blob(path);
+                Ok(())
+            }
+        }
+    }
+
+    fn on_read(&mut self, purpose: Purpose, bytes: Bytes) -> Result<(), Error<E>> {
+        match purpose {
+            Purpose::DockerManifest => self.on_docker_manifest(&bytes),
+            Purpose::OciDocument => self.on_oci_document(&bytes),
+            Purpose::Keep(path) => {
+                self.buffered += bytes.len() as u64;
+                if let Some(old) = self.kept.insert(path, bytes) {
+                    self.buffered -= old.len() as u64;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Decides what to do with the next outer entry: yield it as a live
+    /// layer, read it into memory, or skip it.
+    fn on_entry(&mut self, entry: full::Entry<S>) -> Option<Layer<S>> {
+        if !entry.header().entry_type().is_file() {
+            return None;
+        }
+        let path =
+            String::from_utf8_lossy(normalize_path(entry.header().path_bytes())).into_owned();
+        let purpose = match self.wanted {
+            Wanted::Layers(ref layers) => {
+                let pos = layers[self.next..]
+                    .iter()
+                    .position(|layer| layer.path == path)?;
+                let reused = layers[self.next + pos + 1..]
+                    .iter()
+                    .any(|layer| layer.path == path);
+                if pos == 0 && !reused {
+                    let layer = Layer {
+                        index: self.next,
+                        layer: layers[self.next].clone(),
+                        body: Body::Live(entry),
+                    };
+                    self.next += 1;
+                    return Some(layer);
+                }
+                Purpose::Keep(path)
+            }
+            Wanted::Manifest if path == DOCKER_MANIFEST => Purpose::DockerManifest,
+            Wanted::Manifest if path == OCI_INDEX => Purpose::OciDocument,
+            Wanted::Blob(ref blob) if *blob == path => Purpose::OciDocument,
+            _ => Purpose::Keep(path),
+        };
+        self.reading = Some((entry, BytesMut::new(), purpose));
+        None
+    }
+
+    fn next_kept(&mut self) -> Option<Layer<S>> {
+        let layers = match self.wanted {
+            Wanted::Layers(ref layers) => layers,
+            _ => return None,
+        };
+        let layer = layers.get(self.next)?;
+        let reused = layers[self.next + 1..].iter().any(|l| l.path == layer.path);
+        let bytes = if reused {
+            self.kept.get(&layer.path).cloned()
+        } else {
+            self.kept.remove(&layer.path)
+        }?;
+        if !reused {
+            self.buffered -= bytes.len() as u64;
+        }
+        let layer = Layer {
+            index: self.next,
+            layer: layer.clone(),
+            body: Body::Buffered(Some(bytes)),
+        };
+        self.next += 1;
+        Some(layer)
+    }
+}
+
+impl<E, S, T> Stream for ImageReader<T, S>
+where
+    E: Debug + Send + Sync + 'static,
+    S: Stream<Item = flat::TarItem, Error = Error<E>>,
+    T: Stream<Item = full::Entry<S>, Error = Error<E>>,
+{
+    type Item = Layer<S>;
+    type Error = Error<E>;
+
+    fn poll(&mut self) -> Result<Async<Option<<Self as Stream>::Item>>, <Self as Stream>::Error> {
+        loop {
+            if let Some((ref mut entry, ref mut buf, _)) = self.reading {
+                while let Some(chunk) = try_ready!(entry.poll()) {
+                    if self.buffered + (buf.len() + chunk.len()) as u64 > self.options.max_buffered
+                    {
+                        return Err(Error::Format(
+                            "image entries seen before they are needed exceed the buffer limit",
+                        ));
+                    }
+                    buf.extend_from_slice(&chunk);
+                }
+            }
+            if let Some((_, buf, purpose)) = self.reading.take() {
+                self.on_read(purpose, buf.freeze())?;
+            }
+
+            if let Some(layer) = self.next_kept() {
+                return Ok(Async::Ready(Some(layer)));
+            }
+            if let Wanted::Layers(ref layers) = self.wanted {
+                if self.next == layers.len() {
+                    return Ok(Async::Ready(None));
+                }
+            }
+
+            match try_ready!(self.entries.poll()) {
+                Some(entry) => {
+                    if let Some(layer) = self.on_entry(entry) {
+                        return Ok(Async::Ready(Some(layer)));
+                    }
+                }
+                None => {
+                    return Err(match self.wanted {
+                        Wanted::Layers(_) => Error::Format("layer missing from image archive"),
+                        _ => Error::Format("image manifest not found"),
+                    })
+                }
+            }
+        }
+    }
+}
+
+/// Yields the layers of an image archive given its decoded entries.
+pub fn image_layers<E, S, T>(
+    entries: T,
+    options: ImageOptions,
+) -> impl Stream<Item = Layer<S>, Error = Error<E>>
+where
+    E: Debug + Send + Sync + 'static,
+    S: Stream<Item = flat::TarItem, Error = Error<E>>,
+    T: Stream<Item = full::Entry<S>, Error = Error<E>>,
+{
+    ImageReader {
+        entries,
+        options,
+        reading: None,
+        wanted: Wanted::Manifest,
+        next: 0,
+        kept: HashMap::new(),
+        buffered: 0,
+    }
+}
+
+/// Yields the layers of a `docker save` or OCI layout tar stream.
+pub fn decode_image<TarStream: Stream<Item = Bytes>>(
+    upstream: TarStream,
+    options: ImageOptions,
+) -> impl Stream<
+    Item = Layer<impl Stream<Item = flat::TarItem, Error = Error<TarStream::Error>>>,
+    Error = Error<TarStream::Error>,
+>
+where
+    TarStream::Error: Debug + Sync + Send + 'static,
+{
+    image_layers(full::decode_tar(upstream), options)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::stream;
+
+    fn append(builder: &mut tar::Builder<Vec<u8>>, path: &str, data: &[u8]) {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_mtime(0);
+        header.set_cksum();
+        builder.append_data(&mut header, path, data).unwrap();
+    }
+
+    fn layer_tar(path: &str) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        append(&mut builder, path, b"content");
+        builder.into_inner().unwrap()
+    }
+
+    fn layer_paths(image: Vec<u8>) -> Vec<(usize, bool, String)> {
+        decode_image(
+            stream::once::<_, ()>(Ok(Bytes::from(image))),
+            ImageOptions::new(),
+        )
+        .and_then(|layer| {
+            let info = (layer.index(), layer.is_live());
+            layer
+                .entries()
+                .and_then(|entry| {
+                    let path = entry.header().path().unwrap().display().to_string();
+                    entry.concat2().map(|_| path)
+                })
+                .collect()
+                .map(move |paths| (info.0, info.1, paths.join(",")))
+        })
+        .collect()
+        .wait()
+        .unwrap()
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn test_docker_save() {
+        use std::io::Write;
+
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gz.write_all(&layer_tar("top")).unwrap();
+        let top = gz.finish().unwrap();
+
+        // Manifest first: the bottom layer streams live, the top one was
+        // seen too early and is replayed from memory.
+        let mut builder = tar::Builder::new(Vec::new());
+        append(
+            &mut builder,
+            "manifest.json",
+            br#"[{"Config":"c.json","RepoTags":[],"Layers":["a/layer.tar","b/layer.tar"]}]"#,
+        );
+        append(&mut builder, "b/layer.tar", &top);
+        append(&mut builder, "a/layer.tar", &layer_tar("bottom"));
+        let image = builder.into_inner().unwrap();
+
+        assert_eq!(
+            layer_paths(image),
+            vec![
+                (0, true, "bottom".to_string()),
+                (1, false, "top".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_oci_layout() {
+        let mut builder = tar::Builder::new(Vec::new());
+        append(&mut builder, "blobs/sha256/aa", &layer_tar("bottom"));
+        append(
+            &mut builder,
+            "blobs/sha256/mm",
+            br#"{"layers":[{"mediaType":"application/vnd.oci.image.layer.v1.tar","digest":"sha256:aa","size":1}]}"#,
+        );
+        append(
+            &mut builder,
+            "index.json",
+            br#"{"manifests":[{"digest":"sha256:mm","size":1}]}"#,
+        );
+        let image = builder.into_inner().unwrap();
+
+        assert_eq!(
+            layer_paths(image.clone()),
+            vec![(0, false, "bottom".to_string())]
+        );
+
+        // Everything comes before the index, more than may be kept.
+        let mut options = ImageOptions::new();
+        options.set_max_buffered(1024);
+        match decode_image(stream::once::<_, ()>(Ok(Bytes::from(image))), options)
+            .collect()
+            .wait()
+        {
+            Err(Error::Format(_)) => (),
+            other => panic!("unexpected result: {:?}", other.map(|layers| layers.len())),
+        }
+    }
+}
@@ -0,0 +1,671 @@
+//! GNU incremental archives, as made by `tar --listed-incremental`.
+//!
+//! Every directory is stored as a dumpdir entry (type `D`) whose body lists
+//! the directory's members at dump time, including those that were left out
+//! because they did not change. [`parse_dumpdirs`] decodes these listings,
+//! [`unpack_incremental`] extracts an archive and deletes what is missing
+//! from them, and [`create_incremental`] produces such archives, reading and
+//! returning the [`Snapshot`](snapshot::Snapshot) that tells one level from
+//! the next.
+
+pub mod snapshot;
+
+use self::snapshot::{Snapshot, SnapshotDir};
+use crate::blocking;
+use crate::create::{self, child_path, CreateOptions, FileBody, Visit};
+use crate::decode::flat::{TarEntry, TarItem};
+use crate::decode::{full, Error};
+use crate::encode;
+use crate::unpack;
+use crate::Config;
+use bytes::{Bytes, BytesMut};
+use futures::future::{self, Either};
+use futures::{prelude::*, try_ready};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt::Debug;
+use std::fs;
+use std::io;
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Type flag of dumpdir entries.
+pub const DUMPDIR: u8 = b'D';
+
+#[inline]
+pub fn is_dumpdir(entry: &TarEntry) -> bool {
+    entry.entry_type().as_byte() == DUMPDIR
+}
+
+/// One member of a dumpdir.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DumpdirRecord {
+    /// `Y`: a member stored in the archive.
+    Included(Vec<u8>),
+    /// `N`: a member left out because it did not change.
+    Unchanged(Vec<u8>),
+    /// `D`: a subdirectory, which has a dumpdir of its own.
+    Directory(Vec<u8>),
+    /// `R`: a directory, relative to the archive root, to be renamed to the
+    /// following `RenameTo`; empty for the temporary directory.
+    RenameFrom(Vec<u8>),
+    /// `T`: the new name of the preceding `RenameFrom`; empty for the
+    /// temporary directory.
+    RenameTo(Vec<u8>),
+    /// `X`: the temporary directory used to break rename cycles.
+    TempDir(Vec<u8>),
+}
+
+impl DumpdirRecord {
+    pub(crate) fn encode(&self, out: &mut Vec<u8>) {
+        let (code, name) = match self {
+            DumpdirRecord::Included(name) => (b'Y', name),
+            DumpdirRecord::Unchanged(name) => (b'N', name),
+            DumpdirRecord::Directory(name) => (b'D', name),
+            DumpdirRecord::RenameFrom(name) => (b'R', name),
+            DumpdirRecord::RenameTo(name) => (b'T', name),
+            DumpdirRecord::TempDir(name) => (b'X', name),
+        };
+        out.push(code);
+        out.extend_from_slice(name);
+        out.push(0);
+    }
+
+    /// Name of a member of the directory, for the kinds that are one.
+    pub fn member(&self) -> Option<&[u8]> {
+        match self {
+            DumpdirRecord::Included(name)
+            | DumpdirRecord::Unchanged(name)
+            | DumpdirRecord::Directory(name) => Some(name),
+            _ => None,
+        }
+    }
+}
+
+/// Listing of a directory, the body of a dumpdir entry.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Dumpdir {
+    records: Vec<DumpdirRecord>,
+}
+
+impl Dumpdir {
+    pub fn new(records: Vec<DumpdirRecord>) -> Self {
+        Dumpdir { records }
+    }
+
+    #[inline]
+    pub fn records(&self) -> &[DumpdirRecord] {
+        &self.records
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// Parses NUL-terminated records up to the empty one that ends them.
+    pub fn parse(mut data: &[u8]) -> io::Result<Dumpdir> {
+        let mut records = Vec::new();
+        while !data.is_empty() {
+            let end = data.iter().position(|b| *b == 0).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "unterminated dumpdir record")
+            })?;
+            let (record, rest) = (&data[..end], &data[end + 1..]);
+            data = rest;
+            let (code, name) = match record.split_first() {
+                Some((code, name)) => (*code, name.to_vec()),
+                None => break,
+            };
+            records.push(match code {
+                b'Y' => DumpdirRecord::Included(name),
+                b'N' => DumpdirRecord::Unchanged(name),
+                b'D' => DumpdirRecord::Directory(name),
+                b'R' => DumpdirRecord::RenameFrom(name),
+                b'T' => DumpdirRecord::RenameTo(name),
+                b'X' => DumpdirRecord::TempDir(name),
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "unknown dumpdir record",
+                    ))
+                }
+            });
+        }
+        Ok(Dumpdir { records })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        for record in &self.records {
+            record.encode(&mut out);
+        }
+        out.push(0);
+        out
+    }
+}
+
+/// Output of [`parse_dumpdirs`].
+#[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
+pub enum DumpdirItem {
+    Item(TarItem),
+    /// Listing of the dumpdir entry whose body just ended.
+    Dumpdir(Dumpdir),
+}
+
+impl DumpdirItem {
+    /// Drops listings, for feeding the result to an encoder.
+    pub fn into_item(self) -> Option<TarItem> {
+        match self {
+            DumpdirItem::Item(item) => Some(item),
+            DumpdirItem::Dumpdir(_) => None,
+        }
+    }
+}
+
+struct ParseDumpdirs<S> {
+    upstream: S,
+    /// Body of the current dumpdir entry and how much of it is missing.
+    body: Option<(BytesMut, u64)>,
+}
+
+impl<S> ParseDumpdirs<S> {
+    fn finished(&mut self) -> Option<BytesMut> {
+        match self.body {
+            Some((_, 0)) => self.body.take().map(|(body, _)| body),
+            _ => None,
+        }
+    }
+}
+
+impl<E, S> Stream for ParseDumpdirs<S>
+where
+    E: Debug + Send + Sync + 'static,
+    S: Stream<Item = TarItem, Error = Error<E>>,
+{
+    type Item = DumpdirItem;
+    type Error = Error<E>;
+
+    fn poll(&mut self) -> Result<Async<Option<<Self as Stream>::Item>>, <Self as Stream>::Error> {
+        if let Some(body) = self.finished() {
+            let dumpdir = Dumpdir::parse(&body).map_err(|_| Error::Format("gnu dumpdir"))?;
+            return Ok(Async::Ready(Some(DumpdirItem::Dumpdir(dumpdir))));
+        }
+        let item = try_ready!(self.upstream.poll());
+        match item {
+            Some(TarItem::Entry(ref entry)) => {
+                if self.body.is_some() {
+                    return Err(Error::UnexpectedEof);
+                }
+                if is_dumpdir(entry) {
+                    self.body = Some((BytesMut::new(), entry.size()));
+                }
+            }
+            Some(TarItem::Chunk(ref bytes)) => {
+                if let Some((ref mut body, ref mut remaining)) = self.body {
+                    body.extend_from_slice(bytes);
+                    *remaining = remaining.saturating_sub(bytes.len() as u64);
+                }
+            }
+            None if self.body.is_some() => return Err(Error::UnexpectedEof),
+            None => (),
+        }
+        Ok(Async::Ready(item.map(DumpdirItem::Item)))
+    }
+}
+
+/// Passes a flat item stream through and follows the body of every dumpdir
+/// entry with its parsed listing.
+pub fn parse_dumpdirs<E, S>(items: S) -> impl Stream<Item = DumpdirItem, Error = Error<E>>
+where
+    E: Debug + Send + Sync + 'static,
+    S: Stream<Item = TarItem, Error = Error<E>>,
+{
+    ParseDumpdirs {
+        upstream: items,
+        body: None,
+    }
+}
+
+fn rename_path(dst: &Path, name: &[u8], temp: Option<&PathBuf>) -> io::Result<PathBuf> {
+    let invalid = |message| io::Error::new(io::ErrorKind::InvalidData, message);
+    if name.is_empty() {
+        return temp
+            .cloned()
+            .ok_or_else(|| invalid("rename without temporary directory"));
+    }
+    let path = unpack::bytes_path(name)?;
+    unpack::safe_join(dst, path).ok_or_else(|| invalid("rename escapes destination"))
+}
+
+/// Creates the directory of a dumpdir entry, applies its renames and removes
+/// whatever it does not list.
+fn apply_dumpdir(
+    dst: &Path,
+    header: &TarEntry,
+    dumpdir: &Dumpdir,
+    config: Config,
+) -> io::Result<()> {
+    let path = unpack::entry_path(dst, header)?;
+    unpack::create_special(dst, &path, header)?;
+
+    let mut temp = None;
+    let mut from = None;
+    for record in dumpdir.records() {
+        match record {
+            DumpdirRecord::TempDir(name) => temp = Some(rename_path(dst, name, None)?),
+            DumpdirRecord::RenameFrom(name) => from = Some(rename_path(dst, name, temp.as_ref())?),
+            DumpdirRecord::RenameTo(name) => {
+                let to = rename_path(dst, name, temp.as_ref())?;
+                if let Some(from) = from.take() {
+                    if fs::symlink_metadata(&from).is_ok() {
+                        unpack::remove_any(&to)?;
+                        fs::rename(from, to)?;
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
+    let members: HashSet<&[u8]> = dumpdir
+        .records()
+        .iter()
+        .filter_map(|r| r.member())
+        .collect();
+    for child in fs::read_dir(&path)? {
+        let child = child?;
+        if !members.contains(create::os_bytes(&child.file_name()).as_slice()) {
+            unpack::remove_any(&child.path())?;
+        }
+    }
+    unpack::set_attributes(&path, header, config)
+}
+
+/// Extracts every entry of `entries` under `dst` like
+/// [`unpack`](crate::unpack::unpack), and empties each dumpdir entry's
+/// directory of members that its listing does not name.
+///
+/// This is how a chain of incremental archives restores the tree of the
+/// last one, including deletions and directory renames. GNU tar stores a
+/// directory's dumpdir before its members, so they are not affected.
+pub fn unpack_incremental<E, S, T>(
+    entries: T,
+    dst: PathBuf,
+    config: Config,
+) -> impl Future<Item = (), Error = Error<E>>
+where
+    E: Debug + Send + Sync + 'static,
+    S: Stream<Item = TarItem, Error = Error<E>> + Send + 'static,
+    T: Stream<Item = full::Entry<S>, Error = Error<E>>,
+{
+    let dir_times = unpack::DirTimes::default();
+    let recorder = dir_times.clone();
+    entries
+        .for_each(move |entry| {
+            recorder.record(&dst, &entry, config);
+            if !is_dumpdir(entry.header()) {
+                return Either::A(unpack::unpack_entry(entry, &dst, config));
+            }
+            let header = entry.header().clone();
+            let dst = dst.clone();
+            Either::B(entry.concat2().and_then(move |body| {
+                let dumpdir = match Dumpdir::parse(&body) {
+                    Ok(dumpdir) => dumpdir,
+                    Err(_) => return Either::A(future::err(Error::Format("gnu dumpdir"))),
+                };
+                Either::B(
+                    blocking::run(move || apply_dumpdir(&dst, &header, &dumpdir, config))
+                        .map_err(Error::IoError),
+                )
+            }))
+        })
+        .and_then(move |_| dir_times.apply().map_err(Error::IoError))
+}
+
+enum Body {
+    None,
+    File(PathBuf),
+    Bytes(Bytes),
+}
+
+/// What the scan decided to store for one path.
+struct Planned {
+    entry: TarEntry,
+    body: Body,
+}
+
+/// Decides what goes into an incremental archive, in one pass over the
+/// tree.
+struct Scan<'a> {
+    options: &'a CreateOptions,
+    /// Device and inode of every directory of the previous level, by name.
+    previous: HashMap<Vec<u8>, (u64, u64)>,
+    /// Start of the previous level; `None` for a full dump.
+    since: Option<SystemTime>,
+    planned: Vec<Planned>,
+    snapshot: Snapshot,
+    root_dev: Option<u64>,
+    links: HashMap<(u64, u64), Vec<u8>>,
+    dirs: HashSet<(u64, u64)>,
+}
+
+impl<'a> Scan<'a> {
+    fn directory(&mut self, path: &Path, archive_path: &[u8], visit: Visit) -> io::Result<()> {
+        let Visit {
+            mut entry,
+            id,
+            children,
+            ..
+        } = visit;
+        let root_dev = *self.root_dev.get_or_insert(id.0);
+        let descend = !(self.options.one_file_system() && id.0 != root_dev)
+            && (!self.options.follow_symlinks() || self.dirs.insert(id));
+        if archive_path.is_empty() {
+            entry.set_path_bytes("./");
+        } else {
+            entry.set_path_bytes(child_path(archive_path, b""));
+        }
+        if !descend {
+            // Without a listing, extraction leaves what is there alone.
+            self.planned.push(Planned {
+                entry,
+                body: Body::None,
+            });
+            return Ok(());
+        }
+
+        let name = if archive_path.is_empty() {
+            b".".to_vec()
+        } else {
+            archive_path.to_vec()
+        };
+        // A directory that is new, or was replaced, is dumped in full.
+        let full = self.since.is_none() || self.previous.get(&name) != Some(&id);
+        let mut records = Vec::new();
+        let mut members = Vec::new();
+        for child in children {
+            let child_name = create::os_bytes(child.as_os_str());
+            let child_archive = child_path(archive_path, &child_name);
+            if self.options.excluded(&child_name, &child_archive) {
+                continue;
+            }
+            let child_path = path.join(child);
+            let visit = match create::visit(
+                &child_path,
+                child_archive.clone(),
+                self.options.follow_symlinks(),
+                self.root_dev.filter(|_| self.options.one_file_system()),
+            )? {
+                Some(visit) => visit,
+                None => continue,
+            };
+            let changed = full || Some(visit.changed) >= self.since;
+            records.push(if visit.entry.entry_type().is_dir() {
+                DumpdirRecord::Directory(child_name)
+            } else if changed {
+                DumpdirRecord::Included(child_name)
+            } else {
+                DumpdirRecord::Unchanged(child_name)
+            });
+            if changed || visit.entry.entry_type().is_dir() {
+                members.push((child_path, child_archive, visit));
+            }
+        }
+
+        let dumpdir = Dumpdir::new(records);
+        let body = Bytes::from(dumpdir.to_bytes());
+        entry
+            .set_entry_type(tar::EntryType::new(DUMPDIR))
+            .set_size(body.len() as u64);
+        self.snapshot.directories.push(SnapshotDir {
+            nfs: false,
+            mtime: entry.mtime(),
+            device: id.0,
+            inode: id.1,
+            name,
+            contents: dumpdir,
+        });
+        self.planned.push(Planned {
+            entry,
+            body: Body::Bytes(body),
+        });
+
+        for (child_path, child_archive, visit) in members {
+            if visit.entry.entry_type().is_dir() {
+                self.directory(&child_path, &child_archive, visit)?;
+            } else {
+                self.file(child_path, child_archive, visit);
+            }
+        }
+        Ok(())
+    }
+
+    fn file(&mut self, path: PathBuf, archive_path: Vec<u8>, visit: Visit) {
+        let Visit {
+            mut entry,
+            id,
+            nlink,
+            ..
+        } = visit;
+        if nlink > 1 {
+            if let Some(first) = self.links.get(&id) {
+                entry
+                    .set_entry_type(tar::EntryType::Link)
+                    .set_link_bytes(Some(first.clone()))
+                    .set_size(0);
+                self.planned.push(Planned {
+                    entry,
+                    body: Body::None,
+                });
+                return;
+            }
+            self.links.insert(id, archive_path);
+        }
+        let body = if entry.size() > 0 {
+            Body::File(path)
+        } else {
+            Body::None
+        };
+        self.planned.push(Planned { entry, body });
+    }
+}
+
+fn scan(
+    root: PathBuf,
+    options: CreateOptions,
+    previous: Option<Snapshot>,
+) -> io::Result<(Vec<Planned>, Snapshot)> {
+    let start = SystemTime::now();
+    let visit = create::visit(&root, Vec::new(), true, None)?
+        .filter(|visit| visit.entry.entry_type().is_dir())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a directory"))?;
+    let mut scan = Scan {
+        options: &options,
+        since: previous.as_ref().map(|previous| previous.time),
+        previous: previous
+            .map(|previous| {
+                previous
+                    .directories
+                    .into_iter()
+                    .map(|dir| (dir.name, (dir.device, dir.inode)))
+                    .collect()
+            })
+            .unwrap_or_default(),
+        planned: Vec::new(),
+        snapshot: Snapshot::new(start),
+        root_dev: None,
+        links: HashMap::new(),
+        dirs: HashSet::new(),
+    };
+    scan.directory(&root, b"", visit)?;
+    Ok((scan.planned, scan.snapshot))
+}
+
+struct Dump<E> {
+    planned: VecDeque<Planned>,
+    chunk: Option<Bytes>,
+    file: Option<FileBody>,
+    _error: PhantomData<E>,
+}
+
+impl<E> Stream for Dump<E>
+where
+    E: Debug + Send + Sync + 'static,
+{
+    type Item = TarItem;
+    type Error = Error<E>;
+
+    fn poll(&mut self) -> Result<Async<Option<<Self as Stream>::Item>>, <Self as Stream>::Error> {
+        if let Some(chunk) = self.chunk.take() {
+            return Ok(Async::Ready(Some(TarItem::Chunk(chunk))));
+        }
+        if let Some(ref mut file) = self.file {
+            if let Some(bytes) = try_ready!(file.poll().map_err(Error::IoError)) {
+                return Ok(Async::Ready(Some(TarItem::Chunk(bytes))));
+            }
+            self.file = None;
+        }
+        let Planned { entry, body } = match self.planned.pop_front() {
+            Some(planned) => planned,
+            None => return Ok(Async::Ready(None)),
+        };
+        match body {
+            Body::None => (),
+            Body::File(path) => {
+                self.file = Some(FileBody::new(path, entry.size()));
+            }
+            Body::Bytes(bytes) => self.chunk = Some(bytes),
+        }
+        Ok(Async::Ready(Some(TarItem::Entry(entry))))
+    }
+}
+
+/// Scans the tree below `root` and decides what an incremental archive of
+/// it holds: a dumpdir entry for every directory, the root included as
+/// `./`, and every file that changed since `previous` was taken, or all of
+/// them without one. A directory that was not in `previous`, or has a new
+/// inode, is stored in full.
+///
+/// The future resolves once the scan is done, to the items of the archive
+/// and the snapshot to keep for the next level. The scan holds the metadata
+/// of everything to be stored until it has been streamed.
+pub fn walk_incremental<E>(
+    root: PathBuf,
+    options: CreateOptions,
+    previous: Option<Snapshot>,
+) -> impl Future<Item = (impl Stream<Item = TarItem, Error = Error<E>>, Snapshot), Error = Error<E>>
+where
+    E: Debug + Send + Sync + 'static,
+{
+    blocking::run(move || scan(root, options, previous))
+        .map_err(Error::IoError)
+        .map(|(planned, snapshot)| {
+            let items = Dump {
+                planned: planned.into(),
+                chunk: None,
+                file: None,
+                _error: PhantomData,
+            };
+            (items, snapshot)
+        })
+}
+
+/// Tar byte stream of an incremental archive; see [`walk_incremental`].
+pub fn create_incremental<E>(
+    root: PathBuf,
+    options: CreateOptions,
+    previous: Option<Snapshot>,
+) -> impl Future<Item = (impl Stream<Item = Bytes, Error = Error<E>>, Snapshot), Error = Error<E>>
+where
+    E: Debug + Send + Sync + 'static,
+{
+    walk_incremental(root, options, previous)
+        .map(|(items, snapshot)| (encode::flat::encode_tar(items), snapshot))
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+    use crate::decode::flat;
+    use futures::stream;
+    use std::os::unix::ffi::OsStrExt;
+
+    #[test]
+    fn test_incremental() {
+        let base = std::env::temp_dir().join(format!("tar-async-incr-{}", std::process::id()));
+        let (src, dst) = (base.join("src"), base.join("dst"));
+        let _ = fs::remove_dir_all(&base);
+        fs::create_dir_all(src.join("dir")).unwrap();
+        fs::write(src.join("keep"), b"keep").unwrap();
+        fs::write(src.join("dir/gone"), b"gone").unwrap();
+        // Names are bytes; one that is not UTF-8 must survive the listing.
+        let odd = std::ffi::OsStr::from_bytes(b"odd\xff");
+        fs::write(src.join(odd), b"odd").unwrap();
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        let mut dump = |previous| {
+            let (tar, snapshot) = runtime
+                .block_on(
+                    create_incremental::<()>(src.clone(), CreateOptions::new(), previous)
+                        .and_then(|(tar, snapshot)| tar.concat2().map(|tar| (tar, snapshot))),
+                )
+                .unwrap();
+            runtime
+                .block_on(unpack_incremental(
+                    full::decode_tar(stream::once::<_, ()>(Ok(tar.clone()))),
+                    dst.clone(),
+                    Config::default(),
+                ))
+                .unwrap();
+            let items: Vec<_> = parse_dumpdirs(flat::decode_tar(stream::once::<_, ()>(Ok(tar))))
+                .collect()
+                .wait()
+                .unwrap();
+            (items, snapshot)
+        };
+
+        let (_, level0) = dump(None);
+        assert_eq!(fs::read(dst.join("dir/gone")).unwrap(), b"gone");
+        let snapshot = Snapshot::parse(&level0.to_bytes()).unwrap();
+        assert_eq!(snapshot, level0);
+
+        fs::remove_file(src.join("dir/gone")).unwrap();
+        fs::write(src.join("dir/new"), b"new").unwrap();
+        let (items, _) = dump(Some(level0));
+        let paths: Vec<_> = items
+            .iter()
+            .filter_map(|item| match item {
+                DumpdirItem::Item(TarItem::Entry(entry)) => Some(entry.path_bytes().to_vec()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            paths,
+            vec![b"./".to_vec(), b"dir/".to_vec(), b"dir/new".to_vec()]
+        );
+        let listings: Vec<_> = items
+            .iter()
+            .filter_map(|item| match item {
+                DumpdirItem::Dumpdir(dumpdir) => Some(dumpdir.records().to_vec()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            listings,
+            vec![
+                vec![
+                    DumpdirRecord::Directory(b"dir".to_vec()),
+                    DumpdirRecord::Unchanged(b"keep".to_vec()),
+                    DumpdirRecord::Unchanged(b"odd\xff".to_vec()),
+                ],
+                vec![DumpdirRecord::Included(b"new".to_vec())],
+            ]
+        );
+
+        assert!(!dst.join("dir/gone").exists());
+        assert_eq!(fs::read(dst.join("dir/new")).unwrap(), b"new");
+        assert_eq!(fs::read(dst.join("keep")).unwrap(), b"keep");
+        assert_eq!(fs::read(dst.join(odd)).unwrap(), b"odd");
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
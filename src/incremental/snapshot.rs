@@ -0,0 +1,230 @@
+//! GNU tar snapshot files, as read and written by `--listed-incremental`.
+//!
+//! Only format version 2, used by GNU tar since 1.16, is supported. It is a
+//! header line followed by NUL-terminated fields: the time the dump started,
+//! then for every directory its NFS flag, mtime, device, inode, name and the
+//! dumpdir of its members, closed by an extra NUL.
+
+use super::Dumpdir;
+use crate::blocking;
+use futures::prelude::*;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Header line; GNU tar only accepts files that start with its own name.
+const HEADER: &[u8] = b"GNU tar-1.35-2\n";
+const HEADER_PREFIX: &[u8] = b"GNU tar-";
+
+/// State of a directory at the time of a dump.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SnapshotDir {
+    pub nfs: bool,
+    pub mtime: SystemTime,
+    pub device: u64,
+    pub inode: u64,
+    /// Path of the directory as it was archived, without a trailing `/`.
+    pub name: Vec<u8>,
+    /// Members of the directory; empty if it was not dumped.
+    pub contents: Dumpdir,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Snapshot {
+    /// When the dump started; the next level dumps what changed since.
+    pub time: SystemTime,
+    pub directories: Vec<SnapshotDir>,
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("snapshot file: {}", message),
+    )
+}
+
+fn time_from_parts(secs: i64, nanos: u32) -> SystemTime {
+    if secs >= 0 {
+        UNIX_EPOCH + Duration::new(secs as u64, nanos)
+    } else {
+        UNIX_EPOCH - Duration::from_secs(secs.unsigned_abs()) + Duration::from_nanos(nanos.into())
+    }
+}
+
+fn time_to_parts(time: SystemTime) -> (i64, u32) {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => (since.as_secs() as i64, since.subsec_nanos()),
+        Err(e) => {
+            let before = e.duration();
+            match before.subsec_nanos() {
+                0 => (-(before.as_secs() as i64), 0),
+                nanos => (-(before.as_secs() as i64) - 1, 1_000_000_000 - nanos),
+            }
+        }
+    }
+}
+
+/// Splits off the next NUL-terminated field.
+fn field<'a>(data: &mut &'a [u8]) -> io::Result<&'a [u8]> {
+    let end = data
+        .iter()
+        .position(|b| *b == 0)
+        .ok_or_else(|| invalid("unterminated field"))?;
+    let value = &data[..end];
+    *data = &data[end + 1..];
+    Ok(value)
+}
+
+fn number<T: std::str::FromStr>(data: &mut &[u8]) -> io::Result<T> {
+    std::str::from_utf8(field(data)?)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| invalid("invalid number"))
+}
+
+fn timestamp(data: &mut &[u8]) -> io::Result<SystemTime> {
+    let secs = number(data)?;
+    let nanos = number(data)?;
+    if nanos >= 1_000_000_000 {
+        return Err(invalid("invalid timestamp"));
+    }
+    Ok(time_from_parts(secs, nanos))
+}
+
+fn push_field(out: &mut Vec<u8>, value: &[u8]) {
+    out.extend_from_slice(value);
+    out.push(0);
+}
+
+fn push_time(out: &mut Vec<u8>, time: SystemTime) {
+    let (secs, nanos) = time_to_parts(time);
+    push_field(out, secs.to_string().as_bytes());
+    push_field(out, nanos.to_string().as_bytes());
+}
+
+impl Snapshot {
+    pub fn new(time: SystemTime) -> Self {
+        Snapshot {
+            time,
+            directories: Vec::new(),
+        }
+    }
+
+    /// The record of the directory archived as `name`.
+    pub fn directory(&self, name: &[u8]) -> Option<&SnapshotDir> {
+        self.directories.iter().find(|dir| dir.name == name)
+    }
+
+    pub fn parse(data: &[u8]) -> io::Result<Snapshot> {
+        let line_end = data
+            .iter()
+            .position(|b| *b == b'\n')
+            .ok_or_else(|| invalid("missing header"))?;
+        let header = &data[..line_end];
+        if !header.starts_with(HEADER_PREFIX) {
+            return Err(invalid("missing header"));
+        }
+        let version = header.rsplit(|b| *b == b'-').next().unwrap_or_default();
+        if version != b"2" {
+            return Err(invalid("unsupported format version"));
+        }
+
+        let mut data = &data[line_end + 1..];
+        let mut snapshot = Snapshot::new(timestamp(&mut data)?);
+        while !data.is_empty() {
+            let nfs = match field(&mut data)? {
+                b"0" => false,
+                b"1" => true,
+                _ => return Err(invalid("invalid nfs flag")),
+            };
+            let mtime = timestamp(&mut data)?;
+            let device = number(&mut data)?;
+            let inode = number(&mut data)?;
+            let name = field(&mut data)?.to_vec();
+            let start = data;
+            while !field(&mut data)?.is_empty() {}
+            let contents = &start[..start.len() - data.len()];
+            if !field(&mut data)?.is_empty() {
+                return Err(invalid("missing record terminator"));
+            }
+            snapshot.directories.push(SnapshotDir {
+                nfs,
+                mtime,
+                device,
+                inode,
+                name,
+                contents: Dumpdir::parse(contents)
+                    .map_err(|_| invalid("invalid directory contents"))?,
+            });
+        }
+        Ok(snapshot)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = HEADER.to_vec();
+        push_time(&mut out, self.time);
+        for dir in &self.directories {
+            push_field(&mut out, if dir.nfs { b"1" } else { b"0" });
+            push_time(&mut out, dir.mtime);
+            push_field(&mut out, dir.device.to_string().as_bytes());
+            push_field(&mut out, dir.inode.to_string().as_bytes());
+            push_field(&mut out, &dir.name);
+            for record in dir.contents.records() {
+                record.encode(&mut out);
+            }
+            out.extend_from_slice(&[0, 0]);
+        }
+        out
+    }
+}
+
+/// Reads a snapshot file on the blocking thread pool.
+pub fn read_snapshot(path: PathBuf) -> impl Future<Item = Snapshot, Error = io::Error> {
+    blocking::run(move || Snapshot::parse(&fs::read(path)?))
+}
+
+/// Writes a snapshot file, replacing an existing one only once the new one
+/// is complete.
+pub fn write_snapshot(
+    path: PathBuf,
+    snapshot: &Snapshot,
+) -> impl Future<Item = (), Error = io::Error> {
+    let data = snapshot.to_bytes();
+    blocking::run(move || {
+        let mut temp = path.clone().into_os_string();
+        temp.push(".tmp");
+        fs::write(&temp, data)?;
+        fs::rename(&temp, &path)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::DumpdirRecord;
+    use super::*;
+
+    #[test]
+    fn test_snapshot() {
+        let data: &[u8] = b"GNU tar-1.35-2\n1700000000\x00500\x00\
+            0\x001690000000\x000\x002049\x0012\x00dir\x00Ya\x00Dsub\x00\x00\x00\
+            0\x001690000001\x0010\x002049\x0013\x00dir/sub\x00\x00\x00";
+        let snapshot = Snapshot::parse(data).unwrap();
+        assert_eq!(snapshot.time, time_from_parts(1_700_000_000, 500));
+        assert_eq!(snapshot.directories.len(), 2);
+        let dir = snapshot.directory(b"dir").unwrap();
+        assert_eq!((dir.device, dir.inode), (2049, 12));
+        assert_eq!(
+            dir.contents.records(),
+            &[
+                DumpdirRecord::Included(b"a".to_vec()),
+                DumpdirRecord::Directory(b"sub".to_vec()),
+            ]
+        );
+        assert!(snapshot.directory(b"dir/sub").unwrap().contents.is_empty());
+        assert_eq!(snapshot.to_bytes(), data);
+
+        assert!(Snapshot::parse(b"GNU tar-1.20-1\n").is_err());
+        assert_eq!(time_to_parts(time_from_parts(-2, 250)), (-2, 250));
+    }
+}
@@ -0,0 +1,996 @@
+//! Container image layer semantics (OCI / Docker).
+//!
+//! A layer is a tar whose entries are applied on top of the layers below it.
+//! Deletions are encoded as whiteout files: `.wh.<name>` removes `<name>` from
+//! the lower layers and `.wh..wh..opq` marks its directory as opaque, hiding
+//! everything the lower layers put there.
+
+use crate::blocking;
+use crate::decode::{flat, full, Error};
+use crate::encode;
+use crate::unpack::{self, safe_join, DirTimes};
+use crate::Config;
+use bytes::Bytes;
+use futures::{future, prelude::*, stream, try_ready};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Debug;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+pub const WHITEOUT_PREFIX: &str = ".wh.";
+pub const OPAQUE_MARKER: &str = ".wh..wh..opq";
+
+/// Deletion recorded in a layer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Whiteout {
+    /// Path removed from the lower layers.
+    Path(PathBuf),
+    /// Directory whose lower-layer contents are hidden.
+    Opaque(PathBuf),
+}
+
+/// Interprets an entry path as a whiteout marker.
+pub fn whiteout(path: &Path) -> Option<Whiteout> {
+    let name = path.file_name()?.to_str()?;
+    let parent = path.parent().unwrap_or_else(|| Path::new(""));
+    if name == OPAQUE_MARKER {
+        Some(Whiteout::Opaque(parent.to_path_buf()))
+    } else if name.starts_with(WHITEOUT_PREFIX) && name.len() > WHITEOUT_PREFIX.len() {
+        Some(Whiteout::Path(parent.join(&name[WHITEOUT_PREFIX.len()..])))
+    } else {
+        None
+    }
+}
+
+#[allow(clippy::large_enum_variant)]
+pub enum LayerItem<S: Stream<Item = flat::TarItem>>
+where
+    S::Error: Sync + Send + Debug + 'static,
+{
+    Entry(full::Entry<S>),
+    Whiteout(Whiteout),
+}
+
+/// Splits a layer's entries into regular entries and whiteouts.
+pub fn layer_items<E, S, T>(entries: T) -> impl Stream<Item = LayerItem<S>, Error = Error<E>>
+where
+    E: Debug + Send + Sync + 'static,
+    S: Stream<Item = flat::TarItem, Error = Error<E>>,
+    T: Stream<Item = full::Entry<S>, Error = Error<E>>,
+{
+    entries.and_then(|entry| {
+        let wh = whiteout(entry.header().path().map_err(Error::IoError)?);
+        Ok(match wh {
+            Some(wh) => LayerItem::Whiteout(wh),
+            None => LayerItem::Entry(entry),
+        })
+    })
+}
+
+pub fn decode_layer<TarStream: Stream<Item = Bytes>>(
+    upstream: TarStream,
+) -> impl Stream<
+    Item = LayerItem<impl Stream<Item = flat::TarItem, Error = Error<TarStream::Error>>>,
+    Error = Error<TarStream::Error>,
+>
+where
+    TarStream::Error: std::fmt::Debug + Sync + Send + 'static,
+{
+    layer_items(full::decode_tar(upstream))
+}
+
+/// `path` relative to the layer root; paths that escape it are an error.
+fn relative(path: &Path) -> io::Result<PathBuf> {
+    safe_join(Path::new(""), path).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("entry path escapes destination: {:?}", path),
+        )
+    })
+}
+
+/// Paths written by the layer being applied; kept when a directory turns opaque.
+#[derive(Clone, Default)]
+struct Written(Arc<Mutex<HashSet<PathBuf>>>);
+
+impl Written {
+    fn insert(&self, path: PathBuf) {
+        self.0.lock().unwrap().insert(path);
+    }
+
+    /// True if `path` or anything below it was written by the layer.
+    fn covers(&self, path: &Path) -> bool {
+        self.0.lock().unwrap().iter().any(|w| w.starts_with(path))
+    }
+}
+
+/// Lower-layer children of `dir` that an opaque marker hides.
+fn hidden_children(base: &Path, dir: &Path, written: &Written) -> io::Result<Vec<PathBuf>> {
+    let full_dir = match safe_join(base, dir) {
+        Some(dir) => dir,
+        None => return Ok(Vec::new()),
+    };
+    // A symlink in place of the directory has no children in this tree.
+    unpack::check_parents(base, &full_dir)?;
+    match fs::symlink_metadata(&full_dir) {
+        Ok(ref meta) if !meta.is_dir() => return Ok(Vec::new()),
+        _ => (),
+    }
+    let children = match fs::read_dir(full_dir) {
+        Ok(children) => children,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut hidden = Vec::new();
+    for child in children {
+        let rel = dir.join(child?.file_name());
+        if !written.covers(&rel) {
+            hidden.push(rel);
+        }
+    }
+    Ok(hidden)
+}
+
+type BoxFuture<T, E> = Box<dyn Future<Item = T, Error = Error<E>> + Send>;
+
+struct Applier {
+    dst: PathBuf,
+    config: Config,
+    written: Written,
+    dir_times: DirTimes,
+}
+
+impl Applier {
+    fn apply_entry<E, S>(&self, entry: full::Entry<S>) -> BoxFuture<(), E>
+    where
+        E: Debug + Send + Sync + 'static,
+        S: Stream<Item = flat::TarItem, Error = Error<E>> + Send + 'static,
+    {
+        let is_dir = entry.header().entry_type().is_dir();
+        let target = match unpack::entry_path(&self.dst, entry.header()) {
+            Ok(ref target) if *target == self.dst && !is_dir => {
+                return Box::new(future::err(Error::IoError(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "non-directory entry at the layer root",
+                ))))
+            }
+            Ok(target) => target,
+            Err(e) => return Box::new(future::err(Error::IoError(e))),
+        };
+        self.written
+            .insert(target.strip_prefix(&self.dst).unwrap().to_path_buf());
+        self.dir_times.record(&self.dst, &entry, self.config);
+
+        // Only a directory replaced by a non-directory needs help; unpack
+        // already replaces files and keeps existing directories.
+        let (dst, config) = (self.dst.clone(), self.config);
+        let root = dst.clone();
+        Box::new(
+            blocking::run(move || match fs::symlink_metadata(&target) {
+                Ok(ref meta) if meta.is_dir() && !is_dir => {
+                    unpack::check_parents(&root, &target)?;
+                    fs::remove_dir_all(&target)
+                }
+                _ => Ok(()),
+            })
+            .map_err(Error::IoError)
+            .and_then(move |_| unpack::unpack_entry(entry, &dst, config)),
+        )
+    }
+
+    fn apply_whiteout<E>(&self, whiteout: Whiteout) -> BoxFuture<(), E>
+    where
+        E: Debug + Send + Sync + 'static,
+    {
+        let dst = self.dst.clone();
+        let written = self.written.clone();
+        Box::new(
+            blocking::run(move || match whiteout {
+                Whiteout::Path(path) => match safe_join(&dst, &path) {
+                    Some(target) => {
+                        unpack::check_parents(&dst, &target)?;
+                        unpack::remove_any(&target)
+                    }
+                    None => Ok(()),
+                },
+                Whiteout::Opaque(dir) => match safe_join(Path::new(""), &dir) {
+                    Some(dir) => {
+                        for child in hidden_children(&dst, &dir, &written)? {
+                            unpack::remove_any(&dst.join(child))?;
+                        }
+                        Ok(())
+                    }
+                    None => Ok(()),
+                },
+            })
+            .map_err(Error::IoError),
+        )
+    }
+}
+
+/// Applies a layer on top of the filesystem tree at `dst`.
+///
+/// Whiteouts remove paths, opaque markers clear everything in the directory
+/// that this layer does not itself provide, and an entry replaces whatever
+/// was at its path, even if the file type differs.
+pub fn apply<E, S, L>(
+    layer: L,
+    dst: PathBuf,
+    config: Config,
+) -> impl Future<Item = (), Error = Error<E>>
+where
+    E: Debug + Send + Sync + 'static,
+    S: Stream<Item = flat::TarItem, Error = Error<E>> + Send + 'static,
+    L: Stream<Item = LayerItem<S>, Error = Error<E>>,
+{
+    let applier = Applier {
+        dst,
+        config,
+        written: Written::default(),
+        dir_times: DirTimes::default(),
+    };
+    let dir_times = applier.dir_times.clone();
+
+    layer
+        .for_each(move |item| match item {
+            LayerItem::Entry(entry) => applier.apply_entry(entry),
+            LayerItem::Whiteout(whiteout) => applier.apply_whiteout(whiteout),
+        })
+        .and_then(move |_| dir_times.apply().map_err(Error::IoError))
+}
+
+/// Logical effect of a layer entry on the tree below it.
+pub enum Change<S: Stream<Item = flat::TarItem>>
+where
+    S::Error: Sync + Send + Debug + 'static,
+{
+    /// The path does not exist in the base tree.
+    Added(full::Entry<S>),
+    /// The path exists in the base tree and is replaced.
+    Modified(full::Entry<S>),
+    /// The path exists in the base tree and is removed.
+    Deleted(PathBuf),
+}
+
+impl<S: Stream<Item = flat::TarItem>> Debug for Change<S>
+where
+    S::Error: Sync + Send + Debug + 'static,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Change::Added(entry) => write!(f, "Added({:?})", entry.header().path()),
+            Change::Modified(entry) => write!(f, "Modified({:?})", entry.header().path()),
+            Change::Deleted(path) => write!(f, "Deleted({:?})", path),
+        }
+    }
+}
+
+fn exists(path: PathBuf) -> impl Future<Item = bool, Error = io::Error> {
+    blocking::run(move || match fs::symlink_metadata(path) {
+        Ok(_) => Ok(true),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    })
+}
+
+struct Differ {
+    base: PathBuf,
+    written: Written,
+    opaque: Arc<Mutex<Vec<PathBuf>>>,
+}
+
+impl Differ {
+    fn change<E, S>(&self, item: LayerItem<S>) -> BoxFuture<Option<Change<S>>, E>
+    where
+        E: Debug + Send + Sync + 'static,
+        S: Stream<Item = flat::TarItem, Error = Error<E>> + Send + 'static,
+    {
+        match item {
+            LayerItem::Entry(entry) => {
+                let path = match entry.header().path().and_then(relative) {
+                    Ok(path) => path,
+                    Err(e) => return Box::new(future::err(Error::IoError(e))),
+                };
+                self.written.insert(path.clone());
+                Box::new(
+                    exists(self.base.join(path))
+                        .map(move |exists| match exists {
+                            true => Some(Change::Modified(entry)),
+                            false => Some(Change::Added(entry)),
+                        })
+                        .map_err(Error::IoError),
+                )
+            }
+            LayerItem::Whiteout(Whiteout::Path(path)) => {
+                let path = match relative(&path) {
+                    Ok(path) => path,
+                    Err(_) => return Box::new(future::ok(None)),
+                };
+                Box::new(
+                    exists(self.base.join(&path))
+                        .map(move |exists| Some(Change::Deleted(path)).filter(|_| exists))
+                        .map_err(Error::IoError),
+                )
+            }
+            LayerItem::Whiteout(Whiteout::Opaque(dir)) => {
+                if let Ok(dir) = relative(&dir) {
+                    self.opaque.lock().unwrap().push(dir);
+                }
+                Box::new(future::ok(None))
+            }
+        }
+    }
+
+    fn opaque_deletions(&self) -> impl Future<Item = Vec<PathBuf>, Error = io::Error> {
+        let (base, written, opaque) =
+            (self.base.clone(), self.written.clone(), self.opaque.clone());
+        blocking::run(move || {
+            let mut deleted = Vec::new();
+            for dir in opaque.lock().unwrap().iter() {
+                deleted.extend(hidden_children(&base, dir, &written)?);
+            }
+            Ok(deleted)
+        })
+    }
+}
+
+/// Describes what applying the layer to the tree at `base` would change,
+/// without touching it.
+///
+/// Deletions caused by opaque directories are reported once the whole layer
+/// has been read, since later entries of the same layer stay visible.
+pub fn changes<E, S, L>(layer: L, base: PathBuf) -> impl Stream<Item = Change<S>, Error = Error<E>>
+where
+    E: Debug + Send + Sync + 'static,
+    S: Stream<Item = flat::TarItem, Error = Error<E>> + Send + 'static,
+    L: Stream<Item = LayerItem<S>, Error = Error<E>>,
+{
+    let differ = Arc::new(Differ {
+        base,
+        written: Written::default(),
+        opaque: Arc::new(Mutex::new(Vec::new())),
+    });
+
+    let tail = {
+        let differ = differ.clone();
+        future::lazy(move || differ.opaque_deletions())
+            .map(|deleted| stream::iter_ok(deleted.into_iter().map(Change::Deleted)))
+            .map_err(Error::IoError)
+            .flatten_stream()
+    };
+
+    layer
+        .and_then(move |item| differ.change(item))
+        .filter_map(|change| change)
+        .chain(tail)
+}
+
+/// Paths of lower layers hidden by the layers above them.
+#[derive(Default)]
+struct Shadow {
+    /// Paths provided by an upper layer, and whether they are directories.
+    /// A non-directory also hides everything below it.
+    present: HashMap<PathBuf, bool>,
+    /// Whiteouts: paths removed together with everything below them.
+    removed: HashSet<PathBuf>,
+    /// Directories whose lower contents are hidden.
+    opaque: HashSet<PathBuf>,
+}
+
+impl Shadow {
+    fn hides(&self, path: &Path) -> bool {
+        if self.present.contains_key(path) || self.removed.contains(path) {
+            return true;
+        }
+        path.ancestors().skip(1).any(|dir| {
+            self.removed.contains(dir)
+                || self.opaque.contains(dir)
+                || self.present.get(dir) == Some(&false)
+        })
+    }
+
+    fn merge(&mut self, other: Shadow) {
+        self.present.extend(other.present);
+        self.removed.extend(other.removed);
+        self.opaque.extend(other.opaque);
+    }
+}
+
+/// What the first pass learned about one item of a layer.
+enum Scanned {
+    Whiteout(Whiteout),
+    Entry {
+        path: PathBuf,
+        /// Path as stored in the archive.
+        name: Vec<u8>,
+        entry_type: tar::EntryType,
+        /// Still present in the squashed tree.
+        visible: bool,
+        /// Target of a hardlink, relative to the root.
+        link: Option<PathBuf>,
+    },
+}
+
+/// Position of an entry: layer index, bottom first, and the entry's index
+/// in its layer, whiteouts included.
+type Position = (usize, usize);
+
+/// How a visible hardlink is written when its target is gone.
+enum Fix {
+    /// Link to another path with the same contents.
+    Relink(Vec<u8>),
+    /// Store the body of the regular file at a position under the link's
+    /// path.
+    Materialize(Position),
+}
+
+/// Everything the second pass needs: which entries stay, how links are
+/// fixed up, and the final metadata of every directory.
+#[derive(Default)]
+struct Plan {
+    layers: Vec<Vec<Scanned>>,
+    fixes: HashMap<Position, Fix>,
+    /// Topmost visible entry of every directory; emitted at the lowest layer
+    /// that has the directory, so that it comes before its contents.
+    dirs: HashMap<PathBuf, Option<flat::TarEntry>>,
+    shadow: Shadow,
+    pending: Shadow,
+}
+
+impl Plan {
+    fn scan_entry(&mut self, layer: usize, entry: &flat::TarEntry) -> io::Result<()> {
+        let path = relative(unpack::bytes_path(entry.path_bytes())?)?;
+        let scanned = match whiteout(&path) {
+            Some(Whiteout::Path(path)) => {
+                self.pending.removed.insert(path.clone());
+                Scanned::Whiteout(Whiteout::Path(path))
+            }
+            Some(Whiteout::Opaque(dir)) => {
+                self.pending.opaque.insert(dir.clone());
+                Scanned::Whiteout(Whiteout::Opaque(dir))
+            }
+            None => {
+                let visible = !self.shadow.hides(&path);
+                let entry_type = entry.entry_type();
+                if visible && entry_type.is_dir() {
+                    self.dirs
+                        .entry(path.clone())
+                        .or_insert_with(|| Some(entry.clone()));
+                }
+                self.pending
+                    .present
+                    .insert(path.clone(), entry_type.is_dir());
+                let link = match entry.link_bytes() {
+                    Some(link) if entry_type.is_hard_link() => {
+                        unpack::bytes_path(link).and_then(relative).ok()
+                    }
+                    _ => None,
+                };
+                Scanned::Entry {
+                    path,
+                    name: entry.path_bytes().to_vec(),
+                    entry_type,
+                    visible,
+                    link,
+                }
+            }
+        };
+        self.layers[layer].push(scanned);
+        Ok(())
+    }
+
+    fn end_layer(&mut self) {
+        let pending = std::mem::take(&mut self.pending);
+        self.shadow.merge(pending);
+    }
+
+    fn scanned(&self, (layer, index): Position) -> &Scanned {
+        &self.layers[layer][index]
+    }
+
+    fn visible(&self, at: Position) -> bool {
+        match self.scanned(at) {
+            Scanned::Entry { visible, .. } => *visible,
+            Scanned::Whiteout(_) => false,
+        }
+    }
+
+    /// The entry `target` referred to when the entry at `from` was applied;
+    /// `None` if nothing was there.
+    fn find(&self, (layer, index): Position, target: &Path) -> Option<Position> {
+        let mut end = index;
+        for layer in (0..=layer).rev() {
+            let mut hidden_below = false;
+            for index in (0..end).rev() {
+                match &self.layers[layer][index] {
+                    Scanned::Entry { path, .. } if path == target => return Some((layer, index)),
+                    Scanned::Whiteout(Whiteout::Path(path)) if target.starts_with(path) => {
+                        hidden_below = true
+                    }
+                    Scanned::Whiteout(Whiteout::Opaque(dir))
+                        if target != dir && target.starts_with(dir) =>
+                    {
+                        hidden_below = true
+                    }
+                    _ => (),
+                }
+            }
+            if hidden_below || layer == 0 {
+                return None;
+            }
+            end = self.layers[layer - 1].len();
+        }
+        None
+    }
+
+    /// The regular file a hardlink at `at` shares its contents with.
+    fn origin(&self, mut at: Position, mut target: PathBuf) -> Option<Position> {
+        loop {
+            let found = self.find(at, &target)?;
+            match self.scanned(found) {
+                Scanned::Entry {
+                    link: Some(next), ..
+                } => {
+                    at = found;
+                    target = next.clone();
+                }
+                Scanned::Entry { entry_type, .. } if entry_type.is_file() => return Some(found),
+                _ => return None,
+            }
+        }
+    }
+
+    /// Decides how to write visible hardlinks whose target the upper layers
+    /// replaced or removed. The first such link to a file gets its body, the
+    /// others link to it.
+    fn fix_links(&mut self) {
+        let mut stored: HashMap<Position, Vec<u8>> = HashMap::new();
+        for layer in 0..self.layers.len() {
+            for index in 0..self.layers[layer].len() {
+                let (name, target) = match &self.layers[layer][index] {
+                    Scanned::Entry {
+                        visible: true,
+                        link: Some(target),
+                        name,
+                        ..
+                    } => (name.clone(), target.clone()),
+                    _ => continue,
+                };
+                let at = (layer, index);
+                match self.find(at, &target) {
+                    Some(direct) if self.visible(direct) => continue,
+                    Some(_) => (),
+                    None => continue,
+                }
+                let origin = match self.origin(at, target) {
+                    Some(origin) => origin,
+                    None => continue,
+                };
+                let fix = if self.visible(origin) {
+                    match self.scanned(origin) {
+                        Scanned::Entry { name, .. } => Fix::Relink(name.clone()),
+                        Scanned::Whiteout(_) => continue,
+                    }
+                } else if let Some(name) = stored.get(&origin) {
+                    Fix::Relink(name.clone())
+                } else {
+                    stored.insert(origin, name);
+                    Fix::Materialize(origin)
+                };
+                self.fixes.insert(at, fix);
+            }
+        }
+    }
+}
+
+type Items<E> = Box<dyn Stream<Item = flat::TarItem, Error = Error<E>> + Send>;
+
+/// The entry at an index of a layer, and its body, renamed.
+struct Extract<S> {
+    items: S,
+    index: usize,
+    seen: usize,
+    name: Option<Vec<u8>>,
+    copying: bool,
+}
+
+impl<E, S> Stream for Extract<S>
+where
+    E: Debug + Send + Sync + 'static,
+    S: Stream<Item = flat::TarItem, Error = Error<E>>,
+{
+    type Item = flat::TarItem;
+    type Error = Error<E>;
+
+    fn poll(&mut self) -> Result<Async<Option<<Self as Stream>::Item>>, <Self as Stream>::Error> {
+        loop {
+            match try_ready!(self.items.poll()) {
+                Some(flat::TarItem::Entry(mut entry)) => {
+                    if self.copying {
+                        return Ok(Async::Ready(None));
+                    }
+                    self.seen += 1;
+                    if self.seen - 1 == self.index {
+                        self.copying = true;
+                        entry.set_path_bytes(self.name.take().unwrap_or_default());
+                        return Ok(Async::Ready(Some(flat::TarItem::Entry(entry))));
+                    }
+                }
+                Some(chunk) => {
+                    if self.copying {
+                        return Ok(Async::Ready(Some(chunk)));
+                    }
+                }
+                None if self.copying => return Ok(Async::Ready(None)),
+                None => return Err(Error::Format("hardlink target vanished from its layer")),
+            }
+        }
+    }
+}
+
+type Opener<S> = Arc<Mutex<Vec<Box<dyn FnMut() -> S + Send>>>>;
+
+fn open<S>(layers: &Opener<S>, layer: usize) -> Items<S::Error>
+where
+    S: Stream<Item = Bytes> + Send + 'static,
+    S::Error: Debug + Send + Sync + 'static,
+{
+    Box::new(flat::decode_tar((layers.lock().unwrap()[layer])()))
+}
+
+/// The squashed items of one layer.
+fn emit<S>(layers: Opener<S>, layer: usize, plan: Arc<Mutex<Plan>>) -> Items<S::Error>
+where
+    S: Stream<Item = Bytes> + Send + 'static,
+    S::Error: Debug + Send + Sync + 'static,
+{
+    let mut index = 0;
+    let mut copying = false;
+    let items = open(&layers, layer);
+    Box::new(
+        items
+            .map(move |item| -> Items<S::Error> {
+                let entry = match item {
+                    flat::TarItem::Chunk(chunk) => {
+                        return match copying {
+                            true => Box::new(stream::once(Ok(flat::TarItem::Chunk(chunk)))),
+                            false => Box::new(stream::empty()),
+                        }
+                    }
+                    flat::TarItem::Entry(entry) => entry,
+                };
+                let at = (layer, index);
+                index += 1;
+                copying = false;
+                let mut plan = plan.lock().unwrap();
+                let path = match plan.scanned(at) {
+                    Scanned::Entry { path, .. } if entry.entry_type().is_dir() => path.clone(),
+                    Scanned::Entry { visible: true, .. } => {
+                        return match plan.fixes.get(&at) {
+                            Some(Fix::Materialize(origin)) => Box::new(Extract {
+                                items: open(&layers, origin.0),
+                                index: origin.1,
+                                seen: 0,
+                                name: Some(entry.path_bytes().to_vec()),
+                                copying: false,
+                            }),
+                            Some(Fix::Relink(name)) => {
+                                let mut entry = entry;
+                                entry.set_link_bytes(Some(name.clone()));
+                                Box::new(stream::once(Ok(flat::TarItem::Entry(entry))))
+                            }
+                            None => {
+                                copying = true;
+                                Box::new(stream::once(Ok(flat::TarItem::Entry(entry))))
+                            }
+                        };
+                    }
+                    _ => return Box::new(stream::empty()),
+                };
+                // Directories take the place of their lowest occurrence.
+                match plan.dirs.get_mut(&path).and_then(Option::take) {
+                    Some(dir) => Box::new(stream::once(Ok(flat::TarItem::Entry(dir)))),
+                    None => Box::new(stream::empty()),
+                }
+            })
+            .flatten(),
+    )
+}
+
+/// Flattens layers, given bottom first, into the items of their final
+/// filesystem state.
+///
+/// Every layer is opened twice: once top first to learn which paths the
+/// upper layers hide, keeping only paths in memory, and then bottom first to
+/// emit what is left, so that directories come before their contents and
+/// hardlinks after their targets. A directory is emitted where it first
+/// appears, with the metadata of its topmost entry. A hardlink whose target
+/// an upper layer replaced or removed is stored as a copy of the file it
+/// was linked to, which takes a third read of that file's layer.
+///
+/// Layers are therefore given as functions that open them anew, rather than
+/// as streams: whether an entry of a lower layer survives is only known once
+/// every layer above it has been read, and a single pass would have to hold
+/// the lower layers in memory or on disk until then.
+pub fn squash_items<F, S>(
+    layers: Vec<F>,
+) -> impl Stream<Item = flat::TarItem, Error = Error<S::Error>>
+where
+    F: FnMut() -> S + Send + 'static,
+    S: Stream<Item = Bytes> + Send + 'static,
+    S::Error: Debug + Send + Sync + 'static,
+{
+    let count = layers.len();
+    let layers: Opener<S> = Arc::new(Mutex::new(
+        layers
+            .into_iter()
+            .map(|open| Box::new(open) as Box<dyn FnMut() -> S + Send>)
+            .collect(),
+    ));
+    let plan = Plan {
+        layers: (0..count).map(|_| Vec::new()).collect(),
+        ..Plan::default()
+    };
+
+    let scanning = layers.clone();
+    stream::iter_ok((0..count).rev())
+        .fold(plan, move |plan, layer| {
+            open(&scanning, layer)
+                .fold(plan, move |mut plan, item| {
+                    if let flat::TarItem::Entry(ref entry) = item {
+                        plan.scan_entry(layer, entry).map_err(Error::IoError)?;
+                    }
+                    Ok::<_, Error<S::Error>>(plan)
+                })
+                .map(|mut plan| {
+                    plan.end_layer();
+                    plan
+                })
+        })
+        .map(move |mut plan| {
+            plan.fix_links();
+            let plan = Arc::new(Mutex::new(plan));
+            stream::iter_ok::<_, Error<S::Error>>(0..count)
+                .map(move |layer| emit(layers.clone(), layer, plan.clone()))
+                .flatten()
+        })
+        .flatten_stream()
+}
+
+/// Like [`squash_items`], encoded into a single tar stream.
+pub fn squash<F, S>(layers: Vec<F>) -> impl Stream<Item = Bytes, Error = Error<S::Error>>
+where
+    F: FnMut() -> S + Send + 'static,
+    S: Stream<Item = Bytes> + Send + 'static,
+    S::Error: Debug + Send + Sync + 'static,
+{
+    encode::flat::encode_tar(squash_items(layers))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_whiteout() {
+        assert_eq!(
+            whiteout(Path::new("a/b/.wh.c")),
+            Some(Whiteout::Path(PathBuf::from("a/b/c")))
+        );
+        assert_eq!(
+            whiteout(Path::new("a/.wh..wh..opq")),
+            Some(Whiteout::Opaque(PathBuf::from("a")))
+        );
+        assert_eq!(whiteout(Path::new("a/.wh.")), None);
+        assert_eq!(whiteout(Path::new("a/b")), None);
+    }
+
+    fn layer(files: &[(&str, &[u8])]) -> Bytes {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, data) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_uid(0);
+            header.set_gid(0);
+            header.set_mtime(0);
+            builder.append_data(&mut header, path, *data).unwrap();
+        }
+        Bytes::from(builder.into_inner().unwrap())
+    }
+
+    #[test]
+    fn test_apply() {
+        let dst = std::env::temp_dir().join(format!("tar-async-layer-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dst);
+        for path in &["keep", "gone", "opq/old", "opq/sub/old"] {
+            let path = dst.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, b"lower").unwrap();
+        }
+
+        let upper = layer(&[
+            ("opq/new", b"upper"),
+            ("opq/.wh..wh..opq", b""),
+            (".wh.gone", b""),
+            ("keep", b"upper"),
+        ]);
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime
+            .block_on(apply(
+                decode_layer(stream::once::<_, ()>(Ok(upper))),
+                dst.clone(),
+                Config::default(),
+            ))
+            .unwrap();
+
+        assert_eq!(fs::read(dst.join("keep")).unwrap(), b"upper");
+        assert_eq!(fs::read(dst.join("opq/new")).unwrap(), b"upper");
+        assert!(!dst.join("gone").exists());
+        assert!(!dst.join("opq/old").exists());
+        assert!(!dst.join("opq/sub").exists());
+        fs::remove_dir_all(&dst).unwrap();
+    }
+
+    #[test]
+    fn test_apply_escaping_entry() {
+        let dst = std::env::temp_dir().join(format!("tar-async-escape-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dst);
+        fs::create_dir_all(&dst).unwrap();
+        fs::write(dst.join("keep"), b"lower").unwrap();
+
+        // tar::Builder refuses `..`, so the name is written directly.
+        let mut header = tar::Header::new_gnu();
+        header.as_gnu_mut().unwrap().name[..4].copy_from_slice(b"../x");
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_size(1);
+        header.set_mode(0o644);
+        header.set_cksum();
+        let mut builder = tar::Builder::new(Vec::new());
+        builder.append(&header, &b"x"[..]).unwrap();
+        let upper = Bytes::from(builder.into_inner().unwrap());
+
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        assert!(runtime
+            .block_on(apply(
+                decode_layer(stream::once::<_, ()>(Ok(upper))),
+                dst.clone(),
+                Config::default(),
+            ))
+            .is_err());
+        assert_eq!(fs::read(dst.join("keep")).unwrap(), b"lower");
+        fs::remove_dir_all(&dst).unwrap();
+    }
+
+    fn opener(layer: Bytes) -> impl FnMut() -> stream::Once<Bytes, ()> {
+        move || stream::once(Ok(layer.clone()))
+    }
+
+    #[test]
+    fn test_squash() {
+        let lower = layer(&[
+            ("a/old", b"lower"),
+            ("b/file", b"lower"),
+            ("c/file", b"lower"),
+            ("keep", b"lower"),
+            ("same", b"lower"),
+        ]);
+        let upper = layer(&[
+            ("a/.wh..wh..opq", b""),
+            ("a/new", b"upper"),
+            (".wh.b", b""),
+            ("c", b"upper"),
+            ("same", b"upper"),
+        ]);
+        let squashed = squash(vec![opener(lower), opener(upper)])
+            .concat2()
+            .wait()
+            .unwrap();
+
+        let mut archive = tar::Archive::new(squashed.as_ref());
+        let mut files: Vec<(String, String)> = archive
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let mut entry = entry.unwrap();
+                let mut body = String::new();
+                std::io::Read::read_to_string(&mut entry, &mut body).unwrap();
+                (entry.path().unwrap().display().to_string(), body)
+            })
+            .collect();
+        files.sort();
+        let expected: Vec<(String, String)> = vec![
+            ("a/new", "upper"),
+            ("c", "upper"),
+            ("keep", "lower"),
+            ("same", "upper"),
+        ]
+        .into_iter()
+        .map(|(p, b)| (p.to_string(), b.to_string()))
+        .collect();
+        assert_eq!(files, expected);
+    }
+
+    /// Layer of directories, files with their bodies and hardlinks with
+    /// their targets.
+    fn linked_layer(entries: &[(&str, tar::EntryType, &str)]) -> Bytes {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (path, entry_type, data) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(*entry_type);
+            header.set_mode(0o644);
+            header.set_uid(0);
+            header.set_gid(0);
+            header.set_mtime(0);
+            if entry_type.is_hard_link() {
+                header.set_size(0);
+                builder.append_link(&mut header, path, data).unwrap();
+            } else {
+                header.set_size(data.len() as u64);
+                builder
+                    .append_data(&mut header, path, data.as_bytes())
+                    .unwrap();
+            }
+        }
+        Bytes::from(builder.into_inner().unwrap())
+    }
+
+    #[test]
+    fn test_squash_hardlinks() {
+        use tar::EntryType::{Directory, Link, Regular};
+
+        let bottom = linked_layer(&[
+            ("d/", Directory, ""),
+            ("d/a", Regular, "A"),
+            ("d/b", Regular, "B"),
+        ]);
+        let middle = linked_layer(&[
+            ("l1", Link, "d/a"),
+            ("l2", Link, "d/a"),
+            ("l3", Link, "d/b"),
+            ("d/c", Regular, "C"),
+        ]);
+        let top = linked_layer(&[
+            ("d/a", Regular, "new"),
+            ("d/.wh.b", Regular, ""),
+            ("d/", Directory, ""),
+        ]);
+        let squashed = squash(vec![opener(bottom), opener(middle), opener(top)])
+            .concat2()
+            .wait()
+            .unwrap();
+
+        let mut archive = tar::Archive::new(squashed.as_ref());
+        let entries: Vec<(String, tar::EntryType, String)> = archive
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let mut entry = entry.unwrap();
+                let mut body = String::new();
+                std::io::Read::read_to_string(&mut entry, &mut body).unwrap();
+                let link = entry.link_name().unwrap().map(|l| l.display().to_string());
+                (
+                    entry.path().unwrap().display().to_string(),
+                    entry.header().entry_type(),
+                    link.unwrap_or(body),
+                )
+            })
+            .collect();
+        let expected: Vec<(String, tar::EntryType, String)> = vec![
+            ("d/", Directory, ""),
+            // The files the links shared were replaced and removed above.
+            ("l1", Regular, "A"),
+            ("l2", Link, "l1"),
+            ("l3", Regular, "B"),
+            ("d/c", Regular, "C"),
+            ("d/a", Regular, "new"),
+        ]
+        .into_iter()
+        .map(|(p, t, b)| (p.to_string(), t, b.to_string()))
+        .collect();
+        assert_eq!(entries, expected);
+    }
+}
@@ -1,13 +1,97 @@
+// `failure_derive` expands to impls nested in constants.
+#![allow(non_local_definitions)]
+
+pub mod append;
+pub mod compare;
+pub mod compression;
+pub mod create;
+pub mod deb;
 pub mod decode;
+pub mod diff;
+pub mod digest;
 pub mod encode;
+pub mod entry;
+#[cfg(all(feature = "gzip", feature = "json", feature = "sha2"))]
+pub mod estargz;
+#[cfg(feature = "json")]
+pub mod image;
+pub mod incremental;
+pub mod layer;
+#[cfg(feature = "json")]
+pub mod manifest;
+#[cfg(all(feature = "zstd", feature = "json"))]
+pub mod seekable;
+pub mod source;
+pub mod transform;
+pub mod unpack;
+pub mod volume;
 
+mod blocking;
 mod error;
 
 pub use self::error::Error;
 
+#[derive(Clone, Copy, Debug)]
 pub struct Config {
     unpack_xattrs: bool,
     preserve_permissions: bool,
     preserve_mtime: bool,
     ignore_zeros: bool,
 }
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            unpack_xattrs: false,
+            preserve_permissions: true,
+            preserve_mtime: true,
+            ignore_zeros: false,
+        }
+    }
+}
+
+impl Config {
+    #[inline]
+    pub fn unpack_xattrs(&self) -> bool {
+        self.unpack_xattrs
+    }
+
+    #[inline]
+    pub fn set_unpack_xattrs(&mut self, unpack_xattrs: bool) -> &mut Self {
+        self.unpack_xattrs = unpack_xattrs;
+        self
+    }
+
+    #[inline]
+    pub fn preserve_permissions(&self) -> bool {
+        self.preserve_permissions
+    }
+
+    #[inline]
+    pub fn set_preserve_permissions(&mut self, preserve_permissions: bool) -> &mut Self {
+        self.preserve_permissions = preserve_permissions;
+        self
+    }
+
+    #[inline]
+    pub fn preserve_mtime(&self) -> bool {
+        self.preserve_mtime
+    }
+
+    #[inline]
+    pub fn set_preserve_mtime(&mut self, preserve_mtime: bool) -> &mut Self {
+        self.preserve_mtime = preserve_mtime;
+        self
+    }
+
+    #[inline]
+    pub fn ignore_zeros(&self) -> bool {
+        self.ignore_zeros
+    }
+
+    #[inline]
+    pub fn set_ignore_zeros(&mut self, ignore_zeros: bool) -> &mut Self {
+        self.ignore_zeros = ignore_zeros;
+        self
+    }
+}
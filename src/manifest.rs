@@ -0,0 +1,361 @@
+//! Machine-readable listings of archives, one record per entry.
+//!
+//! [`records`] and [`records_with_digests`] turn a flat item stream into
+//! [`Record`]s; [`json_lines`] and [`mtree::encode`] write them out as JSON
+//! Lines or as a BSD `mtree` specification.
+//!
+//! Paths, link targets, user and group names and extended attribute names
+//! are kept as bytes. In JSON they are strings as written by [`escape`]:
+//! UTF-8 as is, `\` doubled and any other byte as `\x` and two hex digits.
+
+pub mod mtree;
+
+use crate::decode::flat::{TarEntry, TarItem};
+use crate::decode::hardlink::normalize_path;
+use crate::decode::Error;
+use crate::diff;
+use crate::digest::{Digest, Hasher};
+use crate::incremental;
+use bytes::Bytes;
+use futures::prelude::*;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// One entry of a manifest.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Record {
+    /// Path without leading `./` or trailing `/`; empty for the root.
+    #[serde(with = "escaped")]
+    pub path: Vec<u8>,
+    /// `file`, `dir`, `link` (symbolic), `hardlink`, `char`, `block` or
+    /// `fifo`, as `mtree` spells them.
+    #[serde(rename = "type")]
+    pub entry_type: String,
+    pub size: u64,
+    pub mode: u32,
+    pub uid: u64,
+    pub gid: u64,
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "escaped::option"
+    )]
+    pub uname: Option<Vec<u8>>,
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "escaped::option"
+    )]
+    pub gname: Option<Vec<u8>>,
+    /// Seconds since the epoch, with nine decimals, e.g. `1546300800.000000000`.
+    pub mtime: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub atime: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ctime: Option<String>,
+    /// Symlink or hardlink target.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "escaped::option"
+    )]
+    pub link: Option<Vec<u8>>,
+    /// Major and minor number of a device.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device: Option<(u32, u32)>,
+    /// Extended attribute values, in hex.
+    #[serde(
+        default,
+        skip_serializing_if = "BTreeMap::is_empty",
+        with = "escaped::keys"
+    )]
+    pub xattrs: BTreeMap<Vec<u8>, String>,
+    /// Digest of the body, e.g. `sha256:e3b0c442...`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub digest: Option<String>,
+}
+
+/// Record type of an entry; `None` for types that are not files.
+pub fn entry_type_name(entry: &TarEntry) -> Option<&'static str> {
+    let entry_type = entry.entry_type();
+    Some(if entry_type.is_file() {
+        "file"
+    } else if entry_type.is_dir() || incremental::is_dumpdir(entry) {
+        "dir"
+    } else if entry_type.is_symlink() {
+        "link"
+    } else if entry_type.is_hard_link() {
+        "hardlink"
+    } else if entry_type.is_character_special() {
+        "char"
+    } else if entry_type.is_block_special() {
+        "block"
+    } else if entry_type.is_fifo() {
+        "fifo"
+    } else {
+        return None;
+    })
+}
+
+/// Formats a time as seconds since the epoch with nine decimals.
+pub fn timestamp(time: SystemTime) -> String {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(after) => format!("{}.{:09}", after.as_secs(), after.subsec_nanos()),
+        Err(before) => {
+            let before = before.duration();
+            format!("-{}.{:09}", before.as_secs(), before.subsec_nanos())
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Writes bytes as a string: UTF-8 as is, `\` as `\\` and any other byte
+/// as `\x` and two hex digits.
+pub fn escape(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len());
+    for chunk in bytes.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            if c == '\\' {
+                out.push_str("\\\\");
+            } else {
+                out.push(c);
+            }
+        }
+        for b in chunk.invalid() {
+            out.push_str(&format!("\\x{:02x}", b));
+        }
+    }
+    out
+}
+
+/// Reverses [`escape`]; `None` for any other use of `\`.
+pub fn unescape(s: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(s.len());
+    let mut rest = s.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        rest = tail;
+        if b != b'\\' {
+            out.push(b);
+            continue;
+        }
+        match rest.split_first() {
+            Some((b'\\', tail)) => {
+                out.push(b'\\');
+                rest = tail;
+            }
+            Some((b'x', tail)) if tail.len() >= 2 => {
+                let digits = std::str::from_utf8(&tail[..2]).ok()?;
+                out.push(u8::from_str_radix(digits, 16).ok()?);
+                rest = &tail[2..];
+            }
+            _ => return None,
+        }
+    }
+    Some(out)
+}
+
+/// Serde adapters for byte strings written with [`escape`].
+pub(crate) mod escaped {
+    use super::*;
+
+    fn decode<E: serde::de::Error>(s: &str) -> Result<Vec<u8>, E> {
+        unescape(s).ok_or_else(|| E::custom(format!("invalid escape in {:?}", s)))
+    }
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&escape(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        decode(&String::deserialize(deserializer)?)
+    }
+
+    pub mod option {
+        use super::*;
+
+        pub fn serialize<S: Serializer>(
+            bytes: &Option<Vec<u8>>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            bytes.as_deref().map(escape).serialize(serializer)
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Option<Vec<u8>>, D::Error> {
+            Option::<String>::deserialize(deserializer)?
+                .map(|s| decode(&s))
+                .transpose()
+        }
+    }
+
+    pub mod keys {
+        use super::*;
+
+        pub fn serialize<S: Serializer>(
+            map: &BTreeMap<Vec<u8>, String>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            serializer.collect_map(map.iter().map(|(key, value)| (escape(key), value)))
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<BTreeMap<Vec<u8>, String>, D::Error> {
+            BTreeMap::<String, String>::deserialize(deserializer)?
+                .into_iter()
+                .map(|(key, value)| Ok((decode(&key)?, value)))
+                .collect()
+        }
+    }
+}
+
+impl Record {
+    /// Describes `entry`; `None` for entry types that are not files.
+    pub fn new(entry: &TarEntry, digest: Option<&Digest>) -> Option<Record> {
+        Some(Record {
+            path: normalize_path(entry.path_bytes()).to_vec(),
+            entry_type: entry_type_name(entry)?.to_string(),
+            size: entry.size(),
+            mode: entry.mode() & 0o7777,
+            uid: entry.uid(),
+            gid: entry.gid(),
+            uname: entry.uname().filter(|n| !n.is_empty()).map(<[u8]>::to_vec),
+            gname: entry.gname().filter(|n| !n.is_empty()).map(<[u8]>::to_vec),
+            mtime: timestamp(entry.mtime()),
+            atime: entry.atime().map(timestamp),
+            ctime: entry.ctime().map(timestamp),
+            link: entry.link_bytes().map(<[u8]>::to_vec),
+            device: entry.device(),
+            xattrs: entry
+                .xattrs()
+                .iter()
+                .map(|(name, value)| (name.clone(), hex(value)))
+                .collect(),
+            digest: digest.map(Digest::to_string),
+        })
+    }
+}
+
+/// One record per entry of a flat item stream, without digests.
+pub fn records<E, S>(items: S) -> impl Stream<Item = Record, Error = Error<E>>
+where
+    E: Debug + Send + Sync + 'static,
+    S: Stream<Item = TarItem, Error = Error<E>>,
+{
+    items.filter_map(|item| match item {
+        TarItem::Entry(entry) => Record::new(&entry, None),
+        TarItem::Chunk(_) => None,
+    })
+}
+
+/// Like [`records`], with the digest of every body, computed with `hasher`
+/// as it streams by.
+pub fn records_with_digests<E, S, H>(
+    items: S,
+    hasher: H,
+) -> impl Stream<Item = Record, Error = Error<E>>
+where
+    E: Debug + Send + Sync + 'static,
+    S: Stream<Item = TarItem, Error = Error<E>>,
+    H: Hasher,
+{
+    diff::summarize(items, hasher)
+        .filter_map(|summary| Record::new(summary.entry(), summary.digest()))
+}
+
+/// Writes records as JSON Lines, one object per line.
+pub fn json_lines<E, S>(records: S) -> impl Stream<Item = Bytes, Error = Error<E>>
+where
+    E: Debug + Send + Sync + 'static,
+    S: Stream<Item = Record, Error = Error<E>>,
+{
+    records.and_then(|record| {
+        let mut line =
+            serde_json::to_vec(&record).map_err(|e| Error::IoError(std::io::Error::other(e)))?;
+        line.push(b'\n');
+        Ok(Bytes::from(line))
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    #[cfg(feature = "sha2")]
+    fn test_json_lines() {
+        use crate::digest::Sha256;
+        use futures::stream;
+        use std::time::Duration;
+
+        let mut dir = TarEntry::new(tar::EntryType::Directory, "./dir/");
+        dir.set_mtime(UNIX_EPOCH + Duration::new(1_546_300_800, 5))
+            .set_uname(Some(b"root".to_vec()));
+        let mut file = TarEntry::new(tar::EntryType::Regular, "dir/file");
+        file.set_size(2)
+            .set_xattrs(vec![(b"user.tag".to_vec(), b"\x00a".to_vec())]);
+        let items = || {
+            vec![
+                TarItem::Entry(dir.clone()),
+                TarItem::Entry(file.clone()),
+                TarItem::Chunk(Bytes::from_static(b"hi")),
+            ]
+        };
+
+        let json = json_lines(records_with_digests(
+            stream::iter_ok::<_, Error<()>>(items()),
+            Sha256::new(),
+        ))
+        .concat2()
+        .wait()
+        .unwrap();
+        let lines: Vec<Record> = json
+            .split(|b| *b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0].path, b"dir");
+        assert_eq!(lines[0].entry_type, "dir");
+        assert_eq!(lines[0].mtime, "1546300800.000000005");
+        assert_eq!(lines[0].uname.as_deref(), Some(&b"root"[..]));
+        assert_eq!(lines[0].digest, None);
+        assert_eq!(lines[1].xattrs[&b"user.tag"[..]], "0061");
+        assert_eq!(
+            lines[1].digest.as_deref(),
+            Some("sha256:8f434346648f6b96df89dda901c5176b10a6d83961dd3c1ac88b59b2dc327aa4")
+        );
+
+        let plain: Vec<_> = records(stream::iter_ok::<_, Error<()>>(items()))
+            .collect()
+            .wait()
+            .unwrap();
+        assert_eq!(plain[1].digest, None);
+        assert_eq!(plain[1].size, 2);
+    }
+
+    #[test]
+    fn test_escape() {
+        let mut link = TarEntry::new(tar::EntryType::Symlink, &b"caf\xc3\xa9\\\xff"[..]);
+        link.set_link_bytes(Some(b"\xfe".to_vec()))
+            .set_uname(Some(b"\x80".to_vec()))
+            .set_xattrs(vec![(b"user.\xff".to_vec(), b"x".to_vec())]);
+        let record = Record::new(&link, None).unwrap();
+        assert_eq!(escape(&record.path), "caf\u{e9}\\\\\\xff");
+
+        let json = serde_json::to_string(&record).unwrap();
+        assert!(json.contains(r#""path":"café\\\\\\xff""#));
+        assert!(json.contains(r#""link":"\\xfe""#));
+        assert!(json.contains(r#""user.\\xff":"78""#));
+        assert_eq!(serde_json::from_str::<Record>(&json).unwrap(), record);
+
+        assert_eq!(unescape("\\x"), None);
+        assert_eq!(unescape("\\q"), None);
+    }
+}
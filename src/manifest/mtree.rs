@@ -0,0 +1,451 @@
+//! BSD `mtree` specifications, as written by `mtree -c` and `bsdtar`.
+//!
+//! Every record becomes one line of full path and keywords, e.g.
+//! `./dir/file type=file mode=0644 size=2 time=1546300800.000000000`.
+//! Paths and names are encoded with `vis(3)` octal escapes. `mtree` has no
+//! keywords for hardlinks or extended attributes: hardlinks are written as
+//! plain files without a size, and extended attributes are left out.
+//!
+//! [`parse`] reads specifications back, in full-path form as above or in
+//! the relative form of `mtree -c`, with `/set` and `/unset` defaults, for
+//! [`create_from_mtree`](crate::create::mtree::create_from_mtree).
+
+use super::Record;
+use crate::decode::flat::{bytes2path, TarEntry};
+use crate::decode::Error;
+use bytes::Bytes;
+use futures::{prelude::*, stream};
+use std::collections::HashMap;
+use std::fmt::{Debug, Write};
+use std::io;
+use std::path::PathBuf;
+use std::time::{Duration, UNIX_EPOCH};
+
+/// First line of a specification.
+pub const HEADER: &str = "#mtree\n";
+
+/// Escapes whitespace, `\`, `#`, `=` and anything outside printable ASCII
+/// as `\` and three octal digits.
+pub fn escape(s: &[u8]) -> String {
+    let mut out = String::with_capacity(s.len());
+    for &b in s {
+        if b <= b' ' || b >= 0x7f || b == b'\\' || b == b'#' || b == b'=' {
+            write!(out, "\\{:03o}", b).unwrap();
+        } else {
+            out.push(b as char);
+        }
+    }
+    out
+}
+
+/// The line for `record`, with its newline.
+pub fn line(record: &Record) -> String {
+    let mut out = if record.path.is_empty() {
+        ".".to_string()
+    } else {
+        format!("./{}", escape(&record.path))
+    };
+    let hardlink = record.entry_type == "hardlink";
+    let entry_type = if hardlink { "file" } else { &record.entry_type };
+    write!(out, " type={}", entry_type).unwrap();
+    if let Some(uname) = &record.uname {
+        write!(out, " uname={}", escape(uname)).unwrap();
+    }
+    if let Some(gname) = &record.gname {
+        write!(out, " gname={}", escape(gname)).unwrap();
+    }
+    write!(
+        out,
+        " uid={} gid={} mode={:04o}",
+        record.uid, record.gid, record.mode
+    )
+    .unwrap();
+    if record.entry_type == "file" {
+        write!(out, " size={}", record.size).unwrap();
+    }
+    write!(out, " time={}", record.mtime).unwrap();
+    if let (false, Some(link)) = (hardlink, &record.link) {
+        write!(out, " link={}", escape(link)).unwrap();
+    }
+    if let Some((major, minor)) = record.device {
+        write!(out, " device=native,{},{}", major, minor).unwrap();
+    }
+    // `sha256:...` becomes `sha256digest=...`.
+    if let Some((algorithm, hex)) = record.digest.as_ref().and_then(|d| d.split_once(':')) {
+        write!(out, " {}digest={}", algorithm, hex).unwrap();
+    }
+    out.push('\n');
+    out
+}
+
+/// Writes records as an `mtree` specification.
+pub fn encode<E, S>(records: S) -> impl Stream<Item = Bytes, Error = Error<E>>
+where
+    E: Debug + Send + Sync + 'static,
+    S: Stream<Item = Record, Error = Error<E>>,
+{
+    stream::once(Ok(Bytes::from_static(HEADER.as_bytes())))
+        .chain(records.map(|record| Bytes::from(line(&record))))
+}
+
+/// One entry of a parsed specification.
+#[derive(Clone, Debug)]
+pub struct SpecEntry {
+    /// Everything the keywords say, with the size of files left at 0.
+    pub entry: TarEntry,
+    /// File holding the body, from the `contents` keyword.
+    pub contents: Option<PathBuf>,
+    /// Length of the body, from the `size` keyword.
+    pub size: Option<u64>,
+}
+
+/// Reverses [`escape`], and the C-style escapes of `vis -c`.
+pub fn unescape(s: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(s.len());
+    let mut i = 0;
+    while i < s.len() {
+        if s[i] != b'\\' || i + 1 == s.len() {
+            out.push(s[i]);
+            i += 1;
+            continue;
+        }
+        let digits = &s[i + 1..s.len().min(i + 4)];
+        if digits.len() == 3 && digits.iter().all(|d| (b'0'..=b'7').contains(d)) {
+            out.push(digits.iter().fold(0u8, |n, d| (n << 3) | (d - b'0')));
+            i += 4;
+            continue;
+        }
+        out.push(match s[i + 1] {
+            b'a' => 0x07,
+            b'b' => 0x08,
+            b'f' => 0x0c,
+            b'n' => b'\n',
+            b'r' => b'\r',
+            b's' => b' ',
+            b't' => b'\t',
+            b'v' => 0x0b,
+            other => other,
+        });
+        i += 2;
+    }
+    out
+}
+
+fn invalid(line: usize, message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("mtree line {}: {}", line, message),
+    )
+}
+
+/// Keyword values of one entry, defaults included.
+struct Keywords<'a> {
+    values: HashMap<&'a [u8], &'a [u8]>,
+    line: usize,
+}
+
+impl<'a> Keywords<'a> {
+    fn get(&self, keyword: &str) -> Option<&'a [u8]> {
+        self.values.get(keyword.as_bytes()).cloned()
+    }
+
+    fn text(&self, keyword: &str) -> io::Result<Option<&'a str>> {
+        self.get(keyword)
+            .map(|value| {
+                std::str::from_utf8(value).map_err(|_| invalid(self.line, "invalid value"))
+            })
+            .transpose()
+    }
+
+    fn number(&self, keyword: &str, radix: u32) -> io::Result<Option<u64>> {
+        self.text(keyword)?
+            .map(|value| {
+                u64::from_str_radix(value, radix).map_err(|_| invalid(self.line, "invalid number"))
+            })
+            .transpose()
+    }
+
+    fn entry(&self, path: Vec<u8>) -> io::Result<Option<SpecEntry>> {
+        let line = self.line;
+        let entry_type = match self.get("type").unwrap_or(b"file") {
+            b"file" => tar::EntryType::Regular,
+            b"dir" => tar::EntryType::Directory,
+            b"link" => tar::EntryType::Symlink,
+            b"char" => tar::EntryType::Char,
+            b"block" => tar::EntryType::Block,
+            b"fifo" => tar::EntryType::Fifo,
+            // Sockets cannot be archived.
+            b"socket" => return Ok(None),
+            _ => return Err(invalid(line, "unknown type")),
+        };
+        let path = match (path.is_empty(), entry_type.is_dir()) {
+            (true, _) => b"./".to_vec(),
+            (false, true) => [&path[..], b"/"].concat(),
+            (false, false) => path,
+        };
+        let mut entry = TarEntry::new(entry_type, path);
+        if let Some(mode) = self.number("mode", 8)? {
+            entry.set_mode(mode as u32 & 0o7777);
+        }
+        if let Some(uid) = self.number("uid", 10)? {
+            entry.set_uid(uid);
+        }
+        if let Some(gid) = self.number("gid", 10)? {
+            entry.set_gid(gid);
+        }
+        entry
+            .set_uname(self.get("uname").map(unescape))
+            .set_gname(self.get("gname").map(unescape));
+        if let Some(time) = self.text("time")? {
+            let (secs, nanos) = time.split_once('.').unwrap_or((time, "0"));
+            match (secs.parse::<u64>(), nanos.parse::<u32>()) {
+                (Ok(secs), Ok(nanos)) if nanos < 1_000_000_000 => {
+                    entry.set_mtime(UNIX_EPOCH + Duration::new(secs, nanos));
+                }
+                _ => return Err(invalid(line, "invalid time")),
+            }
+        }
+        if entry_type.is_symlink() {
+            let link = self
+                .get("link")
+                .ok_or_else(|| invalid(line, "symlink without link"))?;
+            entry.set_link_bytes(Some(unescape(link)));
+        }
+        if entry_type.is_character_special() || entry_type.is_block_special() {
+            // `format,major,minor`, as written by `mtree -c`.
+            let device = self
+                .text("device")?
+                .ok_or_else(|| invalid(line, "device without numbers"))?;
+            let fields: Vec<_> = device.split(',').collect();
+            match (fields.len(), fields.get(1), fields.get(2)) {
+                (3, Some(major), Some(minor)) => match (major.parse(), minor.parse()) {
+                    (Ok(major), Ok(minor)) => entry.set_device(Some((major, minor))),
+                    _ => return Err(invalid(line, "invalid device")),
+                },
+                _ => return Err(invalid(line, "unsupported device format")),
+            };
+        }
+        let contents = self
+            .get("contents")
+            .or_else(|| self.get("content"))
+            .map(|contents| bytes2path(&unescape(contents)).map(PathBuf::from))
+            .transpose()?;
+        Ok(Some(SpecEntry {
+            entry,
+            contents,
+            size: self.number("size", 10)?,
+        }))
+    }
+}
+
+fn keyword(word: &[u8]) -> Option<(&[u8], &[u8])> {
+    let eq = word.iter().position(|b| *b == b'=')?;
+    Some((&word[..eq], &word[eq + 1..]))
+}
+
+/// Parses a specification into its entries, in order.
+///
+/// Entries get the defaults of [`TarEntry::new`] for whatever the keywords
+/// leave out. Keywords without a tar counterpart (`nlink`, `flags`,
+/// digests and the like) are ignored.
+pub fn parse(spec: &[u8]) -> io::Result<Vec<SpecEntry>> {
+    let mut lines = Vec::new();
+    let mut continued: Option<(usize, Vec<u8>)> = None;
+    for (number, line) in spec.split(|b| *b == b'\n').enumerate() {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let (number, mut joined) = continued.take().unwrap_or((number + 1, Vec::new()));
+        match line.strip_suffix(b"\\") {
+            Some(head) => {
+                joined.extend_from_slice(head);
+                joined.push(b' ');
+                continued = Some((number, joined));
+            }
+            None => {
+                joined.extend_from_slice(line);
+                lines.push((number, joined));
+            }
+        }
+    }
+    lines.extend(continued);
+
+    let mut defaults: HashMap<Vec<u8>, Vec<u8>> = HashMap::new();
+    // Directory of relative entries.
+    let mut cwd: Vec<Vec<u8>> = Vec::new();
+    let mut entries = Vec::new();
+    for (number, line) in &lines {
+        let words: Vec<&[u8]> = line
+            .split(|b| *b == b' ' || *b == b'\t')
+            .filter(|word| !word.is_empty())
+            .collect();
+        let (name, rest) = match words.split_first() {
+            Some((name, _)) if name.starts_with(b"#") => continue,
+            Some((name, rest)) => (*name, rest),
+            None => continue,
+        };
+        match name {
+            b"/set" => {
+                for (key, value) in rest.iter().filter_map(|word| keyword(word)) {
+                    defaults.insert(key.to_vec(), value.to_vec());
+                }
+                continue;
+            }
+            b"/unset" => {
+                for key in rest {
+                    if *key == b"all" {
+                        defaults.clear();
+                    } else {
+                        defaults.remove(*key);
+                    }
+                }
+                continue;
+            }
+            b".." => {
+                cwd.pop();
+                continue;
+            }
+            _ => (),
+        }
+
+        let mut values: HashMap<&[u8], &[u8]> = defaults
+            .iter()
+            .map(|(key, value)| (&key[..], &value[..]))
+            .collect();
+        values.extend(rest.iter().filter_map(|word| keyword(word)));
+        let keywords = Keywords {
+            values,
+            line: *number,
+        };
+
+        let name = unescape(name);
+        let relative = !name.contains(&b'/');
+        let mut components = if relative { cwd.clone() } else { Vec::new() };
+        components.extend(
+            name.split(|b| *b == b'/')
+                .filter(|part| !part.is_empty() && *part != b".")
+                .map(<[u8]>::to_vec),
+        );
+        if components.iter().any(|part| part == b"..") {
+            return Err(invalid(*number, "path outside the root"));
+        }
+        let path = components.join(&b'/');
+        if let Some(spec) = keywords.entry(path)? {
+            if relative && spec.entry.entry_type().is_dir() && name != b"." {
+                cwd.push(name);
+            }
+            entries.push(spec);
+        }
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_line() {
+        let mut record = Record {
+            path: b"dir/a file#1".to_vec(),
+            entry_type: "file".to_string(),
+            size: 2,
+            mode: 0o644,
+            uname: Some(b"root".to_vec()),
+            mtime: "1546300800.000000000".to_string(),
+            digest: Some("sha256:abcd".to_string()),
+            ..Record::default()
+        };
+        assert_eq!(
+            line(&record),
+            "./dir/a\\040file\\0431 type=file uname=root uid=0 gid=0 mode=0644 size=2 \
+             time=1546300800.000000000 sha256digest=abcd\n"
+        );
+
+        record.entry_type = "hardlink".to_string();
+        record.link = Some(b"dir/b".to_vec());
+        record.digest = None;
+        record.uname = None;
+        assert_eq!(
+            line(&record),
+            "./dir/a\\040file\\0431 type=file uid=0 gid=0 mode=0644 time=1546300800.000000000\n"
+        );
+
+        let root = Record {
+            entry_type: "dir".to_string(),
+            mode: 0o755,
+            mtime: "0.000000000".to_string(),
+            ..Record::default()
+        };
+        assert_eq!(
+            line(&root),
+            ". type=dir uid=0 gid=0 mode=0755 time=0.000000000\n"
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        let spec = b"#mtree\n\
+            /set type=file uid=0 gid=0 mode=0644 nlink=1\n\
+            . type=dir mode=0755\n\
+            bin type=dir uname=root\n\
+            \x20   sh mode=0755 size=3 \\\n\
+            \x20       contents=build/sh time=1546300800.5\n\
+            ..\n\
+            dev type=dir\n\
+            \x20   null type=char mode=0666 device=native,1,3\n\
+            ..\n\
+            ./etc/my\\040file size=0\n\
+            ./etc/link type=link link=../bin/sh\n";
+        let entries = parse(spec).unwrap();
+        let summary: Vec<_> = entries
+            .iter()
+            .map(|spec| {
+                (
+                    String::from_utf8_lossy(spec.entry.path_bytes()).into_owned(),
+                    spec.entry.entry_type(),
+                    spec.entry.mode(),
+                )
+            })
+            .collect();
+        let entry = |path: &str, entry_type, mode| (path.to_string(), entry_type, mode);
+        assert_eq!(
+            summary,
+            vec![
+                entry("./", tar::EntryType::Directory, 0o755),
+                entry("bin/", tar::EntryType::Directory, 0o644),
+                entry("bin/sh", tar::EntryType::Regular, 0o755),
+                entry("dev/", tar::EntryType::Directory, 0o644),
+                entry("dev/null", tar::EntryType::Char, 0o666),
+                entry("etc/my file", tar::EntryType::Regular, 0o644),
+                entry("etc/link", tar::EntryType::Symlink, 0o644),
+            ]
+        );
+        assert_eq!(entries[1].entry.uname(), Some(&b"root"[..]));
+        assert_eq!(entries[2].size, Some(3));
+        assert_eq!(entries[2].contents, Some(PathBuf::from("build/sh")));
+        assert_eq!(
+            entries[2].entry.mtime(),
+            UNIX_EPOCH + Duration::new(1_546_300_800, 5)
+        );
+        assert_eq!(entries[4].entry.device(), Some((1, 3)));
+        assert_eq!(entries[6].entry.link_bytes(), Some(&b"../bin/sh"[..]));
+
+        // What `line` writes reads back the same.
+        let record = Record::new(&entries[4].entry, None).unwrap();
+        let reparsed = parse(line(&record).as_bytes()).unwrap();
+        assert_eq!(Record::new(&reparsed[0].entry, None), Some(record));
+
+        // So do names that are not UTF-8.
+        let mut link = TarEntry::new(tar::EntryType::Symlink, &b"odd\xff name\\"[..]);
+        link.set_link_bytes(Some(b"to \xfe".to_vec()))
+            .set_uname(Some(b"\x80=".to_vec()))
+            .set_gname(Some(b"g#".to_vec()));
+        let record = Record::new(&link, None).unwrap();
+        let text = line(&record);
+        assert!(text.starts_with("./odd\\377\\040name\\134 type=link uname=\\200\\075 "));
+        let reparsed = parse(text.as_bytes()).unwrap();
+        assert_eq!(Record::new(&reparsed[0].entry, None), Some(record));
+
+        assert!(parse(b"./a type=bogus\n").is_err());
+        assert!(parse(b"./a/../../b\n").is_err());
+    }
+}
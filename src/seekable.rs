@@ -0,0 +1,549 @@
+//! Seekable `.tar.zst`.
+//!
+//! The encoder cuts zstd frames at entry boundaries and appends two
+//! skippable frames: an index from entry paths to frames, and a seek table
+//! in the [zstd seekable format]. Ordinary zstd decoders skip both and see a
+//! plain tarball. With random access, [`open_seekable`] reads the two
+//! trailing frames and then decompresses only the frames of the entries
+//! asked for.
+//!
+//! [zstd seekable format]: https://github.com/facebook/zstd/blob/dev/contrib/seekable_format/zstd_seekable_compression_format.md
+
+use crate::compression::{self, Compression};
+use crate::decode::flat::{self, TarEntry, TarItem};
+use crate::decode::hardlink::normalize_path;
+use crate::decode::raw::RawTarItem;
+use crate::decode::{full, Error};
+use crate::encode::{self, raw::ZEROS};
+use crate::source::RangeSource;
+use bytes::Bytes;
+use futures::future::{self, Either};
+use futures::{prelude::*, try_ready};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::io::{self, Write};
+use std::mem;
+
+const SKIPPABLE_MAGIC: u32 = 0x184D_2A50;
+const SEEK_TABLE_MAGIC: u32 = 0x184D_2A5E;
+const SEEKABLE_MAGIC: u32 = 0x8F92_EAB1;
+const SEEK_TABLE_FOOTER_SIZE: u64 = 9;
+const SKIPPABLE_HEADER_SIZE: u64 = 8;
+/// Larger entries are spread over several frames; sizes in the seek table
+/// are 32 bits.
+const MAX_FRAME_SIZE: u64 = 1 << 30;
+
+#[derive(Clone, Copy, Debug)]
+pub struct SeekableOptions {
+    level: i32,
+    min_frame_size: u64,
+}
+
+impl Default for SeekableOptions {
+    fn default() -> Self {
+        SeekableOptions {
+            level: 3,
+            min_frame_size: 0,
+        }
+    }
+}
+
+impl SeekableOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[inline]
+    pub fn level(&self) -> i32 {
+        self.level
+    }
+
+    #[inline]
+    pub fn set_level(&mut self, level: i32) -> &mut Self {
+        self.level = level;
+        self
+    }
+
+    #[inline]
+    pub fn min_frame_size(&self) -> u64 {
+        self.min_frame_size
+    }
+
+    /// Keeps adding entries to a frame until it holds this many
+    /// uncompressed bytes. Small entries then share frames, which compresses
+    /// better but makes reading one of them decompress its neighbours too.
+    /// The default, 0, gives every entry its own frame.
+    #[inline]
+    pub fn set_min_frame_size(&mut self, min_frame_size: u64) -> &mut Self {
+        self.min_frame_size = min_frame_size;
+        self
+    }
+}
+
+/// Where an entry lives.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct IndexEntry {
+    /// Normalized path, escaped in the index as in [`manifest`](crate::manifest)
+    /// records.
+    #[serde(with = "crate::manifest::escaped")]
+    pub name: Vec<u8>,
+    /// First frame holding the entry.
+    pub frame: u32,
+    /// Offset of the entry's first header in the uncompressed first frame.
+    pub offset: u64,
+    /// Number of frames the entry spans.
+    pub frames: u32,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct Index {
+    entries: Vec<IndexEntry>,
+}
+
+fn skippable_frame(magic: u32, payload: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(payload.len() + SKIPPABLE_HEADER_SIZE as usize);
+    out.extend_from_slice(&magic.to_le_bytes());
+    out.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    out.extend_from_slice(payload);
+    out
+}
+
+struct Encoder<S> {
+    upstream: S,
+    options: SeekableOptions,
+    frame: Option<zstd::stream::write::Encoder<'static, Vec<u8>>>,
+    /// Compressed and uncompressed size of every finished frame.
+    frames: Vec<(u32, u32)>,
+    compressed: u64,
+    uncompressed: u64,
+    padding: usize,
+    remaining: u64,
+    index: Index,
+    done: bool,
+}
+
+impl<S> Encoder<S> {
+    fn new_frame(&self) -> io::Result<zstd::stream::write::Encoder<'static, Vec<u8>>> {
+        zstd::stream::write::Encoder::new(Vec::new(), self.options.level)
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.uncompressed += data.len() as u64;
+        self.frame.as_mut().unwrap().write_all(data)
+    }
+
+    /// Finishes the current frame into `out` and starts the next one.
+    fn cut(&mut self, out: &mut Vec<u8>) -> io::Result<()> {
+        let tail = self.frame.take().unwrap().finish()?;
+        self.compressed += tail.len() as u64;
+        out.extend(tail);
+        self.frames
+            .push((self.compressed as u32, self.uncompressed as u32));
+        self.compressed = 0;
+        self.uncompressed = 0;
+        self.frame = Some(self.new_frame()?);
+        Ok(())
+    }
+
+    fn drain(&mut self, out: &mut Vec<u8>) {
+        let frame = self.frame.as_mut().unwrap().get_mut();
+        self.compressed += frame.len() as u64;
+        out.append(frame);
+    }
+
+    /// Frames the last indexed entry touches, up to the current one.
+    fn close_entry(&mut self) {
+        let current = self.frames.len() as u32;
+        if let Some(entry) = self.index.entries.last_mut() {
+            entry.frames = current - entry.frame + 1;
+        }
+    }
+
+    fn entry(&mut self, entry: &TarEntry, out: &mut Vec<u8>) -> io::Result<()> {
+        let padding = mem::replace(&mut self.padding, 0);
+        self.write(&ZEROS[..padding])?;
+        self.close_entry();
+        if self.uncompressed > 0 && self.uncompressed >= self.options.min_frame_size {
+            self.cut(out)?;
+        }
+        self.index.entries.push(IndexEntry {
+            name: normalize_path(entry.path_bytes()).to_vec(),
+            frame: self.frames.len() as u32,
+            offset: self.uncompressed,
+            frames: 1,
+        });
+        for item in encode::flat::entry_headers(entry) {
+            match item {
+                RawTarItem::Header(header) => {
+                    let padding = mem::replace(
+                        &mut self.padding,
+                        encode::raw::padding(header.entry_size()?),
+                    );
+                    self.write(&ZEROS[..padding])?;
+                    self.write(header.as_bytes())?;
+                }
+                RawTarItem::Chunk(bytes) => self.write(&bytes)?,
+                RawTarItem::EmptyHeader => self.write(&ZEROS[..512])?,
+            }
+        }
+        self.remaining = entry.size();
+        Ok(())
+    }
+
+    fn chunk(&mut self, mut bytes: Bytes, out: &mut Vec<u8>) -> io::Result<()> {
+        while !bytes.is_empty() {
+            if self.uncompressed >= MAX_FRAME_SIZE {
+                self.cut(out)?;
+            }
+            let room = (MAX_FRAME_SIZE - self.uncompressed).min(bytes.len() as u64);
+            let head = bytes.split_to(room as usize);
+            self.write(&head)?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self, out: &mut Vec<u8>) -> io::Result<()> {
+        let padding = mem::replace(&mut self.padding, 0);
+        self.write(&ZEROS[..padding])?;
+        self.close_entry();
+        self.write(&ZEROS)?;
+        let tail = self.frame.take().unwrap().finish()?;
+        self.compressed += tail.len() as u64;
+        out.extend(tail);
+        self.frames
+            .push((self.compressed as u32, self.uncompressed as u32));
+
+        let json = serde_json::to_vec(&self.index).map_err(io::Error::other)?;
+        let index = skippable_frame(SKIPPABLE_MAGIC, &json);
+        self.frames.push((index.len() as u32, 0));
+        out.extend(index);
+
+        let mut table = Vec::with_capacity(self.frames.len() * 8 + 9);
+        for (compressed, uncompressed) in &self.frames {
+            table.extend_from_slice(&compressed.to_le_bytes());
+            table.extend_from_slice(&uncompressed.to_le_bytes());
+        }
+        table.extend_from_slice(&(self.frames.len() as u32).to_le_bytes());
+        // Descriptor: no checksums.
+        table.push(0);
+        table.extend_from_slice(&SEEKABLE_MAGIC.to_le_bytes());
+        out.extend(skippable_frame(SEEK_TABLE_MAGIC, &table));
+        Ok(())
+    }
+}
+
+impl<E, S> Stream for Encoder<S>
+where
+    E: Debug + Send + Sync + 'static,
+    S: Stream<Item = TarItem, Error = Error<E>>,
+{
+    type Item = Bytes;
+    type Error = Error<E>;
+
+    fn poll(&mut self) -> Result<Async<Option<<Self as Stream>::Item>>, <Self as Stream>::Error> {
+        loop {
+            if self.done {
+                return Ok(Async::Ready(None));
+            }
+            let mut out = Vec::new();
+            match try_ready!(self.upstream.poll()) {
+                Some(TarItem::Entry(entry)) => {
+                    if self.remaining > 0 {
+                        return Err(Error::Format("entry body shorter than its header"));
+                    }
+                    self.entry(&entry, &mut out).map_err(Error::IoError)?;
+                }
+                Some(TarItem::Chunk(bytes)) => {
+                    if bytes.len() as u64 > self.remaining {
+                        return Err(Error::Format("entry body longer than its header"));
+                    }
+                    self.remaining -= bytes.len() as u64;
+                    self.chunk(bytes, &mut out).map_err(Error::IoError)?;
+                }
+                None => {
+                    if self.remaining > 0 {
+                        return Err(Error::Format("entry body shorter than its header"));
+                    }
+                    self.done = true;
+                    self.finish(&mut out).map_err(Error::IoError)?;
+                    return Ok(Async::Ready(Some(Bytes::from(out))));
+                }
+            }
+            self.drain(&mut out);
+            if !out.is_empty() {
+                return Ok(Async::Ready(Some(Bytes::from(out))));
+            }
+        }
+    }
+}
+
+/// Encodes flat items as a seekable `.tar.zst`.
+pub fn encode_seekable<E, S>(
+    items: S,
+    options: SeekableOptions,
+) -> Result<impl Stream<Item = Bytes, Error = Error<E>>, io::Error>
+where
+    E: Debug + Send + Sync + 'static,
+    S: Stream<Item = TarItem, Error = Error<E>>,
+{
+    Ok(Encoder {
+        upstream: items,
+        options,
+        frame: Some(zstd::stream::write::Encoder::new(
+            Vec::new(),
+            options.level,
+        )?),
+        frames: Vec::new(),
+        compressed: 0,
+        uncompressed: 0,
+        padding: 0,
+        remaining: 0,
+        index: Index::default(),
+        done: false,
+    })
+}
+
+/// A seekable `.tar.zst` opened for random access.
+pub struct Seekable<R> {
+    source: R,
+    /// Compressed offset of every frame, plus the end of the last one.
+    offsets: Vec<u64>,
+    index: Index,
+    /// Position of the last entry at every path, which takes precedence as
+    /// it does on extraction.
+    by_name: HashMap<Vec<u8>, usize>,
+}
+
+impl<R: RangeSource> Seekable<R> {
+    /// All entries, in archive order.
+    #[inline]
+    pub fn entries(&self) -> &[IndexEntry] {
+        &self.index.entries
+    }
+
+    pub fn entry(&self, name: &[u8]) -> Option<&IndexEntry> {
+        let index = *self.by_name.get(normalize_path(name))?;
+        self.index.entries.get(index)
+    }
+
+    /// Compressed byte range, as offset and length, of the frames holding
+    /// an entry.
+    pub fn frame_range(&self, name: &[u8]) -> Option<(u64, u64)> {
+        let entry = self.entry(name)?;
+        let start = *self.offsets.get(entry.frame as usize)?;
+        let end = *self.offsets.get((entry.frame + entry.frames) as usize)?;
+        Some((start, end - start))
+    }
+
+    /// Decodes a single entry, decompressing only its frames.
+    pub fn open_entry(
+        &mut self,
+        name: &[u8],
+    ) -> Option<
+        impl Future<
+            Item = full::Entry<impl Stream<Item = TarItem, Error = Error<io::Error>>>,
+            Error = Error<io::Error>,
+        >,
+    > {
+        let (start, len) = self.frame_range(name)?;
+        let mut skip = self.entry(name)?.offset;
+        let frames = self.source.read_range(start, len);
+        let tar = compression::decompress(frames, Some(Compression::Zstd))
+            .map(move |bytes| {
+                let n = skip.min(bytes.len() as u64);
+                skip -= n;
+                bytes.slice_from(n as usize)
+            })
+            .filter(|bytes| !bytes.is_empty());
+        Some(
+            full::decode_items(flat::decode_nested(tar))
+                .into_future()
+                .map_err(|(e, _)| e)
+                .and_then(|(entry, _)| entry.ok_or(Error::Format("entry missing from frame"))),
+        )
+    }
+
+    #[inline]
+    pub fn into_source(self) -> R {
+        self.source
+    }
+}
+
+fn parse_seek_table(table: &[u8], frames: usize) -> Option<Vec<u64>> {
+    let header = SKIPPABLE_HEADER_SIZE as usize;
+    if table.len() < header || table[..4] != SEEK_TABLE_MAGIC.to_le_bytes() {
+        return None;
+    }
+    let mut offsets = Vec::with_capacity(frames + 1);
+    let mut offset = 0;
+    offsets.push(0);
+    for i in 0..frames {
+        let pos = header + i * 8;
+        let mut compressed = [0; 4];
+        compressed.copy_from_slice(&table[pos..pos + 4]);
+        offset += u64::from(u32::from_le_bytes(compressed));
+        offsets.push(offset);
+    }
+    Some(offsets)
+}
+
+/// Opens a seekable `.tar.zst` of `size` bytes written by
+/// [`encode_seekable`], reading its seek table and index.
+pub fn open_seekable<R>(
+    mut source: R,
+    size: u64,
+) -> impl Future<Item = Seekable<R>, Error = Error<io::Error>>
+where
+    R: RangeSource,
+{
+    if size < SEEK_TABLE_FOOTER_SIZE {
+        return Either::A(future::err(Error::Format("not a seekable zstd archive")));
+    }
+    let footer = source
+        .read_range(size - SEEK_TABLE_FOOTER_SIZE, SEEK_TABLE_FOOTER_SIZE)
+        .concat2()
+        .map_err(Error::UpstreamError);
+    Either::B(
+        footer
+            .and_then(move |footer| {
+                let mut word = [0; 4];
+                word.copy_from_slice(&footer[5..9]);
+                if u32::from_le_bytes(word) != SEEKABLE_MAGIC || footer[4] & 0x80 != 0 {
+                    return Err(Error::Format("not a seekable zstd archive"));
+                }
+                word.copy_from_slice(&footer[..4]);
+                let frames = u32::from_le_bytes(word) as u64;
+                let table_size = SKIPPABLE_HEADER_SIZE + frames * 8 + SEEK_TABLE_FOOTER_SIZE;
+                if frames == 0 || table_size > size {
+                    return Err(Error::Format("invalid zstd seek table"));
+                }
+                let table = source.read_range(size - table_size, table_size);
+                Ok((source, frames as usize, table))
+            })
+            .and_then(|(mut source, frames, table)| {
+                table
+                    .concat2()
+                    .map_err(Error::UpstreamError)
+                    .and_then(move |table| {
+                        let offsets = parse_seek_table(&table, frames)
+                            .ok_or(Error::Format("invalid zstd seek table"))?;
+                        // The index is the last frame listed.
+                        let start = offsets[frames - 1];
+                        let index = source.read_range(start, offsets[frames] - start);
+                        Ok((source, offsets, index))
+                    })
+            })
+            .and_then(|(source, offsets, index)| {
+                index
+                    .concat2()
+                    .map_err(Error::UpstreamError)
+                    .and_then(move |frame| {
+                        let header = SKIPPABLE_HEADER_SIZE as usize;
+                        if frame.len() < header || frame[..4] != SKIPPABLE_MAGIC.to_le_bytes() {
+                            return Err(Error::Format("seekable zstd index missing"));
+                        }
+                        let index: Index = serde_json::from_slice(&frame[header..])
+                            .map_err(|_| Error::Format("invalid seekable zstd index"))?;
+                        let by_name = index
+                            .entries
+                            .iter()
+                            .enumerate()
+                            .map(|(i, entry)| (entry.name.clone(), i))
+                            .collect();
+                        Ok(Seekable {
+                            source,
+                            offsets,
+                            index,
+                            by_name,
+                        })
+                    })
+            }),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::stream;
+
+    fn items() -> Vec<TarItem> {
+        let mut items = vec![TarItem::Entry(TarEntry::new(
+            tar::EntryType::Directory,
+            "data/",
+        ))];
+        for (path, body) in &[("data/a", &b"first file"[..]), ("data/b", b"second file")] {
+            let mut entry = TarEntry::new(tar::EntryType::Regular, *path);
+            entry.set_size(body.len() as u64);
+            items.push(TarItem::Entry(entry));
+            items.push(TarItem::Chunk(Bytes::from(*body)));
+        }
+        items
+    }
+
+    fn read(archive: Bytes, name: &[u8]) -> Bytes {
+        let size = archive.len() as u64;
+        let mut seekable = open_seekable(archive, size).wait().unwrap();
+        seekable
+            .open_entry(name)
+            .unwrap()
+            .wait()
+            .unwrap()
+            .concat2()
+            .wait()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_seekable() {
+        for min_frame_size in &[0, 1 << 20] {
+            let mut options = SeekableOptions::new();
+            options.set_min_frame_size(*min_frame_size);
+            let archive = encode_seekable(stream::iter_ok::<_, Error<()>>(items()), options)
+                .unwrap()
+                .concat2()
+                .wait()
+                .unwrap();
+
+            // A plain zstd decoder sees the tarball.
+            let tar = zstd::stream::decode_all(archive.as_ref()).unwrap();
+            let mut plain = tar::Archive::new(tar.as_slice());
+            assert_eq!(plain.entries().unwrap().count(), 3);
+
+            assert_eq!(read(archive.clone(), b"data/a").as_ref(), b"first file");
+            assert_eq!(read(archive.clone(), b"./data/b").as_ref(), b"second file");
+
+            let size = archive.len() as u64;
+            let seekable = open_seekable(archive, size).wait().unwrap();
+            let frames: Vec<u32> = seekable.entries().iter().map(|e| e.frame).collect();
+            if *min_frame_size == 0 {
+                assert_eq!(frames, vec![0, 1, 2]);
+            } else {
+                assert_eq!(frames, vec![0, 0, 0]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_seekable_names() {
+        let mut items = items();
+        for (path, body) in &[(&b"data/\xff"[..], &b"odd"[..]), (b"data/a", b"replaced")] {
+            let mut entry = TarEntry::new(tar::EntryType::Regular, *path);
+            entry.set_size(body.len() as u64);
+            items.push(TarItem::Entry(entry));
+            items.push(TarItem::Chunk(Bytes::from(*body)));
+        }
+        let archive = encode_seekable(
+            stream::iter_ok::<_, Error<()>>(items),
+            SeekableOptions::new(),
+        )
+        .unwrap()
+        .concat2()
+        .wait()
+        .unwrap();
+
+        assert_eq!(read(archive.clone(), b"data/\xff").as_ref(), b"odd");
+        // The last entry at a path wins.
+        assert_eq!(read(archive, b"data/a").as_ref(), b"replaced");
+    }
+}
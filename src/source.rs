@@ -0,0 +1,120 @@
+//! Random access to archive bytes.
+//!
+//! Decoders only ever see a forward stream of `Bytes`. Some features need to go
+//! back to data that has already passed by (hardlink bodies, indexed archives);
+//! they take a [`RangeSource`] that can produce any byte range on demand.
+
+use bytes::{Bytes, BytesMut};
+use futures::{prelude::*, stream, try_ready};
+use std::io::{self, SeekFrom};
+use std::path::{Path, PathBuf};
+use tokio_fs::file::{File, OpenFuture};
+use tokio_io::AsyncRead;
+
+const READ_CHUNK: usize = 64 * 1024;
+
+/// Something that can deliver an arbitrary byte range as a stream of chunks.
+pub trait RangeSource {
+    type Body: Stream<Item = Bytes, Error = io::Error>;
+
+    /// Streams `len` bytes starting at `offset`.
+    ///
+    /// The body fails with `UnexpectedEof` if the source is shorter than requested.
+    fn read_range(&mut self, offset: u64, len: u64) -> Self::Body;
+}
+
+/// Archive kept in memory.
+impl RangeSource for Bytes {
+    type Body = stream::Once<Bytes, io::Error>;
+
+    fn read_range(&mut self, offset: u64, len: u64) -> Self::Body {
+        let end = offset.saturating_add(len);
+        stream::once(if end <= self.len() as u64 {
+            Ok(self.slice(offset as usize, end as usize))
+        } else {
+            Err(io::ErrorKind::UnexpectedEof.into())
+        })
+    }
+}
+
+/// Archive stored in a local file; every range opens its own handle.
+#[derive(Clone, Debug)]
+pub struct FileSource {
+    path: PathBuf,
+}
+
+impl FileSource {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        FileSource {
+            path: path.as_ref().to_owned(),
+        }
+    }
+
+    #[inline]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl RangeSource for FileSource {
+    type Body = FileRange;
+
+    fn read_range(&mut self, offset: u64, len: u64) -> Self::Body {
+        FileRange {
+            state: FileRangeState::Opening(File::open(self.path.clone())),
+            file: None,
+            offset,
+            remaining: len,
+        }
+    }
+}
+
+enum FileRangeState {
+    Opening(OpenFuture<PathBuf>),
+    Seeking,
+    Reading,
+}
+
+pub struct FileRange {
+    state: FileRangeState,
+    file: Option<File>,
+    offset: u64,
+    remaining: u64,
+}
+
+impl Stream for FileRange {
+    type Item = Bytes;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Result<Async<Option<Bytes>>, io::Error> {
+        loop {
+            match self.state {
+                FileRangeState::Opening(ref mut f) => {
+                    self.file = Some(try_ready!(f.poll()));
+                    self.state = FileRangeState::Seeking;
+                }
+                FileRangeState::Seeking => {
+                    let file = self.file.as_mut().unwrap();
+                    try_ready!(file.poll_seek(SeekFrom::Start(self.offset)));
+                    self.state = FileRangeState::Reading;
+                }
+                FileRangeState::Reading => {
+                    if self.remaining == 0 {
+                        self.file = None;
+                        return Ok(Async::Ready(None));
+                    }
+                    let file = self.file.as_mut().unwrap();
+                    let mut buf = BytesMut::new();
+                    buf.resize(std::cmp::min(self.remaining, READ_CHUNK as u64) as usize, 0);
+                    let n = try_ready!(file.poll_read(buf.as_mut()));
+                    if n == 0 {
+                        return Err(io::ErrorKind::UnexpectedEof.into());
+                    }
+                    buf.truncate(n);
+                    self.remaining -= n as u64;
+                    return Ok(Async::Ready(Some(buf.freeze())));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,202 @@
+//! Tar-to-tar rewriting.
+//!
+//! [`transform`] runs a closure over every entry of a flat item stream. The
+//! closure may edit the entry in place, drop it, or insert new entries in
+//! front of it. Bodies of kept entries are forwarded as the original chunks,
+//! so rewriting metadata costs no copies and no temporary files.
+
+pub mod edit;
+pub mod normalize;
+#[cfg(feature = "regex")]
+pub mod path;
+
+use crate::decode::flat::{self, TarEntry, TarItem};
+use crate::decode::Error;
+use crate::encode;
+use bytes::Bytes;
+use futures::{prelude::*, try_ready};
+use std::collections::VecDeque;
+use std::fmt::Debug;
+
+/// What happens to an entry after the closure has seen it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    /// Write the entry, as edited, followed by its original body.
+    Keep,
+    /// Leave out the entry and its body.
+    Drop,
+}
+
+/// Entries to insert in front of the current one.
+#[derive(Default)]
+pub struct Inserts(Vec<TarItem>);
+
+impl Inserts {
+    /// Queues `entry` with an in-memory body; its size is set to match.
+    pub fn push(&mut self, mut entry: TarEntry, body: Bytes) -> &mut Self {
+        entry.set_size(body.len() as u64);
+        self.0.push(TarItem::Entry(entry));
+        if !body.is_empty() {
+            self.0.push(TarItem::Chunk(body));
+        }
+        self
+    }
+}
+
+struct Transform<S, F> {
+    upstream: S,
+    f: F,
+    pending: VecDeque<TarItem>,
+    dropping: bool,
+}
+
+impl<E, S, F> Stream for Transform<S, F>
+where
+    E: Debug + Send + Sync + 'static,
+    S: Stream<Item = TarItem, Error = Error<E>>,
+    F: FnMut(&mut TarEntry, &mut Inserts) -> Action,
+{
+    type Item = TarItem;
+    type Error = Error<E>;
+
+    fn poll(&mut self) -> Result<Async<Option<<Self as Stream>::Item>>, <Self as Stream>::Error> {
+        loop {
+            if let Some(item) = self.pending.pop_front() {
+                return Ok(Async::Ready(Some(item)));
+            }
+            match try_ready!(self.upstream.poll()) {
+                Some(TarItem::Entry(mut entry)) => {
+                    let mut inserts = Inserts::default();
+                    let action = (self.f)(&mut entry, &mut inserts);
+                    self.pending.extend(inserts.0);
+                    self.dropping = action == Action::Drop;
+                    if !self.dropping {
+                        self.pending.push_back(TarItem::Entry(entry));
+                    }
+                }
+                Some(TarItem::Chunk(bytes)) => {
+                    if !self.dropping {
+                        return Ok(Async::Ready(Some(TarItem::Chunk(bytes))));
+                    }
+                }
+                None => return Ok(Async::Ready(None)),
+            }
+        }
+    }
+}
+
+/// Applies `f` to every entry of a flat item stream.
+///
+/// `f` must not change the size of an entry it keeps, since the original
+/// body follows it. To add entries at the end of the archive, chain them to
+/// the returned stream.
+pub fn transform<E, S, F>(items: S, f: F) -> impl Stream<Item = TarItem, Error = Error<E>>
+where
+    E: Debug + Send + Sync + 'static,
+    S: Stream<Item = TarItem, Error = Error<E>>,
+    F: FnMut(&mut TarEntry, &mut Inserts) -> Action,
+{
+    Transform {
+        upstream: items,
+        f,
+        pending: VecDeque::new(),
+        dropping: false,
+    }
+}
+
+/// Decodes a tar byte stream, applies `f` to every entry and encodes the
+/// result again.
+pub fn rewrite<TarStream, F>(
+    upstream: TarStream,
+    f: F,
+) -> impl Stream<Item = Bytes, Error = Error<TarStream::Error>>
+where
+    TarStream: Stream<Item = Bytes>,
+    TarStream::Error: Debug + Send + Sync + 'static,
+    F: FnMut(&mut TarEntry, &mut Inserts) -> Action,
+{
+    encode::flat::encode_tar(transform(flat::decode_tar(upstream), f))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::stream;
+
+    fn items() -> Vec<TarItem> {
+        let mut keep = TarEntry::new(tar::EntryType::Regular, "keep");
+        keep.set_size(4).set_uid(1000);
+        let mut drop = TarEntry::new(tar::EntryType::Regular, "drop");
+        drop.set_size(3);
+        vec![
+            TarItem::Entry(keep),
+            TarItem::Chunk(Bytes::from_static(b"body")),
+            TarItem::Entry(drop),
+            TarItem::Chunk(Bytes::from_static(b"old")),
+        ]
+    }
+
+    #[test]
+    fn test_transform() {
+        let input = items();
+        let body_ptr = match input[1] {
+            TarItem::Chunk(ref bytes) => bytes.as_ptr(),
+            _ => unreachable!(),
+        };
+
+        let output = transform(stream::iter_ok::<_, Error<()>>(input), |entry, inserts| {
+            if entry.path_bytes() == b"drop" {
+                inserts.push(
+                    TarEntry::new(tar::EntryType::Regular, "new"),
+                    Bytes::from_static(b"fresh"),
+                );
+                return Action::Drop;
+            }
+            entry.set_uid(0);
+            Action::Keep
+        })
+        .collect()
+        .wait()
+        .unwrap();
+
+        match (&output[0], &output[1], &output[2], &output[3]) {
+            (
+                TarItem::Entry(keep),
+                TarItem::Chunk(body),
+                TarItem::Entry(new),
+                TarItem::Chunk(fresh),
+            ) => {
+                assert_eq!(keep.uid(), 0);
+                assert_eq!(body.as_ptr(), body_ptr);
+                assert_eq!(new.path_bytes(), b"new");
+                assert_eq!(new.size(), 5);
+                assert_eq!(fresh.as_ref(), b"fresh");
+            }
+            other => panic!("unexpected items: {:?}", other),
+        }
+        assert_eq!(output.len(), 4);
+    }
+
+    #[test]
+    fn test_rewrite() {
+        let tar = encode::flat::encode_tar(stream::iter_ok::<_, Error<()>>(items()))
+            .concat2()
+            .wait()
+            .unwrap();
+        let rewritten = rewrite(stream::once::<_, ()>(Ok(tar)), |entry, _| {
+            entry.set_mode(0o600);
+            Action::Keep
+        })
+        .concat2()
+        .wait()
+        .unwrap();
+
+        let mut archive = tar::Archive::new(rewritten.as_ref());
+        let modes: Vec<_> = archive
+            .entries()
+            .unwrap()
+            .map(|entry| entry.unwrap().header().mode().unwrap())
+            .collect();
+        assert_eq!(modes, vec![0o600, 0o600]);
+    }
+}
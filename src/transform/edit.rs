@@ -0,0 +1,438 @@
+//! Deleting and replacing entries by path, like `tar --delete` and `tar -u`.
+//!
+//! [`edit`] works on flat items, so extension headers are not items of their
+//! own: PAX and GNU long name headers of a removed entry go with it, and
+//! those of a replacement are generated from its metadata when it is
+//! encoded. [`edit_tar`] works on raw items instead and copies every entry
+//! it keeps, extension headers included, as it was.
+
+use crate::decode::flat::{TarEntry, TarItem};
+use crate::decode::hardlink::normalize_path;
+use crate::decode::raw::{self, RawTarItem};
+use crate::decode::Error;
+use crate::encode;
+use bytes::Bytes;
+use futures::{prelude::*, stream, try_ready};
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::fmt::Debug;
+
+/// Body of a replacement entry.
+pub type Body<E> = Box<dyn Stream<Item = Bytes, Error = Error<E>> + Send>;
+
+/// Paths to delete or replace.
+pub struct Edits<E>
+where
+    E: Debug + Send + Sync + 'static,
+{
+    deletes: HashSet<Vec<u8>>,
+    replaces: BTreeMap<Vec<u8>, Option<(TarEntry, Body<E>)>>,
+}
+
+impl<E> Default for Edits<E>
+where
+    E: Debug + Send + Sync + 'static,
+{
+    fn default() -> Self {
+        Edits {
+            deletes: HashSet::new(),
+            replaces: BTreeMap::new(),
+        }
+    }
+}
+
+impl<E> Edits<E>
+where
+    E: Debug + Send + Sync + 'static,
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Removes every entry at `path`, and below it if it is a directory.
+    pub fn delete<P: AsRef<[u8]>>(&mut self, path: P) -> &mut Self {
+        self.deletes.insert(normalize_path(path.as_ref()).to_vec());
+        self
+    }
+
+    /// Writes `entry` and `body` in place of the first entry at `path` and
+    /// drops any later ones; if there is none, they are added at the end.
+    ///
+    /// The size of `entry` must be the length of `body`.
+    pub fn replace<P: AsRef<[u8]>>(
+        &mut self,
+        path: P,
+        entry: TarEntry,
+        body: Body<E>,
+    ) -> &mut Self {
+        self.replaces
+            .insert(normalize_path(path.as_ref()).to_vec(), Some((entry, body)));
+        self
+    }
+
+    fn deleted(&self, path: &[u8]) -> bool {
+        if self.deletes.is_empty() {
+            return false;
+        }
+        // The path itself or any of its parents.
+        path.iter()
+            .enumerate()
+            .filter(|(_, b)| **b == b'/')
+            .map(|(i, _)| &path[..i])
+            .chain(Some(path))
+            .any(|prefix| self.deletes.contains(prefix))
+    }
+}
+
+struct Edit<S, E>
+where
+    E: Debug + Send + Sync + 'static,
+{
+    upstream: stream::Fuse<S>,
+    edits: Edits<E>,
+    /// Leaving out the body of a removed or replaced entry.
+    dropping: bool,
+    body: Option<Body<E>>,
+}
+
+impl<E, S> Edit<S, E>
+where
+    E: Debug + Send + Sync + 'static,
+{
+    /// Starts a replacement; returns its entry.
+    fn start(&mut self, replacement: (TarEntry, Body<E>)) -> TarItem {
+        let (entry, body) = replacement;
+        self.body = Some(body);
+        TarItem::Entry(entry)
+    }
+}
+
+impl<E, S> Stream for Edit<S, E>
+where
+    E: Debug + Send + Sync + 'static,
+    S: Stream<Item = TarItem, Error = Error<E>>,
+{
+    type Item = TarItem;
+    type Error = Error<E>;
+
+    fn poll(&mut self) -> Result<Async<Option<<Self as Stream>::Item>>, <Self as Stream>::Error> {
+        loop {
+            if let Some(ref mut body) = self.body {
+                match try_ready!(body.poll()) {
+                    Some(bytes) => return Ok(Async::Ready(Some(TarItem::Chunk(bytes)))),
+                    None => self.body = None,
+                }
+            }
+            match try_ready!(self.upstream.poll()) {
+                Some(TarItem::Entry(entry)) => {
+                    let path = normalize_path(entry.path_bytes());
+                    self.dropping = self.edits.deleted(path);
+                    if self.dropping {
+                        continue;
+                    }
+                    if let Some(slot) = self.edits.replaces.get_mut(path) {
+                        self.dropping = true;
+                        if let Some(replacement) = slot.take() {
+                            return Ok(Async::Ready(Some(self.start(replacement))));
+                        }
+                        continue;
+                    }
+                    return Ok(Async::Ready(Some(TarItem::Entry(entry))));
+                }
+                Some(TarItem::Chunk(bytes)) => {
+                    if !self.dropping {
+                        return Ok(Async::Ready(Some(TarItem::Chunk(bytes))));
+                    }
+                }
+                None => {
+                    let next = self.edits.replaces.values_mut().find_map(Option::take);
+                    return Ok(Async::Ready(
+                        next.map(|replacement| self.start(replacement)),
+                    ));
+                }
+            }
+        }
+    }
+}
+
+/// Applies `edits` to a flat item stream in a single pass.
+///
+/// Hardlinks are left as they are, so links to a deleted entry dangle, as
+/// they do with `tar --delete`.
+pub fn edit<E, S>(items: S, edits: Edits<E>) -> impl Stream<Item = TarItem, Error = Error<E>>
+where
+    E: Debug + Send + Sync + 'static,
+    S: Stream<Item = TarItem, Error = Error<E>>,
+{
+    Edit {
+        upstream: items.fuse(),
+        edits,
+        dropping: false,
+        body: None,
+    }
+}
+
+/// Path of an entry, from the extension headers that precede its header.
+fn group_path(group: &[RawTarItem], header: &tar::Header) -> Vec<u8> {
+    let mut path = header.path_bytes().into_owned();
+    let mut kind = None;
+    for item in group {
+        match item {
+            RawTarItem::Header(extension) => kind = Some(extension.entry_type()),
+            RawTarItem::Chunk(body) => match kind {
+                Some(tar::EntryType::GNULongName) => {
+                    let end = body.iter().position(|b| *b == 0).unwrap_or(body.len());
+                    path = body[..end].to_vec();
+                }
+                Some(tar::EntryType::XHeader) => {
+                    if let Some(value) = raw::pax_value(body, b"path") {
+                        path = value.to_vec();
+                    }
+                }
+                _ => (),
+            },
+            RawTarItem::EmptyHeader => (),
+        }
+    }
+    path
+}
+
+struct RawEdit<S, E>
+where
+    E: Debug + Send + Sync + 'static,
+{
+    upstream: stream::Fuse<S>,
+    edits: Edits<E>,
+    /// Extension headers, and their bodies, of the entry to come.
+    group: Vec<RawTarItem>,
+    /// Body chunks belong to the last extension header.
+    in_group: bool,
+    /// Leaving out the body of a removed or replaced entry.
+    dropping: bool,
+    queue: VecDeque<RawTarItem>,
+    body: Option<Body<E>>,
+    ended: bool,
+}
+
+impl<E, S> RawEdit<S, E>
+where
+    E: Debug + Send + Sync + 'static,
+{
+    /// Queues the headers of a replacement and starts its body.
+    fn start(&mut self, replacement: (TarEntry, Body<E>)) {
+        let (entry, body) = replacement;
+        self.queue.extend(encode::flat::entry_headers(&entry));
+        self.body = Some(body);
+    }
+
+    fn header(&mut self, header: tar::Header) {
+        // Global headers apply to everything after them and are kept.
+        if header.entry_type().is_pax_global_extensions() {
+            self.in_group = false;
+            self.dropping = false;
+            self.queue.push_back(RawTarItem::Header(header));
+            return;
+        }
+        if raw::is_extension(&header) {
+            self.group.push(RawTarItem::Header(header));
+            self.in_group = true;
+            return;
+        }
+        self.in_group = false;
+        let path = group_path(&self.group, &header);
+        let path = normalize_path(&path);
+        let group = std::mem::take(&mut self.group);
+        self.dropping = self.edits.deleted(path);
+        if self.dropping {
+            return;
+        }
+        if let Some(slot) = self.edits.replaces.get_mut(path) {
+            self.dropping = true;
+            if let Some(replacement) = slot.take() {
+                self.start(replacement);
+            }
+            return;
+        }
+        self.queue.extend(group);
+        self.queue.push_back(RawTarItem::Header(header));
+    }
+}
+
+impl<E, S> Stream for RawEdit<S, E>
+where
+    E: Debug + Send + Sync + 'static,
+    S: Stream<Item = RawTarItem, Error = Error<E>>,
+{
+    type Item = RawTarItem;
+    type Error = Error<E>;
+
+    fn poll(&mut self) -> Result<Async<Option<<Self as Stream>::Item>>, <Self as Stream>::Error> {
+        loop {
+            if let Some(item) = self.queue.pop_front() {
+                return Ok(Async::Ready(Some(item)));
+            }
+            if let Some(ref mut body) = self.body {
+                match try_ready!(body.poll()) {
+                    Some(bytes) => return Ok(Async::Ready(Some(RawTarItem::Chunk(bytes)))),
+                    None => self.body = None,
+                }
+            }
+            if self.ended {
+                match self.edits.replaces.values_mut().find_map(Option::take) {
+                    Some(replacement) => {
+                        self.start(replacement);
+                        continue;
+                    }
+                    None => return Ok(Async::Ready(None)),
+                }
+            }
+            match try_ready!(self.upstream.poll()) {
+                Some(RawTarItem::Header(header)) => self.header(header),
+                Some(RawTarItem::Chunk(bytes)) => {
+                    if self.in_group {
+                        self.group.push(RawTarItem::Chunk(bytes));
+                    } else if !self.dropping {
+                        return Ok(Async::Ready(Some(RawTarItem::Chunk(bytes))));
+                    }
+                }
+                // The end-of-archive marker; whatever follows is ignored.
+                Some(RawTarItem::EmptyHeader) | None => {
+                    if !self.group.is_empty() {
+                        return Err(Error::Format("extension header without an entry"));
+                    }
+                    self.ended = true;
+                }
+            }
+        }
+    }
+}
+
+/// Applies `edits` to a tar byte stream in a single pass.
+///
+/// Kept entries are copied block for block, with their PAX and GNU
+/// extension headers; only replacements are encoded. Global PAX headers
+/// stay where they are.
+pub fn edit_tar<TarStream>(
+    upstream: TarStream,
+    edits: Edits<TarStream::Error>,
+) -> impl Stream<Item = Bytes, Error = Error<TarStream::Error>>
+where
+    TarStream: Stream<Item = Bytes>,
+    TarStream::Error: Debug + Send + Sync + 'static,
+{
+    encode::raw::encode_tar(RawEdit {
+        upstream: raw::decode_tar(upstream).fuse(),
+        edits,
+        group: Vec::new(),
+        in_group: false,
+        dropping: false,
+        queue: VecDeque::new(),
+        body: None,
+        ended: false,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_edit_tar() {
+        let mut items = Vec::new();
+        for (path, body) in &[
+            ("dir/", ""),
+            ("dir/a", "a"),
+            ("dir/sub/b", "b"),
+            ("keep", "keep"),
+            ("log", "old"),
+        ] {
+            let entry_type = if path.ends_with('/') {
+                tar::EntryType::Directory
+            } else {
+                tar::EntryType::Regular
+            };
+            let mut entry = TarEntry::new(entry_type, *path);
+            entry.set_size(body.len() as u64);
+            // Long enough for a PAX header of its own.
+            if *path == "dir/a" {
+                entry.set_path_bytes(format!("dir/{}", "a".repeat(200)));
+            }
+            items.push(TarItem::Entry(entry));
+            if !body.is_empty() {
+                items.push(TarItem::Chunk(Bytes::from(*body)));
+            }
+        }
+        // Entries that a round trip through flat items would alter: a GNU
+        // long name and a PAX record that the decoder does not keep.
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(1);
+        header.set_mode(0o644);
+        header.set_mtime(0);
+        builder
+            .append_data(&mut header, format!("long/{}", "l".repeat(150)), &b"l"[..])
+            .unwrap();
+        let record = b"15 comment=hey\n";
+        let mut pax = tar::Header::new_ustar();
+        pax.set_entry_type(tar::EntryType::XHeader);
+        pax.set_path("PaxHeaders/kept").unwrap();
+        pax.set_size(record.len() as u64);
+        pax.set_cksum();
+        builder.append(&pax, &record[..]).unwrap();
+        let mut header = tar::Header::new_ustar();
+        header.set_size(1);
+        header.set_mode(0o644);
+        builder.append_data(&mut header, "kept", &b"k"[..]).unwrap();
+        let mut verbatim = builder.into_inner().unwrap();
+        verbatim.truncate(verbatim.len() - 1024);
+
+        let encoded = encode::flat::encode_tar(stream::iter_ok::<_, Error<()>>(items))
+            .concat2()
+            .wait()
+            .unwrap();
+        let mut tar = verbatim.clone();
+        tar.extend_from_slice(&encoded);
+        let tar = Bytes::from(tar);
+
+        let mut edits = Edits::new();
+        let mut log = TarEntry::new(tar::EntryType::Regular, "log");
+        log.set_size(3);
+        let mut added = TarEntry::new(tar::EntryType::Regular, "added");
+        added.set_size(0);
+        edits
+            .delete("./dir")
+            .replace("log", log, Box::new(stream::once(Ok(Bytes::from("new")))))
+            .replace("added", added, Box::new(stream::empty()));
+        let edited = edit_tar(stream::once::<_, ()>(Ok(tar)), edits)
+            .concat2()
+            .wait()
+            .unwrap();
+
+        let mut archive = tar::Archive::new(edited.as_ref());
+        let entries: Vec<_> = archive
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let mut entry = entry.unwrap();
+                let mut body = String::new();
+                std::io::Read::read_to_string(&mut entry, &mut body).unwrap();
+                (entry.path_bytes().into_owned(), body)
+            })
+            .collect();
+        assert_eq!(
+            entries,
+            vec![
+                (
+                    format!("long/{}", "l".repeat(150)).into_bytes(),
+                    "l".to_string()
+                ),
+                (b"kept".to_vec(), "k".to_string()),
+                (b"keep".to_vec(), "keep".to_string()),
+                (b"log".to_vec(), "new".to_string()),
+                (b"added".to_vec(), String::new()),
+            ]
+        );
+        assert!(edited.starts_with(&verbatim));
+        // Nothing is left of the PAX header of the long path.
+        assert!(!edited.windows(4).any(|w| w == b"aaaa"));
+    }
+}
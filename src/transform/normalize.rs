@@ -0,0 +1,321 @@
+//! Reproducible archives.
+//!
+//! [`normalize`] strips everything from an item stream that depends on the
+//! machine or the moment an archive was built: owners, timestamps newer than
+//! `SOURCE_DATE_EPOCH`, umask-dependent modes and the order in which the
+//! filesystem returned directory entries. Feeding the result to the encoder
+//! gives byte-identical archives for identical inputs.
+
+use crate::decode::flat::{TarEntry, TarItem};
+use crate::decode::hardlink::normalize_path;
+use crate::decode::Error;
+use bytes::Bytes;
+use futures::future::Either;
+use futures::{prelude::*, try_ready};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{env, mem, vec};
+
+#[derive(Clone, Debug)]
+pub struct Normalize {
+    source_date_epoch: Option<SystemTime>,
+    sort: bool,
+}
+
+impl Default for Normalize {
+    fn default() -> Self {
+        Normalize {
+            source_date_epoch: None,
+            sort: true,
+        }
+    }
+}
+
+impl Normalize {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Takes the mtime clamp from the `SOURCE_DATE_EPOCH` environment
+    /// variable; a missing or malformed value leaves mtimes alone.
+    pub fn from_env() -> Self {
+        let epoch = env::var("SOURCE_DATE_EPOCH")
+            .ok()
+            .and_then(|value| value.trim().parse::<u64>().ok())
+            .map(|secs| UNIX_EPOCH + Duration::from_secs(secs));
+        let mut normalize = Self::default();
+        normalize.set_source_date_epoch(epoch);
+        normalize
+    }
+
+    #[inline]
+    pub fn source_date_epoch(&self) -> Option<SystemTime> {
+        self.source_date_epoch
+    }
+
+    /// Mtimes later than this are set to it.
+    #[inline]
+    pub fn set_source_date_epoch(&mut self, epoch: Option<SystemTime>) -> &mut Self {
+        self.source_date_epoch = epoch;
+        self
+    }
+
+    #[inline]
+    pub fn sort(&self) -> bool {
+        self.sort
+    }
+
+    /// Whether to sort entries by path, which buffers the whole archive in
+    /// memory. Defaults to `true`.
+    #[inline]
+    pub fn set_sort(&mut self, sort: bool) -> &mut Self {
+        self.sort = sort;
+        self
+    }
+
+    /// Normalizes the metadata of a single entry. Usable on its own as a
+    /// [`transform`](super::transform) closure when the input order is
+    /// already deterministic.
+    pub fn apply(&self, entry: &mut TarEntry) {
+        if let Some(epoch) = self.source_date_epoch {
+            if entry.mtime() > epoch {
+                entry.set_mtime(epoch);
+            }
+        }
+        entry
+            .set_uid(0)
+            .set_gid(0)
+            .set_uname(None)
+            .set_gname(None)
+            .set_atime(None)
+            .set_ctime(None);
+
+        let mode = match entry.entry_type() {
+            tar::EntryType::Symlink => 0o777,
+            tar::EntryType::Directory => 0o755,
+            _ if entry.mode() & 0o111 != 0 => 0o755,
+            _ => 0o644,
+        };
+        entry.set_mode(mode);
+
+        if !entry.xattrs().is_empty() {
+            let mut xattrs = entry.xattrs().to_vec();
+            xattrs.sort();
+            entry.set_xattrs(xattrs);
+        }
+    }
+}
+
+/// Path order of normalized archives: component by component, so that a
+/// directory's members come right after it, as in a walk of the tree.
+pub fn path_order(a: &[u8], b: &[u8]) -> Ordering {
+    a.split(|c| *c == b'/').cmp(b.split(|c| *c == b'/'))
+}
+
+/// Sorts buffered entries by [`path_order`].
+///
+/// A hardlink may end up in front of the entry carrying the data. The first
+/// of the group in path order then takes over the data, and the original
+/// holder becomes a link to it.
+fn sort_entries(mut entries: Vec<(TarEntry, Vec<Bytes>)>) -> Vec<(TarEntry, Vec<Bytes>)> {
+    entries.sort_by(|(a, _), (b, _)| {
+        path_order(
+            normalize_path(a.path_bytes()),
+            normalize_path(b.path_bytes()),
+        )
+    });
+
+    let index: HashMap<Vec<u8>, usize> = entries
+        .iter()
+        .enumerate()
+        .map(|(i, (entry, _))| (normalize_path(entry.path_bytes()).to_vec(), i))
+        .collect();
+    // Data holders that moved, by their original path.
+    let mut moved: HashMap<Vec<u8>, Vec<u8>> = HashMap::new();
+
+    for i in 0..entries.len() {
+        if entries[i].0.entry_type() != tar::EntryType::Link {
+            continue;
+        }
+        let target = match entries[i].0.link_bytes() {
+            Some(link) => normalize_path(link).to_vec(),
+            None => continue,
+        };
+        if let Some(holder) = moved.get(&target) {
+            let holder = holder.clone();
+            entries[i].0.set_link_bytes(Some(holder));
+            continue;
+        }
+        let j = match index.get(&target) {
+            Some(&j) if j > i => j,
+            _ => continue,
+        };
+
+        let path = entries[i].0.path_bytes().to_vec();
+        let (link, holder) = {
+            let (front, back) = entries.split_at_mut(j);
+            (&mut front[i], &mut back[0])
+        };
+        let mut data = holder.0.clone();
+        data.set_path_bytes(path.clone());
+        mem::swap(&mut link.1, &mut holder.1);
+        holder
+            .0
+            .set_entry_type(tar::EntryType::Link)
+            .set_link_bytes(Some(path.clone()))
+            .set_size(0);
+        link.0 = data;
+        moved.insert(target, path);
+    }
+    entries
+}
+
+enum State {
+    Collecting(Vec<(TarEntry, Vec<Bytes>)>),
+    Emitting(vec::IntoIter<(TarEntry, Vec<Bytes>)>, vec::IntoIter<Bytes>),
+}
+
+struct Normalized<S> {
+    upstream: S,
+    normalize: Normalize,
+    state: State,
+}
+
+impl<E, S> Stream for Normalized<S>
+where
+    E: Debug + Send + Sync + 'static,
+    S: Stream<Item = TarItem, Error = Error<E>>,
+{
+    type Item = TarItem;
+    type Error = Error<E>;
+
+    fn poll(&mut self) -> Result<Async<Option<<Self as Stream>::Item>>, <Self as Stream>::Error> {
+        loop {
+            match self.state {
+                State::Collecting(ref mut entries) => match try_ready!(self.upstream.poll()) {
+                    Some(TarItem::Entry(mut entry)) => {
+                        self.normalize.apply(&mut entry);
+                        entries.push((entry, Vec::new()));
+                    }
+                    Some(TarItem::Chunk(bytes)) => match entries.last_mut() {
+                        Some((_, body)) => body.push(bytes),
+                        None => return Err(Error::Format("body chunk without an entry")),
+                    },
+                    None => {
+                        let entries = sort_entries(mem::take(entries));
+                        self.state = State::Emitting(entries.into_iter(), Vec::new().into_iter());
+                    }
+                },
+                State::Emitting(ref mut entries, ref mut body) => {
+                    if let Some(bytes) = body.next() {
+                        return Ok(Async::Ready(Some(TarItem::Chunk(bytes))));
+                    }
+                    return Ok(Async::Ready(entries.next().map(|(entry, chunks)| {
+                        *body = chunks.into_iter();
+                        TarItem::Entry(entry)
+                    })));
+                }
+            }
+        }
+    }
+}
+
+/// Normalizes a flat item stream for reproducible output.
+///
+/// Without sorting, entries are edited as they pass. With sorting, nothing is
+/// emitted until the upstream ends.
+pub fn normalize<E, S>(
+    items: S,
+    normalize: Normalize,
+) -> impl Stream<Item = TarItem, Error = Error<E>>
+where
+    E: Debug + Send + Sync + 'static,
+    S: Stream<Item = TarItem, Error = Error<E>>,
+{
+    if normalize.sort {
+        Either::A(Normalized {
+            upstream: items,
+            normalize,
+            state: State::Collecting(Vec::new()),
+        })
+    } else {
+        Either::B(super::transform(items, move |entry, _| {
+            normalize.apply(entry);
+            super::Action::Keep
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::encode;
+    use futures::stream;
+
+    fn archive(order: &[&str]) -> Bytes {
+        let items = order.iter().flat_map(|path| {
+            let mut entry = TarEntry::new(tar::EntryType::Regular, *path);
+            entry
+                .set_size(1)
+                .set_uid(1000)
+                .set_uname(Some(b"builder".to_vec()))
+                .set_mode(0o775)
+                .set_mtime(UNIX_EPOCH + Duration::from_secs(2_000_000_000))
+                .set_atime(Some(SystemTime::now()));
+            if *path == "b" {
+                entry
+                    .set_entry_type(tar::EntryType::Link)
+                    .set_link_bytes(Some(b"c".to_vec()))
+                    .set_size(0);
+                vec![TarItem::Entry(entry)]
+            } else {
+                vec![
+                    TarItem::Entry(entry),
+                    TarItem::Chunk(Bytes::from(path.as_bytes())),
+                ]
+            }
+        });
+        let mut config = Normalize::new();
+        config.set_source_date_epoch(Some(UNIX_EPOCH + Duration::from_secs(1_000)));
+        let items = normalize(
+            stream::iter_ok::<_, Error<()>>(items.collect::<Vec<_>>()),
+            config,
+        );
+        encode::flat::encode_tar(items).concat2().wait().unwrap()
+    }
+
+    #[test]
+    fn test_normalize() {
+        let tar = archive(&["c", "a", "b"]);
+        assert_eq!(tar, archive(&["a", "c", "b"]));
+
+        let mut archive = tar::Archive::new(tar.as_ref());
+        let entries: Vec<_> = archive
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                let header = entry.header();
+                assert_eq!(header.uid().unwrap(), 0);
+                assert_eq!(header.username().unwrap(), Some(""));
+                assert_eq!(header.mtime().unwrap(), 1_000);
+                assert_eq!(header.mode().unwrap(), 0o755);
+                (
+                    String::from_utf8_lossy(&entry.path_bytes()).into_owned(),
+                    header.entry_type(),
+                    entry.link_name_bytes().map(|l| l.into_owned()),
+                )
+            })
+            .collect();
+        assert_eq!(
+            entries,
+            vec![
+                ("a".to_string(), tar::EntryType::Regular, None),
+                ("b".to_string(), tar::EntryType::Regular, None),
+                ("c".to_string(), tar::EntryType::Link, Some(b"b".to_vec())),
+            ]
+        );
+    }
+}
@@ -0,0 +1,517 @@
+//! Path rewriting in the manner of `tar --strip-components` and `--transform`.
+//!
+//! A [`PathRewrite`] first strips leading components, then applies its
+//! substitution rules in order, then adds a prefix. Hardlink targets are
+//! archive paths too and go through the same steps; symlink targets are only
+//! touched by rules that ask for it.
+
+use crate::decode::flat::{TarEntry, TarItem};
+use crate::decode::hardlink::normalize_path;
+use crate::decode::Error;
+use failure::Fail;
+use futures::{prelude::*, try_ready};
+use regex::bytes::{Regex, RegexBuilder};
+use std::collections::HashMap;
+use std::fmt::Debug;
+
+#[derive(Debug, Fail)]
+pub enum ParseError {
+    #[fail(display = "invalid transform expression: {}", 0)]
+    Syntax(&'static str),
+    #[fail(display = "invalid regex: {}", 0)]
+    Regex(regex::Error),
+}
+
+/// A sed-like substitution.
+#[derive(Clone, Debug)]
+pub struct Rule {
+    regex: Regex,
+    replacement: Vec<u8>,
+    global: bool,
+    regular: bool,
+    symlink: bool,
+    hardlink: bool,
+}
+
+/// Splits `s` on unescaped `delim`, unescaping it.
+fn split_expr(s: &str, delim: char) -> Vec<String> {
+    let mut parts = vec![String::new()];
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(next) if next == delim => parts.last_mut().unwrap().push(next),
+                Some(next) => {
+                    parts.last_mut().unwrap().push('\\');
+                    parts.last_mut().unwrap().push(next);
+                }
+                None => parts.last_mut().unwrap().push('\\'),
+            },
+            c if c == delim => parts.push(String::new()),
+            c => parts.last_mut().unwrap().push(c),
+        }
+    }
+    parts
+}
+
+/// Converts a POSIX basic regex, as `tar --transform` takes, to the regex
+/// crate's syntax. `\(`, `\)`, `\{` and `\}` group and count, and GNU's `\+`,
+/// `\?` and `\|` repeat and alternate; bare, these match themselves, as does
+/// `*` where nothing precedes it. Backslashes in brackets are literal.
+fn bre(pattern: &str) -> String {
+    let mut out = String::with_capacity(pattern.len());
+    let mut chars = pattern.chars().peekable();
+    // Whether a `*` here would have nothing to repeat.
+    let mut start = true;
+    while let Some(c) = chars.next() {
+        let was_start = start;
+        start = false;
+        match c {
+            '\\' => match chars.next() {
+                Some(c @ ('(' | ')' | '{' | '}' | '+' | '?' | '|')) => {
+                    out.push(c);
+                    start = c == '(' || c == '|';
+                }
+                Some(other) => {
+                    out.push('\\');
+                    out.push(other);
+                }
+                None => out.push_str("\\\\"),
+            },
+            '(' | ')' | '{' | '}' | '+' | '?' | '|' => {
+                out.push('\\');
+                out.push(c);
+            }
+            '*' if was_start => out.push_str("\\*"),
+            '^' if was_start => {
+                out.push(c);
+                start = true;
+            }
+            '[' => {
+                out.push('[');
+                if chars.peek() == Some(&'^') {
+                    out.push(chars.next().unwrap());
+                }
+                // A leading `]` is part of the set.
+                if chars.peek() == Some(&']') {
+                    chars.next();
+                    out.push_str("\\]");
+                }
+                while let Some(c) = chars.next() {
+                    match c {
+                        ']' => break,
+                        // Character classes such as `[:alpha:]` pass through.
+                        '[' if chars.peek() == Some(&':') => {
+                            out.push('[');
+                            for c in chars.by_ref() {
+                                out.push(c);
+                                if c == ']' {
+                                    break;
+                                }
+                            }
+                        }
+                        '\\' | '[' | '&' | '~' => {
+                            out.push('\\');
+                            out.push(c);
+                        }
+                        c => out.push(c),
+                    }
+                }
+                out.push(']');
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Converts sed replacement syntax (`\1`, `&`) to the regex crate's.
+fn replacement(sed: &str) -> Vec<u8> {
+    let mut out = String::with_capacity(sed.len());
+    let mut chars = sed.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(d) if d.is_ascii_digit() => {
+                    out.push_str("${");
+                    out.push(d);
+                    out.push('}');
+                }
+                Some('$') => out.push_str("$$"),
+                Some(other) => out.push(other),
+                None => out.push('\\'),
+            },
+            '&' => out.push_str("${0}"),
+            '$' => out.push_str("$$"),
+            c => out.push(c),
+        }
+    }
+    out.into_bytes()
+}
+
+impl Rule {
+    /// Replaces the first match of `regex` in regular paths, symlink targets
+    /// and hardlink targets, as [`parse`](Rule::parse) does by default.
+    /// `regex` uses the regex crate's syntax and `replacement` its `$1`.
+    pub fn new(regex: &str, replacement: &str) -> Result<Rule, ParseError> {
+        Ok(Rule {
+            regex: Regex::new(regex).map_err(ParseError::Regex)?,
+            replacement: replacement.as_bytes().to_vec(),
+            global: false,
+            regular: true,
+            symlink: true,
+            hardlink: true,
+        })
+    }
+
+    /// Parses a `tar --transform` expression such as `s,^usr/,opt/,g`.
+    ///
+    /// The pattern is a POSIX basic regex, as with `sed` and GNU tar: groups
+    /// are written `\(...\)` and intervals `\{m,n\}`, while bare parentheses,
+    /// braces, `+`, `?` and `|` match themselves. The replacement may refer
+    /// to groups as `\1` and to the match as `&`.
+    /// Flags: `g` replaces all matches, `i` ignores case, and `r`, `s`, `h`
+    /// (or `R`, `S`, `H` to exclude) choose whether regular names, symlink
+    /// targets and hardlink targets are rewritten; all three are by default.
+    pub fn parse(expr: &str) -> Result<Rule, ParseError> {
+        let mut chars = expr.chars();
+        if chars.next() != Some('s') {
+            return Err(ParseError::Syntax("expected 's'"));
+        }
+        let delim = chars
+            .next()
+            .ok_or(ParseError::Syntax("missing delimiter"))?;
+        let parts = split_expr(chars.as_str(), delim);
+        if parts.len() != 3 {
+            return Err(ParseError::Syntax("expected s/regex/replacement/flags"));
+        }
+
+        let mut builder = RegexBuilder::new(&bre(&parts[0]));
+        let mut rule = Rule {
+            regex: Regex::new("").unwrap(),
+            replacement: replacement(&parts[1]),
+            global: false,
+            regular: true,
+            symlink: true,
+            hardlink: true,
+        };
+        for flag in parts[2].chars() {
+            match flag {
+                'g' => rule.global = true,
+                'i' => {
+                    builder.case_insensitive(true);
+                }
+                'x' => (),
+                'r' | 'R' => rule.regular = flag == 'r',
+                's' | 'S' => rule.symlink = flag == 's',
+                'h' | 'H' => rule.hardlink = flag == 'h',
+                _ => return Err(ParseError::Syntax("unknown flag")),
+            }
+        }
+        rule.regex = builder.build().map_err(ParseError::Regex)?;
+        Ok(rule)
+    }
+
+    fn apply(&self, path: &[u8]) -> Vec<u8> {
+        if self.global {
+            self.regex.replace_all(path, self.replacement.as_slice())
+        } else {
+            self.regex.replace(path, self.replacement.as_slice())
+        }
+        .into_owned()
+    }
+}
+
+/// Which kind of path is being rewritten.
+#[derive(Clone, Copy)]
+enum Kind {
+    Regular,
+    Symlink,
+    Hardlink,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct PathRewrite {
+    strip_components: usize,
+    rules: Vec<Rule>,
+    prefix: Option<Vec<u8>>,
+}
+
+impl PathRewrite {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[inline]
+    pub fn strip_components(&self) -> usize {
+        self.strip_components
+    }
+
+    #[inline]
+    pub fn set_strip_components(&mut self, strip_components: usize) -> &mut Self {
+        self.strip_components = strip_components;
+        self
+    }
+
+    #[inline]
+    pub fn add_rule(&mut self, rule: Rule) -> &mut Self {
+        self.rules.push(rule);
+        self
+    }
+
+    #[inline]
+    pub fn prefix(&self) -> Option<&[u8]> {
+        self.prefix.as_deref()
+    }
+
+    /// Directory put in front of every rewritten path.
+    #[inline]
+    pub fn set_prefix<P: Into<Vec<u8>>>(&mut self, prefix: Option<P>) -> &mut Self {
+        self.prefix = prefix.map(Into::into);
+        self
+    }
+
+    fn rewrite(&self, path: &[u8], kind: Kind) -> Option<Vec<u8>> {
+        let mut path = match kind {
+            Kind::Symlink => path.to_vec(),
+            _ => {
+                let components: Vec<&[u8]> = normalize_path(path)
+                    .split(|b| *b == b'/')
+                    .filter(|c| !c.is_empty() && *c != b".")
+                    .skip(self.strip_components)
+                    .collect();
+                components.join(&b'/')
+            }
+        };
+        for rule in &self.rules {
+            let applies = match kind {
+                Kind::Regular => rule.regular,
+                Kind::Symlink => rule.symlink,
+                Kind::Hardlink => rule.hardlink,
+            };
+            if applies {
+                path = rule.apply(&path);
+            }
+        }
+        if path.is_empty() {
+            return None;
+        }
+        if let (Some(prefix), Kind::Regular) | (Some(prefix), Kind::Hardlink) =
+            (self.prefix.as_ref(), kind)
+        {
+            let mut prefixed = prefix.clone();
+            if !prefixed.ends_with(b"/") {
+                prefixed.push(b'/');
+            }
+            prefixed.extend_from_slice(&path);
+            path = prefixed;
+        }
+        Some(path)
+    }
+
+    /// Rewrites an entry path; `None` when nothing is left of it.
+    pub fn rewrite_path(&self, path: &[u8]) -> Option<Vec<u8>> {
+        self.rewrite(path, Kind::Regular)
+    }
+
+    /// Rewrites the path and link of `entry` in place. Returns `false`, with
+    /// the entry untouched, when the path or a hardlink target ends up empty.
+    pub fn apply(&self, entry: &mut TarEntry) -> bool {
+        let path = match self.rewrite(entry.path_bytes(), Kind::Regular) {
+            Some(path) => path,
+            None => return false,
+        };
+        let link = match (entry.link_bytes(), entry.entry_type()) {
+            (Some(link), tar::EntryType::Link) => match self.rewrite(link, Kind::Hardlink) {
+                Some(link) => Some(link),
+                None => return false,
+            },
+            (Some(link), tar::EntryType::Symlink) => Some(
+                self.rewrite(link, Kind::Symlink)
+                    .unwrap_or_else(|| link.to_vec()),
+            ),
+            (link, _) => link.map(<[u8]>::to_vec),
+        };
+        entry.set_path_bytes(path).set_link_bytes(link);
+        true
+    }
+}
+
+/// Output of [`rewrite_paths`]: items plus notices about problem entries.
+#[derive(Debug)]
+pub enum Rewritten {
+    Item(TarItem),
+    /// Nothing was left of the path or hardlink target; the entry and its
+    /// body were dropped. The entry is reported as it was before rewriting.
+    Emptied(TarEntry),
+    /// Two different original paths were rewritten to the same path. The
+    /// later entry still follows as an item.
+    Collision {
+        path: Vec<u8>,
+        original: Vec<u8>,
+        earlier: Vec<u8>,
+    },
+}
+
+impl Rewritten {
+    /// Drops notices, for feeding the result to an encoder.
+    pub fn into_item(self) -> Option<TarItem> {
+        match self {
+            Rewritten::Item(item) => Some(item),
+            _ => None,
+        }
+    }
+}
+
+struct RewritePaths<S> {
+    upstream: S,
+    rewrite: PathRewrite,
+    seen: HashMap<Vec<u8>, Vec<u8>>,
+    pending: Option<TarEntry>,
+    dropping: bool,
+}
+
+impl<E, S> Stream for RewritePaths<S>
+where
+    E: Debug + Send + Sync + 'static,
+    S: Stream<Item = TarItem, Error = Error<E>>,
+{
+    type Item = Rewritten;
+    type Error = Error<E>;
+
+    fn poll(&mut self) -> Result<Async<Option<<Self as Stream>::Item>>, <Self as Stream>::Error> {
+        if let Some(entry) = self.pending.take() {
+            return Ok(Async::Ready(Some(Rewritten::Item(TarItem::Entry(entry)))));
+        }
+        loop {
+            match try_ready!(self.upstream.poll()) {
+                Some(TarItem::Entry(mut entry)) => {
+                    let original = normalize_path(entry.path_bytes()).to_vec();
+                    if !self.rewrite.apply(&mut entry) {
+                        self.dropping = true;
+                        return Ok(Async::Ready(Some(Rewritten::Emptied(entry))));
+                    }
+                    self.dropping = false;
+                    let path = entry.path_bytes().to_vec();
+                    match self.seen.insert(path.clone(), original.clone()) {
+                        Some(earlier) if earlier != original => {
+                            self.pending = Some(entry);
+                            return Ok(Async::Ready(Some(Rewritten::Collision {
+                                path,
+                                original,
+                                earlier,
+                            })));
+                        }
+                        _ => return Ok(Async::Ready(Some(Rewritten::Item(TarItem::Entry(entry))))),
+                    }
+                }
+                Some(TarItem::Chunk(bytes)) => {
+                    if !self.dropping {
+                        return Ok(Async::Ready(Some(Rewritten::Item(TarItem::Chunk(bytes)))));
+                    }
+                }
+                None => return Ok(Async::Ready(None)),
+            }
+        }
+    }
+}
+
+/// Rewrites the paths of a flat item stream.
+///
+/// Remembers every rewritten path to detect collisions, so memory grows with
+/// the number of entries.
+pub fn rewrite_paths<E, S>(
+    items: S,
+    rewrite: PathRewrite,
+) -> impl Stream<Item = Rewritten, Error = Error<E>>
+where
+    E: Debug + Send + Sync + 'static,
+    S: Stream<Item = TarItem, Error = Error<E>>,
+{
+    RewritePaths {
+        upstream: items,
+        rewrite,
+        seen: HashMap::new(),
+        pending: None,
+        dropping: false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bytes::Bytes;
+    use futures::stream;
+
+    #[test]
+    fn test_rule() {
+        let rule = Rule::parse(r"s,^usr/\(lib\)\{0\,1\},opt/&-\1/,").unwrap();
+        assert_eq!(rule.apply(b"usr/lib/x"), b"opt/usr/lib-lib//x".to_vec());
+        let rule = Rule::parse(r"s,(a+)?|{b},[&],").unwrap();
+        assert_eq!(rule.apply(b"x(a+)?|{b}"), b"x[(a+)?|{b}]".to_vec());
+        assert_eq!(rule.apply(b"aab"), b"aab".to_vec());
+        let rule = Rule::parse(r"s/*[\.]x\+/-/").unwrap();
+        assert_eq!(rule.apply(br"a*\xx"), b"a-".to_vec());
+        let rule = Rule::parse(r"s/^[^]a[:digit:]]*//").unwrap();
+        assert_eq!(rule.apply(b"bc1]ad"), b"1]ad".to_vec());
+        let rule = Rule::parse("s/a/b/g").unwrap();
+        assert_eq!(rule.apply(b"banana"), b"bbnbnb".to_vec());
+        assert!(Rule::parse("s/a/b").is_err());
+    }
+
+    #[test]
+    fn test_rewrite_paths() {
+        let mut rewrite = PathRewrite::new();
+        rewrite
+            .set_strip_components(1)
+            .add_rule(Rule::parse("s/^bin$/sbin/").unwrap())
+            .set_prefix(Some("root"));
+
+        let mut link = TarEntry::new(tar::EntryType::Link, "pkg-2/bin");
+        link.set_link_bytes(Some(b"./pkg-1/tool".to_vec()));
+        let items = vec![
+            TarItem::Entry(TarEntry::new(tar::EntryType::Directory, "pkg-1/")),
+            TarItem::Entry({
+                let mut tool = TarEntry::new(tar::EntryType::Regular, "pkg-1/tool");
+                tool.set_size(2);
+                tool
+            }),
+            TarItem::Chunk(Bytes::from_static(b"hi")),
+            TarItem::Entry(link),
+            TarItem::Entry(TarEntry::new(tar::EntryType::Regular, "pkg-1/sbin")),
+        ];
+
+        let output: Vec<String> = rewrite_paths(stream::iter_ok::<_, Error<()>>(items), rewrite)
+            .map(|item| match item {
+                Rewritten::Item(TarItem::Entry(entry)) => format!(
+                    "{} {:?}",
+                    String::from_utf8_lossy(entry.path_bytes()),
+                    entry.link_bytes().map(String::from_utf8_lossy)
+                ),
+                Rewritten::Item(TarItem::Chunk(_)) => "chunk".to_string(),
+                Rewritten::Emptied(entry) => {
+                    format!("emptied {}", String::from_utf8_lossy(entry.path_bytes()))
+                }
+                Rewritten::Collision { path, earlier, .. } => format!(
+                    "collision {} {}",
+                    String::from_utf8_lossy(&path),
+                    String::from_utf8_lossy(&earlier)
+                ),
+            })
+            .collect()
+            .wait()
+            .unwrap();
+
+        assert_eq!(
+            output,
+            vec![
+                "emptied pkg-1/",
+                "root/tool None",
+                "chunk",
+                "root/sbin Some(\"root/tool\")",
+                "collision root/sbin pkg-2/bin",
+                "root/sbin None",
+            ]
+        );
+    }
+}
@@ -0,0 +1,363 @@
+//! Extraction of decoded entries into a directory.
+//!
+//! Filesystem calls go through `tokio-fs` (or the same blocking mechanism), so
+//! the returned futures must run on a tokio thread pool.
+
+use crate::blocking;
+use crate::decode::{flat, full, Error};
+use crate::incremental;
+use crate::Config;
+use bytes::Bytes;
+use futures::{future, prelude::*};
+use std::fmt::Debug;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// Joins an archive path onto `dst`, refusing paths that would escape it.
+///
+/// Leading `/` and `.` components are dropped, `..` is rejected.
+pub fn safe_join(dst: &Path, path: &Path) -> Option<PathBuf> {
+    let mut out = dst.to_path_buf();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => out.push(part),
+            Component::CurDir | Component::RootDir | Component::Prefix(_) => (),
+            Component::ParentDir => return None,
+        }
+    }
+    Some(out)
+}
+
+pub(crate) fn entry_path(dst: &Path, header: &flat::TarEntry) -> io::Result<PathBuf> {
+    let path = bytes_path(header.path_bytes())?;
+    safe_join(dst, path).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("entry path escapes destination: {:?}", path),
+        )
+    })
+}
+
+/// Path of raw archive bytes; only UTF-8 outside of unix.
+#[cfg(unix)]
+pub(crate) fn bytes_path(bytes: &[u8]) -> io::Result<&Path> {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+
+    Ok(Path::new(OsStr::from_bytes(bytes)))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn bytes_path(bytes: &[u8]) -> io::Result<&Path> {
+    flat::bytes2path(bytes)
+}
+
+/// Refuses a path below `dst` whose parent directories include a symlink.
+///
+/// The path text is already confined by [`safe_join`], but an earlier entry
+/// may have planted a symlink, e.g. `a -> /etc`, that a later `a/passwd`
+/// would write through. The last component is not checked; it is replaced.
+pub(crate) fn check_parents(dst: &Path, path: &Path) -> io::Result<()> {
+    let mut components = match path.strip_prefix(dst) {
+        Ok(rel) => rel.components(),
+        Err(_) => return Ok(()),
+    };
+    components.next_back();
+    let mut current = dst.to_path_buf();
+    for component in components {
+        current.push(component);
+        match fs::symlink_metadata(&current) {
+            Ok(ref meta) if meta.file_type().is_symlink() => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("entry path leads through a symlink: {:?}", current),
+                ))
+            }
+            Ok(_) => (),
+            // Nothing further down exists yet.
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Removes whatever is at `path`, if anything.
+pub(crate) fn remove_any(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(ref meta) if meta.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Makes room for a new non-directory entry at `path`.
+fn prepare_file(dst: &Path, path: &Path) -> io::Result<()> {
+    check_parents(dst, path)?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    remove_any(path)
+}
+
+pub(crate) fn set_attributes(
+    path: &Path,
+    header: &flat::TarEntry,
+    config: Config,
+) -> io::Result<()> {
+    let symlink = header.entry_type().is_symlink();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+
+        if config.preserve_permissions() && !symlink {
+            fs::set_permissions(path, fs::Permissions::from_mode(header.mode() & 0o7777))?;
+        }
+    }
+    if config.preserve_mtime() {
+        let mtime = filetime::FileTime::from_system_time(header.mtime());
+        if symlink {
+            filetime::set_symlink_file_times(path, mtime, mtime)?;
+        } else {
+            filetime::set_file_mtime(path, mtime)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+pub(crate) fn make_fifo(path: &Path, mode: u32) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    if unsafe { libc::mkfifo(c_path.as_ptr(), (mode & 0o777) as libc::mode_t) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn make_symlink(link: &Path, path: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(link, path)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn make_fifo(_path: &Path, _mode: u32) -> io::Result<()> {
+    Err(io::Error::other("fifo entries are not supported"))
+}
+
+#[cfg(not(unix))]
+fn make_symlink(_link: &Path, _path: &Path) -> io::Result<()> {
+    Err(io::Error::other("symlink entries are not supported"))
+}
+
+/// Creates a non-file entry (directory, link, fifo) synchronously.
+pub(crate) fn create_special(dst: &Path, path: &Path, header: &flat::TarEntry) -> io::Result<bool> {
+    let entry_type = header.entry_type();
+    if entry_type.is_dir() || incremental::is_dumpdir(header) {
+        check_parents(dst, path)?;
+        match fs::symlink_metadata(path) {
+            Ok(ref meta) if meta.is_dir() => (),
+            Ok(_) => {
+                fs::remove_file(path)?;
+                fs::create_dir_all(path)?;
+            }
+            Err(_) => fs::create_dir_all(path)?,
+        }
+    } else if entry_type.is_symlink() {
+        let link = header
+            .link_bytes()
+            .map(bytes_path)
+            .transpose()?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "symlink without target"))?;
+        prepare_file(dst, path)?;
+        make_symlink(link, path)?;
+    } else if entry_type.is_hard_link() {
+        let link = header
+            .link_bytes()
+            .map(bytes_path)
+            .transpose()?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "hardlink without target"))?;
+        let target = safe_join(dst, link).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "hardlink target escapes destination",
+            )
+        })?;
+        check_parents(dst, &target)?;
+        prepare_file(dst, path)?;
+        fs::hard_link(target, path)?;
+    } else if entry_type.is_fifo() {
+        prepare_file(dst, path)?;
+        make_fifo(path, header.mode())?;
+    } else {
+        return Ok(false);
+    }
+    Ok(true)
+}
+
+/// Extracts a single entry under `dst`, consuming its body.
+///
+/// Entry types that cannot be represented (devices, GNU extensions) are
+/// skipped, and GNU dumpdirs are created as plain directories. Hardlink
+/// targets must already exist under `dst`.
+pub fn unpack_entry<E, S>(
+    entry: full::Entry<S>,
+    dst: &Path,
+    config: Config,
+) -> Box<dyn Future<Item = (), Error = Error<E>> + Send>
+where
+    E: Debug + Send + Sync + 'static,
+    S: Stream<Item = flat::TarItem, Error = Error<E>> + Send + 'static,
+{
+    let header = entry.header().clone();
+    let path = match entry_path(dst, &header) {
+        Ok(path) => path,
+        Err(e) => return Box::new(future::err(Error::IoError(e))),
+    };
+
+    if !header.entry_type().is_file() {
+        let dst = dst.to_path_buf();
+        return Box::new(
+            blocking::run(move || {
+                if create_special(&dst, &path, &header)? && !header.entry_type().is_hard_link() {
+                    set_attributes(&path, &header, config)?;
+                }
+                Ok(())
+            })
+            .map_err(Error::IoError),
+        );
+    }
+
+    let (dst, file_path) = (dst.to_path_buf(), path.clone());
+    Box::new(
+        blocking::run(move || prepare_file(&dst, &file_path))
+            .and_then({
+                let path = path.clone();
+                move |_| tokio_fs::File::create(path)
+            })
+            .map_err(Error::IoError)
+            .and_then(|file| {
+                entry.fold(file, |file, chunk: Bytes| {
+                    tokio_io::io::write_all(file, chunk)
+                        .map(|(file, _)| file)
+                        .map_err(Error::IoError)
+                })
+            })
+            .and_then(move |file| {
+                drop(file);
+                blocking::run(move || set_attributes(&path, &header, config))
+                    .map_err(Error::IoError)
+            }),
+    )
+}
+
+/// Directory mtimes are clobbered by writing their contents, so they are
+/// collected during extraction and applied once at the end.
+#[derive(Clone, Default)]
+pub(crate) struct DirTimes(Arc<Mutex<Vec<(PathBuf, SystemTime)>>>);
+
+impl DirTimes {
+    pub(crate) fn record<S>(&self, dst: &Path, entry: &full::Entry<S>, config: Config)
+    where
+        S: Stream<Item = flat::TarItem>,
+        S::Error: Sync + Send + Debug + 'static,
+    {
+        let header = entry.header();
+        if config.preserve_mtime()
+            && (header.entry_type().is_dir() || incremental::is_dumpdir(header))
+        {
+            if let Ok(path) = entry_path(dst, header) {
+                self.0.lock().unwrap().push((path, header.mtime()));
+            }
+        }
+    }
+
+    pub(crate) fn apply(self) -> impl Future<Item = (), Error = io::Error> {
+        blocking::run(move || {
+            let mut dirs = self.0.lock().unwrap();
+            // Deepest first, so that setting a child does not touch its parent again.
+            while let Some((path, mtime)) = dirs.pop() {
+                filetime::set_file_mtime(&path, filetime::FileTime::from_system_time(mtime))?;
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Extracts every entry of `entries` under `dst`.
+pub fn unpack<E, S, T>(
+    entries: T,
+    dst: PathBuf,
+    config: Config,
+) -> impl Future<Item = (), Error = Error<E>>
+where
+    E: Debug + Send + Sync + 'static,
+    S: Stream<Item = flat::TarItem, Error = Error<E>> + Send + 'static,
+    T: Stream<Item = full::Entry<S>, Error = Error<E>>,
+{
+    let dir_times = DirTimes::default();
+    let recorder = dir_times.clone();
+    entries
+        .for_each(move |entry| {
+            recorder.record(&dst, &entry, config);
+            unpack_entry(entry, &dst, config)
+        })
+        .and_then(move |_| dir_times.apply().map_err(Error::IoError))
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::*;
+    use futures::stream;
+
+    #[test]
+    fn test_unpack_through_symlink() {
+        let base = std::env::temp_dir().join(format!("tar-async-unpack-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&base);
+        let (dst, outside) = (base.join("dst"), base.join("outside"));
+        fs::create_dir_all(&dst).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+
+        let header = || {
+            let mut header = tar::Header::new_gnu();
+            header.set_uid(0);
+            header.set_gid(0);
+            header.set_mtime(0);
+            header
+        };
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut link = header();
+        link.set_entry_type(tar::EntryType::Symlink);
+        link.set_size(0);
+        link.set_mode(0o777);
+        builder.append_link(&mut link, "a", &outside).unwrap();
+        let mut file = header();
+        file.set_size(4);
+        file.set_mode(0o644);
+        builder
+            .append_data(&mut file, "a/passwd", &b"root"[..])
+            .unwrap();
+        let tar = Bytes::from(builder.into_inner().unwrap());
+
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        assert!(runtime
+            .block_on(unpack(
+                full::decode_tar(stream::once::<_, ()>(Ok(tar))),
+                dst.clone(),
+                Config::default(),
+            ))
+            .is_err());
+        assert!(fs::symlink_metadata(dst.join("a"))
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert!(!outside.join("passwd").exists());
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
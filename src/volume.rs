@@ -0,0 +1,737 @@
+//! GNU multi-volume archives.
+//!
+//! GNU tar splits an archive into volumes at block boundaries, possibly in
+//! the middle of a file's body. Every volume may start with a volume header
+//! (type `V`) carrying a label, and a volume that continues a file starts
+//! with a continuation header (type `M`) giving the file's name, the offset
+//! of the body part that follows and the size of the whole body. A file
+//! whose name does not fit in the continuation header gets a GNU long name
+//! header before it. Extended headers are never split from the header they
+//! describe.
+//!
+//! [`split_volumes`] cuts a tar byte stream into such volumes, and
+//! [`join_volumes`] turns a sequence of volumes back into the original
+//! stream, checking that each continuation picks up where the previous
+//! volume stopped.
+
+use crate::decode::raw::{is_extension, pax_value, SizeTracker};
+use crate::decode::Error;
+use bytes::{Bytes, BytesMut};
+use futures::{prelude::*, stream, try_ready};
+use std::fmt::Debug;
+use std::io;
+
+const BLOCK_SIZE: u64 = 512;
+const VOLUME_HEADER: u8 = b'V';
+const CONTINUATION: u8 = b'M';
+/// Name of GNU long name headers.
+const LONG_LINK: &[u8] = b"././@LongLink";
+
+#[derive(Clone, Debug)]
+pub struct VolumeOptions {
+    volume_size: u64,
+    label: Option<Vec<u8>>,
+}
+
+impl VolumeOptions {
+    /// Options for volumes of at most `volume_size` bytes.
+    ///
+    /// # Panics
+    ///
+    /// If `volume_size` is not a multiple of 512, or too small to hold the
+    /// volume and continuation headers and a block of data.
+    pub fn new(volume_size: u64) -> Self {
+        assert!(
+            volume_size.is_multiple_of(BLOCK_SIZE) && volume_size >= 3 * BLOCK_SIZE,
+            "invalid volume size"
+        );
+        VolumeOptions {
+            volume_size,
+            label: None,
+        }
+    }
+
+    #[inline]
+    pub fn volume_size(&self) -> u64 {
+        self.volume_size
+    }
+
+    #[inline]
+    pub fn label(&self) -> Option<&[u8]> {
+        self.label.as_deref()
+    }
+
+    /// Starts every volume with a volume header; from the second volume on,
+    /// GNU tar style, the label is followed by ` Volume <n>`.
+    #[inline]
+    pub fn set_label(&mut self, label: Option<Vec<u8>>) -> &mut Self {
+        self.label = label;
+        self
+    }
+}
+
+#[inline]
+fn padded(size: u64) -> u64 {
+    (size + BLOCK_SIZE - 1) & !(BLOCK_SIZE - 1)
+}
+
+/// Writes a GNU numeric field: octal, or base-256 if it does not fit.
+fn set_number(field: &mut [u8], value: u64) {
+    let octal = format!("{:0width$o}", value, width = field.len() - 1);
+    if octal.len() < field.len() {
+        field[..octal.len()].copy_from_slice(octal.as_bytes());
+        field[octal.len()] = 0;
+    } else {
+        for (i, byte) in field.iter_mut().rev().enumerate() {
+            *byte = if i < 8 { (value >> (8 * i)) as u8 } else { 0 };
+        }
+        field[0] |= 0x80;
+    }
+}
+
+fn number(field: &[u8]) -> Option<u64> {
+    if field.first().is_some_and(|b| b & 0x80 != 0) {
+        return Some(
+            field[1..]
+                .iter()
+                .fold(u64::from(field[0] & 0x7f), |n, b| (n << 8) | u64::from(*b)),
+        );
+    }
+    let digits: &[u8] = &field[..field.iter().position(|b| *b == 0).unwrap_or(field.len())];
+    let digits = std::str::from_utf8(digits).ok()?.trim();
+    u64::from_str_radix(digits, 8).ok()
+}
+
+/// The regular file whose body is being passed.
+struct File {
+    /// Full path, which may be longer than the header's own.
+    name: Vec<u8>,
+    header: tar::Header,
+    size: u64,
+    /// Body bytes passed so far.
+    offset: u64,
+}
+
+/// Follows the block structure of a tar byte stream.
+#[derive(Default)]
+struct Tracker {
+    /// Start of a header block.
+    header: BytesMut,
+    /// Bytes left of the current body, padding included.
+    remaining: u64,
+    /// Bytes left of the current body proper.
+    body: u64,
+    sizes: SizeTracker,
+    /// Type and body of the GNU long name or PAX header being read.
+    extension: Option<(u8, BytesMut)>,
+    /// Path given by the extended headers read so far.
+    long_name: Option<Vec<u8>>,
+    /// Whether extended headers were read whose entry header was not.
+    extending: bool,
+    file: Option<File>,
+}
+
+impl Tracker {
+    fn feed(&mut self, mut bytes: &[u8]) -> io::Result<()> {
+        while !bytes.is_empty() {
+            if self.remaining > 0 {
+                let len = self.remaining.min(bytes.len() as u64);
+                let body = &bytes[..self.body.min(len) as usize];
+                self.sizes.chunk(body);
+                if let Some((_, ref mut extension)) = self.extension {
+                    extension.extend_from_slice(body);
+                }
+                self.body -= body.len() as u64;
+                self.remaining -= len;
+                bytes = &bytes[len as usize..];
+                if let Some(ref mut file) = self.file {
+                    file.offset = (file.offset + len).min(file.size);
+                }
+                if self.remaining == 0 {
+                    self.file = None;
+                    self.end_extension();
+                }
+                continue;
+            }
+            let len = (BLOCK_SIZE as usize - self.header.len()).min(bytes.len());
+            self.header.extend_from_slice(&bytes[..len]);
+            bytes = &bytes[len..];
+            if self.header.len() < BLOCK_SIZE as usize {
+                continue;
+            }
+            let block = self.header.take();
+            if block.iter().all(|b| *b == 0) {
+                continue;
+            }
+            let mut header = tar::Header::new_old();
+            header.as_mut_bytes().copy_from_slice(&block);
+            let size = self.sizes.header(&header)?;
+            self.remaining = padded(size);
+            self.body = size;
+            if is_extension(&header) {
+                self.extending = true;
+                let kind = header.entry_type().as_byte();
+                if kind == b'L' || kind == b'x' {
+                    self.extension = Some((kind, BytesMut::new()));
+                }
+                if size == 0 {
+                    self.end_extension();
+                }
+                continue;
+            }
+            self.extending = false;
+            let name = self
+                .long_name
+                .take()
+                .unwrap_or_else(|| header.path_bytes().into_owned());
+            if size > 0 && header.entry_type().is_file() {
+                self.file = Some(File {
+                    name,
+                    header,
+                    size,
+                    offset: 0,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Takes the path out of the extended header just read, if any.
+    fn end_extension(&mut self) {
+        match self.extension.take() {
+            Some((b'L', body)) => {
+                let name = body.split(|b| *b == 0).next().unwrap_or(&body);
+                self.long_name = Some(name.to_vec());
+            }
+            Some((_, body)) => {
+                if let Some(path) = pax_value(&body, b"path") {
+                    self.long_name = Some(path.to_vec());
+                }
+            }
+            None => (),
+        }
+    }
+
+    /// The file whose body continues past the current position.
+    fn continued(&self) -> Option<&File> {
+        self.file.as_ref().filter(|_| self.remaining > 0)
+    }
+
+    #[inline]
+    fn in_header(&self) -> bool {
+        !self.header.is_empty()
+    }
+}
+
+fn volume_header(label: &[u8], number: u32) -> tar::Header {
+    let mut name = label.to_vec();
+    if number > 1 {
+        name.extend_from_slice(format!(" Volume {}", number).as_bytes());
+    }
+    let mut header = tar::Header::new_gnu();
+    {
+        let gnu = header.as_gnu_mut().unwrap();
+        let len = name.len().min(gnu.name.len());
+        gnu.name[..len].copy_from_slice(&name[..len]);
+    }
+    header.set_entry_type(tar::EntryType::new(VOLUME_HEADER));
+    header.set_size(0);
+    header.set_mode(0);
+    header.set_mtime(0);
+    header.set_cksum();
+    header
+}
+
+/// Headers that continue `file` in a new volume: a GNU long name header
+/// if its path does not fit, then the continuation header.
+fn continuation_headers(file: &File) -> BytesMut {
+    let original = &file.header;
+    let mut out = BytesMut::new();
+    let mut header = tar::Header::new_gnu();
+    let name_len = header.as_gnu().unwrap().name.len();
+    if file.name.len() > name_len {
+        let mut long = tar::Header::new_gnu();
+        long.as_gnu_mut().unwrap().name[..LONG_LINK.len()].copy_from_slice(LONG_LINK);
+        long.set_entry_type(tar::EntryType::GNULongName);
+        long.set_size(file.name.len() as u64 + 1);
+        long.set_mode(0o644);
+        long.set_uid(0);
+        long.set_gid(0);
+        long.set_mtime(0);
+        long.set_cksum();
+        out.extend_from_slice(long.as_bytes());
+        out.extend_from_slice(&file.name);
+        out.resize(
+            out.len() + padded(file.name.len() as u64 + 1) as usize - file.name.len(),
+            0,
+        );
+    }
+    {
+        let gnu = header.as_gnu_mut().unwrap();
+        let len = file.name.len().min(name_len);
+        gnu.name[..len].copy_from_slice(&file.name[..len]);
+        set_number(&mut gnu.offset, file.offset);
+        set_number(&mut gnu.realsize, file.size);
+    }
+    header.set_entry_type(tar::EntryType::new(CONTINUATION));
+    header.set_size(file.size - file.offset);
+    header.set_mode(original.mode().unwrap_or(0o644));
+    header.set_uid(original.uid().unwrap_or(0));
+    header.set_gid(original.gid().unwrap_or(0));
+    header.set_mtime(original.mtime().unwrap_or(0));
+    if let Ok(Some(name)) = original.username() {
+        header.set_username(name).ok();
+    }
+    if let Ok(Some(name)) = original.groupname() {
+        header.set_groupname(name).ok();
+    }
+    header.set_cksum();
+    out.extend_from_slice(header.as_bytes());
+    out
+}
+
+/// Output of [`split_volumes`].
+#[derive(Debug)]
+pub enum VolumeItem {
+    /// Start of the volume with this number, counting from 1; the chunks
+    /// up to the next one belong to it.
+    Volume(u32),
+    Chunk(Bytes),
+}
+
+struct SplitVolumes<S> {
+    upstream: stream::Fuse<S>,
+    options: VolumeOptions,
+    tracker: Tracker,
+    volume: u32,
+    /// Bytes in the current volume so far.
+    used: u64,
+    /// Input not yet passed on.
+    pending: Option<Bytes>,
+    /// Headers that start the current volume.
+    prefix: Option<Bytes>,
+    /// Header blocks read, with any extended headers before them and their
+    /// bodies, that have yet to make a whole entry header.
+    held: BytesMut,
+    /// A whole entry header, with its extended headers, to pass on in one
+    /// piece.
+    group: Option<Bytes>,
+}
+
+impl<S> SplitVolumes<S> {
+    /// Starts the next volume, continuing the current file unless `fresh`.
+    fn start_volume<E: Debug + Send + Sync + 'static>(
+        &mut self,
+        fresh: bool,
+    ) -> Result<VolumeItem, Error<E>> {
+        self.volume += 1;
+        self.used = 0;
+        let mut prefix = BytesMut::new();
+        if let Some(ref label) = self.options.label {
+            prefix.extend_from_slice(volume_header(label, self.volume).as_bytes());
+        }
+        if let (false, Some(file)) = (fresh, self.tracker.continued()) {
+            prefix.extend_from_slice(&continuation_headers(file));
+        }
+        if prefix.len() as u64 >= self.options.volume_size {
+            return Err(Error::Format("volume headers do not leave room for data"));
+        }
+        if !prefix.is_empty() {
+            self.prefix = Some(prefix.freeze());
+        }
+        Ok(VolumeItem::Volume(self.volume))
+    }
+}
+
+impl<E, S> Stream for SplitVolumes<S>
+where
+    E: Debug + Send + Sync + 'static,
+    S: Stream<Item = Bytes, Error = Error<E>>,
+{
+    type Item = VolumeItem;
+    type Error = Error<E>;
+
+    fn poll(&mut self) -> Result<Async<Option<<Self as Stream>::Item>>, <Self as Stream>::Error> {
+        if let Some(prefix) = self.prefix.take() {
+            self.used += prefix.len() as u64;
+            return Ok(Async::Ready(Some(VolumeItem::Chunk(prefix))));
+        }
+        loop {
+            if let Some(group) = self.group.take() {
+                if self.used + group.len() as u64 <= self.options.volume_size {
+                    self.used += group.len() as u64;
+                    return Ok(Async::Ready(Some(VolumeItem::Chunk(group))));
+                }
+                // Extended headers stay with the header they describe.
+                let label = self.options.label.as_ref().map_or(0, |_| BLOCK_SIZE);
+                if label + group.len() as u64 > self.options.volume_size {
+                    return Err(Error::Format("extended headers do not fit in a volume"));
+                }
+                self.group = Some(group);
+                return Ok(Async::Ready(Some(self.start_volume(true)?)));
+            }
+
+            let mut bytes = match self.pending.take() {
+                Some(bytes) => bytes,
+                None => loop {
+                    match try_ready!(self.upstream.poll()) {
+                        Some(ref bytes) if bytes.is_empty() => (),
+                        Some(bytes) => break bytes,
+                        None if self.held.is_empty() => return Ok(Async::Ready(None)),
+                        None => return Err(Error::UnexpectedEof),
+                    }
+                },
+            };
+
+            if self.volume == 0 || self.used == self.options.volume_size {
+                // Only start a volume once there is something to put into it.
+                self.pending = Some(bytes);
+                return Ok(Async::Ready(Some(self.start_volume(false)?)));
+            }
+
+            if self.tracker.remaining == 0 || self.tracker.extending {
+                // Header blocks and extension bodies are held until they
+                // make a whole entry header.
+                let len = if self.tracker.remaining > 0 {
+                    self.tracker.remaining
+                } else {
+                    BLOCK_SIZE - self.tracker.header.len() as u64
+                };
+                if bytes.len() as u64 > len {
+                    self.pending = Some(bytes.split_off(len as usize));
+                }
+                self.tracker.feed(&bytes).map_err(Error::IoError)?;
+                self.held.extend_from_slice(&bytes);
+                if !self.tracker.extending && !self.tracker.in_header() {
+                    self.group = Some(self.held.take().freeze());
+                }
+                continue;
+            }
+
+            let room = self.options.volume_size - self.used;
+            let len = room.min(self.tracker.remaining);
+            if bytes.len() as u64 > len {
+                self.pending = Some(bytes.split_off(len as usize));
+            }
+            self.tracker.feed(&bytes).map_err(Error::IoError)?;
+            self.used += bytes.len() as u64;
+            return Ok(Async::Ready(Some(VolumeItem::Chunk(bytes))));
+        }
+    }
+}
+
+/// Splits a tar byte stream into volumes of at most
+/// [`volume_size`](VolumeOptions::volume_size) bytes, as GNU tar's
+/// `--multi-volume` does.
+///
+/// Each volume starts with a [`VolumeItem::Volume`] marker; its chunks are
+/// the volume's contents. No volume is started without content, so the
+/// last one may be shorter. Fails if an entry's extended headers, or the
+/// headers continuing a file, leave no room in a volume.
+pub fn split_volumes<E, S>(
+    tar: S,
+    options: VolumeOptions,
+) -> impl Stream<Item = VolumeItem, Error = Error<E>>
+where
+    E: Debug + Send + Sync + 'static,
+    S: Stream<Item = Bytes, Error = Error<E>>,
+{
+    SplitVolumes {
+        upstream: tar.fuse(),
+        options,
+        tracker: Tracker::default(),
+        volume: 0,
+        used: 0,
+        pending: None,
+        prefix: None,
+        held: BytesMut::new(),
+        group: None,
+    }
+}
+
+struct JoinVolumes<V, S> {
+    volumes: stream::Fuse<V>,
+    current: Option<S>,
+    tracker: Tracker,
+    /// Start of the current volume, until its headers have been dealt with.
+    head: Option<BytesMut>,
+    /// What followed the headers.
+    tail: Option<Bytes>,
+}
+
+impl<E, V, S> JoinVolumes<V, S>
+where
+    E: Debug + Send + Sync + 'static,
+    V: Stream<Item = S, Error = E>,
+    S: Stream<Item = Bytes, Error = E>,
+{
+    /// Drops the volume and continuation headers at the start of `head`,
+    /// once it holds enough of the volume, or all of it if `end`.
+    fn process_head(&mut self, end: bool) -> Result<(), Error<E>> {
+        let head = self.head.as_mut().unwrap();
+        // Length of the headers to drop.
+        let mut pos = 0;
+        let mut label = false;
+        let mut long_name = false;
+        loop {
+            let block = pos + BLOCK_SIZE as usize;
+            if head.len() < block {
+                if !end {
+                    return Ok(());
+                }
+                if head.len() > pos || long_name {
+                    return Err(Error::UnexpectedEof);
+                }
+                break;
+            }
+            let mut header = tar::Header::new_old();
+            header.as_mut_bytes().copy_from_slice(&head[pos..block]);
+            let entry_type = header.entry_type();
+            if entry_type.as_byte() == VOLUME_HEADER && !label && pos == 0 {
+                label = true;
+                pos = block;
+                continue;
+            }
+            let file = match self.tracker.continued() {
+                Some(file) => file,
+                None if entry_type.as_byte() == CONTINUATION => {
+                    return Err(Error::Format("unexpected continuation header"))
+                }
+                None => break,
+            };
+            if entry_type == tar::EntryType::GNULongName && !long_name {
+                // The full name of the continued file.
+                long_name = true;
+                pos = block + padded(header.entry_size().map_err(Error::IoError)?) as usize;
+                continue;
+            }
+            let gnu = header
+                .as_gnu()
+                .filter(|_| entry_type.as_byte() == CONTINUATION)
+                .ok_or(Error::Format("volume does not continue the split file"))?;
+            if number(&gnu.offset) != Some(file.offset) || number(&gnu.realsize) != Some(file.size)
+            {
+                return Err(Error::Format("volume continues at the wrong offset"));
+            }
+            pos = block;
+            break;
+        }
+        let rest = self.head.take().unwrap().split_off(pos).freeze();
+        if !rest.is_empty() {
+            self.tail = Some(rest);
+        }
+        Ok(())
+    }
+}
+
+impl<E, V, S> Stream for JoinVolumes<V, S>
+where
+    E: Debug + Send + Sync + 'static,
+    V: Stream<Item = S, Error = E>,
+    S: Stream<Item = Bytes, Error = E>,
+{
+    type Item = Bytes;
+    type Error = Error<E>;
+
+    fn poll(&mut self) -> Result<Async<Option<<Self as Stream>::Item>>, <Self as Stream>::Error> {
+        loop {
+            if let Some(tail) = self.tail.take() {
+                self.tracker.feed(&tail).map_err(Error::IoError)?;
+                return Ok(Async::Ready(Some(tail)));
+            }
+            let item = match self.current {
+                Some(ref mut current) => try_ready!(current.poll().map_err(Error::UpstreamError)),
+                None => match try_ready!(self.volumes.poll().map_err(Error::UpstreamError)) {
+                    Some(volume) => {
+                        if self.tracker.in_header() || self.tracker.extending {
+                            return Err(Error::Format("volume ends inside a header"));
+                        }
+                        self.current = Some(volume);
+                        self.head = Some(BytesMut::new());
+                        continue;
+                    }
+                    None => return Ok(Async::Ready(None)),
+                },
+            };
+            match item {
+                Some(bytes) => match self.head {
+                    Some(ref mut head) => {
+                        head.extend_from_slice(&bytes);
+                        self.process_head(false)?;
+                    }
+                    None => {
+                        self.tracker.feed(&bytes).map_err(Error::IoError)?;
+                        return Ok(Async::Ready(Some(bytes)));
+                    }
+                },
+                None => {
+                    self.current = None;
+                    if self.head.is_some() {
+                        self.process_head(true)?;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Joins volumes, in order, back into the tar byte stream they were split
+/// from. Volume headers are dropped; a continuation header must match the
+/// offset and size of the file the previous volume left unfinished.
+pub fn join_volumes<E, V, S>(volumes: V) -> impl Stream<Item = Bytes, Error = Error<E>>
+where
+    E: Debug + Send + Sync + 'static,
+    V: Stream<Item = S, Error = E>,
+    S: Stream<Item = Bytes, Error = E>,
+{
+    JoinVolumes {
+        volumes: volumes.fuse(),
+        current: None,
+        tracker: Tracker::default(),
+        head: None,
+        tail: None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn split(tar: &[u8], options: VolumeOptions) -> Vec<Vec<u8>> {
+        let items = split_volumes(
+            stream::iter_ok::<_, Error<()>>(tar.chunks(700).map(Bytes::from).collect::<Vec<_>>()),
+            options,
+        )
+        .collect()
+        .wait()
+        .unwrap();
+        let mut volumes = Vec::new();
+        for item in items {
+            match item {
+                VolumeItem::Volume(number) => {
+                    volumes.push(Vec::new());
+                    assert_eq!(number as usize, volumes.len());
+                }
+                VolumeItem::Chunk(bytes) => volumes.last_mut().unwrap().extend_from_slice(&bytes),
+            }
+        }
+        volumes
+    }
+
+    fn join(volumes: Vec<Vec<u8>>) -> Result<Vec<u8>, Error<()>> {
+        let volumes = volumes
+            .into_iter()
+            .map(|volume| stream::once::<_, ()>(Ok(Bytes::from(volume))));
+        join_volumes(stream::iter_ok(volumes))
+            .concat2()
+            .wait()
+            .map(|tar| tar.to_vec())
+    }
+
+    #[test]
+    fn test_volumes() {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (name, size) in &[("a", 100), ("big", 3000), ("c", 10)] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(*size as u64);
+            header.set_cksum();
+            let body: Vec<u8> = (0..*size).map(|i| i as u8).collect();
+            builder
+                .append_data(&mut header, name, body.as_slice())
+                .unwrap();
+        }
+        let tar = builder.into_inner().unwrap();
+
+        let mut options = VolumeOptions::new(2048);
+        options.set_label(Some(b"backup".to_vec()));
+        let volumes = split(&tar, options);
+        assert!(volumes.len() > 2);
+        for volume in &volumes {
+            assert!(volume.len() <= 2048 && volume.len().is_multiple_of(512));
+            assert_eq!(volume[156], VOLUME_HEADER);
+        }
+        // The header of "big" fills the first volume, its body goes on over
+        // the next two.
+        for (volume, offset) in &[(1, 0), (2, 1024)] {
+            let continuation = tar::Header::from_byte_slice(&volumes[*volume][512..1024]);
+            assert_eq!(continuation.entry_type().as_byte(), CONTINUATION);
+            assert_eq!(continuation.path_bytes().as_ref(), b"big");
+            assert_eq!(continuation.entry_size().unwrap(), 3000 - offset);
+            assert_eq!(
+                number(&continuation.as_gnu().unwrap().offset),
+                Some(*offset)
+            );
+        }
+        assert_eq!(join(volumes.clone()).unwrap(), tar);
+
+        let mut swapped = volumes;
+        swapped.swap(1, 2);
+        assert!(join(swapped).is_err());
+    }
+
+    #[test]
+    fn test_extended_headers() {
+        let long_name = "d".repeat(150);
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(2000);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, "a", &[1; 2000][..])
+            .unwrap();
+        {
+            // The size of "pax" is only in its extended header.
+            let tar = builder.get_mut();
+            let mut pax = tar::Header::new_ustar();
+            pax.set_entry_type(tar::EntryType::XHeader);
+            pax.set_path("PaxHeaders/pax").unwrap();
+            pax.set_size(13);
+            pax.set_cksum();
+            tar.extend_from_slice(pax.as_bytes());
+            tar.extend_from_slice(b"13 size=3000\n");
+            tar.resize(tar.len() + 512 - 13, 0);
+            let mut file = tar::Header::new_ustar();
+            file.set_path("pax").unwrap();
+            file.set_size(0);
+            file.set_cksum();
+            tar.extend_from_slice(file.as_bytes());
+            tar.extend((0..3000).map(|i| i as u8));
+            tar.resize(tar.len() + 72, 0);
+        }
+        let mut header = tar::Header::new_gnu();
+        header.set_size(2000);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, &long_name, &[2; 2000][..])
+            .unwrap();
+        let tar = builder.into_inner().unwrap();
+
+        let mut options = VolumeOptions::new(4096);
+        options.set_label(Some(b"backup".to_vec()));
+        let volumes = split(&tar, options);
+
+        // The extended header and the header it describes move on together.
+        assert_eq!(volumes[0].len(), 3072);
+        assert_eq!(volumes[1].len(), 4096);
+        assert_eq!(volumes[1][512 + 156], b'x');
+        assert_eq!(volumes[1][1536..1539], b"pax"[..]);
+        let continuation = tar::Header::from_byte_slice(&volumes[2][512..1024]);
+        assert_eq!(continuation.entry_type().as_byte(), CONTINUATION);
+        let gnu = continuation.as_gnu().unwrap();
+        assert_eq!(number(&gnu.realsize), Some(3000));
+        assert_eq!(number(&gnu.offset), Some(2048));
+
+        // Continuations of the long name carry it whole.
+        let continued: Vec<_> = volumes
+            .iter()
+            .filter(|volume| volume[512 + 156] == b'L')
+            .collect();
+        assert!(!continued.is_empty());
+        for volume in continued {
+            assert_eq!(volume[1024..1174], *long_name.as_bytes());
+            assert_eq!(volume[1536 + 156], CONTINUATION);
+        }
+
+        assert_eq!(join(volumes).unwrap(), tar);
+    }
+}